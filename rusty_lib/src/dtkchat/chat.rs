@@ -1,19 +1,21 @@
 /// RUSTY chat by baakeydow
+use std::collections::HashSet;
 use std::io::{Read, Write};
 
 use base64::{engine::general_purpose, Engine};
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use mongodb::{
     bson::{self, doc, to_document, Binary, Bson, Document},
-    options::FindOptions,
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
 };
 
 use crate::{
-    dtkchat::chat_utils::{get_chat_collection_name, get_chat_db_name},
+    dtkchat::chat_utils::{get_chat_collection_name, get_chat_db_name, get_chat_message_id, parse_mentions},
     dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_main_db, get_mongodb_uri},
+    dtkutils::dtk_error::DtkError,
 };
 
 use super::chat_model::{DtkChat, DtkChatMessage, DtkChatUser};
@@ -32,6 +34,34 @@ fn get_str_from_binary_string(binary_string: &str) -> Result<String, &'static st
     Ok(binary_string[binary_prefix.len()..binary_string.len() - 1].to_owned())
 }
 
+fn dtk_chat_from_document(chat_doc: Document) -> DtkChat {
+    let doc_messages = chat_doc.get("messages").unwrap().as_array().unwrap().to_vec();
+    let uncompressed_messages = doc_messages
+        .into_iter()
+        .map(|msg| {
+            let test_message = msg.as_document().unwrap().get("message").unwrap();
+            let compressed_data = general_purpose::STANDARD
+                .decode(get_str_from_binary_string(test_message.as_str().unwrap()).unwrap())
+                .map_err(|e| format!("{}", e))
+                .unwrap();
+            let mut decoder = GzDecoder::new(&compressed_data[..]);
+            let mut decompressed_data = vec![];
+            decoder.read_to_end(&mut decompressed_data).unwrap();
+
+            let decompressed_text = String::from_utf8(decompressed_data).unwrap();
+            let mut message: DtkChatMessage = bson::from_bson(msg).unwrap();
+            message.message = decompressed_text;
+            if message.id.is_empty() {
+                message.id = get_chat_message_id(&message.sender_id, &message.date, &message.message);
+            }
+            message
+        })
+        .collect::<Vec<DtkChatMessage>>();
+    let mut data: DtkChat = bson::from_bson(bson::Bson::Document(chat_doc)).unwrap();
+    data.messages = uncompressed_messages;
+    data
+}
+
 pub async fn get_all_chat_users() -> Vec<DtkChatUser> {
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let coll = client.database(&get_mongodb_main_db()).collection::<Document>("users");
@@ -62,36 +92,7 @@ pub async fn get_all_dtk_chat_for_user(user: DtkChatUser) -> Vec<DtkChat> {
         .build();
     let cursor = coll.find(filter, options).await.unwrap();
     let mut dtk_chat_data: Vec<DtkChat> = cursor
-        .map(|res| {
-            let chat_doc = res.clone().unwrap();
-            let doc_messages = chat_doc.get("messages").unwrap().as_array().unwrap().to_vec();
-            let uncompressed_messages = doc_messages
-                .into_iter()
-                .map(|msg| {
-                    // println!("msg: {:#?}", msg);
-                    let test_message = msg.as_document().unwrap().get("message").unwrap();
-                    let compressed_data = general_purpose::STANDARD
-                        .decode(&get_str_from_binary_string(test_message.as_str().unwrap()).unwrap())
-                        .map_err(|e| format!("{}", e))
-                        .unwrap();
-                    let mut decoder = GzDecoder::new(&compressed_data[..]);
-                    let mut decompressed_data = vec![];
-                    decoder.read_to_end(&mut decompressed_data).unwrap();
-
-                    let decompressed_text = String::from_utf8(decompressed_data).unwrap();
-                    let sender_id = msg.as_document().unwrap().get("sender_id").unwrap().as_str().unwrap();
-                    let date = msg.as_document().unwrap().get("date").unwrap().as_str().unwrap();
-                    DtkChatMessage {
-                        message: decompressed_text,
-                        sender_id: sender_id.to_string(),
-                        date: date.to_string(),
-                    }
-                })
-                .collect::<Vec<DtkChatMessage>>();
-            let mut data: DtkChat = bson::from_bson(bson::Bson::Document(res.unwrap())).unwrap();
-            data.messages = uncompressed_messages;
-            data
-        })
+        .map(|res| dtk_chat_from_document(res.unwrap()))
         .collect::<Vec<DtkChat>>()
         .await;
     if dtk_chat_data.is_empty() {
@@ -115,39 +116,137 @@ pub async fn get_all_dtk_chat_for_user(user: DtkChatUser) -> Vec<DtkChat> {
     dtk_chat_data
 }
 
-pub async fn create_dtk_chat_message(dtk_chat: DtkChat) {
+/// Stream every chat of a user, or a single channel, one decoded `DtkChat` at a time
+pub async fn get_dtk_chat_stream_for_user(user_id: &str, channel_id: Option<String>) -> impl Stream<Item = DtkChat> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_chat_db_name())
+        .collection::<Document>(get_chat_collection_name().as_str());
+    let mut filter = doc! { "users.id": user_id };
+    if let Some(channel_id) = channel_id {
+        filter.insert("channel_id", channel_id);
+    }
+    let options = FindOptions::builder()
+        .sort(mongodb::bson::doc! {"last_update": -1})
+        .build();
+    let cursor = coll.find(filter, options).await.unwrap();
+    cursor.map(|res| dtk_chat_from_document(res.unwrap()))
+}
+
+/// Fill the id of a new message and the users it mentions, the sender excluded
+fn with_message_meta(mut msg: DtkChatMessage, users: &[DtkChatUser]) -> DtkChatMessage {
+    if msg.id.is_empty() {
+        msg.id = get_chat_message_id(&msg.sender_id, &msg.date, &msg.message);
+    }
+    msg.mentions = parse_mentions(&msg.message, users)
+        .into_iter()
+        .filter(|user_id| *user_id != msg.sender_id)
+        .collect();
+    msg
+}
+
+/// Message as stored in mongo, with its text compressed
+fn to_stored_message(msg: &DtkChatMessage) -> Bson {
+    let bson = Bson::Binary(Binary {
+        bytes: gzip_text(&msg.message),
+        subtype: mongodb::bson::spec::BinarySubtype::BinaryOld,
+    });
+    mongodb::bson::to_bson(&DtkChatMessage {
+        message: bson.to_string(),
+        ..msg.clone()
+    })
+    .unwrap()
+}
+
+/// Store the id of messages saved before they had one, duplicates of the same message are dropped.
+/// Only chats still holding such messages are touched, so it can run at every start.
+pub async fn migrate_chat_message_ids() -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_chat_db_name())
+        .collection::<Document>(get_chat_collection_name().as_str());
+    let filter = doc! { "messages": { "$elemMatch": { "id": { "$exists": false } } } };
+    let mut cursor = coll.find(filter, None).await?;
+    let mut migrated = 0;
+    while let Some(chat_doc) = cursor.next().await {
+        let chat = dtk_chat_from_document(chat_doc?);
+        let mut seen = HashSet::new();
+        let messages = chat
+            .messages
+            .into_iter()
+            .filter(|msg| seen.insert(msg.id.clone()))
+            .map(|msg| to_stored_message(&msg))
+            .collect::<Vec<Bson>>();
+        coll.update_one(
+            doc! { "channel_id": &chat.channel_id },
+            doc! { "$set": { "messages": messages } },
+            None,
+        )
+        .await?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+/// Save new messages in a channel, returns the ones that were not stored yet
+pub async fn create_dtk_chat_message(dtk_chat: DtkChat) -> Vec<DtkChatMessage> {
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let coll = client
         .database(&get_chat_db_name())
         .collection::<Document>(get_chat_collection_name().as_str());
     let filter = doc! { "channel_id": dtk_chat.channel_id.clone() };
-    let chat_doc = to_document(&dtk_chat).unwrap();
-    let messages = chat_doc.get("messages").unwrap().as_array().unwrap().to_vec();
-    let users = chat_doc.get("users").unwrap().as_array().unwrap().to_vec();
-    let compressed_messages = messages
+    let stored = coll.find_one(filter.clone(), None).await.unwrap().map(dtk_chat_from_document);
+    let mut stored_ids = match &stored {
+        Some(chat) => chat.messages.iter().map(|msg| msg.id.clone()).collect::<HashSet<String>>(),
+        None => HashSet::new(),
+    };
+    // users come from the client, mentions only reach registered members the channel already had
+    let member_ids = stored
+        .as_ref()
+        .map(|chat| &chat.users)
+        .unwrap_or(&dtk_chat.users)
+        .iter()
+        .map(|user| user.id.clone())
+        .collect::<HashSet<String>>();
+    let members = get_all_chat_users()
+        .await
         .into_iter()
-        .map(|m| {
-            let sender_id = m.as_document().unwrap().get("sender_id").unwrap().as_str().unwrap();
-            let date = m.as_document().unwrap().get("date").unwrap().as_str().unwrap();
-            let message = m.as_document().unwrap().get("message").unwrap().as_str().unwrap();
-            let compressed_text = gzip_text(message);
-            let bson = Bson::Binary(Binary {
-                bytes: compressed_text,
-                subtype: mongodb::bson::spec::BinarySubtype::BinaryOld,
-            });
-            mongodb::bson::to_bson(&DtkChatMessage {
-                sender_id: sender_id.to_string(),
-                date: date.to_string(),
-                message: bson.to_string(),
-            })
-            .unwrap()
-        })
-        .collect::<Vec<Bson>>();
-    let update = doc! { "$set": {"last_update": dtk_chat.last_update}, "$addToSet": { "users": { "$each": users }, "messages": { "$each": compressed_messages } } };
-    let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
-    coll.update_one(filter, update, Some(options))
+        .filter(|user| member_ids.contains(&user.id))
+        .collect::<Vec<DtkChatUser>>();
+    let users = to_document(&dtk_chat).unwrap().get("users").unwrap().as_array().unwrap().to_vec();
+    // clients post the messages they already have, only the ones not stored yet are pushed
+    let messages = dtk_chat
+        .messages
+        .into_iter()
+        .map(|msg| with_message_meta(msg, &members))
+        .filter(|msg| stored_ids.insert(msg.id.clone()))
+        .collect::<Vec<DtkChatMessage>>();
+    let update = doc! {
+        "$set": { "last_update": dtk_chat.last_update },
+        "$addToSet": { "users": { "$each": users } },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .projection(doc! { "_id": 1 })
+        .build();
+    let chat_id = coll
+        .find_one_and_update(filter, update, Some(options))
         .await
+        .expect("Failed to insert chat data")
+        .and_then(|chat| chat.get("_id").cloned())
         .expect("Failed to insert chat data");
+    // the id check is part of the filter, so a message posted concurrently is only pushed once
+    let mut pushed = vec![];
+    for msg in messages {
+        let filter = doc! { "_id": chat_id.clone(), "messages.id": { "$ne": msg.id.clone() } };
+        let update = doc! { "$push": { "messages": to_stored_message(&msg) } };
+        let result = coll.update_one(filter, update, None).await.expect("Failed to insert chat data");
+        if result.matched_count > 0 {
+            pushed.push(msg);
+        }
+    }
+    pushed
 }
 
 #[cfg(test)]
@@ -183,18 +282,50 @@ mod tests {
             email: "qw@".to_string(),
             name: "User 2".to_string(),
         });
-        chat.add_message(DtkChatMessage {
-            sender_id: "User 1".to_string(),
-            date: chrono::Utc::now().to_string(),
-            message: "How are you doing?".to_string(),
-        });
-        chat.add_message(DtkChatMessage {
-            sender_id: "User 2".to_string(),
-            date: chrono::Utc::now().to_string(),
-            message: "I'm doing well, thanks. How about you?".to_string(),
-        });
+        chat.add_message(DtkChatMessage::new(
+            "User 1".to_string(),
+            chrono::Utc::now().to_string(),
+            "How are you doing?".to_string(),
+        ));
+        chat.add_message(DtkChatMessage::new(
+            "User 2".to_string(),
+            chrono::Utc::now().to_string(),
+            "I'm doing well, thanks. How about you?".to_string(),
+        ));
         println!("Chat: {:#?}", chat);
         assert_eq!(chat.users.len(), 2);
         assert_eq!(chat.messages.len(), 2);
     }
+
+    #[test]
+    fn resume_after_message_id() {
+        let mut chat = DtkChat::new("id1-id2".to_string());
+        for text in ["one", "two", "three"] {
+            chat.add_message(DtkChatMessage::new(
+                "1".to_string(),
+                "2023-04-01 10:00:00 UTC".to_string(),
+                text.to_string(),
+            ));
+        }
+        let first_id = chat.messages[0].id.clone();
+        assert_eq!(first_id, get_chat_message_id("1", "2023-04-01 10:00:00 UTC", "one"));
+        assert_ne!(first_id, chat.messages[1].id);
+        let after = chat.messages_after(&first_id);
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].message, "two");
+        assert!(chat.messages_after(&chat.messages[2].id).is_empty());
+        assert!(chat.messages_after("unknown").is_empty());
+    }
+
+    #[test]
+    fn thread_replies() {
+        let mut chat = DtkChat::new("id1-id2".to_string());
+        let root = DtkChatMessage::new("1".to_string(), chrono::Utc::now().to_string(), "root".to_string());
+        let mut reply = DtkChatMessage::new("2".to_string(), chrono::Utc::now().to_string(), "reply".to_string());
+        reply.parent_id = Some(root.id.clone());
+        chat.add_message(root.clone());
+        chat.add_message(reply);
+        assert_eq!(chat.thread(&root.id).len(), 1);
+        assert_eq!(chat.thread(&root.id)[0].message, "reply");
+    }
 }
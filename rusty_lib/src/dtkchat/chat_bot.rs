@@ -0,0 +1,411 @@
+//! Slash-command bots for chat sessions
+
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use super::chat::{get_all_chat_users, get_all_dtk_chat_for_user};
+use super::chat_model::{ChatForUsers, DtkChatUser};
+use crate::dtkpocket::pocket::search_pocket_data;
+use crate::dtkpocket::pocket_query::escape_text_search;
+use crate::dtkutils::dtk_error::DtkError;
+use crate::dtkutils::dtk_github::search_starred;
+
+/// Max items listed in a bot reply
+const BOT_RESULT_LIMIT: i64 = 5;
+
+/// What the bot gets to work with, `user` comes from the verified token
+#[derive(Clone, Debug)]
+pub struct BotContext {
+    pub user: DtkChatUser,
+    pub channel_id: String,
+    /// Whatever follows the command, trimmed
+    pub args: String,
+}
+
+/// Where the reply goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BotReply {
+    /// Only to the session that sent the command
+    Private(String),
+    /// To everyone in the room
+    Room(String),
+    /// Only to the sender, already serialized so sent as is
+    Payload(String),
+    /// The session moves to another room
+    Join(String),
+    /// The session prefixes its messages with a new name
+    Rename(String),
+}
+
+/// Bot reply as sent over the websocket
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatBotMessage {
+    pub id: String,
+    pub bot: String,
+    pub user_id: String,
+    pub text: String,
+}
+
+impl ChatBotMessage {
+    pub fn new(bot: &str, user_id: &str, text: &str) -> ChatBotMessage {
+        ChatBotMessage {
+            id: "bot".to_string(),
+            bot: bot.to_string(),
+            user_id: user_id.to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+pub trait ChatBot: Send + Sync {
+    /// Command handled by the bot, with its leading slash, e.g. `/github stars`
+    fn command(&self) -> &'static str;
+
+    /// One line usage shown by `/help`
+    fn help(&self) -> &'static str;
+
+    /// Commands that only change the session run without a token check, before any other message
+    fn requires_auth(&self) -> bool {
+        true
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>>;
+}
+
+/// Registered bots, commands are matched word by word and the longest one wins
+#[derive(Default)]
+pub struct BotRegistry {
+    bots: Vec<Arc<dyn ChatBot>>,
+}
+
+impl std::fmt::Debug for BotRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let commands: Vec<&str> = self.bots.iter().map(|bot| bot.command()).collect();
+        f.debug_struct("BotRegistry").field("bots", &commands).finish()
+    }
+}
+
+impl BotRegistry {
+    /// Registry with the session commands, the Pocket search and github stars bots, and `/help`
+    pub fn with_default_bots() -> BotRegistry {
+        let mut registry = BotRegistry::default();
+        registry.register(Arc::new(JoinBot));
+        registry.register(Arc::new(NameBot));
+        registry.register(Arc::new(ChatListBot("/get")));
+        registry.register(Arc::new(ChatListBot("/post")));
+        registry.register(Arc::new(PocketSearchBot));
+        registry.register(Arc::new(GithubStarsBot));
+        registry.register_help();
+        registry
+    }
+
+    pub fn register(&mut self, bot: Arc<dyn ChatBot>) {
+        self.bots.push(bot);
+    }
+
+    /// Register `/help`, listing the bots registered so far
+    pub fn register_help(&mut self) {
+        let usage = format!("{}\n/help - {}", self.help(), HelpBot::USAGE);
+        self.register(Arc::new(HelpBot(usage)));
+    }
+
+    /// Bot handling the command line, with the remaining arguments
+    pub fn find(&self, line: &str) -> Option<(Arc<dyn ChatBot>, String)> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        self.bots
+            .iter()
+            .filter_map(|bot| {
+                let command: Vec<&str> = bot.command().split_whitespace().collect();
+                match words.starts_with(&command) {
+                    true => Some((bot.clone(), command.len())),
+                    false => None,
+                }
+            })
+            .max_by_key(|(_, len)| *len)
+            .map(|(bot, len)| (bot, words[len..].join(" ")))
+    }
+
+    /// Usage of every registered bot
+    pub fn help(&self) -> String {
+        self.bots
+            .iter()
+            .map(|bot| format!("{} - {}", bot.command(), bot.help()))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Move the session to another room
+pub struct JoinBot;
+
+impl ChatBot for JoinBot {
+    fn command(&self) -> &'static str {
+        "/join"
+    }
+
+    fn help(&self) -> &'static str {
+        "join another room, e.g. /join general"
+    }
+
+    fn requires_auth(&self) -> bool {
+        false
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move {
+            match ctx.args.split_whitespace().next() {
+                Some(room) => Ok(BotReply::Join(room.to_string())),
+                None => Err(DtkError::from("room is required")),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Set the name prefixing the session messages
+pub struct NameBot;
+
+impl ChatBot for NameBot {
+    fn command(&self) -> &'static str {
+        "/name"
+    }
+
+    fn help(&self) -> &'static str {
+        "set your name in the room, e.g. /name baakey"
+    }
+
+    fn requires_auth(&self) -> bool {
+        false
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move {
+            match ctx.args.is_empty() {
+                true => Err(DtkError::from("name is required")),
+                false => Ok(BotReply::Rename(ctx.args.clone())),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Send the caller chats and the chat users back, `/post` uses it to refresh after posting
+pub struct ChatListBot(pub &'static str);
+
+impl ChatBot for ChatListBot {
+    fn command(&self) -> &'static str {
+        self.0
+    }
+
+    fn help(&self) -> &'static str {
+        "reload your chats"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move {
+            let chat = get_all_dtk_chat_for_user(ctx.user.clone()).await;
+            let users = get_all_chat_users().await;
+            let chat_for_users = ChatForUsers {
+                chat,
+                users,
+                id: "single".to_string(),
+                count: 0,
+            };
+            serde_json::to_string(&chat_for_users)
+                .map(BotReply::Payload)
+                .map_err(|err| DtkError::from(err.to_string().as_str()))
+        }
+        .boxed()
+    }
+}
+
+/// List the registered commands
+pub struct HelpBot(String);
+
+impl HelpBot {
+    const USAGE: &'static str = "list the available commands";
+}
+
+impl ChatBot for HelpBot {
+    fn command(&self) -> &'static str {
+        "/help"
+    }
+
+    fn help(&self) -> &'static str {
+        HelpBot::USAGE
+    }
+
+    fn requires_auth(&self) -> bool {
+        false
+    }
+
+    fn run<'a>(&'a self, _ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move { Ok(BotReply::Payload(self.0.clone())) }.boxed()
+    }
+}
+
+/// Search the caller saved Pocket items
+pub struct PocketSearchBot;
+
+impl ChatBot for PocketSearchBot {
+    fn command(&self) -> &'static str {
+        "/pocket search"
+    }
+
+    fn help(&self) -> &'static str {
+        "search your saved Pocket items, e.g. /pocket search rust"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move {
+            if escape_text_search(&ctx.args).is_empty() {
+                return Err(DtkError::from("Invalid search"));
+            }
+            let items = search_pocket_data(&ctx.user.id, &ctx.args, BOT_RESULT_LIMIT).await;
+            if items.is_empty() {
+                return Ok(BotReply::Private(format!("No Pocket item found for {:?}", ctx.args)));
+            }
+            let lines: Vec<String> = items
+                .iter()
+                .map(|item| format!("- {} {}", item.title, item.url))
+                .collect();
+            Ok(BotReply::Private(lines.join("\n")))
+        }
+        .boxed()
+    }
+}
+
+/// Search the starred repositories saved by `save_all_starred`
+pub struct GithubStarsBot;
+
+impl ChatBot for GithubStarsBot {
+    fn command(&self) -> &'static str {
+        "/github stars"
+    }
+
+    fn help(&self) -> &'static str {
+        "share starred repositories with the room, optionally filtered, e.g. /github stars rust"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+        async move {
+            let repos = search_starred(Some(&ctx.args), BOT_RESULT_LIMIT).await;
+            if repos.is_empty() {
+                return Ok(BotReply::Private(format!("No starred repository found for {:?}", ctx.args)));
+            }
+            let lines: Vec<String> = repos
+                .iter()
+                .map(|repo| {
+                    format!(
+                        "- {} ({} stars) {}",
+                        repo.full_name.as_deref().unwrap_or(""),
+                        repo.stargazers_count.unwrap_or(0),
+                        repo.html_url.as_deref().unwrap_or("")
+                    )
+                })
+                .collect();
+            Ok(BotReply::Room(lines.join("\n")))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoBot(&'static str);
+
+    impl ChatBot for EchoBot {
+        fn command(&self) -> &'static str {
+            self.0
+        }
+
+        fn help(&self) -> &'static str {
+            "echo"
+        }
+
+        fn run<'a>(&'a self, ctx: &'a BotContext) -> BoxFuture<'a, Result<BotReply, DtkError>> {
+            async move { Ok(BotReply::Private(format!("{} {}", self.0, ctx.args))) }.boxed()
+        }
+    }
+
+    #[test]
+    fn find_longest_command() {
+        let mut registry = BotRegistry::default();
+        registry.register(Arc::new(EchoBot("/pocket")));
+        registry.register(Arc::new(EchoBot("/pocket search")));
+        let (bot, args) = registry.find("/pocket  search rust lang").unwrap();
+        assert_eq!(bot.command(), "/pocket search");
+        assert_eq!(args, "rust lang");
+        let (bot, args) = registry.find("/pocket list").unwrap();
+        assert_eq!(bot.command(), "/pocket");
+        assert_eq!(args, "list");
+        assert!(registry.find("/pocketsearch").is_none());
+        assert!(registry.find("/github stars").is_none());
+        assert_eq!(registry.help(), "/pocket - echo\n/pocket search - echo");
+    }
+
+    #[tokio::test]
+    async fn run_found_bot() {
+        let registry = BotRegistry::with_default_bots();
+        let mut registry_with_echo = BotRegistry::default();
+        registry_with_echo.register(Arc::new(EchoBot("/echo")));
+        assert!(registry.find("/echo hi").is_none());
+        let (bot, args) = registry_with_echo.find("/echo hi").unwrap();
+        let ctx = BotContext {
+            user: DtkChatUser {
+                id: "1".to_string(),
+                name: "baakey".to_string(),
+                email: "baakey@rusty.com".to_string(),
+            },
+            channel_id: "general".to_string(),
+            args,
+        };
+        assert_eq!(bot.run(&ctx).await.unwrap(), BotReply::Private("/echo hi".to_string()));
+        // invalid searches are refused before touching mongodb
+        let (bot, args) = registry.find("/pocket search").unwrap();
+        let ctx = BotContext { args, ..ctx };
+        assert!(bot.run(&ctx).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn session_commands() {
+        let registry = BotRegistry::with_default_bots();
+        let ctx = |line: &str| {
+            let (bot, args) = registry.find(line).unwrap();
+            let ctx = BotContext {
+                user: DtkChatUser {
+                    id: "1".to_string(),
+                    name: "baakey".to_string(),
+                    email: String::new(),
+                },
+                channel_id: "general".to_string(),
+                args,
+            };
+            (bot, ctx)
+        };
+        let (bot, join) = ctx("/join rust");
+        assert!(!bot.requires_auth());
+        assert_eq!(bot.run(&join).await.unwrap(), BotReply::Join("rust".to_string()));
+        let (bot, join) = ctx("/join");
+        assert!(bot.run(&join).await.is_err());
+        let (bot, name) = ctx("/name baakey dow");
+        assert_eq!(bot.run(&name).await.unwrap(), BotReply::Rename("baakey dow".to_string()));
+        let (bot, get) = ctx("/get");
+        assert!(bot.requires_auth());
+        assert_eq!(get.args, "");
+        let (bot, help) = ctx("/help");
+        match bot.run(&help).await.unwrap() {
+            BotReply::Payload(text) => {
+                assert!(text.starts_with("/join - "));
+                assert!(text.contains("\n/post - "));
+                assert!(text.ends_with("/help - list the available commands"));
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Render `DtkChat` transcripts as JSON lines, Markdown or standalone HTML
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use super::chat_model::{DtkChat, DtkChatMessage, DtkChatUser};
+
+const EXPORT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatExportFormat {
+    Json,
+    Markdown,
+    Html,
+}
+
+impl ChatExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ChatExportFormat::Json => "application/x-ndjson; charset=utf-8",
+            ChatExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ChatExportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChatExportFormat::Json => "jsonl",
+            ChatExportFormat::Markdown => "md",
+            ChatExportFormat::Html => "html",
+        }
+    }
+}
+
+impl FromStr for ChatExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" | "jsonl" | "ndjson" => Ok(ChatExportFormat::Json),
+            "md" | "markdown" => Ok(ChatExportFormat::Markdown),
+            "html" => Ok(ChatExportFormat::Html),
+            _ => Err(format!("Unknown export format: {s}")),
+        }
+    }
+}
+
+/// One exported message, used as a JSON line
+#[derive(Serialize, Debug)]
+pub struct ChatExportLine<'a> {
+    pub channel_id: &'a str,
+    pub sender_id: &'a str,
+    pub sender_name: &'a str,
+    pub date: String,
+    pub message: &'a str,
+}
+
+/// Renders chats chunk by chunk so a transcript can be streamed
+#[derive(Clone, Debug)]
+pub struct ChatExporter {
+    pub format: ChatExportFormat,
+    pub timezone: Tz,
+    sender_names: HashMap<String, String>,
+}
+
+impl ChatExporter {
+    pub fn new(format: ChatExportFormat, timezone: Tz, users: Vec<DtkChatUser>) -> ChatExporter {
+        ChatExporter {
+            format,
+            timezone,
+            sender_names: users.into_iter().map(|user| (user.id, user.name)).collect(),
+        }
+    }
+
+    /// Resolve a sender id to a user name, falling back to the raw id
+    pub fn sender_name<'a>(&'a self, sender_id: &'a str) -> &'a str {
+        self.sender_names.get(sender_id).map(|name| name.as_str()).unwrap_or(sender_id)
+    }
+
+    /// Convert a stored message date to the export timezone, keeping the raw value if it can't be parsed
+    pub fn format_date(&self, date: &str) -> String {
+        match parse_chat_date(date) {
+            Some(date) => date.with_timezone(&self.timezone).format(EXPORT_DATE_FORMAT).to_string(),
+            None => date.to_string(),
+        }
+    }
+
+    pub fn header(&self) -> String {
+        match self.format {
+            ChatExportFormat::Json => String::new(),
+            ChatExportFormat::Markdown => "# Chat export\n\n".to_string(),
+            ChatExportFormat::Html => format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Chat export</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>Chat export</h1>\n",
+                HTML_STYLE
+            ),
+        }
+    }
+
+    pub fn footer(&self) -> String {
+        match self.format {
+            ChatExportFormat::Html => "</body>\n</html>\n".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Render one channel with all its messages
+    pub fn render_chat(&self, chat: &DtkChat) -> String {
+        let mut out = String::new();
+        match self.format {
+            ChatExportFormat::Json => {
+                for msg in &chat.messages {
+                    out.push_str(&self.render_json_line(chat, msg));
+                    out.push('\n');
+                }
+            }
+            ChatExportFormat::Markdown => {
+                out.push_str(&format!("## {}\n\n", chat.channel_id));
+                out.push_str(&format!("_Participants: {}_\n\n", self.participants(chat)));
+                for msg in &chat.messages {
+                    out.push_str(&format!(
+                        "**{}** ({})\n\n{}\n\n",
+                        self.sender_name(&msg.sender_id),
+                        self.format_date(&msg.date),
+                        msg.message
+                    ));
+                }
+            }
+            ChatExportFormat::Html => {
+                out.push_str("<section class=\"channel\">\n");
+                out.push_str(&format!("<h2>{}</h2>\n", escape_html(&chat.channel_id)));
+                out.push_str(&format!(
+                    "<p class=\"participants\">Participants: {}</p>\n",
+                    escape_html(&self.participants(chat))
+                ));
+                for msg in &chat.messages {
+                    out.push_str(&format!(
+                        "<div class=\"message\"><span class=\"sender\">{}</span> <time>{}</time><p>{}</p></div>\n",
+                        escape_html(self.sender_name(&msg.sender_id)),
+                        escape_html(&self.format_date(&msg.date)),
+                        escape_html(&msg.message).replace('\n', "<br>")
+                    ));
+                }
+                out.push_str("</section>\n");
+            }
+        }
+        out
+    }
+
+    fn render_json_line(&self, chat: &DtkChat, msg: &DtkChatMessage) -> String {
+        serde_json::to_string(&ChatExportLine {
+            channel_id: &chat.channel_id,
+            sender_id: &msg.sender_id,
+            sender_name: self.sender_name(&msg.sender_id),
+            date: self.format_date(&msg.date),
+            message: &msg.message,
+        })
+        .unwrap()
+    }
+
+    fn participants(&self, chat: &DtkChat) -> String {
+        chat.users
+            .iter()
+            .map(|user| self.sender_names.get(&user.id).unwrap_or(&user.name).as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; max-width: 48rem; margin: 2rem auto; }
+.channel { margin-bottom: 2rem; }
+.participants { color: #666; }
+.message { border-bottom: 1px solid #eee; padding: 0.5rem 0; }
+.sender { font-weight: bold; }
+time { color: #888; font-size: 0.85em; }
+";
+
+/// Parse a stored chat date, either `Utc::now().to_string()` or RFC 3339
+pub fn parse_chat_date(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let naive = date.trim_end_matches(" UTC");
+    NaiveDateTime::parse_from_str(naive, "%Y-%m-%d %H:%M:%S%.f")
+        .ok()
+        .map(|naive| Utc.from_utc_datetime(&naive))
+}
+
+/// Parse an IANA timezone name, defaulting to UTC
+pub fn parse_export_timezone(timezone: Option<&str>) -> Result<Tz, String> {
+    match timezone {
+        Some(timezone) if !timezone.is_empty() => timezone.parse::<Tz>(),
+        _ => Ok(Tz::UTC),
+    }
+}
+
+/// Escape text for safe inclusion in HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_chat() -> (DtkChat, Vec<DtkChatUser>) {
+        let users = vec![
+            DtkChatUser {
+                id: "1".to_string(),
+                email: "bl@".to_string(),
+                name: "User 1".to_string(),
+            },
+            DtkChatUser {
+                id: "2".to_string(),
+                email: "qw@".to_string(),
+                name: "User 2".to_string(),
+            },
+        ];
+        let mut chat = DtkChat::new("1-2".to_string());
+        users.iter().cloned().for_each(|user| chat.add_user(user));
+        chat.add_message(DtkChatMessage::new(
+            "1".to_string(),
+            "2023-04-01 10:00:00.123456 UTC".to_string(),
+            "<b>How</b> are you doing?".to_string(),
+        ));
+        chat.add_message(DtkChatMessage::new(
+            "3".to_string(),
+            "2023-04-01T10:05:00Z".to_string(),
+            "Fine".to_string(),
+        ));
+        (chat, users)
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert!(parse_chat_date(&Utc::now().to_string()).is_some());
+        assert!(parse_chat_date("2023-04-01T10:05:00.000Z").is_some());
+        assert!(parse_chat_date("yesterday").is_none());
+        assert!(parse_export_timezone(Some("Europe/Paris")).is_ok());
+        assert_eq!(parse_export_timezone(None), Ok(Tz::UTC));
+        assert!(parse_export_timezone(Some("Mars/Olympus")).is_err());
+    }
+
+    #[test]
+    fn export_json_lines() {
+        let (chat, users) = get_test_chat();
+        let exporter = ChatExporter::new(ChatExportFormat::Json, "Europe/Paris".parse().unwrap(), users);
+        let out = exporter.render_chat(&chat);
+        let lines: Vec<serde_json::Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["sender_name"], "User 1");
+        assert_eq!(lines[0]["date"], "2023-04-01 12:00:00 CEST");
+        assert_eq!(lines[1]["sender_name"], "3");
+    }
+
+    #[test]
+    fn export_markdown_and_html() {
+        let (chat, users) = get_test_chat();
+        let markdown = ChatExporter::new(ChatExportFormat::Markdown, Tz::UTC, users.clone()).render_chat(&chat);
+        assert!(markdown.starts_with("## 1-2\n\n_Participants: User 1, User 2_"));
+        assert!(markdown.contains("**User 1** (2023-04-01 10:00:00 UTC)"));
+        let exporter = ChatExporter::new(ChatExportFormat::Html, Tz::UTC, users);
+        let html = format!("{}{}{}", exporter.header(), exporter.render_chat(&chat), exporter.footer());
+        assert!(html.contains("&lt;b&gt;How&lt;/b&gt; are you doing?"));
+        assert!(html.ends_with("</html>\n"));
+    }
+}
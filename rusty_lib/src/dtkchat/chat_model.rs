@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use super::chat_utils::get_chat_message_id;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DtkUser {
     pub id: String,
@@ -17,6 +19,14 @@ pub struct ChatForUsers {
     pub users: Vec<DtkChatUser>,
 }
 
+/// Export options sent along with the user payload
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatExportRequest {
+    pub channel_id: Option<String>,
+    pub format: Option<String>,
+    pub timezone: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DtkChatUser {
     pub id: String,
@@ -26,9 +36,30 @@ pub struct DtkChatUser {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DtkChatMessage {
+    #[serde(default)]
+    pub id: String,
     pub sender_id: String,
     pub date: String,
     pub message: String,
+    /// Message this one replies to, when it belongs to a thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// Ids of the channel users mentioned with `@name`
+    #[serde(default)]
+    pub mentions: Vec<String>,
+}
+
+impl DtkChatMessage {
+    pub fn new(sender_id: String, date: String, message: String) -> DtkChatMessage {
+        DtkChatMessage {
+            id: get_chat_message_id(&sender_id, &date, &message),
+            sender_id,
+            date,
+            message,
+            parent_id: None,
+            mentions: Vec::new(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -56,4 +87,21 @@ impl DtkChat {
     pub fn add_message(&mut self, message: DtkChatMessage) {
         self.messages.push(message);
     }
+
+    /// Replies posted in the thread started by the given message
+    pub fn thread(&self, parent_id: &str) -> Vec<DtkChatMessage> {
+        self.messages
+            .iter()
+            .filter(|msg| msg.parent_id.as_deref() == Some(parent_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Messages stored after the given message id, none if the id is unknown
+    pub fn messages_after(&self, message_id: &str) -> Vec<DtkChatMessage> {
+        match self.messages.iter().position(|msg| msg.id == message_id) {
+            Some(pos) => self.messages[pos + 1..].to_vec(),
+            None => Vec::new(),
+        }
+    }
 }
\ No newline at end of file
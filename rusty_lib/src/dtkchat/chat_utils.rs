@@ -1,3 +1,5 @@
+use super::chat_model::{ChatForUsers, DtkChatUser};
+
 /// Get mongodb db name
 pub fn get_chat_db_name() -> String {
     std::env::var("RUSTY_CHAT_DB").unwrap_or_else(|_| "rusty_chat".into())
@@ -6,4 +8,105 @@ pub fn get_chat_db_name() -> String {
 /// Get mongodb collection name
 pub fn get_chat_collection_name() -> String {
     std::env::var("RUSTY_CHAT_COLL").unwrap_or_else(|_| "chat_data".into())
-}
\ No newline at end of file
+}
+
+/// Stable message id derived from its content, so re-posted messages keep the same id
+pub fn get_chat_message_id(sender_id: &str, date: &str, message: &str) -> String {
+    // FNV-1a, stable across builds unlike std's DefaultHasher
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in [sender_id, date, message].join("\0").bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
+}
+
+/// Find the channel users mentioned as `@name`, the longest matching name wins
+pub fn parse_mentions(message: &str, users: &[DtkChatUser]) -> Vec<String> {
+    let lower_message = message.to_lowercase();
+    let mut mentions: Vec<String> = Vec::new();
+    for (pos, _) in lower_message.match_indices('@') {
+        let rest = &lower_message[pos + 1..];
+        let mentioned = users
+            .iter()
+            .filter(|user| !user.name.is_empty())
+            .filter(|user| {
+                let name = user.name.to_lowercase();
+                rest.starts_with(&name)
+                    && !rest[name.len()..]
+                        .chars()
+                        .next()
+                        .map(|c| c.is_alphanumeric() || c == '_')
+                        .unwrap_or(false)
+            })
+            .max_by_key(|user| user.name.len());
+        if let Some(user) = mentioned {
+            if !mentions.contains(&user.id) {
+                mentions.push(user.id.clone());
+            }
+        }
+    }
+    mentions
+}
+
+/// Chat payloads carry the id of their last message so clients can resume with `Last-Event-ID`
+pub fn get_event_id(payload: &str) -> Option<String> {
+    let chat_for_users = serde_json::from_str::<ChatForUsers>(payload).ok()?;
+    let last_message = chat_for_users.chat.first()?.messages.last()?;
+    Some(last_message.id.clone())
+}
+
+/// Format a payload as one SSE event, splitting multi-line data
+pub fn format_sse_event(id: Option<&str>, data: &str) -> String {
+    let mut event = String::new();
+    if let Some(id) = id {
+        event.push_str(&format!("id: {id}\n"));
+    }
+    for line in data.lines() {
+        event.push_str(&format!("data: {line}\n"));
+    }
+    event.push('\n');
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtkchat::chat_model::{DtkChat, DtkChatMessage};
+
+    #[test]
+    fn mentions() {
+        let users = ["Bob", "Bob Smith", "alice"]
+            .iter()
+            .enumerate()
+            .map(|(id, name)| DtkChatUser {
+                id: id.to_string(),
+                name: name.to_string(),
+                email: format!("{name}@rusty.com"),
+            })
+            .collect::<Vec<DtkChatUser>>();
+        assert_eq!(parse_mentions("hey @bob smith and @Alice!", &users), vec!["1", "2"]);
+        assert_eq!(parse_mentions("@Bob, @bob again", &users), vec!["0"]);
+        assert!(parse_mentions("mail alice@rusty.com or @bobby", &users).is_empty());
+    }
+
+    #[test]
+    fn sse_events() {
+        assert_eq!(format_sse_event(Some("42"), "one\r\ntwo"), "id: 42\ndata: one\ndata: two\n\n");
+        assert_eq!(format_sse_event(None, "{}"), "data: {}\n\n");
+        let mut chat = DtkChat::new("id1-id2".to_string());
+        let payload = |chat: &DtkChat| {
+            serde_json::to_string(&ChatForUsers {
+                chat: vec![chat.clone()],
+                users: vec![],
+                id: "single".to_string(),
+                count: 0,
+            })
+            .unwrap()
+        };
+        assert_eq!(get_event_id(&payload(&chat)), None);
+        chat.add_message(DtkChatMessage::new("1".to_string(), "now".to_string(), "hey".to_string()));
+        assert_eq!(get_event_id(&payload(&chat)), Some(get_chat_message_id("1", "now", "hey")));
+        assert_eq!(get_event_id("!!! unknown command"), None);
+    }
+}
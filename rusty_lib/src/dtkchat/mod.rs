@@ -1,5 +1,7 @@
 #![allow(missing_docs)]
 /// DTKChat
 pub mod chat;
+pub mod chat_bot;
+pub mod chat_export;
 pub mod chat_model;
 pub mod chat_utils;
\ No newline at end of file
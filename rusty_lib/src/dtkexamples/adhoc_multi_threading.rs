@@ -35,9 +35,9 @@ pub struct Tasks<'a, T: std::marker::Send> {
 }
 
 impl<'a, T: 'static + std::marker::Send> Tasks<'a, T> {
-    fn spawn<F: 'static>(&self, exec_task: F, thread_num: u8)
+    fn spawn<F>(&self, exec_task: F, thread_num: u8)
     where
-        F: Fn(&Arc<Mutex<Vec<TaskSpawner<T>>>>, u8) + std::marker::Send + Copy,
+        F: 'static + Fn(&Arc<Mutex<Vec<TaskSpawner<T>>>>, u8) + std::marker::Send + Copy,
     {
         let mut thread_handles = Vec::new();
         for i in 0..thread_num {
@@ -57,9 +57,9 @@ impl<'a, T: 'static + std::marker::Send> Tasks<'a, T> {
 }
 
 /// Spawn Tasks
-pub fn spawn_threads<F: 'static>(exec_task: F, to_spawn: u8) -> Arc<Mutex<Vec<TaskSpawner<String>>>>
+pub fn spawn_threads<F>(exec_task: F, to_spawn: u8) -> Arc<Mutex<Vec<TaskSpawner<String>>>>
 where
-    F: Fn(&Arc<Mutex<Vec<TaskSpawner<String>>>>, u8) + std::marker::Send + Copy,
+    F: 'static + Fn(&Arc<Mutex<Vec<TaskSpawner<String>>>>, u8) + std::marker::Send + Copy,
 {
     let arc_data: Arc<Mutex<Vec<TaskSpawner<String>>>> = Arc::new(Mutex::new(Vec::new()));
     let task_spawner = Tasks {
@@ -45,7 +45,7 @@ mod tests {
         // `Self` is the implementor type: `Sheep`.
         fn new(name: &'static str) -> Sheep {
             Sheep {
-                name: name,
+                name,
                 naked: false,
             }
         }
@@ -14,7 +14,7 @@ async fn test_github_data() {
     let response = match send_get_request(&request_url).await {
         Ok(r) => r,
         Err(err) => {
-            println!("Request failed: {}", err.to_string());
+            println!("Request failed: {}", err);
             return;
         }
     };
@@ -28,8 +28,7 @@ async fn test_github_data() {
         };
     }
     let github_data = get_all_stargazers("baakeydow", "flipper0-rust-hello-world").await;
-    if github_data.is_ok() {
-        let data = github_data.unwrap();
+    if let Ok(data) = github_data {
         println!("Repo data: {:#?}", data.len());
     } else {
         println!("Error");
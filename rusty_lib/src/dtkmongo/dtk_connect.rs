@@ -11,9 +11,7 @@ pub async fn get_dtkmongo_client(conn_str: &str) -> Client {
     client_options.app_name = Some("core-rusty-api".to_string());
 
     // Get a handle to the deployment.
-    let client = Client::with_options(client_options).expect("failed to connect");
-
-    client
+    Client::with_options(client_options).expect("failed to connect")
 }
 
 /// Get mongodb URI
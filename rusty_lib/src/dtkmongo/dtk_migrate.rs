@@ -0,0 +1,35 @@
+//! One-off data migrations, the ones applied are recorded in mongodb
+
+use std::future::Future;
+
+use mongodb::bson::{doc, Document};
+use mongodb::options::UpdateOptions;
+
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_main_db, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Get mongodb collection name recording applied migrations
+pub fn get_migrations_collection_name() -> String {
+    std::env::var("RUSTY_MIGRATIONS_COLL").unwrap_or_else(|_| "migrations".into())
+}
+
+/// Run `migration` unless `name` was already applied, returns what it changed or None when skipped.
+/// A failed migration is not recorded, it runs again on the next start.
+pub async fn run_migration<F, Fut>(name: &str, migration: F) -> Result<Option<u64>, DtkError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<u64, DtkError>>,
+{
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_mongodb_main_db())
+        .collection::<Document>(&get_migrations_collection_name());
+    if coll.find_one(doc! { "_id": name }, None).await?.is_some() {
+        return Ok(None);
+    }
+    let changed = migration().await?;
+    let update = doc! { "$set": { "changed": changed as i64, "applied_at": chrono::Utc::now().to_rfc3339() } };
+    let options = UpdateOptions::builder().upsert(true).build();
+    coll.update_one(doc! { "_id": name }, update, options).await?;
+    Ok(Some(changed))
+}
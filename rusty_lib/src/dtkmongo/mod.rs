@@ -1,2 +1,3 @@
 //! RUSTY_MONGODB
-pub mod dtk_connect;
\ No newline at end of file
+pub mod dtk_connect;
+pub mod dtk_migrate;
@@ -0,0 +1,7 @@
+//! RUSTY_NOTIFY
+
+pub mod notify;
+pub mod notify_channel;
+pub mod notify_model;
+pub mod notify_smtp;
+pub mod notify_utils;
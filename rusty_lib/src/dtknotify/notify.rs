@@ -0,0 +1,162 @@
+//! Store notifications in the user inbox and deliver them through the enabled channels
+
+use std::sync::{Arc, RwLock};
+
+use futures::StreamExt;
+use mongodb::{bson::doc, options::FindOptions, Collection};
+
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::limit_tracker::Messenger;
+
+use super::notify_channel::{NotificationChannel, SmtpChannel, WebhookChannel};
+use super::notify_model::{DtkNotification, DtkNotifySettings};
+use super::notify_utils::*;
+
+/// Notification dispatcher shared by the api, channels can be registered at any time
+#[derive(Default)]
+pub struct Notifier {
+    channels: RwLock<Vec<Arc<dyn NotificationChannel>>>,
+}
+
+impl std::fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<&str> = self.channels.read().unwrap().iter().map(|channel| channel.name()).collect();
+        f.debug_struct("Notifier").field("channels", &names).finish()
+    }
+}
+
+impl Notifier {
+    /// Notifier with the webhook channel, and the SMTP channel when RUSTY_SMTP_HOST is set
+    pub fn from_env() -> Notifier {
+        let notifier = Notifier::default();
+        notifier.add_channel(Arc::new(WebhookChannel::new()));
+        if let Some(host) = get_smtp_host() {
+            notifier.add_channel(Arc::new(SmtpChannel {
+                host,
+                port: get_smtp_port(),
+                from: get_smtp_from(),
+            }));
+        }
+        notifier
+    }
+
+    /// Register a delivery channel
+    pub fn add_channel(&self, channel: Arc<dyn NotificationChannel>) {
+        self.channels.write().unwrap().push(channel);
+    }
+
+    /// Save the notification in the user inbox, then deliver it through every channel the user enabled
+    pub async fn notify(&self, notification: DtkNotification) {
+        save_notification(&notification).await;
+        let settings = get_notify_settings(&notification.user_id).await;
+        let channels = self.channels.read().unwrap().clone();
+        for channel in channels.iter().filter(|channel| settings.is_enabled(channel.name())) {
+            if let Err(err) = channel.deliver(&notification, &settings).await {
+                log::warn!(
+                    "[NOTIFY] {} delivery failed for {} => {}",
+                    channel.name(),
+                    notification.user_id,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Notify in the background, outside of a tokio runtime the notification is only logged
+    pub fn notify_later(self: &Arc<Self>, notification: DtkNotification) {
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                let notifier = self.clone();
+                handle.spawn(async move { notifier.notify(notification).await });
+            }
+            Err(_) => log::warn!("[NOTIFY] No runtime, dropping {:?}", notification),
+        }
+    }
+}
+
+/// `Messenger` sending every message as a notification to one user
+pub struct NotifyMessenger {
+    /// Shared notifier
+    pub notifier: Arc<Notifier>,
+    /// Recipient
+    pub user_id: String,
+    /// Notification kind
+    pub kind: String,
+    /// Notification title
+    pub title: String,
+}
+
+impl Messenger for NotifyMessenger {
+    fn send(&self, msg: &str) {
+        self.notifier
+            .notify_later(DtkNotification::new(&self.user_id, &self.kind, &self.title, msg));
+    }
+}
+
+async fn get_notify_collection() -> Collection<DtkNotification> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    client
+        .database(&get_notify_db_name())
+        .collection::<DtkNotification>(&get_notify_collection_name())
+}
+
+async fn get_notify_settings_collection() -> Collection<DtkNotifySettings> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    client
+        .database(&get_notify_db_name())
+        .collection::<DtkNotifySettings>(&get_notify_settings_collection_name())
+}
+
+/// Save a notification in the user inbox
+pub async fn save_notification(notification: &DtkNotification) {
+    get_notify_collection()
+        .await
+        .insert_one(notification, None)
+        .await
+        .expect("Failed to insert notification");
+}
+
+/// Get the user inbox, newest first
+pub async fn get_notifications(user_id: &str, unread_only: bool) -> Vec<DtkNotification> {
+    let mut filter = doc! { "user_id": user_id };
+    if unread_only {
+        filter.insert("read", false);
+    }
+    let options = FindOptions::builder().sort(doc! {"date": -1}).build();
+    let cursor = get_notify_collection().await.find(filter, options).await.unwrap();
+    cursor.map(|res| res.unwrap()).collect::<Vec<DtkNotification>>().await
+}
+
+/// Mark some notifications, or the whole inbox, as read
+pub async fn mark_notifications_read(user_id: &str, notification_ids: Option<Vec<String>>) -> u64 {
+    let mut filter = doc! { "user_id": user_id, "read": false };
+    if let Some(notification_ids) = notification_ids {
+        filter.insert("id", doc! { "$in": notification_ids });
+    }
+    get_notify_collection()
+        .await
+        .update_many(filter, doc! { "$set": { "read": true } }, None)
+        .await
+        .unwrap()
+        .modified_count
+}
+
+/// Get user delivery settings, defaults to websocket only
+pub async fn get_notify_settings(user_id: &str) -> DtkNotifySettings {
+    get_notify_settings_collection()
+        .await
+        .find_one(doc! { "user_id": user_id }, None)
+        .await
+        .unwrap()
+        .unwrap_or_else(|| DtkNotifySettings::new(user_id))
+}
+
+/// Save user delivery settings
+pub async fn save_notify_settings(settings: &DtkNotifySettings) {
+    let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+    get_notify_settings_collection()
+        .await
+        .replace_one(doc! { "user_id": &settings.user_id }, settings, options)
+        .await
+        .expect("Failed to save notification settings");
+}
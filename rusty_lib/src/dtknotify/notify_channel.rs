@@ -0,0 +1,208 @@
+//! Pluggable notification delivery channels
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use url::{Host, Url};
+
+use crate::dtkutils::dtk_error::DtkError;
+
+use super::notify_model::{DtkNotification, DtkNotifySettings};
+use super::notify_smtp::{send_smtp_mail, SmtpMail};
+
+/// A way to reach a user, enabled per user by name in `DtkNotifySettings.channels`
+pub trait NotificationChannel: Send + Sync {
+    /// Channel name used in user settings
+    fn name(&self) -> &'static str;
+
+    /// Deliver one notification
+    fn deliver<'a>(
+        &'a self,
+        notification: &'a DtkNotification,
+        settings: &'a DtkNotifySettings,
+    ) -> BoxFuture<'a, Result<(), DtkError>>;
+}
+
+/// Public unicast address, not the server itself nor a private, link-local or reserved network
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                // carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolve the url host once and pin one address, every address it resolves to must be public
+pub async fn resolve_public_addr(url: &Url) -> Result<SocketAddr, DtkError> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await?
+        .collect::<Vec<SocketAddr>>();
+    match addrs.first() {
+        Some(addr) if addrs.iter().all(|addr| is_public_ip(addr.ip())) => Ok(*addr),
+        _ => Err(DtkError::from(format!("{host} must resolve to public addresses").as_str())),
+    }
+}
+
+/// Webhooks are http(s) urls that don't point to the server or its private network.
+/// Host names are checked again once resolved, at delivery.
+pub fn check_webhook_url(webhook_url: &str) -> Result<Url, DtkError> {
+    let url = Url::parse(webhook_url).map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(DtkError::from("Webhook url must be http or https"));
+    }
+    let is_public = match url.host() {
+        Some(Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_lowercase();
+            domain.contains('.')
+                && ![".localhost", ".local", ".internal"]
+                    .iter()
+                    .any(|suffix| domain.ends_with(suffix))
+        }
+        None => false,
+    };
+    match is_public {
+        true => Ok(url),
+        false => Err(DtkError::from("Webhook url must point to a public host")),
+    }
+}
+
+/// POST the notification as JSON to the user webhook
+#[derive(Default)]
+pub struct WebhookChannel;
+
+impl WebhookChannel {
+    /// new method
+    pub fn new() -> WebhookChannel {
+        WebhookChannel
+    }
+}
+
+impl NotificationChannel for WebhookChannel {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        notification: &'a DtkNotification,
+        settings: &'a DtkNotifySettings,
+    ) -> BoxFuture<'a, Result<(), DtkError>> {
+        async move {
+            let url = settings
+                .webhook_url
+                .as_ref()
+                .ok_or_else(|| DtkError::from("No webhook url"))?;
+            let url = check_webhook_url(url)?;
+            // pinned, so the host can't resolve to a private address in between
+            let addr = resolve_public_addr(&url).await?;
+            let client = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .resolve(url.host_str().unwrap_or_default(), addr)
+                .timeout(Duration::from_secs(10))
+                .build()?;
+            let response = client
+                .post(url)
+                .header("User-agent", "Reqwest")
+                .json(notification)
+                .send()
+                .await
+                .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+            if !response.status().is_success() {
+                return Err(DtkError::from(format!("Webhook answered {}", response.status()).as_str()));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Mail the notification through an SMTP relay
+pub struct SmtpChannel {
+    /// Relay host
+    pub host: String,
+    /// Relay port
+    pub port: u16,
+    /// Sender address
+    pub from: String,
+}
+
+impl NotificationChannel for SmtpChannel {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        notification: &'a DtkNotification,
+        settings: &'a DtkNotifySettings,
+    ) -> BoxFuture<'a, Result<(), DtkError>> {
+        async move {
+            let to = settings.email.as_ref().ok_or_else(|| DtkError::from("No email"))?;
+            let mail = SmtpMail {
+                from: &self.from,
+                to,
+                subject: &notification.title,
+                body: &notification.message,
+            };
+            send_smtp_mail(&self.host, self.port, mail).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_urls() {
+        assert!(check_webhook_url("https://hooks.rusty.com/notify?key=1").is_ok());
+        assert!(check_webhook_url("http://93.184.216.34:8080/hook").is_ok());
+        for url in [
+            "ftp://hooks.rusty.com/notify",
+            "file:///etc/passwd",
+            "http://localhost:1342/hey",
+            "http://api.localhost/",
+            "http://mongo:27017/",
+            "http://127.0.0.1/",
+            "http://10.0.0.12/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:192.168.1.1]/",
+            "not a url",
+        ] {
+            assert!(check_webhook_url(url).is_err(), "{url}");
+        }
+    }
+}
@@ -0,0 +1,78 @@
+#![allow(missing_docs)]
+
+use serde::{Deserialize, Serialize};
+
+/// Notification kept in the user inbox and pushed through delivery channels
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DtkNotification {
+    pub id: String,
+    pub user_id: String,
+    /// mention, quota, scheduler...
+    pub kind: String,
+    pub title: String,
+    pub message: String,
+    pub data: Option<serde_json::Value>,
+    /// RFC 3339 date so the inbox sorts by string
+    pub date: String,
+    pub read: bool,
+}
+
+impl DtkNotification {
+    pub fn new(user_id: &str, kind: &str, title: &str, message: &str) -> DtkNotification {
+        DtkNotification {
+            id: mongodb::bson::oid::ObjectId::new().to_hex(),
+            user_id: user_id.to_string(),
+            kind: kind.to_string(),
+            title: title.to_string(),
+            message: message.to_string(),
+            data: None,
+            date: chrono::Utc::now().to_rfc3339(),
+            read: false,
+        }
+    }
+
+    pub fn with_data(mut self, data: serde_json::Value) -> DtkNotification {
+        self.data = Some(data);
+        self
+    }
+}
+
+/// Per-user delivery settings, the inbox always keeps every notification
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DtkNotifySettings {
+    pub user_id: String,
+    /// Enabled delivery channels by name: ws, webhook, smtp
+    pub channels: Vec<String>,
+    pub email: Option<String>,
+    pub webhook_url: Option<String>,
+}
+
+impl DtkNotifySettings {
+    pub fn new(user_id: &str) -> DtkNotifySettings {
+        DtkNotifySettings {
+            user_id: user_id.to_string(),
+            channels: vec!["ws".to_string()],
+            email: None,
+            webhook_url: None,
+        }
+    }
+
+    pub fn is_enabled(&self, channel: &str) -> bool {
+        self.channels.iter().any(|name| name == channel)
+    }
+}
+
+/// Inbox options sent along with the user payload
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotifyInboxRequest {
+    pub unread_only: Option<bool>,
+    pub notification_ids: Option<Vec<String>>,
+}
+
+/// Settings sent along with the user payload
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotifySettingsRequest {
+    pub channels: Option<Vec<String>>,
+    pub email: Option<String>,
+    pub webhook_url: Option<String>,
+}
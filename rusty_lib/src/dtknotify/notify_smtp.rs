@@ -0,0 +1,222 @@
+//! Minimal SMTP client for a trusted relay (no TLS, no AUTH)
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Mail to send through the relay
+pub struct SmtpMail<'a> {
+    /// Envelope and header sender
+    pub from: &'a str,
+    /// Envelope and header recipient
+    pub to: &'a str,
+    /// Subject header
+    pub subject: &'a str,
+    /// Plain text body
+    pub body: &'a str,
+}
+
+/// Plain `local@domain` address, nothing that could end an SMTP command or a header
+pub fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let is_atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c);
+    !local.is_empty()
+        && local.len() <= 64
+        && local.chars().all(is_atext)
+        && !domain.is_empty()
+        && domain.len() <= 255
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Values put in SMTP commands or headers must hold on a single line
+pub fn has_line_break(value: &str) -> bool {
+    value.contains(['\r', '\n'])
+}
+
+/// Read a (possibly multi-line) reply and check its code
+async fn expect_reply<R>(reader: &mut R, expected: u16) -> Result<(), DtkError>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(DtkError::from("SMTP connection closed"));
+        }
+        let code = line.get(0..3).and_then(|code| code.parse::<u16>().ok());
+        // "250-" continues a multi-line reply, "250 " ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match code {
+            Some(code) if code == expected => Ok(()),
+            _ => Err(DtkError::from(format!("Unexpected SMTP reply: {}", line.trim_end()).as_str())),
+        };
+    }
+}
+
+/// Format mail content, lines starting with a dot are doubled as required by the DATA command
+pub fn format_smtp_data(mail: &SmtpMail) -> String {
+    let mut data = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        mail.from,
+        mail.to,
+        mail.subject,
+        chrono::Utc::now().to_rfc2822()
+    );
+    for line in mail.body.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+    data
+}
+
+/// Send a mail through the relay at host:port
+pub async fn send_smtp_mail(host: &str, port: u16, mail: SmtpMail<'_>) -> Result<(), DtkError> {
+    if !is_valid_email(mail.from) || !is_valid_email(mail.to) {
+        return Err(DtkError::from("Invalid mail address"));
+    }
+    if has_line_break(mail.subject) {
+        return Err(DtkError::from("Invalid mail subject"));
+    }
+    let stream = TcpStream::connect((host, port)).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    expect_reply(&mut reader, 220).await?;
+    let commands = [
+        (format!("EHLO {host}\r\n"), 250),
+        (format!("MAIL FROM:<{}>\r\n", mail.from), 250),
+        (format!("RCPT TO:<{}>\r\n", mail.to), 250),
+        ("DATA\r\n".to_string(), 354),
+        (format_smtp_data(&mail), 250),
+        ("QUIT\r\n".to_string(), 221),
+    ];
+    for (command, expected) in commands {
+        write_half.write_all(command.as_bytes()).await?;
+        expect_reply(&mut reader, expected).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Local stand-in SMTP server, returns the received DATA
+    async fn smtp_stand_in(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+        write_half.write_all(b"220 localhost ready\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                return data;
+            }
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    write_half.write_all(b"250 queued\r\n").await.unwrap();
+                } else {
+                    data.push_str(&line);
+                }
+                continue;
+            }
+            let reply: &[u8] = match line.get(0..4).unwrap_or("") {
+                "EHLO" => b"250-localhost\r\n250 8BITMIME\r\n",
+                "MAIL" | "RCPT" => b"250 OK\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                }
+                "QUIT" => {
+                    write_half.write_all(b"221 bye\r\n").await.unwrap();
+                    return data;
+                }
+                _ => b"500 unknown\r\n",
+            };
+            write_half.write_all(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn send_mail_to_stand_in() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smtp_stand_in(listener));
+        let res = send_smtp_mail(
+            "127.0.0.1",
+            port,
+            SmtpMail {
+                from: "rusty@rusty.com",
+                to: "baakey@rusty.com",
+                subject: "Quota",
+                body: "Warning: You've used up over 75% of your quota!\n.hidden line",
+            },
+        )
+        .await;
+        assert!(res.is_ok(), "{:?}", res);
+        let data = server.await.unwrap();
+        assert!(data.contains("Subject: Quota\r\n"));
+        assert!(data.contains("75% of your quota!\r\n"));
+        assert!(data.contains("\r\n..hidden line\r\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn refused_recipient() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service\r\n").await.unwrap();
+        });
+        let mail = SmtpMail {
+            from: "rusty@rusty.com",
+            to: "baakey@rusty.com",
+            subject: "Quota",
+            body: "",
+        };
+        assert!(send_smtp_mail("127.0.0.1", port, mail).await.is_err());
+    }
+
+    #[test]
+    fn mail_addresses() {
+        assert!(is_valid_email("baakey@rusty.com"));
+        assert!(is_valid_email("first.last+tag@mail.rusty.com"));
+        assert!(!is_valid_email("baakey@rusty.com>\r\nRCPT TO:<other@rusty.com"));
+        assert!(!is_valid_email("baakey@rusty.com\nBcc: other@rusty.com"));
+        assert!(!is_valid_email("Baakey <baakey@rusty.com>"));
+        assert!(!is_valid_email("baakey@"));
+        assert!(!is_valid_email("rusty.com"));
+        assert!(has_line_break("Quota\r\nBcc: other@rusty.com"));
+        assert!(!has_line_break("Quota"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn injected_subject() {
+        let mail = SmtpMail {
+            from: "rusty@rusty.com",
+            to: "baakey@rusty.com",
+            subject: "Quota\r\nBcc: other@rusty.com",
+            body: "",
+        };
+        // refused before connecting
+        assert!(send_smtp_mail("127.0.0.1", 9, mail).await.is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! Notify utils
+
+/// Get mongodb db name
+pub fn get_notify_db_name() -> String {
+    std::env::var("RUSTY_NOTIFY_DB").unwrap_or_else(|_| "rusty_notify".into())
+}
+
+/// Get mongodb collection name for notifications
+pub fn get_notify_collection_name() -> String {
+    std::env::var("RUSTY_NOTIFY_COLL").unwrap_or_else(|_| "notifications".into())
+}
+
+/// Get mongodb collection name for per-user delivery settings
+pub fn get_notify_settings_collection_name() -> String {
+    std::env::var("RUSTY_NOTIFY_SETTINGS_COLL").unwrap_or_else(|_| "notification_settings".into())
+}
+
+/// Get the user receiving system notifications such as scheduler failures
+pub fn get_notify_admin_id() -> Option<String> {
+    std::env::var("RUSTY_NOTIFY_ADMIN_ID").ok().filter(|id| !id.is_empty())
+}
+
+/// Get SMTP relay host, SMTP delivery is disabled when not set
+pub fn get_smtp_host() -> Option<String> {
+    std::env::var("RUSTY_SMTP_HOST").ok().filter(|host| !host.is_empty())
+}
+
+/// Get SMTP relay port
+pub fn get_smtp_port() -> u16 {
+    std::env::var("RUSTY_SMTP_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(25)
+}
+
+/// Get SMTP sender address
+pub fn get_smtp_from() -> String {
+    std::env::var("RUSTY_SMTP_FROM").unwrap_or_else(|_| "rusty@rusty.com".into())
+}
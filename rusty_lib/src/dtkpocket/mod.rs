@@ -2,5 +2,18 @@
 
 pub mod pocket;
 pub mod pocket_model;
+pub mod pocket_archive;
 pub mod pocket_auth;
+pub mod pocket_autotag;
+pub mod pocket_classifier;
+pub mod pocket_collection;
+pub mod pocket_dedup;
+pub mod pocket_feed;
+pub mod pocket_health;
+pub mod pocket_index;
+pub mod pocket_profile;
+pub mod pocket_query;
+pub mod pocket_stats;
+pub mod pocket_tags;
+pub mod pocket_transfer;
 pub mod pocket_utils;
@@ -1,39 +1,327 @@
 //! Mongodb Pocket data operations
 
+use std::sync::Arc;
+
 use futures::stream::StreamExt;
-use mongodb::bson::Document;
+use mongodb::bson::{doc, Bson, Document};
 use mongodb::options::{AggregateOptions, FindOptions};
-use std::collections::HashMap;
+use mongodb::Collection;
 
+use super::pocket_archive::remove_pocket_archives;
+use super::pocket_collection::remove_from_pocket_collections;
+use super::pocket_index::{index_pocket_items, PocketIndex};
 use super::pocket_model::*;
+use super::pocket_query::{escape_text_search, parse_pocket_facets, pocket_facets};
+use super::pocket_stats::PocketStatsCache;
+use super::pocket_tags::normalize_tag;
+use super::pocket_transfer::POCKET_IMPORT_ID_PREFIX;
 use super::pocket_utils::*;
 use crate::dtkmongo::dtk_connect::*;
 use crate::dtkpocket::pocket_auth;
+use crate::dtkutils::dtk_error::DtkError;
 
-/// Save user pocket data from some time ago
-pub async fn save_pocket(user_id: String, token: String, since: Option<i64>) {
+/// Save user pocket data changed since the given time, returns what changed and the `since` to use next time
+pub async fn save_pocket(
+    user_id: String,
+    token: String,
+    since: Option<i64>,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<(PocketSyncSummary, Option<i64>), DtkError> {
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let db_name = get_pocket_db_name();
     let coll_name = get_pocket_collection_name();
-    let pocket_data = pocket_auth::retreive_pocket_data(&token, since).await;
-    if let Ok(pocket_list) = serde_json::from_value::<HashMap<String, PocketData>>(pocket_data.unwrap().list) {
-        update_pocket_data(&client, &db_name, &coll_name, &user_id, pocket_list).await;
+    let pocket_data = pocket_auth::retreive_pocket_data(&token, since).await?;
+    if let Some(error) = pocket_data.error {
+        return Err(DtkError::from(error.as_str()));
+    }
+    let pocket_list = parse_pocket_list(pocket_data.list)?;
+    let summary = update_pocket_data(&client, &db_name, &coll_name, &user_id, pocket_list, index).await?;
+    Ok((summary, parse_pocket_since(&pocket_data.since)))
+}
+
+/// A sync still marked running after this long is considered dead, e.g. the server restarted mid sync
+const POCKET_SYNC_TIMEOUT_SECS: i64 = 60 * 60;
+
+/// Sync one user from its cursor, or from scratch when `full_resync` is set.
+/// The cursor only moves forward once every item has been saved,
+/// and a user is only synced by one task at a time.
+pub async fn sync_user_pocket(
+    user_id: &str,
+    full_resync: bool,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<PocketSyncSummary, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let user_coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_users_collection_name());
+    let filter = doc! { "user_id": user_id };
+    let started_at = chrono::Utc::now().timestamp();
+    let claim_filter = doc! {
+        "user_id": user_id,
+        "$or": [
+            { "sync_status": { "$ne": "running" } },
+            { "sync_started_at": { "$not": { "$gt": started_at - POCKET_SYNC_TIMEOUT_SECS } } },
+        ],
+    };
+    let claim = doc! { "$set": { "sync_status": "running", "sync_started_at": started_at } };
+    let user = match user_coll.find_one_and_update(claim_filter, claim, None).await? {
+        Some(user) => user,
+        None => match user_coll.find_one(filter.clone(), None).await? {
+            Some(_) => return Err(DtkError::from("Pocket sync already running")),
+            None => return Err(DtkError::from("Pocket user not found")),
+        },
+    };
+    let access_token = user.get_str("pocket_token").unwrap_or_default().to_string();
+    let since = match full_resync {
+        true => None,
+        false => get_pocket_since(&user),
+    };
+    let res = save_pocket(user_id.to_string(), access_token, since, index).await;
+    let update = match &res {
+        Ok((summary, next_since)) => doc! { "$set": {
+            "sync_since": next_since.unwrap_or(started_at),
+            "sync_status": "ok",
+            "last_sync_at": chrono::Utc::now().to_rfc3339(),
+            "last_sync_summary": mongodb::bson::to_document(summary).unwrap(),
+        }, "$unset": { "sync_error": "" } },
+        Err(err) => doc! { "$set": {
+            "sync_status": "failed",
+            "sync_error": err.to_string(),
+        } },
+    };
+    user_coll.update_one(filter, update, None).await?;
+    res.map(|(summary, _)| summary)
+}
+
+/// Apply an action to our copy of the user items
+async fn apply_pocket_action(
+    coll: &Collection<Document>,
+    user_id: &str,
+    action: &PocketAction,
+) -> Result<(), DtkError> {
+    let normalize = |tags: &Vec<String>| tags.iter().map(|tag| normalize_tag(tag)).collect::<Vec<String>>();
+    let (item_id, update) = match action {
+        PocketAction::Archive { item_id } => (item_id, doc! { "$set": { "status": 1 } }),
+        PocketAction::Readd { item_id } => (item_id, doc! { "$set": { "status": 0 } }),
+        PocketAction::Favorite { item_id } => (item_id, doc! { "$set": { "favorite": 1 } }),
+        PocketAction::Unfavorite { item_id } => (item_id, doc! { "$set": { "favorite": 0 } }),
+        PocketAction::TagsAdd { item_id, tags } => {
+            (item_id, doc! { "$addToSet": { "tags": { "$each": normalize(tags) } } })
+        }
+        PocketAction::TagsRemove { item_id, tags } => (item_id, doc! { "$pullAll": { "tags": normalize(tags) } }),
+        PocketAction::TagsReplace { item_id, tags } => (item_id, doc! { "$set": { "tags": normalize(tags) } }),
+        PocketAction::Delete { item_id } => {
+            coll.delete_one(doc! { "user_id": user_id, "item_id": item_id }, None).await?;
+            return Ok(());
+        }
+        PocketAction::TagRename { old_tag, new_tag } => {
+            let (old_tag, new_tag) = (old_tag.to_lowercase(), new_tag.to_lowercase());
+            let filter = doc! { "user_id": user_id, "tags": &old_tag };
+            coll.update_many(filter.clone(), doc! { "$addToSet": { "tags": &new_tag } }, None)
+                .await?;
+            if old_tag != new_tag {
+                coll.update_many(filter, doc! { "$pull": { "tags": &old_tag } }, None).await?;
+            }
+            return Ok(());
+        }
+    };
+    coll.update_one(doc! { "user_id": user_id, "item_id": item_id }, update, None)
+        .await?;
+    Ok(())
+}
+
+/// Send actions to Pocket, then apply the ones it accepted to our copy.
+/// Refused actions are reported as such and leave our copy untouched.
+/// Actions on items imported without Pocket are only applied to our copy.
+pub async fn run_pocket_actions(
+    user_id: &str,
+    actions: Vec<PocketAction>,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<Vec<PocketActionResult>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let db = client.database(&get_pocket_db_name());
+    // items imported without Pocket only exist in our copy, Pocket doesn't know their ids
+    let is_local = |action: &PocketAction| {
+        matches!(action.item_id(), Some(item_id) if item_id.starts_with(POCKET_IMPORT_ID_PREFIX))
+    };
+    let remote_actions: Vec<PocketAction> = actions.iter().filter(|action| !is_local(action)).cloned().collect();
+    let mut remote_results = match remote_actions.is_empty() {
+        true => vec![],
+        false => {
+            let user = db
+                .collection::<Document>(&get_pocket_users_collection_name())
+                .find_one(doc! { "user_id": user_id }, None)
+                .await?
+                .ok_or_else(|| DtkError::from("Pocket user not found"))?;
+            let access_token = user.get_str("pocket_token").unwrap_or_default().to_string();
+            pocket_auth::send_pocket_actions(&access_token, &remote_actions).await?
+        }
+    }
+    .into_iter();
+    let results: Vec<bool> = actions
+        .iter()
+        .map(|action| is_local(action) || remote_results.next().unwrap_or(false))
+        .collect();
+    let coll = db.collection::<Document>(&get_pocket_collection_name());
+    let mut changed_ids = Vec::new();
+    let mut deleted_ids = Vec::new();
+    for (action, ok) in actions.iter().zip(results.iter()) {
+        if !*ok {
+            continue;
+        }
+        apply_pocket_action(&coll, user_id, action).await?;
+        match action {
+            PocketAction::Delete { item_id } => {
+                deleted_ids.push(item_id.clone());
+                changed_ids.push(item_id.clone());
+            }
+            PocketAction::TagRename { new_tag, .. } => {
+                let filter = doc! { "user_id": user_id, "tags": new_tag.to_lowercase() };
+                let item_ids = coll.distinct("item_id", filter, None).await?;
+                changed_ids.extend(item_ids.into_iter().filter_map(|id| id.as_str().map(String::from)));
+            }
+            _ => changed_ids.extend(action.item_id().map(String::from)),
+        }
+    }
+    // deleted items leave their archives and collections too, e.g. duplicates merged away
+    if let Err(err) = remove_pocket_archives(&client, user_id, &deleted_ids).await {
+        log::error!("[POCKET] Could not remove archives of {} => {}", user_id, err);
+    }
+    if let Err(err) = remove_from_pocket_collections(&client, user_id, &deleted_ids).await {
+        log::error!("[POCKET] Could not remove collection items of {} => {}", user_id, err);
+    }
+    if let Err(err) = reindex_pocket_items(index, user_id, changed_ids).await {
+        log::error!("[POCKET] Could not reindex changed items of {} => {}", user_id, err);
+    }
+    Ok(actions
+        .into_iter()
+        .zip(results)
+        .map(|(action, ok)| PocketActionResult { action, ok })
+        .collect())
+}
+
+/// Reindex items changed locally as now stored in mongodb, the ones gone are removed from the index
+pub async fn reindex_pocket_items(
+    index: Option<Arc<PocketIndex>>,
+    user_id: &str,
+    item_ids: Vec<String>,
+) -> Result<(), DtkError> {
+    let index = match index {
+        Some(index) if !item_ids.is_empty() => index,
+        _ => return Ok(()),
+    };
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let filter = doc! { "user_id": user_id, "item_id": { "$in": &item_ids } };
+    let mut cursor = coll.find(filter, None).await?;
+    let mut items = Vec::new();
+    while let Some(item) = cursor.next().await {
+        items.push(item?);
+    }
+    let deleted_ids = item_ids
+        .into_iter()
+        .filter(|item_id| !items.iter().any(|item| &item.item_id == item_id))
+        .collect();
+    tokio::spawn(index_pocket_items(index, user_id.to_string(), items, deleted_ids));
+    Ok(())
+}
+
+/// Number of items saved by the user
+pub async fn count_pocket_items(user_id: &str) -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    Ok(coll.count_documents(doc! { "user_id": user_id }, None).await?)
+}
+
+/// Index the items missing from the search index and drop the ones gone from mongodb,
+/// e.g. items saved before the index existed or while it was unavailable. Returns the items indexed.
+pub async fn backfill_pocket_index(index: Arc<PocketIndex>) -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let to_strings = |values: Vec<Bson>| -> Vec<String> {
+        values
+            .into_iter()
+            .filter_map(|value| value.as_str().map(String::from))
+            .collect()
+    };
+    let mut indexed = 0;
+    for user_id in to_strings(coll.distinct("user_id", None, None).await?) {
+        let saved_ids = to_strings(coll.distinct("item_id", doc! { "user_id": &user_id }, None).await?);
+        let mut indexed_ids = index.user_item_ids(&user_id)?;
+        let missing_ids: Vec<&String> = saved_ids.iter().filter(|item_id| !indexed_ids.remove(*item_id)).collect();
+        if missing_ids.is_empty() && indexed_ids.is_empty() {
+            continue;
+        }
+        let mut cursor = coll
+            .find(doc! { "user_id": &user_id, "item_id": { "$in": missing_ids } }, None)
+            .await?;
+        let mut items = Vec::new();
+        while let Some(item) = cursor.next().await {
+            items.push(item?);
+        }
+        indexed += items.len() as u64;
+        index_pocket_items(index.clone(), user_id, items, indexed_ids.into_iter().collect()).await;
     }
+    Ok(indexed)
 }
 
-/// Save pocket data from all users
-pub async fn save_all_pocket() {
+/// Convert dates stored as `%Y-%m-%d %H:%M:%S` strings into BSON datetimes, the epoch means never read or favorited.
+/// Only documents still holding strings are touched, so it can run at every start.
+pub async fn migrate_pocket_dates() -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let mut filter_any_string = Vec::new();
+    let mut set = Document::new();
+    for field in POCKET_DATE_FIELDS {
+        let value = format!("${field}");
+        let parsed = doc! { "$dateFromString": {
+            "dateString": &value,
+            "format": "%Y-%m-%d %H:%M:%S",
+            "timezone": "UTC",
+            "onError": null,
+        } };
+        let converted = match field {
+            "time_read" | "time_favorited" => doc! {
+                "$cond": [{ "$in": [&value, ["1970-01-01 00:00:00", "0", ""]] }, null, parsed]
+            },
+            _ => parsed,
+        };
+        set.insert(
+            field,
+            doc! { "$cond": [{ "$eq": [{ "$type": &value }, "string"] }, converted, &value] },
+        );
+        filter_any_string.push(doc! { field: { "$type": "string" } });
+    }
+    let res = coll
+        .update_many(doc! { "$or": filter_any_string }, vec![doc! { "$set": set }], None)
+        .await?;
+    Ok(res.modified_count)
+}
+
+/// Save pocket data from all users, their cached stats are dropped once synced
+pub async fn save_all_pocket(index: Option<Arc<PocketIndex>>, stats: Arc<PocketStatsCache>) {
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let user_coll = client
         .database(&get_pocket_db_name())
-        .collection::<Document>("pocket_users");
+        .collection::<Document>(&get_pocket_users_collection_name());
     let mut users = user_coll.find(None, None).await.unwrap();
     while let Some(user) = users.next().await {
         let user = user.unwrap();
         let user_id = user.get_str("user_id").unwrap();
-        let access_token = user.get_str("pocket_token").unwrap();
-        let since = get_pocket_since().await;
-        save_pocket(user_id.to_string(), access_token.to_string(), since).await;
+        let res = sync_user_pocket(user_id, false, index.clone()).await;
+        stats.invalidate(user_id);
+        match res {
+            Ok(summary) => log::info!("[POCKET] synced {} => {:?}", user_id, summary),
+            Err(err) => log::error!("[POCKET] sync failed for {} => {}", user_id, err),
+        }
     }
 }
 
@@ -86,3 +374,146 @@ pub async fn get_pocket_data(filters: mongodb::bson::Document, dedup_excerpt: bo
         }
     }
 }
+
+/// Get one page of items, the total count and facet counts of the filtered items
+/// in a single aggregation, `with_sources` adds the instagram and twitter lists.
+pub async fn get_pocket_page(
+    filters: Document,
+    page: &PocketPageQuery,
+    with_sources: bool,
+) -> Result<PocketPage, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let direction = if page.ascending { 1 } else { -1 };
+    let sort_key = match &page.ranking {
+        Some(ranking) => page.sort.ranked_key(ranking),
+        None => page.sort.sort_key(),
+    };
+    let sort = doc! { "$sort": { "sort_key": direction, "item_id": direction } };
+    let mut items = vec![];
+    if let Some(cursor) = &page.cursor {
+        items.push(doc! { "$match": cursor.filter(page.ascending) });
+    }
+    items.push(sort.clone());
+    // one more item tells whether there is a next page
+    items.push(doc! { "$limit": page.limit + 1 });
+    let mut facets = doc! {
+        "items": items,
+        "total": [{ "$count": "count" }],
+    };
+    facets.extend(pocket_facets());
+    if with_sources {
+        facets.insert(
+            "instagram",
+            vec![
+                doc! { "$match": { "src_type": "instagram" } },
+                sort.clone(),
+                doc! { "$limit": page.limit },
+            ],
+        );
+        facets.insert(
+            "twitter",
+            vec![
+                doc! { "$match": { "src_type": "twitter" } },
+                doc! { "$group": { "_id": "$excerpt", "data": { "$first": "$$ROOT" } } },
+                doc! { "$replaceRoot": { "newRoot": "$data" } },
+                sort,
+                doc! { "$limit": page.limit },
+            ],
+        );
+    }
+    let pipeline = vec![
+        doc! { "$match": filters },
+        doc! { "$addFields": { "sort_key": sort_key } },
+        doc! { "$facet": facets },
+    ];
+    log::debug!("pipeline: {:?}", pipeline);
+    let mut cursor = coll.aggregate(pipeline, None).await?;
+    let result = match cursor.next().await {
+        Some(result) => result?,
+        None => return Ok(PocketPage::default()),
+    };
+    let to_items = |facet: &str| -> Vec<Document> {
+        result
+            .get_array(facet)
+            .map(|docs| docs.iter().filter_map(|doc| doc.as_document().cloned()).collect())
+            .unwrap_or_default()
+    };
+    // `total` still counts the items that can not be read, they are logged to be fixed
+    let to_pocket_data = |docs: Vec<Document>| -> Vec<DtkPocketData> {
+        docs.into_iter()
+            .filter_map(|doc| {
+                let item_id = doc.get_str("item_id").unwrap_or_default().to_string();
+                mongodb::bson::from_document::<DtkPocketData>(doc)
+                    .map_err(|err| log::error!("[POCKET] Unreadable pocket item {} => {}", item_id, err))
+                    .ok()
+            })
+            .collect()
+    };
+    let mut items = to_items("items");
+    let next_cursor = match items.len() as i64 > page.limit {
+        true => {
+            items.truncate(page.limit as usize);
+            items.last().map(|last| {
+                PocketCursor {
+                    key: last.get("sort_key").cloned().unwrap_or(Bson::Null),
+                    item_id: last.get_str("item_id").unwrap_or_default().to_string(),
+                }
+                .encode()
+            })
+        }
+        false => None,
+    };
+    // counts come back as int32 or int64 depending on their size
+    let to_count = |count: Option<&Bson>| match count {
+        Some(Bson::Int32(count)) => *count as u64,
+        Some(Bson::Int64(count)) => *count as u64,
+        _ => 0,
+    };
+    let total = to_count(to_items("total").first().and_then(|total| total.get("count")));
+    Ok(PocketPage {
+        items: to_pocket_data(items),
+        total,
+        next_cursor,
+        facets: parse_pocket_facets(&result),
+        instagram: to_pocket_data(to_items("instagram")),
+        twitter: to_pocket_data(to_items("twitter")),
+    })
+}
+
+/// Every tag of the items matching `filters`, sorted, whatever the page or the facets
+pub async fn get_pocket_tags(filters: Document) -> Result<Vec<String>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let mut tags: Vec<String> = coll
+        .distinct("tags", filters, None)
+        .await?
+        .into_iter()
+        .filter_map(|tag| tag.as_str().map(String::from))
+        .collect();
+    tags.sort();
+    Ok(tags)
+}
+
+/// Full text search in the user pocket items, best matches first, `search` is escaped
+pub async fn search_pocket_data(user_id: &str, search: &str, limit: i64) -> Vec<DtkPocketData> {
+    let search = escape_text_search(search);
+    if search.is_empty() {
+        return vec![];
+    }
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let filters = mongodb::bson::doc! { "user_id": user_id, "$text": { "$search": search } };
+    let options = FindOptions::builder()
+        .sort(mongodb::bson::doc! { "score": { "$meta": "textScore" } })
+        .limit(limit)
+        .build();
+    let cursor = coll.find(filters, options).await.unwrap();
+    cursor.map(|res| res.unwrap()).collect::<Vec<DtkPocketData>>().await
+}
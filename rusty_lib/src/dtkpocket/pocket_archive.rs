@@ -0,0 +1,451 @@
+//! Offline Markdown snapshots of saved pocket items, kept under `runtime/`
+#![allow(missing_docs)]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReplaceOptions, ReturnDocument};
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::pocket_model::DtkPocketData;
+use super::pocket_utils::*;
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+use crate::dtkutils::dtk_readability::{convert_html_to_md, HtmlToMdMode};
+use crate::dtkutils::dtk_reqwest::{fetch_html, send_public_request, FETCH_USER_AGENT};
+
+/// Waiting for a first or another attempt
+pub const POCKET_ARCHIVE_PENDING: &str = "pending";
+/// Claimed by an archiver run
+pub const POCKET_ARCHIVE_RUNNING: &str = "running";
+pub const POCKET_ARCHIVE_OK: &str = "ok";
+/// Given up after `RUSTY_POCKET_ARCHIVE_MAX_ATTEMPTS`
+pub const POCKET_ARCHIVE_FAILED: &str = "failed";
+
+/// Time allowed to fetch a page or an image
+const POCKET_ARCHIVE_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest wait between two attempts
+const POCKET_ARCHIVE_MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+/// Largest image kept, bigger ones are skipped
+const POCKET_ARCHIVE_MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+/// A claim older than this is from a run that died, the archive is due again
+const POCKET_ARCHIVE_CLAIM_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// A run is skipped while the previous one is still going
+static POCKET_ARCHIVER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Archive state of one item, the snapshot files live in `RUSTY_POCKET_ARCHIVE_DIR`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PocketArchive {
+    pub user_id: String,
+    pub item_id: String,
+    pub url: String,
+    pub image_url: Option<String>,
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: BsonDateTime,
+    pub archived_at: Option<BsonDateTime>,
+    /// Sha256 of the markdown snapshot
+    pub content_hash: Option<String>,
+    pub error: Option<String>,
+    pub has_html: bool,
+    /// Content type of the saved main image
+    pub image_type: Option<String>,
+    /// When an archiver run claimed it
+    #[serde(default)]
+    pub claimed_at: Option<BsonDateTime>,
+}
+
+impl PocketArchive {
+    pub fn pending(user_id: &str, item: &DtkPocketData) -> PocketArchive {
+        PocketArchive {
+            user_id: user_id.to_string(),
+            item_id: item.item_id.clone(),
+            url: item.url.clone(),
+            image_url: item.image_src(),
+            status: POCKET_ARCHIVE_PENDING.to_string(),
+            attempts: 0,
+            next_attempt_at: BsonDateTime::now(),
+            archived_at: None,
+            content_hash: None,
+            error: None,
+            has_html: false,
+            image_type: None,
+            claimed_at: None,
+        }
+    }
+
+    /// Where a snapshot file of the item is stored, ids are encoded so that distinct ids never share a file
+    pub fn path(&self, dir: &Path, format: PocketArchiveFormat) -> PathBuf {
+        let safe = |id: &str| -> String {
+            id.bytes()
+                .map(|byte| match byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                    true => (byte as char).to_string(),
+                    false => format!("%{byte:02X}"),
+                })
+                .collect()
+        };
+        dir.join(safe(&self.user_id))
+            .join(format!("{}.{}", safe(&self.item_id), format.extension()))
+    }
+
+    /// Read a snapshot file with its content type
+    pub async fn read(&self, format: PocketArchiveFormat) -> Result<(Vec<u8>, String), DtkError> {
+        let content_type = match format {
+            PocketArchiveFormat::Markdown => "text/markdown; charset=utf-8".to_string(),
+            PocketArchiveFormat::Html => "text/html; charset=utf-8".to_string(),
+            PocketArchiveFormat::Image => self
+                .image_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+        };
+        let available = match format {
+            PocketArchiveFormat::Markdown => self.status == POCKET_ARCHIVE_OK,
+            PocketArchiveFormat::Html => self.has_html,
+            PocketArchiveFormat::Image => self.image_type.is_some(),
+        };
+        if !available {
+            return Err(DtkError::from("Snapshot not available"));
+        }
+        let dir = get_pocket_archive_dir();
+        let content = tokio::fs::read(self.path(Path::new(&dir), format)).await?;
+        Ok((content, content_type))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PocketArchiveFormat {
+    Markdown,
+    Html,
+    Image,
+}
+
+impl PocketArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PocketArchiveFormat::Markdown => "md",
+            PocketArchiveFormat::Html => "html",
+            PocketArchiveFormat::Image => "img",
+        }
+    }
+}
+
+impl FromStr for PocketArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "md" | "markdown" => Ok(PocketArchiveFormat::Markdown),
+            "html" => Ok(PocketArchiveFormat::Html),
+            "image" | "img" => Ok(PocketArchiveFormat::Image),
+            _ => Err(format!("Unknown archive format: {s}")),
+        }
+    }
+}
+
+/// Body of `/pocket/archive`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketArchiveRequest {
+    pub item_id: String,
+    /// markdown (default), html or image
+    pub format: Option<String>,
+}
+
+/// Wait before the next attempt, doubled after each failure
+pub fn archive_backoff(retry_secs: u64, attempts: u32) -> Duration {
+    let factor = 2_u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_secs(retry_secs.saturating_mul(factor)).min(POCKET_ARCHIVE_MAX_BACKOFF)
+}
+
+/// Keeps `delay` between two requests to the same domain
+#[derive(Debug)]
+pub struct DomainThrottle {
+    delay: Duration,
+    last_fetch: HashMap<String, Instant>,
+}
+
+impl DomainThrottle {
+    pub fn new(delay: Duration) -> DomainThrottle {
+        DomainThrottle {
+            delay,
+            last_fetch: HashMap::new(),
+        }
+    }
+
+    /// Time left before the domain can be requested again
+    pub fn wait_time(&self, domain: &str, now: Instant) -> Duration {
+        match self.last_fetch.get(domain) {
+            Some(last) => (*last + self.delay).saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Sleep until the url domain can be requested, then mark it as requested
+    pub async fn wait(&mut self, url: &str) {
+        let domain = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_default();
+        let wait = self.wait_time(&domain, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        self.last_fetch.insert(domain, Instant::now());
+    }
+}
+
+fn get_archive_coll(client: &Client) -> Collection<PocketArchive> {
+    client
+        .database(&get_pocket_db_name())
+        .collection::<PocketArchive>(&get_pocket_archive_collection_name())
+}
+
+/// Queue synced items for archiving, an item is archived again when its url changed
+pub async fn queue_pocket_archives(client: &Client, user_id: &str, items: &[DtkPocketData]) -> Result<u32, DtkError> {
+    let coll = get_archive_coll(client);
+    let mut queued = 0;
+    for item in items {
+        let filter = doc! { "user_id": user_id, "item_id": &item.item_id };
+        let existing = coll.find_one(filter.clone(), None).await?;
+        if existing.map(|archive| archive.url == item.url).unwrap_or(false) {
+            continue;
+        }
+        let options = ReplaceOptions::builder().upsert(true).build();
+        coll.replace_one(filter, PocketArchive::pending(user_id, item), options)
+            .await?;
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/// Forget archives of deleted items, snapshot files included
+pub async fn remove_pocket_archives(client: &Client, user_id: &str, item_ids: &[String]) -> Result<(), DtkError> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+    let coll = get_archive_coll(client);
+    let filter = doc! { "user_id": user_id, "item_id": { "$in": item_ids } };
+    let mut archives = coll.find(filter.clone(), None).await?;
+    let dir = get_pocket_archive_dir();
+    while let Some(archive) = archives.next().await {
+        let archive = archive?;
+        for format in [PocketArchiveFormat::Markdown, PocketArchiveFormat::Html, PocketArchiveFormat::Image] {
+            tokio::fs::remove_file(archive.path(Path::new(&dir), format)).await.ok();
+        }
+    }
+    coll.delete_many(filter, None).await?;
+    Ok(())
+}
+
+/// Archive of a user item, whatever its status
+pub async fn get_pocket_archive(user_id: &str, item_id: &str) -> Result<Option<PocketArchive>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let archive = get_archive_coll(&client)
+        .find_one(doc! { "user_id": user_id, "item_id": item_id }, None)
+        .await?;
+    Ok(archive)
+}
+
+/// Fetch a file of at most `max_bytes`
+async fn fetch_bytes(url: &str, max_bytes: usize) -> Result<(Vec<u8>, Option<String>), DtkError> {
+    let mut response = send_public_request(reqwest::Method::GET, url, FETCH_USER_AGENT)
+        .await?
+        .error_for_status()?;
+    let too_large = || DtkError::from(format!("Larger than {max_bytes} bytes").as_str());
+    if response.content_length().unwrap_or(0) > max_bytes as u64 {
+        return Err(too_large());
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok((bytes, content_type))
+}
+
+/// Fetch and store the snapshot files, fills in the archive on success
+async fn archive_item(archive: &mut PocketArchive, dir: &Path) -> Result<(), DtkError> {
+    let (html, final_url) = tokio::time::timeout(POCKET_ARCHIVE_FETCH_TIMEOUT, fetch_html(&archive.url))
+        .await
+        .map_err(|_| DtkError::from("Timed out"))??;
+    let md = convert_html_to_md(&html, Some(&final_url), HtmlToMdMode::Article).markdown;
+    if md.trim().is_empty() {
+        return Err(DtkError::from("Empty page"));
+    }
+    let md_path = archive.path(dir, PocketArchiveFormat::Markdown);
+    if let Some(parent) = md_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&md_path, &md).await?;
+    archive.content_hash = Some(format!("{:x}", Sha256::digest(md.as_bytes())));
+    if is_pocket_archive_html() {
+        tokio::fs::write(archive.path(dir, PocketArchiveFormat::Html), &html).await?;
+        archive.has_html = true;
+    }
+    if let (true, Some(image_url)) = (is_pocket_archive_images(), archive.image_url.clone()) {
+        // a missing image does not fail the snapshot
+        let fetch = fetch_bytes(&image_url, POCKET_ARCHIVE_MAX_IMAGE_BYTES);
+        match tokio::time::timeout(POCKET_ARCHIVE_FETCH_TIMEOUT, fetch).await {
+            Ok(Ok((bytes, content_type))) => {
+                tokio::fs::write(archive.path(dir, PocketArchiveFormat::Image), bytes).await?;
+                archive.image_type = Some(content_type.unwrap_or_else(|| "application/octet-stream".to_string()));
+            }
+            Ok(Err(err)) => log::warn!("[POCKET] Could not archive image {} => {}", image_url, err),
+            Err(_) => log::warn!("[POCKET] Timed out archiving image {}", image_url),
+        }
+    }
+    Ok(())
+}
+
+/// Claim the next archive due, so that it is archived by a single run
+async fn claim_pocket_archive(coll: &Collection<PocketArchive>) -> Result<Option<PocketArchive>, DtkError> {
+    let now = BsonDateTime::now();
+    let stale = BsonDateTime::from_millis(now.timestamp_millis() - POCKET_ARCHIVE_CLAIM_TIMEOUT.as_millis() as i64);
+    let filter = doc! { "$or": [
+        { "status": POCKET_ARCHIVE_PENDING, "next_attempt_at": { "$lte": now } },
+        { "status": POCKET_ARCHIVE_RUNNING, "claimed_at": { "$lt": stale } },
+    ] };
+    let update = doc! { "$set": { "status": POCKET_ARCHIVE_RUNNING, "claimed_at": now } };
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "next_attempt_at": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+    Ok(coll.find_one_and_update(filter, update, options).await?)
+}
+
+/// Clears the running flag, even when the run panicked
+struct PocketArchiverRun;
+
+impl Drop for PocketArchiverRun {
+    fn drop(&mut self) {
+        POCKET_ARCHIVER_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Archive the items due, one domain request every `RUSTY_POCKET_ARCHIVE_DOMAIN_DELAY` seconds.
+/// Failures are retried with an exponential backoff until `RUSTY_POCKET_ARCHIVE_MAX_ATTEMPTS`.
+pub async fn run_pocket_archiver() {
+    if POCKET_ARCHIVER_RUNNING.swap(true, Ordering::SeqCst) {
+        log::debug!("[POCKET] Archiver still running");
+        return;
+    }
+    let _run = PocketArchiverRun;
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = get_archive_coll(&client);
+    let dir = get_pocket_archive_dir();
+    let mut throttle = DomainThrottle::new(Duration::from_secs(get_pocket_archive_domain_delay()));
+    let (mut archived, mut failed) = (0, 0);
+    for _ in 0..get_pocket_archive_batch() {
+        let mut archive = match claim_pocket_archive(&coll).await {
+            Ok(Some(archive)) => archive,
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("[POCKET] Could not claim an archive => {}", err);
+                break;
+            }
+        };
+        throttle.wait(&archive.url).await;
+        archive.attempts += 1;
+        archive.claimed_at = None;
+        match archive_item(&mut archive, Path::new(&dir)).await {
+            Ok(()) => {
+                archive.status = POCKET_ARCHIVE_OK.to_string();
+                archive.archived_at = Some(BsonDateTime::now());
+                archive.error = None;
+                archived += 1;
+            }
+            Err(err) => {
+                log::warn!("[POCKET] Could not archive {} => {}", archive.url, err);
+                archive.error = Some(err.to_string());
+                if archive.attempts >= get_pocket_archive_max_attempts() {
+                    archive.status = POCKET_ARCHIVE_FAILED.to_string();
+                } else {
+                    archive.status = POCKET_ARCHIVE_PENDING.to_string();
+                    let backoff = archive_backoff(get_pocket_archive_retry_secs(), archive.attempts);
+                    let next_attempt = chrono::Utc::now().timestamp_millis() + backoff.as_millis() as i64;
+                    archive.next_attempt_at = BsonDateTime::from_millis(next_attempt);
+                }
+                failed += 1;
+            }
+        }
+        // a sync may have queued the item again meanwhile, its pending state wins
+        let filter = doc! {
+            "user_id": &archive.user_id,
+            "item_id": &archive.item_id,
+            "status": POCKET_ARCHIVE_RUNNING,
+        };
+        if let Err(err) = coll.replace_one(filter, &archive, None).await {
+            log::error!("[POCKET] Could not save archive state of {} => {}", archive.item_id, err);
+        }
+    }
+    log::info!("[POCKET] Archiver run => archived: {}, failed: {}", archived, failed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtkpocket::pocket_model::{test_pocket_item, PocketData};
+
+    #[test]
+    fn backoff_doubles_up_to_a_day() {
+        assert_eq!(archive_backoff(300, 1), Duration::from_secs(300));
+        assert_eq!(archive_backoff(300, 2), Duration::from_secs(600));
+        assert_eq!(archive_backoff(300, 4), Duration::from_secs(2400));
+        assert_eq!(archive_backoff(300, 40), POCKET_ARCHIVE_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn throttle_per_domain() {
+        let mut throttle = DomainThrottle::new(Duration::from_secs(5));
+        let now = Instant::now();
+        assert_eq!(throttle.wait_time("rusty.com", now), Duration::ZERO);
+        throttle.last_fetch.insert("rusty.com".to_string(), now);
+        assert_eq!(throttle.wait_time("rusty.com", now + Duration::from_secs(2)), Duration::from_secs(3));
+        assert_eq!(throttle.wait_time("rusty.com", now + Duration::from_secs(6)), Duration::ZERO);
+        assert_eq!(throttle.wait_time("other.com", now), Duration::ZERO);
+    }
+
+    #[test]
+    fn snapshot_paths_and_formats() {
+        let pocket_item = PocketData {
+            image: Some(serde_json::json!({ "src": "https://rusty.com/a.png" })),
+            ..PocketData::default()
+        };
+        let item = test_pocket_item("42", "https://rusty.com", pocket_item);
+        let mut archive = PocketArchive::pending("baakey", &item);
+        assert_eq!(archive.image_url.as_deref(), Some("https://rusty.com/a.png"));
+        assert_eq!(
+            archive.path(Path::new("runtime/pocket_archive"), PocketArchiveFormat::Markdown),
+            PathBuf::from("runtime/pocket_archive/baakey/42.md")
+        );
+        archive.user_id = "../baakey".to_string();
+        archive.item_id = "4/2".to_string();
+        assert_eq!(
+            archive.path(Path::new("archive"), PocketArchiveFormat::Html),
+            PathBuf::from("archive/%2E%2E%2Fbaakey/4%2F2.html")
+        );
+        // ids differing only by unsafe chars get distinct files
+        archive.item_id = "4.2".to_string();
+        assert_eq!(
+            archive.path(Path::new("archive"), PocketArchiveFormat::Html),
+            PathBuf::from("archive/%2E%2E%2Fbaakey/4%2E2.html")
+        );
+        assert_eq!("Markdown".parse::<PocketArchiveFormat>(), Ok(PocketArchiveFormat::Markdown));
+        assert_eq!("img".parse::<PocketArchiveFormat>(), Ok(PocketArchiveFormat::Image));
+        assert!("pdf".parse::<PocketArchiveFormat>().is_err());
+    }
+}
@@ -8,13 +8,16 @@ use crate::{
     },
 };
 
-use super::pocket_model::{self, PocketCodeResponseBody, PocketTokenResponseBody};
+use super::pocket_model::{self, PocketAction, PocketCodeResponseBody, PocketTokenResponseBody};
+use crate::dtkutils::dtk_error::DtkError;
 
 const REDIRECT_URI: &str = "http://baakey.rusty.com/auth_pocket";
 const POCKET_USER_REQUEST_URI: &str = "https://getpocket.com/v3/oauth/request";
 const POCKET_AUTH_URI: &str = "https://getpocket.com/v3/oauth/authorize";
 const POCKET_RETRIEVE_URI: &str = "https://getpocket.com/v3/get";
 const POCKET_PUSH_URI: &str = "https://getpocket.com/v3/send";
+/// Actions sent per `v3/send` request
+const POCKET_SEND_BATCH_SIZE: usize = 50;
 
 fn get_payload_for_pocket_code() -> serde_json::Value {
     serde_json::json!({
@@ -26,7 +29,7 @@ fn get_payload_for_pocket_code() -> serde_json::Value {
 
 /// get auth url for user to accepet connection
 pub async fn get_user_auth_url() -> Result<(String, String), String> {
-    let response = match send_post_request(&POCKET_USER_REQUEST_URI, get_payload_for_pocket_code()).await {
+    let response = match send_post_request(POCKET_USER_REQUEST_URI, get_payload_for_pocket_code()).await {
         Ok(r) => get_dtk_response(r).await,
         Err(err) => {
             let err_str = err.to_string();
@@ -57,7 +60,7 @@ pub async fn get_access_token(code: &str) -> Result<PocketTokenResponseBody, ()>
         "consumer_key": &get_pocket_consumer_key(),
         "code": code
     });
-    let response = match send_post_request(&POCKET_AUTH_URI, payload).await {
+    let response = match send_post_request(POCKET_AUTH_URI, payload).await {
         Ok(r) => get_dtk_response(r).await,
         Err(err) => {
             let err_str = err.to_string();
@@ -80,21 +83,27 @@ pub struct PocketPushData {
     pub title: String,
     /// tags to add to the url
     pub tags: String,
+    /// unix time the url was saved at, now when None
+    pub time: Option<i64>,
 }
 /// puch new data to pocket
 pub async fn push_pocket_data(
     access_token: &str,
     data_to_push: Vec<PocketPushData>,
-) -> Result<pocket_model::PocketSendResponse, ()> {
+) -> Result<pocket_model::PocketSendResponse, DtkError> {
     let actions = data_to_push
         .iter()
         .map(|data| {
-            serde_json::json!({
+            let mut action = serde_json::json!({
                 "action": "add",
                 "url": &data.url,
                 "title": &data.title,
                 "tags": &data.tags,
-            })
+            });
+            if let Some(time) = data.time {
+                action["time"] = serde_json::json!(time);
+            }
+            action
         })
         .collect::<Vec<serde_json::Value>>();
     let payload = serde_json::json!({
@@ -102,17 +111,14 @@ pub async fn push_pocket_data(
         "access_token": &access_token,
         "actions": actions,
     });
-    let response = match send_post_request(&POCKET_PUSH_URI, payload).await {
-        Ok(r) => get_dtk_response(r).await,
-        Err(err) => {
-            let err_str = err.to_string();
-            println!("Request failed: {}", err_str);
-            return Err(());
-        }
-    };
-    let raw_json = &response.res.unwrap();
-    let pocket_body_response: pocket_model::PocketSendResponse = serde_json::from_value(raw_json.clone()).expect("wtf");
-
+    let response = send_post_request(POCKET_PUSH_URI, payload)
+        .await
+        .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    let response = check_pocket_response(response, "Pocket add failed")?;
+    let pocket_body_response = response
+        .json::<pocket_model::PocketSendResponse>()
+        .await
+        .map_err(|err| DtkError::from(err.to_string().as_str()))?;
     Ok(pocket_body_response)
 }
 
@@ -120,7 +126,7 @@ pub async fn push_pocket_data(
 pub async fn retreive_pocket_data(
     access_token: &str,
     since: Option<i64>,
-) -> Result<pocket_model::PocketExtractResponse, ()> {
+) -> Result<pocket_model::PocketExtractResponse, DtkError> {
     let payload = serde_json::json!({
         "consumer_key": &get_pocket_consumer_key(),
         "access_token": &access_token,
@@ -129,17 +135,96 @@ pub async fn retreive_pocket_data(
         "sort": "newest",
         "detailType": "complete"
     });
-    let response = match send_post_request(&POCKET_RETRIEVE_URI, payload).await {
-        Ok(r) => get_dtk_response(r).await,
-        Err(err) => {
-            let err_str = err.to_string();
-            println!("Request failed: {}", err_str);
-            return Err(());
-        }
-    };
-    let raw_json = &response.res.unwrap();
-    let pocket_body_response: pocket_model::PocketExtractResponse =
-        serde_json::from_value(raw_json.clone()).expect("wtf");
-
+    let response = send_post_request(POCKET_RETRIEVE_URI, payload)
+        .await
+        .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    let response = check_pocket_response(response, "Pocket retrieve failed")?;
+    let pocket_body_response = response
+        .json::<pocket_model::PocketExtractResponse>()
+        .await
+        .map_err(|err| DtkError::from(err.to_string().as_str()))?;
     Ok(pocket_body_response)
 }
+
+/// Pocket tells what went wrong in the `X-Error` header of non 200 responses
+fn check_pocket_response(response: reqwest::Response, default_error: &str) -> Result<reqwest::Response, DtkError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let error = response
+        .headers()
+        .get("X-Error")
+        .and_then(|error| error.to_str().ok())
+        .unwrap_or(default_error)
+        .to_string();
+    Err(DtkError::from(format!("{} ({})", error, response.status()).as_str()))
+}
+
+/// One result per action, `false` means Pocket refused it, added items come back as objects
+pub fn parse_action_results(action_results: &serde_json::Value, expected: usize) -> Vec<bool> {
+    let mut results: Vec<bool> = action_results
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|result| !matches!(result, serde_json::Value::Bool(false) | serde_json::Value::Null))
+                .collect()
+        })
+        .unwrap_or_default();
+    results.resize(expected, false);
+    results
+}
+
+/// Send actions to pocket in batches, returns whether each action succeeded
+pub async fn send_pocket_actions(access_token: &str, actions: &[PocketAction]) -> Result<Vec<bool>, DtkError> {
+    let mut results = Vec::with_capacity(actions.len());
+    for batch in actions.chunks(POCKET_SEND_BATCH_SIZE) {
+        let payload = serde_json::json!({
+            "consumer_key": &get_pocket_consumer_key(),
+            "access_token": &access_token,
+            "actions": batch.iter().map(|action| action.to_pocket_json()).collect::<Vec<serde_json::Value>>(),
+        });
+        let response = send_post_request(POCKET_PUSH_URI, payload)
+            .await
+            .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+        let response = check_pocket_response(response, "Pocket send failed")?;
+        let send_response = response
+            .json::<pocket_model::PocketSendResponse>()
+            .await
+            .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+        results.extend(parse_action_results(&send_response.action_results, batch.len()));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pocket_action_payload() {
+        let action = PocketAction::TagsAdd {
+            item_id: "42".to_string(),
+            tags: vec!["rust".to_string(), "async".to_string()],
+        };
+        assert_eq!(
+            action.to_pocket_json(),
+            serde_json::json!({ "action": "tags_add", "item_id": "42", "tags": "rust,async" })
+        );
+        let action = PocketAction::TagRename {
+            old_tag: "rs".to_string(),
+            new_tag: "rust".to_string(),
+        };
+        assert_eq!(
+            action.to_pocket_json(),
+            serde_json::json!({ "action": "tag_rename", "old_tag": "rs", "new_tag": "rust" })
+        );
+    }
+
+    #[test]
+    fn pocket_action_results() {
+        let results = serde_json::json!([true, false, { "item_id": "42" }]);
+        assert_eq!(parse_action_results(&results, 4), vec![true, false, true, false]);
+        assert_eq!(parse_action_results(&serde_json::Value::Null, 1), vec![false]);
+    }
+}
@@ -0,0 +1,355 @@
+//! User defined rules tagging saved items
+#![allow(missing_docs)]
+
+use std::sync::Arc;
+
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::Client;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::pocket::{reindex_pocket_items, run_pocket_actions};
+use super::pocket_index::PocketIndex;
+use super::pocket_classifier::{host_matches, url_host, POCKET_FALLBACK_TAG};
+use super::pocket_model::{DtkPocketData, PocketAction};
+use super::pocket_tags::normalize_tag;
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_db_name, get_pocket_users_collection_name};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Tags added to the items matching every condition set, at least one is required
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketTagRule {
+    /// Host of the url or one of its parents
+    pub domain: Option<String>,
+    /// Regex matched against the whole url
+    pub url_pattern: Option<String>,
+    /// Any of these words in the title, case insensitive
+    pub title_keywords: Vec<String>,
+    pub lang: Option<String>,
+    pub word_count_min: Option<u32>,
+    pub word_count_max: Option<u32>,
+    pub tags: Vec<String>,
+}
+
+/// Rules of a user, stored as `tag_rules` in the pocket user
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketTagRules {
+    pub rules: Vec<PocketTagRule>,
+    /// Also add the tags in Pocket, otherwise only in our copy
+    pub push_to_pocket: bool,
+}
+
+/// Body of the `/pocket/tag_rules` endpoints, the stored rules are used when missing
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PocketTagRulesRequest {
+    pub tag_rules: Option<PocketTagRules>,
+}
+
+/// Tags an item gets from the rules
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PocketAutoTagChange {
+    pub item_id: String,
+    pub url: String,
+    pub title: String,
+    pub added: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct CompiledTagRule {
+    rule: PocketTagRule,
+    url_pattern: Option<Regex>,
+    tags: Vec<String>,
+}
+
+impl CompiledTagRule {
+    fn matches(&self, item: &DtkPocketData) -> bool {
+        let rule = &self.rule;
+        if let Some(domain) = &rule.domain {
+            let host = url_host(&item.url).unwrap_or_default();
+            if host.is_empty() || !host_matches(&host, domain) {
+                return false;
+            }
+        }
+        if let Some(url_pattern) = &self.url_pattern {
+            if !url_pattern.is_match(&item.url) {
+                return false;
+            }
+        }
+        if !rule.title_keywords.is_empty() {
+            let title = item.title.to_lowercase();
+            if !rule.title_keywords.iter().any(|keyword| title.contains(&keyword.trim().to_lowercase())) {
+                return false;
+            }
+        }
+        if let Some(lang) = &rule.lang {
+            if !item.lang.eq_ignore_ascii_case(lang.trim()) {
+                return false;
+            }
+        }
+        if rule.word_count_min.is_some() || rule.word_count_max.is_some() {
+            let word_count = item.word_count.trim().parse::<u32>().unwrap_or(0);
+            if rule.word_count_min.map(|min| word_count < min).unwrap_or(false)
+                || rule.word_count_max.map(|max| word_count > max).unwrap_or(false)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PocketAutoTagger {
+    rules: Vec<CompiledTagRule>,
+    pub push_to_pocket: bool,
+}
+
+impl PocketAutoTagger {
+    /// Err on an invalid regex, a rule without condition or without tags
+    pub fn new(tag_rules: &PocketTagRules) -> Result<PocketAutoTagger, DtkError> {
+        let mut rules = vec![];
+        for (index, rule) in tag_rules.rules.iter().enumerate() {
+            let invalid = |error: &str| DtkError::from(format!("Rule {index}: {error}").as_str());
+            let has_condition = rule.domain.is_some()
+                || rule.url_pattern.is_some()
+                || !rule.title_keywords.is_empty()
+                || rule.lang.is_some()
+                || rule.word_count_min.is_some()
+                || rule.word_count_max.is_some();
+            if !has_condition {
+                return Err(invalid("no condition"));
+            }
+            let tags: Vec<String> = rule
+                .tags
+                .iter()
+                .map(|tag| normalize_tag(tag))
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            if tags.is_empty() {
+                return Err(invalid("no tags"));
+            }
+            let url_pattern = match &rule.url_pattern {
+                Some(pattern) => Some(Regex::new(pattern).map_err(|err| invalid(&err.to_string()))?),
+                None => None,
+            };
+            rules.push(CompiledTagRule {
+                rule: rule.clone(),
+                url_pattern,
+                tags,
+            });
+        }
+        Ok(PocketAutoTagger {
+            rules,
+            push_to_pocket: tag_rules.push_to_pocket,
+        })
+    }
+
+    /// Rules stored for the user, none when unset
+    pub async fn for_user(client: &Client, user_id: &str) -> Result<PocketAutoTagger, DtkError> {
+        PocketAutoTagger::new(&get_pocket_tag_rules(client, user_id).await?)
+    }
+
+    /// Tags of matching rules the item is missing
+    pub fn new_tags(&self, item: &DtkPocketData) -> Vec<String> {
+        let mut tags: Vec<String> = vec![];
+        for rule in self.rules.iter().filter(|rule| rule.matches(item)) {
+            for tag in rule.tags.iter() {
+                if !item.tags.contains(tag) && !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        tags
+    }
+
+    /// Add the new tags to the item, they replace the fallback tag
+    pub fn apply(&self, item: &mut DtkPocketData) -> Vec<String> {
+        let added = self.new_tags(item);
+        if !added.is_empty() {
+            if item.tags == [POCKET_FALLBACK_TAG] {
+                item.tags.clear();
+            }
+            item.tags.extend(added.iter().cloned());
+        }
+        added
+    }
+}
+
+/// Tag rules stored in the pocket user
+pub async fn get_pocket_tag_rules(client: &Client, user_id: &str) -> Result<PocketTagRules, DtkError> {
+    let user = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_users_collection_name())
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or_else(|| DtkError::from("Pocket user not found"))?;
+    Ok(match user.get_document("tag_rules") {
+        Ok(tag_rules) => mongodb::bson::from_document(tag_rules.clone())
+            .map_err(|err| DtkError::from(err.to_string().as_str()))?,
+        Err(_) => PocketTagRules::default(),
+    })
+}
+
+/// Validate and store the user tag rules
+pub async fn set_pocket_tag_rules(user_id: &str, tag_rules: &PocketTagRules) -> Result<(), DtkError> {
+    PocketAutoTagger::new(tag_rules)?;
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let tag_rules = mongodb::bson::to_document(tag_rules).map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    let res = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_users_collection_name())
+        .update_one(doc! { "user_id": user_id }, doc! { "$set": { "tag_rules": tag_rules } }, None)
+        .await?;
+    match res.matched_count {
+        0 => Err(DtkError::from("Pocket user not found")),
+        _ => Ok(()),
+    }
+}
+
+/// Items of the user the rules would tag, the stored rules are used when `tag_rules` is None
+pub async fn preview_tag_rules(
+    user_id: &str,
+    tag_rules: Option<&PocketTagRules>,
+) -> Result<Vec<PocketAutoTagChange>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let tagger = match tag_rules {
+        Some(tag_rules) => PocketAutoTagger::new(tag_rules)?,
+        None => PocketAutoTagger::for_user(&client, user_id).await?,
+    };
+    let items = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name())
+        .find(doc! { "user_id": user_id }, None)
+        .await?
+        .filter_map(|item| async { item.ok() })
+        .collect::<Vec<DtkPocketData>>()
+        .await;
+    Ok(items
+        .into_iter()
+        .filter_map(|item| {
+            let added = tagger.new_tags(&item);
+            (!added.is_empty()).then_some(PocketAutoTagChange {
+                item_id: item.item_id,
+                url: item.url,
+                title: item.title,
+                added,
+            })
+        })
+        .collect())
+}
+
+/// Apply the stored rules to the items saved before them
+pub async fn backfill_tag_rules(
+    user_id: &str,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<Vec<PocketAutoTagChange>, DtkError> {
+    let changes = preview_tag_rules(user_id, None).await?;
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let tagger = PocketAutoTagger::for_user(&client, user_id).await?;
+    let actions = changes
+        .iter()
+        .map(|change| PocketAction::TagsAdd {
+            item_id: change.item_id.clone(),
+            tags: change.added.clone(),
+        })
+        .collect::<Vec<PocketAction>>();
+    if actions.is_empty() {
+        return Ok(changes);
+    }
+    if tagger.push_to_pocket {
+        run_pocket_actions(user_id, actions, index).await?;
+        return Ok(changes);
+    }
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    for change in changes.iter() {
+        let filter = doc! { "user_id": user_id, "item_id": &change.item_id };
+        let mut fallback_only = filter.clone();
+        fallback_only.insert("tags", vec![POCKET_FALLBACK_TAG]);
+        coll.update_one(fallback_only, doc! { "$set": { "tags": [] } }, None).await?;
+        coll.update_one(filter, doc! { "$addToSet": { "tags": { "$each": &change.added } } }, None)
+            .await?;
+    }
+    let item_ids = changes.iter().map(|change| change.item_id.clone()).collect();
+    reindex_pocket_items(index, user_id, item_ids).await?;
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtkpocket::pocket_model::{test_pocket_item, PocketData};
+
+    fn item(url: &str, title: &str, lang: &str, word_count: &str) -> DtkPocketData {
+        let pocket_item = PocketData {
+            given_title: title.to_string(),
+            lang: lang.to_string(),
+            word_count: word_count.to_string(),
+            ..PocketData::default()
+        };
+        test_pocket_item("42", url, pocket_item)
+    }
+
+    fn rule(tags: &[&str]) -> PocketTagRule {
+        PocketTagRule {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..PocketTagRule::default()
+        }
+    }
+
+    #[test]
+    fn rules_match_every_condition() {
+        let tagger = PocketAutoTagger::new(&PocketTagRules {
+            rules: vec![
+                PocketTagRule {
+                    domain: Some("rust-lang.org".to_string()),
+                    ..rule(&["Lang/Rust"])
+                },
+                PocketTagRule {
+                    title_keywords: vec!["Async".to_string(), "tokio".to_string()],
+                    lang: Some("en".to_string()),
+                    ..rule(&["async"])
+                },
+                PocketTagRule {
+                    url_pattern: Some(r"/blog/\d{4}/".to_string()),
+                    word_count_min: Some(2000),
+                    ..rule(&["longread"])
+                },
+            ],
+            push_to_pocket: false,
+        })
+        .unwrap();
+        let mut blog = item("https://blog.rust-lang.org/blog/2023/async", "Async Rust", "en", "2500");
+        assert_eq!(tagger.apply(&mut blog), vec!["lang/rust", "async", "longread"]);
+        assert_eq!(blog.tags, vec!["lang/rust", "async", "longread"]);
+        assert!(tagger.apply(&mut blog).is_empty());
+        let french = item("https://rusty.com/blog/2023/a", "Tokio", "fr", "500");
+        assert!(tagger.new_tags(&french).is_empty());
+        let untyped = item("https://notrust-lang.org", "Tokio", "EN", "");
+        assert_eq!(tagger.new_tags(&untyped), vec!["async"]);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let rules = |rule: PocketTagRule| PocketTagRules {
+            rules: vec![rule],
+            push_to_pocket: false,
+        };
+        assert!(PocketAutoTagger::new(&rules(rule(&["rust"]))).is_err());
+        let no_tags = PocketTagRule {
+            lang: Some("en".to_string()),
+            ..rule(&[" / "])
+        };
+        assert!(PocketAutoTagger::new(&rules(no_tags)).is_err());
+        let bad_regex = PocketTagRule {
+            url_pattern: Some("(".to_string()),
+            ..rule(&["rust"])
+        };
+        assert!(PocketAutoTagger::new(&rules(bad_regex)).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Rule driven source type and tags of saved urls
+#![allow(missing_docs)]
+
+use std::path::Path;
+
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::pocket_model::PocketSrcType;
+use super::pocket_utils::{get_pocket_classifier_file, get_pocket_collection_name, get_pocket_db_name};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Tag of items without any other tag
+pub const POCKET_FALLBACK_TAG: &str = "article";
+
+/// One classification rule, the first matching rule wins
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketSrcRule {
+    /// Hosts matched exactly or as a parent domain, `www.` is ignored, empty matches any host
+    pub hosts: Vec<String>,
+    /// Regex the url path must match
+    pub path_pattern: Option<String>,
+    pub src_type: String,
+    /// Tags added to matching items
+    pub tags: Vec<String>,
+}
+
+impl PocketSrcRule {
+    pub fn new(hosts: &[&str], src_type: PocketSrcType, tags: &[&str]) -> PocketSrcRule {
+        PocketSrcRule {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            path_pattern: None,
+            src_type: src_type.to_string().to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+}
+
+/// Content of `RUSTY_POCKET_CLASSIFIER_FILE`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketClassifierConfig {
+    /// Checked before the default rules
+    pub rules: Vec<PocketSrcRule>,
+    /// Drop the default rules, only `rules` are used
+    pub replace_defaults: bool,
+}
+
+/// Source type and tags derived from an url
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PocketClassification {
+    pub src_type: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    rule: PocketSrcRule,
+    path: Option<Regex>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PocketClassifier {
+    rules: Vec<CompiledRule>,
+}
+
+impl Default for PocketClassifier {
+    fn default() -> Self {
+        PocketClassifier::new(PocketClassifier::default_rules()).unwrap()
+    }
+}
+
+/// Host of an url, lowercase and without `www.`
+pub fn url_host(url: &str) -> Option<String> {
+    let url = url::Url::parse(url.trim()).ok()?;
+    let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+    Some(host.strip_prefix("www.").map(String::from).unwrap_or(host))
+}
+
+/// Whether `host` is `rule_host` or one of its subdomains
+pub fn host_matches(host: &str, rule_host: &str) -> bool {
+    let rule_host = rule_host.trim().trim_start_matches("www.").to_lowercase();
+    host == rule_host || host.ends_with(&format!(".{rule_host}"))
+}
+
+impl PocketClassifier {
+    pub fn new(rules: Vec<PocketSrcRule>) -> Result<PocketClassifier, DtkError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let path = match &rule.path_pattern {
+                    Some(pattern) => Some(Regex::new(pattern).map_err(|err| DtkError::from(err.to_string().as_str()))?),
+                    None => None,
+                };
+                Ok(CompiledRule { rule, path })
+            })
+            .collect::<Result<Vec<CompiledRule>, DtkError>>()?;
+        Ok(PocketClassifier { rules })
+    }
+
+    pub fn default_rules() -> Vec<PocketSrcRule> {
+        let mastodon_hosts = [
+            "mastodon.social",
+            "mastodon.online",
+            "fosstodon.org",
+            "hachyderm.io",
+            "infosec.exchange",
+            "mas.to",
+        ];
+        vec![
+            PocketSrcRule::new(&["instagram.com", "instagr.am"], PocketSrcType::INSTAGRAM, &["instagram"]),
+            PocketSrcRule::new(&["twitter.com", "x.com"], PocketSrcType::TWITTER, &["twitter"]),
+            PocketSrcRule::new(&["youtube.com", "youtu.be"], PocketSrcType::YOUTUBE, &["youtube"]),
+            PocketSrcRule::new(&["github.com", "github1s.com"], PocketSrcType::GITHUB, &["github", "tech"]),
+            PocketSrcRule::new(&["reddit.com", "redd.it"], PocketSrcType::REDDIT, &["reddit"]),
+            PocketSrcRule::new(&["news.ycombinator.com"], PocketSrcType::HACKERNEWS, &["hackernews", "tech"]),
+            PocketSrcRule::new(&mastodon_hosts, PocketSrcType::MASTODON, &["mastodon"]),
+            // other instances, recognized by their `/@user/status_id` urls
+            PocketSrcRule {
+                path_pattern: Some(r"^/@[A-Za-z0-9_.]+/\d+/?$".to_string()),
+                ..PocketSrcRule::new(&[], PocketSrcType::MASTODON, &["mastodon"])
+            },
+            PocketSrcRule::new(&["arxiv.org"], PocketSrcType::ARXIV, &["arxiv", "paper"]),
+            PocketSrcRule::new(
+                &["stackoverflow.com", "stackexchange.com", "superuser.com", "serverfault.com"],
+                PocketSrcType::STACKOVERFLOW,
+                &["stackoverflow", "tech"],
+            ),
+            PocketSrcRule::new(&["medium.com"], PocketSrcType::MEDIUM, &["medium"]),
+            PocketSrcRule::new(&["vimeo.com"], PocketSrcType::VIMEO, &["vimeo"]),
+        ]
+    }
+
+    /// Default rules extended by `RUSTY_POCKET_CLASSIFIER_FILE` when it exists
+    pub fn from_file(path: &Path) -> Result<PocketClassifier, DtkError> {
+        let config = std::fs::read_to_string(path)?;
+        let config: PocketClassifierConfig =
+            serde_json::from_str(&config).map_err(|err| DtkError::from(err.to_string().as_str()))?;
+        let mut rules = config.rules;
+        if !config.replace_defaults {
+            rules.extend(PocketClassifier::default_rules());
+        }
+        PocketClassifier::new(rules)
+    }
+
+    /// Classifier from `RUSTY_POCKET_CLASSIFIER_FILE`, the default rules when missing or invalid
+    pub fn from_env() -> PocketClassifier {
+        let file = get_pocket_classifier_file();
+        let path = Path::new(&file);
+        if file.is_empty() || !path.exists() {
+            return PocketClassifier::default();
+        }
+        PocketClassifier::from_file(path).unwrap_or_else(|err| {
+            log::error!("[POCKET] Invalid classifier config {} => {}", file, err);
+            PocketClassifier::default()
+        })
+    }
+
+    pub fn classify(&self, url: &str) -> PocketClassification {
+        let host = url_host(url).unwrap_or_default();
+        let path = url::Url::parse(url.trim())
+            .map(|url| url.path().to_string())
+            .unwrap_or_default();
+        self.rules
+            .iter()
+            .find(|compiled| {
+                let rule = &compiled.rule;
+                let host_ok =
+                    rule.hosts.is_empty() || rule.hosts.iter().any(|rule_host| host_matches(&host, rule_host));
+                let path_ok = compiled.path.as_ref().map(|path_re| path_re.is_match(&path)).unwrap_or(true);
+                !host.is_empty() && host_ok && path_ok
+            })
+            .map(|compiled| PocketClassification {
+                src_type: compiled.rule.src_type.clone(),
+                tags: compiled.rule.tags.iter().map(|tag| tag.to_lowercase()).collect(),
+            })
+            .unwrap_or_else(|| PocketClassification {
+                src_type: PocketSrcType::ARTICLE.to_string().to_string(),
+                tags: vec![],
+            })
+    }
+
+    /// User tags followed by the derived ones, or the fallback tag when there are none
+    pub fn tags(&self, url: &str, user_tags: &[String]) -> Vec<String> {
+        let mut tags: Vec<String> = user_tags
+            .iter()
+            .filter(|tag| user_tags.len() > 1 || tag.as_str() != POCKET_FALLBACK_TAG)
+            .cloned()
+            .collect();
+        for tag in self.classify(url).tags {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        if tags.is_empty() {
+            tags.push(POCKET_FALLBACK_TAG.to_string());
+        }
+        tags
+    }
+}
+
+/// Classify stored items again and record their derived tags, returns how many changed
+pub async fn reclassify_pocket_data(classifier: &PocketClassifier) -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let options = FindOptions::builder()
+        .projection(doc! { "url": 1, "src_type": 1, "tags": 1 })
+        .build();
+    let mut items = coll.find(None, options).await?;
+    let mut changed = 0;
+    while let Some(item) = items.next().await {
+        let item = item?;
+        let url = item.get_str("url").unwrap_or_default();
+        let tags: Vec<String> = item
+            .get_array("tags")
+            .map(|tags| tags.iter().filter_map(|tag| tag.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        let classification = classifier.classify(url);
+        let new_tags = classifier.tags(url, &tags);
+        let derived_tags: Vec<&String> = new_tags
+            .iter()
+            .filter(|tag| classification.tags.contains(tag) || tag.as_str() == POCKET_FALLBACK_TAG)
+            .collect();
+        let update = doc! {
+            "$set": { "src_type": &classification.src_type, "tags": &new_tags },
+            "$addToSet": { "derived_tags": { "$each": &derived_tags } },
+        };
+        changed += coll.update_one(doc! { "_id": item.get("_id") }, update, None).await?.modified_count;
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn src_type(url: &str) -> String {
+        PocketClassifier::default().classify(url).src_type
+    }
+
+    #[test]
+    fn classify_hosts() {
+        assert_eq!(src_type("https://github.com/baakeydow/rusty-playground"), "github");
+        assert_eq!(src_type("https://gist.github.com/baakeydow/42"), "github");
+        assert_eq!(src_type("https://notgithub.com/baakeydow"), "article");
+        assert_eq!(src_type("https://example.com/?ref=github.com"), "article");
+        assert_eq!(src_type("https://x.com/rustlang/status/1"), "twitter");
+        assert_eq!(src_type("https://www.youtube.com/watch?v=42"), "youtube");
+        assert_eq!(src_type("https://old.reddit.com/r/rust"), "reddit");
+        assert_eq!(src_type("https://news.ycombinator.com/item?id=1"), "hackernews");
+        assert_eq!(src_type("https://fosstodon.org/@rust/1"), "mastodon");
+        assert_eq!(src_type("https://social.rusty.com/@baakey/110000000000000001"), "mastodon");
+        assert_eq!(src_type("https://arxiv.org/abs/1706.03762"), "arxiv");
+        assert_eq!(src_type("https://unix.stackexchange.com/questions/1"), "stackoverflow");
+        assert_eq!(src_type("https://medium.com/@baakey/rust"), "medium");
+        assert_eq!(src_type("https://vimeo.com/42"), "vimeo");
+        assert_eq!(src_type("not an url"), "article");
+    }
+
+    #[test]
+    fn derived_tags() {
+        let classifier = PocketClassifier::default();
+        let rust = vec!["rust".to_string()];
+        assert_eq!(classifier.tags("https://github.com/rust-lang", &rust), vec!["rust", "github", "tech"]);
+        assert_eq!(classifier.tags("https://rusty.com", &[]), vec!["article"]);
+        assert_eq!(classifier.tags("https://rusty.com", &rust), vec!["rust"]);
+        // the fallback tag goes away once the url gets tags of its own
+        assert_eq!(classifier.tags("https://reddit.com/r/rust", &["article".to_string()]), vec!["reddit"]);
+    }
+
+    #[test]
+    fn config_rules_come_first() {
+        let config: PocketClassifierConfig = serde_json::from_str(
+            r#"{ "rules": [
+                { "hosts": ["github.com"], "path_pattern": "^/rust-lang/", "src_type": "rust", "tags": ["Rust"] },
+                { "hosts": ["lobste.rs"], "src_type": "lobsters", "tags": ["tech"] }
+            ] }"#,
+        )
+        .unwrap();
+        let mut rules = config.rules;
+        rules.extend(PocketClassifier::default_rules());
+        let classifier = PocketClassifier::new(rules).unwrap();
+        assert_eq!(
+            classifier.classify("https://github.com/rust-lang/rust"),
+            PocketClassification {
+                src_type: "rust".to_string(),
+                tags: vec!["rust".to_string()]
+            }
+        );
+        assert_eq!(classifier.classify("https://github.com/baakeydow").src_type, "github");
+        assert_eq!(classifier.classify("https://lobste.rs/s/42").src_type, "lobsters");
+        assert!(PocketClassifier::new(vec![PocketSrcRule {
+            path_pattern: Some("(".to_string()),
+            ..PocketSrcRule::default()
+        }])
+        .is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! Collections: curated and ordered lists of pocket items, shareable like profile items
+#![allow(missing_docs)]
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOptions, IndexOptions, UpdateOptions};
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+use super::pocket::get_pocket_data;
+use super::pocket_model::{pocket_date, to_bson_date, QualifiedPocketData};
+use super::pocket_profile::{is_valid_slug, PocketProfile, PocketVisibility};
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_collections_collection_name, get_pocket_db_name};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// An item of a collection with the owner note about it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketCollectionItem {
+    pub item_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// A collection, `slug` is unique per user.
+/// Public collections are listed on the profile, unlisted ones are only reached by their slug.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketCollection {
+    #[serde(default)]
+    pub user_id: String,
+    pub slug: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "default_collection_visibility")]
+    pub visibility: PocketVisibility,
+    #[serde(default)]
+    pub items: Vec<PocketCollectionItem>,
+    #[serde(default, with = "pocket_date")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "pocket_date")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Collections are not shared until told otherwise
+fn default_collection_visibility() -> PocketVisibility {
+    PocketVisibility::Private
+}
+
+/// A collection without its items
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketCollectionSummary {
+    pub slug: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub visibility: PocketVisibility,
+    pub item_count: usize,
+    #[serde(with = "pocket_date")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&PocketCollection> for PocketCollectionSummary {
+    fn from(collection: &PocketCollection) -> Self {
+        PocketCollectionSummary {
+            slug: collection.slug.clone(),
+            title: collection.title.clone(),
+            description: collection.description.clone(),
+            visibility: collection.visibility,
+            item_count: collection.items.len(),
+            updated_at: collection.updated_at,
+        }
+    }
+}
+
+/// An item of a collection view, with its note
+#[derive(Clone, Debug, Serialize)]
+pub struct PocketCollectionEntry {
+    pub note: Option<String>,
+    pub item: QualifiedPocketData,
+}
+
+/// A collection with its items in order, items gone or hidden are left out
+#[derive(Clone, Debug, Serialize)]
+pub struct PocketCollectionView {
+    #[serde(flatten)]
+    pub summary: PocketCollectionSummary,
+    pub items: Vec<PocketCollectionEntry>,
+}
+
+/// Body of `/pocket/collections/save`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketCollectionRequest {
+    pub collection: PocketCollection,
+}
+
+/// Body of `/pocket/collections/get` and `/pocket/collections/delete`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketCollectionSlugRequest {
+    pub slug: String,
+}
+
+/// Body of `/pocket/collections/items`, edits are applied in order
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketCollectionEditRequest {
+    pub slug: String,
+    pub edits: Vec<PocketCollectionEdit>,
+}
+
+/// Edit of the items of a collection, positions start at 0
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "edit", rename_all = "snake_case")]
+pub enum PocketCollectionEdit {
+    /// At the end without position
+    Add {
+        item_id: String,
+        note: Option<String>,
+        position: Option<usize>,
+    },
+    Remove { item_id: String },
+    Move { item_id: String, position: usize },
+    /// A None note removes it
+    Note { item_id: String, note: Option<String> },
+    /// Every item of the collection in their new order
+    Reorder { item_ids: Vec<String> },
+}
+
+fn clean_note(note: &Option<String>) -> Option<String> {
+    note.as_deref().map(str::trim).filter(|note| !note.is_empty()).map(String::from)
+}
+
+impl PocketCollectionEdit {
+    pub fn apply(&self, items: &mut Vec<PocketCollectionItem>) -> Result<(), DtkError> {
+        let position_of = |items: &[PocketCollectionItem], item_id: &str| {
+            items
+                .iter()
+                .position(|item| item.item_id == item_id)
+                .ok_or_else(|| DtkError::from(format!("Item {item_id} is not in the collection").as_str()))
+        };
+        match self {
+            PocketCollectionEdit::Add { item_id, note, position } => {
+                if items.iter().any(|item| &item.item_id == item_id) {
+                    return Err(DtkError::from(format!("Item {item_id} is already in the collection").as_str()));
+                }
+                let item = PocketCollectionItem {
+                    item_id: item_id.clone(),
+                    note: clean_note(note),
+                };
+                items.insert(position.unwrap_or(items.len()).min(items.len()), item);
+            }
+            PocketCollectionEdit::Remove { item_id } => {
+                items.remove(position_of(items, item_id)?);
+            }
+            PocketCollectionEdit::Move { item_id, position } => {
+                let item = items.remove(position_of(items, item_id)?);
+                items.insert((*position).min(items.len()), item);
+            }
+            PocketCollectionEdit::Note { item_id, note } => {
+                let index = position_of(items, item_id)?;
+                items[index].note = clean_note(note);
+            }
+            PocketCollectionEdit::Reorder { item_ids } => {
+                let current: HashSet<&String> = items.iter().map(|item| &item.item_id).collect();
+                let reordered: HashSet<&String> = item_ids.iter().collect();
+                if item_ids.len() != items.len() || current != reordered {
+                    return Err(DtkError::from("Reorder needs every item of the collection once"));
+                }
+                items.sort_by_key(|item| item_ids.iter().position(|item_id| item_id == &item.item_id));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn collections_coll(client: &Client) -> Collection<PocketCollection> {
+    client
+        .database(&get_pocket_db_name())
+        .collection::<PocketCollection>(&get_pocket_collections_collection_name())
+}
+
+/// Create the collection indexes, once at startup
+pub async fn create_pocket_collection_indexes() -> Result<(), DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let slug = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "slug": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let items = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "items.item_id": 1 })
+        .build();
+    let listed = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "visibility": 1, "updated_at": -1 })
+        .build();
+    collections_coll(&client).create_indexes(vec![slug, items, listed], None).await?;
+    Ok(())
+}
+
+/// Fail unless every item belongs to the user
+async fn check_items(client: &Client, user_id: &str, item_ids: &[&String]) -> Result<(), DtkError> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+    let found = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name())
+        .count_documents(doc! { "user_id": user_id, "item_id": { "$in": item_ids } }, None)
+        .await?;
+    match found as usize == item_ids.len() {
+        true => Ok(()),
+        false => Err(DtkError::from("Unknown item in the collection")),
+    }
+}
+
+/// Collections of the user, most recently updated first, only the given visibility when set
+pub async fn get_pocket_collections(
+    user_id: &str,
+    visibility: Option<PocketVisibility>,
+) -> Result<Vec<PocketCollectionSummary>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let mut filter = doc! { "user_id": user_id };
+    if let Some(visibility) = visibility {
+        filter.insert("visibility", mongodb::bson::to_bson(&visibility).unwrap());
+    }
+    let options = FindOptions::builder().sort(doc! { "updated_at": -1 }).build();
+    let mut collections = collections_coll(&client).find(filter, options).await?;
+    let mut summaries = vec![];
+    while let Some(collection) = collections.next().await {
+        summaries.push(PocketCollectionSummary::from(&collection?));
+    }
+    Ok(summaries)
+}
+
+pub async fn get_pocket_collection(user_id: &str, slug: &str) -> Result<Option<PocketCollection>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let filter = doc! { "user_id": user_id, "slug": slug.to_lowercase() };
+    Ok(collections_coll(&client).find_one(filter, None).await?)
+}
+
+/// Collection with its items, the ones private on `profile` are left out when given
+pub async fn view_pocket_collection(
+    collection: &PocketCollection,
+    profile: Option<&PocketProfile>,
+) -> PocketCollectionView {
+    let item_ids: Vec<&String> = collection.items.iter().map(|item| &item.item_id).collect();
+    let filters = doc! { "user_id": &collection.user_id, "item_id": { "$in": item_ids } };
+    let found = get_pocket_data(filters, false).await;
+    let items = collection
+        .items
+        .iter()
+        .filter_map(|entry| {
+            let item = found.iter().find(|item| item.item_id == entry.item_id)?;
+            if profile.map(|profile| profile.visibility(item) == PocketVisibility::Private) == Some(true) {
+                return None;
+            }
+            Some(PocketCollectionEntry {
+                note: entry.note.clone(),
+                item: QualifiedPocketData::from(item.clone()),
+            })
+        })
+        .collect::<Vec<PocketCollectionEntry>>();
+    let mut summary = PocketCollectionSummary::from(collection);
+    summary.item_count = items.len();
+    PocketCollectionView { summary, items }
+}
+
+/// Create or update a collection, its items are replaced by the given ones
+pub async fn save_pocket_collection(
+    user_id: &str,
+    collection: &PocketCollection,
+) -> Result<PocketCollection, DtkError> {
+    let slug = collection.slug.trim().to_lowercase();
+    if !is_valid_slug(&slug) {
+        return Err(DtkError::from("Invalid slug"));
+    }
+    let title = collection.title.trim();
+    if title.is_empty() {
+        return Err(DtkError::from("Empty title"));
+    }
+    let mut items: Vec<PocketCollectionItem> = vec![];
+    for item in collection.items.iter() {
+        let edit = PocketCollectionEdit::Add {
+            item_id: item.item_id.clone(),
+            note: item.note.clone(),
+            position: None,
+        };
+        edit.apply(&mut items)?;
+    }
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    check_items(&client, user_id, &items.iter().map(|item| &item.item_id).collect::<Vec<&String>>()).await?;
+    let now = to_bson_date(Utc::now());
+    let update = doc! { "$set": {
+        "title": title,
+        "description": clean_note(&collection.description),
+        "visibility": mongodb::bson::to_bson(&collection.visibility).unwrap(),
+        "items": mongodb::bson::to_bson(&items).unwrap(),
+        "updated_at": now,
+    }, "$setOnInsert": { "created_at": now } };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collections_coll(&client)
+        .update_one(doc! { "user_id": user_id, "slug": &slug }, update, options)
+        .await?;
+    get_pocket_collection(user_id, &slug)
+        .await?
+        .ok_or_else(|| DtkError::from("Collection not saved"))
+}
+
+/// Apply item edits, nothing is saved unless every edit applies and the collection didn't change meanwhile
+pub async fn edit_pocket_collection(
+    user_id: &str,
+    slug: &str,
+    edits: &[PocketCollectionEdit],
+) -> Result<PocketCollection, DtkError> {
+    let mut collection = get_pocket_collection(user_id, slug)
+        .await?
+        .ok_or_else(|| DtkError::from("Collection not found"))?;
+    for edit in edits {
+        edit.apply(&mut collection.items)?;
+    }
+    let added: Vec<&String> = edits
+        .iter()
+        .filter_map(|edit| match edit {
+            PocketCollectionEdit::Add { item_id, .. } => Some(item_id),
+            _ => None,
+        })
+        .collect();
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    check_items(&client, user_id, &added).await?;
+    let update = doc! { "$set": {
+        "items": mongodb::bson::to_bson(&collection.items).unwrap(),
+        "updated_at": to_bson_date(Utc::now()),
+    } };
+    // the edits apply to the collection as read, not to a concurrent change of it
+    let filter = doc! {
+        "user_id": user_id,
+        "slug": &collection.slug,
+        "updated_at": to_bson_date(collection.updated_at),
+    };
+    let res = collections_coll(&client).update_one(filter, update, None).await?;
+    if res.matched_count == 0 {
+        return Err(DtkError::from("Collection changed meanwhile, reload it"));
+    }
+    get_pocket_collection(user_id, &collection.slug)
+        .await?
+        .ok_or_else(|| DtkError::from("Collection not found"))
+}
+
+/// Whether a collection was deleted
+pub async fn delete_pocket_collection(user_id: &str, slug: &str) -> Result<bool, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let filter = doc! { "user_id": user_id, "slug": slug.to_lowercase() };
+    let res = collections_coll(&client).delete_one(filter, None).await?;
+    Ok(res.deleted_count > 0)
+}
+
+/// Take deleted items out of the user collections
+pub async fn remove_from_pocket_collections(
+    client: &Client,
+    user_id: &str,
+    item_ids: &[String],
+) -> Result<(), DtkError> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+    let filter = doc! { "user_id": user_id, "items.item_id": { "$in": item_ids } };
+    let update = doc! { "$pull": { "items": { "item_id": { "$in": item_ids } } } };
+    collections_coll(client).update_many(filter, update, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item_ids(items: &[PocketCollectionItem]) -> Vec<&str> {
+        items.iter().map(|item| item.item_id.as_str()).collect()
+    }
+
+    fn add(item_id: &str, position: Option<usize>) -> PocketCollectionEdit {
+        PocketCollectionEdit::Add {
+            item_id: item_id.to_string(),
+            note: Some(" read first ".to_string()),
+            position,
+        }
+    }
+
+    #[test]
+    fn edit_items() {
+        let mut items = vec![];
+        for edit in [add("1", None), add("2", None), add("3", Some(0))] {
+            edit.apply(&mut items).unwrap();
+        }
+        assert_eq!(item_ids(&items), vec!["3", "1", "2"]);
+        assert_eq!(items[0].note.as_deref(), Some("read first"));
+        assert!(add("1", None).apply(&mut items).is_err());
+        let moved = PocketCollectionEdit::Move {
+            item_id: "3".to_string(),
+            position: 10,
+        };
+        moved.apply(&mut items).unwrap();
+        assert_eq!(item_ids(&items), vec!["1", "2", "3"]);
+        let note = PocketCollectionEdit::Note {
+            item_id: "1".to_string(),
+            note: Some("  ".to_string()),
+        };
+        note.apply(&mut items).unwrap();
+        assert_eq!(items[0].note, None);
+        PocketCollectionEdit::Remove { item_id: "2".to_string() }.apply(&mut items).unwrap();
+        assert!(PocketCollectionEdit::Remove { item_id: "2".to_string() }.apply(&mut items).is_err());
+        assert_eq!(item_ids(&items), vec!["1", "3"]);
+    }
+
+    #[test]
+    fn reorder_items() {
+        let mut items = vec![];
+        for edit in [add("1", None), add("2", None), add("3", None)] {
+            edit.apply(&mut items).unwrap();
+        }
+        let reorder = |item_ids: &[&str]| PocketCollectionEdit::Reorder {
+            item_ids: item_ids.iter().map(|item_id| item_id.to_string()).collect(),
+        };
+        reorder(&["3", "1", "2"]).apply(&mut items).unwrap();
+        assert_eq!(item_ids(&items), vec!["3", "1", "2"]);
+        assert!(reorder(&["3", "1"]).apply(&mut items).is_err());
+        assert!(reorder(&["3", "1", "1"]).apply(&mut items).is_err());
+        assert!(reorder(&["3", "1", "4"]).apply(&mut items).is_err());
+    }
+
+    #[test]
+    fn collection_from_json() {
+        let edit: PocketCollectionEdit =
+            serde_json::from_str(r#"{ "edit": "move", "item_id": "1", "position": 0 }"#).unwrap();
+        assert_eq!(
+            edit,
+            PocketCollectionEdit::Move {
+                item_id: "1".to_string(),
+                position: 0
+            }
+        );
+        let collection: PocketCollection =
+            serde_json::from_str(r#"{ "slug": "onboarding", "title": "Onboarding", "items": [{ "item_id": "1" }] }"#)
+                .unwrap();
+        assert_eq!(collection.visibility, PocketVisibility::Private);
+        assert_eq!(PocketCollectionSummary::from(&collection).item_count, 1);
+    }
+}
@@ -0,0 +1,352 @@
+//! Canonical urls and duplicate items
+#![allow(missing_docs)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::IndexModel;
+use serde::{Deserialize, Serialize};
+
+use super::pocket::run_pocket_actions;
+use super::pocket_index::PocketIndex;
+use super::pocket_model::{DtkPocketData, PocketAction, PocketActionResult};
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_db_name};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Query params only used to track where a visit came from
+const TRACKING_PARAMS: [&str; 12] = [
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "ref_src", "ref_url", "_hsenc",
+    "_hsmi",
+];
+
+/// Share params of a site, they mean something else on other hosts
+const HOST_TRACKING_PARAMS: [(&str, &[&str]); 4] = [
+    ("twitter.com", &["s", "t"]),
+    ("youtube.com", &["si", "feature"]),
+    ("youtu.be", &["si", "feature"]),
+    ("open.spotify.com", &["si"]),
+];
+
+/// Hosts serving the same content under several names
+fn canonical_host(host: &str) -> &str {
+    match host {
+        "x.com" | "mobile.twitter.com" | "mobile.x.com" => "twitter.com",
+        "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => "youtube.com",
+        "m.facebook.com" => "facebook.com",
+        "old.reddit.com" | "np.reddit.com" => "reddit.com",
+        "m.wikipedia.org" => "wikipedia.org",
+        host => host,
+    }
+}
+
+fn is_tracking_param(host: &str, key: &str) -> bool {
+    let key = key.to_lowercase();
+    key.starts_with("utm_")
+        || TRACKING_PARAMS.contains(&key.as_str())
+        || HOST_TRACKING_PARAMS
+            .iter()
+            .any(|(params_host, params)| *params_host == host && params.contains(&key.as_str()))
+}
+
+/// Url without tracking params, `www.` and trailing slash, with one host per site.
+/// Urls that can't be parsed are only trimmed.
+pub fn canonicalize_url(url: &str) -> String {
+    let parsed = match url::Url::parse(url.trim()) {
+        Ok(parsed) if parsed.host_str().is_some() => parsed,
+        _ => return url.trim().to_string(),
+    };
+    let host = parsed.host_str().unwrap_or_default().trim_end_matches('.').to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let mut host = canonical_host(host).to_string();
+    let mut path = parsed.path().trim_end_matches('/').to_string();
+    let mut query: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(&host, key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if host == "youtu.be" || (host == "youtube.com" && path.starts_with("/shorts/")) {
+        let video_id = path.rsplit('/').next().unwrap_or_default().to_string();
+        host = "youtube.com".to_string();
+        path = "/watch".to_string();
+        query.retain(|(key, _)| key != "v");
+        query.push(("v".to_string(), video_id));
+    }
+    query.sort();
+    let scheme = parsed.scheme();
+    let mut canonical = match parsed.port() {
+        Some(port) => format!("{scheme}://{host}:{port}{path}"),
+        None => format!("{scheme}://{host}{path}"),
+    };
+    if !query.is_empty() {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        canonical.push('?');
+        canonical.push_str(&query);
+    }
+    // fragments address content in single page apps
+    if let Some(fragment) = parsed.fragment().filter(|fragment| !fragment.is_empty()) {
+        canonical.push('#');
+        canonical.push_str(fragment);
+    }
+    canonical
+}
+
+/// Lowercase words of a title, None when too short to tell items apart
+fn title_key(title: &str) -> Option<String> {
+    let words: Vec<String> = title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    (words.len() >= 3).then(|| words.join(" "))
+}
+
+/// Items saved more than once, `reason` is "url" when they share a canonical url, "title" otherwise
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PocketDuplicateGroup {
+    pub canonical_url: String,
+    pub reason: String,
+    /// Oldest first
+    pub items: Vec<DtkPocketData>,
+}
+
+fn find_root(parents: &mut [usize], index: usize) -> usize {
+    let mut root = index;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[index] = root;
+    root
+}
+
+/// Group items with the same canonical url, or the same title on the same host
+pub fn find_pocket_duplicates(items: Vec<DtkPocketData>) -> Vec<PocketDuplicateGroup> {
+    let mut parents: Vec<usize> = (0..items.len()).collect();
+    let mut first_by_key: HashMap<String, usize> = HashMap::new();
+    for (index, item) in items.iter().enumerate() {
+        let canonical_url = canonicalize_url(&item.url);
+        let host = url::Url::parse(&canonical_url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from));
+        let mut keys = vec![format!("url {canonical_url}")];
+        if let (Some(host), Some(title)) = (host, title_key(&item.title)) {
+            keys.push(format!("title {host} {title}"));
+        }
+        for key in keys {
+            let first = *first_by_key.entry(key).or_insert(index);
+            let (root, other) = (find_root(&mut parents, first), find_root(&mut parents, index));
+            parents[other] = root;
+        }
+    }
+    let mut groups: BTreeMap<usize, Vec<DtkPocketData>> = BTreeMap::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let root = find_root(&mut parents, index);
+        groups.entry(root).or_default().push(item);
+    }
+    groups
+        .into_values()
+        .filter(|items| items.len() > 1)
+        .map(|mut items| {
+            items.sort_by(|a, b| a.time_added.cmp(&b.time_added).then(a.item_id.cmp(&b.item_id)));
+            let canonical_url = canonicalize_url(&items[0].url);
+            let same_url = items.iter().all(|item| canonicalize_url(&item.url) == canonical_url);
+            PocketDuplicateGroup {
+                canonical_url,
+                reason: if same_url { "url" } else { "title" }.to_string(),
+                items,
+            }
+        })
+        .collect()
+}
+
+/// Duplicate groups of the user items
+pub async fn get_pocket_duplicates(user_id: &str) -> Result<Vec<PocketDuplicateGroup>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let items = coll
+        .find(doc! { "user_id": user_id }, None)
+        .await?
+        .filter_map(|item| async { item.ok() })
+        .collect::<Vec<DtkPocketData>>()
+        .await;
+    Ok(find_pocket_duplicates(items))
+}
+
+/// Body of `/pocket/duplicates/merge`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketMergeRequest {
+    pub keep: String,
+    pub remove: Vec<String>,
+}
+
+/// Actions moving the tags and favorite of `removed` items to `kept`, then deleting them
+pub fn merge_actions(kept: &DtkPocketData, removed: &[DtkPocketData]) -> Vec<PocketAction> {
+    let mut tags: Vec<String> = vec![];
+    for tag in removed.iter().flat_map(|item| item.tags.iter()) {
+        if !kept.tags.contains(tag) && !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+    let mut actions = vec![];
+    if !tags.is_empty() {
+        actions.push(PocketAction::TagsAdd {
+            item_id: kept.item_id.clone(),
+            tags,
+        });
+    }
+    if kept.favorite == 0 && removed.iter().any(|item| item.favorite != 0) {
+        actions.push(PocketAction::Favorite {
+            item_id: kept.item_id.clone(),
+        });
+    }
+    actions.extend(removed.iter().map(|item| PocketAction::Delete {
+        item_id: item.item_id.clone(),
+    }));
+    actions
+}
+
+/// Merge duplicates into the kept item, in Pocket and in our copy.
+/// Removed items leave the search index, their archives and the user collections.
+pub async fn merge_pocket_duplicates(
+    user_id: &str,
+    request: &PocketMergeRequest,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<Vec<PocketActionResult>, DtkError> {
+    if request.remove.is_empty() || request.remove.contains(&request.keep) {
+        return Err(DtkError::from("Nothing to merge"));
+    }
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let mut item_ids = request.remove.clone();
+    item_ids.push(request.keep.clone());
+    let mut items = coll
+        .find(doc! { "user_id": user_id, "item_id": { "$in": &item_ids } }, None)
+        .await?
+        .filter_map(|item| async { item.ok() })
+        .collect::<Vec<DtkPocketData>>()
+        .await;
+    if items.len() != item_ids.len() {
+        return Err(DtkError::from("Unknown item"));
+    }
+    let kept_index = items.iter().position(|item| item.item_id == request.keep).unwrap();
+    let kept = items.remove(kept_index);
+    run_pocket_actions(user_id, merge_actions(&kept, &items), index).await
+}
+
+/// Store the canonical url of items saved before it existed, run once as a migration
+pub async fn backfill_canonical_urls() -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let model = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "canonical_url": 1 })
+        .build();
+    coll.create_index(model, None).await?;
+    let options = FindOptions::builder()
+        .projection(doc! { "url": 1, "canonical_url": 1 })
+        .build();
+    let mut items = coll.find(None, options).await?;
+    let mut changed = 0;
+    while let Some(item) = items.next().await {
+        let item = item?;
+        let canonical_url = canonicalize_url(item.get_str("url").unwrap_or_default());
+        if item.get_str("canonical_url").ok() == Some(canonical_url.as_str()) {
+            continue;
+        }
+        let update = doc! { "$set": { "canonical_url": canonical_url } };
+        coll.update_one(doc! { "_id": item.get("_id") }, update, None).await?;
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtkpocket::pocket_model::{test_pocket_item, PocketData};
+
+    fn item(item_id: &str, url: &str, title: &str, time_added: &str) -> DtkPocketData {
+        let pocket_item = PocketData {
+            given_title: title.to_string(),
+            time_added: time_added.to_string(),
+            ..PocketData::default()
+        };
+        test_pocket_item(item_id, url, pocket_item)
+    }
+
+    #[test]
+    fn canonical_urls() {
+        let canonical = "https://rusty.com/post?id=42";
+        assert_eq!(canonicalize_url("https://www.rusty.com/post/?id=42&utm_source=x&fbclid=1"), canonical);
+        assert_eq!(canonicalize_url("https://RUSTY.com/post?utm_medium=mail&id=42"), canonical);
+        assert_eq!(canonicalize_url("http://rusty.com/post?id=42#top"), "http://rusty.com/post?id=42#top");
+        assert_eq!(canonicalize_url("https://rusty.com/app#/item/42"), "https://rusty.com/app#/item/42");
+        assert_eq!(canonicalize_url("https://rusty.com/a?feature=x&si=1"), "https://rusty.com/a?feature=x&si=1");
+        assert_eq!(canonicalize_url("https://rusty.com/"), "https://rusty.com");
+        assert_eq!(canonicalize_url("https://rusty.com/a?b=2&a=1"), "https://rusty.com/a?a=1&b=2");
+        let video = "https://youtube.com/watch?v=abc";
+        assert_eq!(canonicalize_url("https://youtu.be/abc?si=share"), video);
+        assert_eq!(canonicalize_url("https://m.youtube.com/watch?v=abc&feature=share"), video);
+        assert_eq!(canonicalize_url("https://www.youtube.com/shorts/abc"), video);
+        let tweet = "https://twitter.com/rustlang/status/1";
+        assert_eq!(canonicalize_url("https://x.com/rustlang/status/1?s=20&t=abc"), tweet);
+        assert_eq!(canonicalize_url("https://mobile.twitter.com/rustlang/status/1"), tweet);
+        assert_eq!(canonicalize_url("https://twitter.com/search?q=rust&s=20"), "https://twitter.com/search?q=rust");
+        assert_eq!(canonicalize_url("https://rusty.com:8080/a"), "https://rusty.com:8080/a");
+        assert_eq!(canonicalize_url(" not an url "), "not an url");
+    }
+
+    #[test]
+    fn duplicate_groups() {
+        let groups = find_pocket_duplicates(vec![
+            item("1", "https://rusty.com/post?utm_source=x", "Rust", "1678886402"),
+            item("2", "https://www.rusty.com/post/", "Rust", "1678886401"),
+            item("3", "https://rusty.com/other", "Rust", "1678886400"),
+            item("4", "https://blog.rusty.com/a", "Why we love Rust", "1678886400"),
+            item("5", "https://blog.rusty.com/b", "Why we love rust!", "1678886401"),
+            item("6", "https://other.com/c", "Why we love Rust", "1678886400"),
+        ]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].reason, "url");
+        assert_eq!(groups[0].canonical_url, "https://rusty.com/post");
+        let item_ids = |group: &PocketDuplicateGroup| -> Vec<String> {
+            group.items.iter().map(|item| item.item_id.clone()).collect()
+        };
+        assert_eq!(item_ids(&groups[0]), vec!["2", "1"]);
+        assert_eq!(groups[1].reason, "title");
+        assert_eq!(item_ids(&groups[1]), vec!["4", "5"]);
+    }
+
+    #[test]
+    fn merge_moves_tags_and_favorite() {
+        let kept = item("1", "https://rusty.com", "Rust", "1678886400");
+        let mut removed = item("2", "https://rusty.com/", "Rust", "1678886401");
+        removed.tags = vec!["rust".to_string(), "article".to_string()];
+        removed.favorite = 1;
+        assert_eq!(
+            merge_actions(&kept, &[removed]),
+            vec![
+                PocketAction::TagsAdd {
+                    item_id: "1".to_string(),
+                    tags: vec!["rust".to_string()]
+                },
+                PocketAction::Favorite {
+                    item_id: "1".to_string()
+                },
+                PocketAction::Delete {
+                    item_id: "2".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! RSS, Atom and JSON feeds of public pocket items
+#![allow(missing_docs)]
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
+use mongodb::bson::doc;
+use mongodb::options::FindOptions;
+use sha2::{Digest, Sha256};
+
+use super::pocket_model::DtkPocketData;
+use super::pocket_profile::PocketProfile;
+use super::pocket_tags::{normalize_tag, tag_matchers};
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_db_name, get_pocket_feed_size, get_pocket_public_url};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PocketFeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FromStr for PocketFeedFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_lowercase().as_str() {
+            "rss" | "rss.xml" => Ok(PocketFeedFormat::Rss),
+            "atom" | "atom.xml" => Ok(PocketFeedFormat::Atom),
+            "json" | "feed.json" => Ok(PocketFeedFormat::Json),
+            format => Err(format!("Unknown feed format: {format}")),
+        }
+    }
+}
+
+impl PocketFeedFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PocketFeedFormat::Rss => "application/rss+xml; charset=utf-8",
+            PocketFeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            PocketFeedFormat::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PocketFeedFormat::Rss => "rss",
+            PocketFeedFormat::Atom => "atom",
+            PocketFeedFormat::Json => "json",
+        }
+    }
+}
+
+/// Latest public items of a profile, or of one of its tags
+#[derive(Clone, Debug)]
+pub struct PocketFeed {
+    pub title: String,
+    /// Page of the profile
+    pub link: String,
+    /// Tag of a tag feed
+    pub tag: Option<String>,
+    /// Newest first
+    pub items: Vec<DtkPocketData>,
+    /// Last visibility change of the profile, hiding an item doesn't make the feed older
+    pub updated_at: DateTime<Utc>,
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // not allowed in xml 1.0
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Guess the image type from its extension, feed readers need one
+fn image_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or_default().to_lowercase();
+    match path.rsplit('.').next().unwrap_or_default() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "image/jpeg",
+    }
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT` as used by `Last-Modified`
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Saved url, the canonical one is only meant to spot duplicates
+fn item_link(item: &DtkPocketData) -> &str {
+    &item.url
+}
+
+fn item_title(item: &DtkPocketData) -> &str {
+    match item.title.is_empty() {
+        true => item_link(item),
+        false => &item.title,
+    }
+}
+
+impl PocketFeed {
+    /// `{link}/feed/{format}`, followed by the tag of a tag feed
+    pub fn feed_url(&self, format: PocketFeedFormat) -> String {
+        let mut url = match url::Url::parse(&self.link) {
+            Ok(url) => url,
+            Err(_) => return format!("{}/feed/{}", self.link, format.name()),
+        };
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(["feed", format.name()]);
+            if let Some(tag) = &self.tag {
+                segments.extend(tag.split('/'));
+            }
+        }
+        url.to_string()
+    }
+
+    /// Most recent change of the items or of the profile visibilities
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.items
+            .iter()
+            .map(|item| item.time_added.max(item.time_updated))
+            .fold(self.updated_at, DateTime::max)
+    }
+
+    /// Changes with the items, their updates, titles and tags, the profile visibilities and the format
+    pub fn etag(&self, format: PocketFeedFormat) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format.name());
+        hasher.update(&self.title);
+        hasher.update(self.updated_at.timestamp_millis().to_be_bytes());
+        for item in self.items.iter() {
+            hasher.update(&item.item_id);
+            hasher.update(item.time_updated.timestamp().to_be_bytes());
+            // lengths keep ("ab", "c") apart from ("a", "bc")
+            for field in std::iter::once(&item.title).chain(item.tags.iter()) {
+                hasher.update((field.len() as u64).to_be_bytes());
+                hasher.update(field);
+            }
+            hasher.update([0xff]);
+        }
+        let hash = format!("{:x}", hasher.finalize());
+        format!("\"{}\"", &hash[..32])
+    }
+
+    /// Whether a reader sending these `If-None-Match` and `If-Modified-Since` already has the feed
+    pub fn is_not_modified(
+        &self,
+        format: PocketFeedFormat,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> bool {
+        // If-None-Match wins when both are sent
+        if let Some(if_none_match) = if_none_match {
+            let etag = self.etag(format);
+            return if_none_match
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag);
+        }
+        if_modified_since
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .map(|since| self.last_modified().timestamp() <= since.timestamp())
+            .unwrap_or(false)
+    }
+
+    pub fn render(&self, format: PocketFeedFormat) -> String {
+        match format {
+            PocketFeedFormat::Rss => self.to_rss(),
+            PocketFeedFormat::Atom => self.to_atom(),
+            PocketFeedFormat::Json => self.to_json_feed(),
+        }
+    }
+
+    fn to_rss(&self) -> String {
+        let mut rss = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        rss.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\"><channel>\n");
+        rss.push_str(&format!("<title>{}</title>\n", escape_xml(&self.title)));
+        rss.push_str(&format!("<link>{}</link>\n", escape_xml(&self.link)));
+        rss.push_str(&format!("<description>{}</description>\n", escape_xml(&self.title)));
+        rss.push_str(&format!(
+            "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+            escape_xml(&self.feed_url(PocketFeedFormat::Rss))
+        ));
+        rss.push_str(&format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            self.last_modified().to_rfc2822()
+        ));
+        for item in self.items.iter() {
+            rss.push_str("<item>\n");
+            rss.push_str(&format!("<title>{}</title>\n", escape_xml(item_title(item))));
+            rss.push_str(&format!("<link>{}</link>\n", escape_xml(item_link(item))));
+            rss.push_str(&format!(
+                "<guid isPermaLink=\"false\">pocket-{}</guid>\n",
+                escape_xml(&item.item_id)
+            ));
+            rss.push_str(&format!("<pubDate>{}</pubDate>\n", item.time_added.to_rfc2822()));
+            if let Some(excerpt) = item.excerpt.as_deref() {
+                rss.push_str(&format!("<description>{}</description>\n", escape_xml(excerpt)));
+            }
+            for tag in item.tags.iter() {
+                rss.push_str(&format!("<category>{}</category>\n", escape_xml(tag)));
+            }
+            if let Some(image) = item.image_src() {
+                rss.push_str(&format!(
+                    "<enclosure url=\"{}\" type=\"{}\" length=\"0\"/>\n",
+                    escape_xml(&image),
+                    image_type(&image)
+                ));
+            }
+            rss.push_str("</item>\n");
+        }
+        rss.push_str("</channel></rss>\n");
+        rss
+    }
+
+    fn to_atom(&self) -> String {
+        let mut atom = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        atom.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        let feed_url = escape_xml(&self.feed_url(PocketFeedFormat::Atom));
+        atom.push_str(&format!("<id>{feed_url}</id>\n"));
+        atom.push_str(&format!("<title>{}</title>\n", escape_xml(&self.title)));
+        atom.push_str(&format!("<updated>{}</updated>\n", self.last_modified().to_rfc3339()));
+        atom.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&self.link)));
+        atom.push_str(&format!("<link rel=\"self\" href=\"{feed_url}\"/>\n"));
+        for item in self.items.iter() {
+            atom.push_str("<entry>\n");
+            atom.push_str(&format!("<id>urn:pocket:{}</id>\n", escape_xml(&item.item_id)));
+            atom.push_str(&format!("<title>{}</title>\n", escape_xml(item_title(item))));
+            atom.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(item_link(item))));
+            atom.push_str(&format!("<published>{}</published>\n", item.time_added.to_rfc3339()));
+            atom.push_str(&format!(
+                "<updated>{}</updated>\n",
+                item.time_added.max(item.time_updated).to_rfc3339()
+            ));
+            if let Some(excerpt) = item.excerpt.as_deref() {
+                atom.push_str(&format!("<summary>{}</summary>\n", escape_xml(excerpt)));
+            }
+            for tag in item.tags.iter() {
+                atom.push_str(&format!("<category term=\"{}\"/>\n", escape_xml(tag)));
+            }
+            if let Some(image) = item.image_src() {
+                atom.push_str(&format!(
+                    "<link rel=\"enclosure\" href=\"{}\" type=\"{}\"/>\n",
+                    escape_xml(&image),
+                    image_type(&image)
+                ));
+            }
+            atom.push_str("</entry>\n");
+        }
+        atom.push_str("</feed>\n");
+        atom
+    }
+
+    fn to_json_feed(&self) -> String {
+        let items: Vec<serde_json::Value> = self
+            .items
+            .iter()
+            .map(|item| {
+                let mut json_item = serde_json::json!({
+                    "id": format!("pocket-{}", item.item_id),
+                    "url": item_link(item),
+                    "title": item_title(item),
+                    "date_published": item.time_added.to_rfc3339(),
+                    "date_modified": item.time_added.max(item.time_updated).to_rfc3339(),
+                    "tags": &item.tags,
+                });
+                if let Some(excerpt) = item.excerpt.as_deref() {
+                    json_item["summary"] = excerpt.into();
+                    json_item["content_text"] = excerpt.into();
+                } else {
+                    json_item["content_text"] = "".into();
+                }
+                if let Some(image) = item.image_src() {
+                    json_item["image"] = image.into();
+                }
+                json_item
+            })
+            .collect();
+        serde_json::json!({
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": &self.title,
+            "home_page_url": &self.link,
+            "feed_url": self.feed_url(PocketFeedFormat::Json),
+            "items": items,
+        })
+        .to_string()
+    }
+}
+
+/// Latest public items of the profile, of `tag` and its descendants when given
+pub async fn get_pocket_feed(profile: &PocketProfile, tag: Option<&str>) -> Result<PocketFeed, DtkError> {
+    let mut filters = profile.public_filter();
+    let tag = tag.map(normalize_tag).filter(|tag| !tag.is_empty());
+    if let Some(tag) = &tag {
+        filters.insert("tags", doc! { "$in": tag_matchers(std::slice::from_ref(tag)) });
+    }
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let options = FindOptions::builder()
+        .sort(doc! { "time_added": -1, "item_id": -1 })
+        .limit(get_pocket_feed_size())
+        .build();
+    let items = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name())
+        .find(filters, options)
+        .await?
+        .filter_map(|item| async { item.ok() })
+        .collect::<Vec<DtkPocketData>>()
+        .await;
+    let name = profile.display_name.clone().unwrap_or_else(|| profile.slug.clone());
+    Ok(PocketFeed {
+        title: match &tag {
+            Some(tag) => format!("{name} - {tag}"),
+            None => name,
+        },
+        link: format!("{}/pocket/public/{}", get_pocket_public_url(), profile.slug),
+        tag,
+        items,
+        updated_at: profile.updated_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::dtkpocket::pocket_model::{test_pocket_item, PocketData};
+
+    fn feed() -> PocketFeed {
+        let pocket_item = PocketData {
+            given_title: "Rust & <friends>".to_string(),
+            excerpt: "Fearless \u{1}concurrency".to_string(),
+            time_added: "1678886400".to_string(),
+            time_updated: "1678890000".to_string(),
+            image: Some(serde_json::json!({ "src": "https://rusty.com/a.png?w=1" })),
+            ..PocketData::default()
+        };
+        let item = test_pocket_item("42", "https://www.rusty.com/post/?utm_source=x", pocket_item);
+        PocketFeed {
+            title: "baakey".to_string(),
+            link: "https://rusty.com/pocket/public/baakey".to_string(),
+            tag: None,
+            items: vec![item],
+            updated_at: Utc.timestamp_opt(0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn rss_and_atom() {
+        let rss = feed().render(PocketFeedFormat::Rss);
+        assert!(rss.contains("<title>Rust &amp; &lt;friends&gt;</title>"));
+        assert!(rss.contains("<link>https://www.rusty.com/post/?utm_source=x</link>"));
+        assert!(rss.contains("<description>Fearless concurrency</description>"));
+        assert!(rss.contains("<pubDate>Wed, 15 Mar 2023 13:20:00 +0000</pubDate>"));
+        assert!(rss.contains("<enclosure url=\"https://rusty.com/a.png?w=1\" type=\"image/png\" length=\"0\"/>"));
+        assert!(rss.contains("<atom:link href=\"https://rusty.com/pocket/public/baakey/feed/rss\""));
+        let atom = feed().render(PocketFeedFormat::Atom);
+        assert!(atom.contains("<published>2023-03-15T13:20:00+00:00</published>"));
+        assert!(atom.contains("<updated>2023-03-15T14:20:00+00:00</updated>"));
+        assert!(atom.contains("<link rel=\"enclosure\" href=\"https://rusty.com/a.png?w=1\" type=\"image/png\"/>"));
+    }
+
+    #[test]
+    fn json_feed() {
+        let json: serde_json::Value = serde_json::from_str(&feed().render(PocketFeedFormat::Json)).unwrap();
+        assert_eq!(json["feed_url"], "https://rusty.com/pocket/public/baakey/feed/json");
+        assert_eq!(json["items"][0]["url"], "https://www.rusty.com/post/?utm_source=x");
+        assert_eq!(json["items"][0]["title"], "Rust & <friends>");
+        assert_eq!(json["items"][0]["image"], "https://rusty.com/a.png?w=1");
+        assert_eq!(json["items"][0]["date_published"], "2023-03-15T13:20:00+00:00");
+    }
+
+    #[test]
+    fn conditional_headers() {
+        let feed = feed();
+        assert_eq!(http_date(feed.last_modified()), "Wed, 15 Mar 2023 14:20:00 GMT");
+        let etag = feed.etag(PocketFeedFormat::Rss);
+        assert_eq!(etag.len(), 34);
+        assert_eq!(etag, feed.etag(PocketFeedFormat::Rss));
+        assert_ne!(etag, feed.etag(PocketFeedFormat::Atom));
+        let mut edited = feed.clone();
+        edited.items[0].tags.push("rust".to_string());
+        assert_ne!(etag, edited.etag(PocketFeedFormat::Rss));
+        edited = feed.clone();
+        edited.items[0].title = "Rust".to_string();
+        assert_ne!(etag, edited.etag(PocketFeedFormat::Rss));
+        // the newest item got hidden
+        edited.items.clear();
+        edited.updated_at = Utc.with_ymd_and_hms(2023, 3, 16, 0, 0, 0).unwrap();
+        assert_ne!(etag, edited.etag(PocketFeedFormat::Rss));
+        assert!(edited.last_modified() > feed.last_modified());
+        let tag_feed = PocketFeed {
+            tag: Some("lang/c sharp".to_string()),
+            ..feed.clone()
+        };
+        assert_eq!(
+            tag_feed.feed_url(PocketFeedFormat::Atom),
+            "https://rusty.com/pocket/public/baakey/feed/atom/lang/c%20sharp"
+        );
+        assert!(feed.is_not_modified(PocketFeedFormat::Rss, Some(&format!("W/{etag}, \"other\"")), None));
+        assert!(!feed.is_not_modified(PocketFeedFormat::Rss, Some("\"other\""), Some("Wed, 15 Mar 2023 14:20:00 GMT")));
+        assert!(feed.is_not_modified(PocketFeedFormat::Rss, None, Some("Wed, 15 Mar 2023 14:20:00 GMT")));
+        assert!(!feed.is_not_modified(PocketFeedFormat::Rss, None, Some("Wed, 15 Mar 2023 14:19:59 GMT")));
+        assert!(!feed.is_not_modified(PocketFeedFormat::Rss, None, Some("yesterday")));
+        assert_eq!("Atom.xml".parse::<PocketFeedFormat>(), Ok(PocketFeedFormat::Atom));
+        assert!("html".parse::<PocketFeedFormat>().is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Link health of saved pocket items, checked in the background
+#![allow(missing_docs)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+
+use super::pocket::run_pocket_actions;
+use super::pocket_index::PocketIndex;
+use super::pocket_archive::DomainThrottle;
+use super::pocket_classifier::{host_matches, url_host};
+use super::pocket_model::{pocket_date, to_bson_date, PocketAction};
+use super::pocket_utils::*;
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+use crate::dtkutils::dtk_reqwest::send_public_request;
+
+/// Tag of broken items, when `RUSTY_POCKET_TAG_DEAD_LINKS` is set
+pub const POCKET_DEAD_LINK_TAG: &str = "dead-link";
+
+/// Where expired domains end up once parked
+const PARKED_HOSTS: [&str; 10] = [
+    "sedoparking.com",
+    "sedo.com",
+    "dan.com",
+    "afternic.com",
+    "hugedomains.com",
+    "bodis.com",
+    "parkingcrew.net",
+    "above.com",
+    "undeveloped.com",
+    "domainmarket.com",
+];
+
+/// Time allowed to check a link, redirects included
+const POCKET_LINK_CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+/// User agent of the link checker
+const POCKET_LINK_CHECK_USER_AGENT: &str = "Mozilla/5.0 (compatible; rusty-link-checker)";
+
+/// A run is skipped while the previous one is still going
+static POCKET_LINK_CHECK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Result of the last link check of an item
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketLinkHealth {
+    /// Url checked, the item is checked again once its url changes
+    pub url: String,
+    /// None when no response came
+    pub status_code: Option<u16>,
+    /// Where redirects ended, when elsewhere
+    pub final_url: Option<String>,
+    #[serde(with = "pocket_date")]
+    pub checked_at: DateTime<Utc>,
+    /// Failed checks in a row
+    pub failures: u32,
+    pub error: Option<String>,
+    /// Failed `RUSTY_POCKET_LINK_MAX_FAILURES` times in a row
+    pub broken: bool,
+}
+
+/// How a check counts towards the failures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PocketLinkOutcome {
+    Ok,
+    Failed,
+    /// Refused, most likely because we are a bot, failures are kept as they are
+    Inconclusive,
+}
+
+/// Whether the url is on a domain parking service
+pub fn is_parked_url(url: &str) -> bool {
+    url_host(url)
+        .map(|host| PARKED_HOSTS.iter().any(|parked| host_matches(&host, parked)))
+        .unwrap_or(false)
+}
+
+pub fn link_outcome(status_code: Option<u16>, final_url: Option<&str>) -> PocketLinkOutcome {
+    if final_url.map(is_parked_url).unwrap_or(false) {
+        return PocketLinkOutcome::Failed;
+    }
+    match status_code {
+        Some(200..=399) => PocketLinkOutcome::Ok,
+        Some(401 | 403 | 429) => PocketLinkOutcome::Inconclusive,
+        _ => PocketLinkOutcome::Failed,
+    }
+}
+
+impl PocketLinkHealth {
+    /// Health once checked, failures carry over from the previous check of the same url
+    pub fn checked(
+        previous: Option<&PocketLinkHealth>,
+        url: &str,
+        status_code: Option<u16>,
+        final_url: Option<String>,
+        error: Option<String>,
+        max_failures: u32,
+    ) -> PocketLinkHealth {
+        let previous_failures = previous
+            .filter(|previous| previous.url == url)
+            .map(|previous| previous.failures)
+            .unwrap_or(0);
+        let failures = match link_outcome(status_code, final_url.as_deref()) {
+            PocketLinkOutcome::Ok => 0,
+            PocketLinkOutcome::Failed => previous_failures + 1,
+            PocketLinkOutcome::Inconclusive => previous_failures,
+        };
+        PocketLinkHealth {
+            url: url.to_string(),
+            status_code,
+            final_url: final_url.filter(|final_url| final_url != url),
+            checked_at: Utc::now(),
+            failures,
+            error,
+            broken: failures >= max_failures.max(1),
+        }
+    }
+
+    /// Document stored under `link_health`
+    pub fn to_document(&self) -> Document {
+        let mut document = mongodb::bson::to_document(self).unwrap();
+        document.insert("checked_at", to_bson_date(self.checked_at));
+        document
+    }
+}
+
+/// Item fields the checker needs
+#[derive(Clone, Debug, Deserialize)]
+struct PocketLinkItem {
+    user_id: String,
+    item_id: String,
+    url: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    link_health: Option<PocketLinkHealth>,
+}
+
+/// Outcome of a checker run
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketLinkCheckSummary {
+    pub checked: u32,
+    pub broken: u32,
+    pub recovered: u32,
+}
+
+/// HEAD the url, then GET it as some servers refuse HEAD; only public hosts are checked
+async fn check_link(url: &str) -> (Option<u16>, Option<String>, Option<String>) {
+    let mut result = (None, None, None);
+    for method in [reqwest::Method::HEAD, reqwest::Method::GET] {
+        let request = send_public_request(method, url, POCKET_LINK_CHECK_USER_AGENT);
+        match tokio::time::timeout(POCKET_LINK_CHECK_TIMEOUT, request).await {
+            Ok(Ok(response)) => {
+                let status_code = response.status().as_u16();
+                result = (Some(status_code), Some(response.url().to_string()), None);
+                if status_code < 400 {
+                    break;
+                }
+            }
+            Ok(Err(err)) => result = (None, None, Some(err.to_string())),
+            Err(_) => result = (None, None, Some("Timed out".to_string())),
+        }
+    }
+    result
+}
+
+/// Check the items of a host one after the other
+async fn check_host_links(
+    items: Vec<PocketLinkItem>,
+    delay: Duration,
+    max_failures: u32,
+) -> Vec<(PocketLinkItem, PocketLinkHealth)> {
+    let mut throttle = DomainThrottle::new(delay);
+    let mut checked = vec![];
+    for item in items {
+        throttle.wait(&item.url).await;
+        let (status_code, final_url, error) = check_link(&item.url).await;
+        let health = PocketLinkHealth::checked(
+            item.link_health.as_ref(),
+            &item.url,
+            status_code,
+            final_url,
+            error,
+            max_failures,
+        );
+        checked.push((item, health));
+    }
+    checked
+}
+
+/// Check the links due, oldest checks first: hosts are checked concurrently,
+/// one request per host every `RUSTY_POCKET_LINK_CHECK_DOMAIN_DELAY` seconds
+pub async fn check_pocket_links(index: Option<Arc<PocketIndex>>) -> Result<PocketLinkCheckSummary, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let interval = get_pocket_link_check_interval() as i64;
+    let cutoff = to_bson_date(Utc::now() - chrono::Duration::seconds(interval));
+    let filter = doc! {
+        "url": { "$regex": "^https?://", "$options": "i" },
+        "$or": [
+            { "link_health": { "$exists": false } },
+            { "link_health.checked_at": { "$lt": cutoff } },
+            { "$expr": { "$ne": ["$link_health.url", "$url"] } },
+        ],
+    };
+    let options = FindOptions::builder()
+        .projection(doc! { "user_id": 1, "item_id": 1, "url": 1, "tags": 1, "link_health": 1 })
+        .sort(doc! { "link_health.checked_at": 1 })
+        .limit(get_pocket_link_check_batch())
+        .build();
+    let mut by_host: BTreeMap<String, Vec<PocketLinkItem>> = BTreeMap::new();
+    let mut items = coll.clone_with_type::<PocketLinkItem>().find(filter, options).await?;
+    while let Some(item) = items.next().await {
+        let item = item?;
+        by_host.entry(url_host(&item.url).unwrap_or_default()).or_default().push(item);
+    }
+    let delay = Duration::from_secs(get_pocket_link_check_domain_delay());
+    let max_failures = get_pocket_link_max_failures();
+    let checked: Vec<(PocketLinkItem, PocketLinkHealth)> = futures::stream::iter(by_host.into_values())
+        .map(|items| check_host_links(items, delay, max_failures))
+        .buffer_unordered(get_pocket_link_check_concurrency().max(1))
+        .flat_map(futures::stream::iter)
+        .collect()
+        .await;
+
+    let mut summary = PocketLinkCheckSummary::default();
+    let mut tag_actions: HashMap<String, Vec<PocketAction>> = HashMap::new();
+    let tag_dead_links = is_pocket_tag_dead_links();
+    for (item, health) in checked {
+        let filter = doc! { "user_id": &item.user_id, "item_id": &item.item_id };
+        coll.update_one(filter, doc! { "$set": { "link_health": health.to_document() } }, None)
+            .await?;
+        summary.checked += 1;
+        let was_broken = item.link_health.map(|previous| previous.broken).unwrap_or(false);
+        match (was_broken, health.broken) {
+            (false, true) => summary.broken += 1,
+            (true, false) => summary.recovered += 1,
+            _ => (),
+        }
+        let tagged = item.tags.iter().any(|tag| tag == POCKET_DEAD_LINK_TAG);
+        let tags = vec![POCKET_DEAD_LINK_TAG.to_string()];
+        let action = match (health.broken, tagged) {
+            (true, false) => PocketAction::TagsAdd {
+                item_id: item.item_id,
+                tags,
+            },
+            (false, true) => PocketAction::TagsRemove {
+                item_id: item.item_id,
+                tags,
+            },
+            _ => continue,
+        };
+        if tag_dead_links {
+            tag_actions.entry(item.user_id).or_default().push(action);
+        }
+    }
+    for (user_id, actions) in tag_actions {
+        if let Err(err) = run_pocket_actions(&user_id, actions, index.clone()).await {
+            log::error!("[POCKET] Could not tag dead links of {} => {}", user_id, err);
+        }
+    }
+    Ok(summary)
+}
+
+/// Clears the running flag, even when the run panicked
+struct PocketLinkCheckRun;
+
+impl Drop for PocketLinkCheckRun {
+    fn drop(&mut self) {
+        POCKET_LINK_CHECK_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Scheduled link check, see `check_pocket_links`
+pub async fn run_pocket_link_checker(index: Option<Arc<PocketIndex>>) {
+    if POCKET_LINK_CHECK_RUNNING.swap(true, Ordering::SeqCst) {
+        log::debug!("[POCKET] Link checker still running");
+        return;
+    }
+    let _run = PocketLinkCheckRun;
+    match check_pocket_links(index).await {
+        Ok(summary) => log::info!(
+            "[POCKET] Link checker run => checked: {}, broken: {}, recovered: {}",
+            summary.checked,
+            summary.broken,
+            summary.recovered
+        ),
+        Err(err) => log::error!("[POCKET] Link checker failed => {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_outcomes() {
+        assert_eq!(link_outcome(Some(200), Some("https://rusty.com")), PocketLinkOutcome::Ok);
+        assert_eq!(link_outcome(Some(404), None), PocketLinkOutcome::Failed);
+        assert_eq!(link_outcome(None, None), PocketLinkOutcome::Failed);
+        assert_eq!(link_outcome(Some(403), None), PocketLinkOutcome::Inconclusive);
+        assert_eq!(
+            link_outcome(Some(200), Some("https://www.hugedomains.com/domain_profile.cfm?d=rusty.com")),
+            PocketLinkOutcome::Failed
+        );
+        assert!(!is_parked_url("https://sedo.com.rusty.com"));
+    }
+
+    #[test]
+    fn consecutive_failures() {
+        let url = "https://rusty.com/gone";
+        let first = PocketLinkHealth::checked(None, url, Some(404), Some(url.to_string()), None, 2);
+        assert_eq!((first.failures, first.broken), (1, false));
+        assert_eq!(first.final_url, None);
+        let second = PocketLinkHealth::checked(Some(&first), url, None, None, Some("dns".to_string()), 2);
+        assert_eq!((second.failures, second.broken), (2, true));
+        let refused = PocketLinkHealth::checked(Some(&second), url, Some(429), None, None, 2);
+        assert_eq!((refused.failures, refused.broken), (2, true));
+        let home = Some("https://rusty.com/".to_string());
+        let back = PocketLinkHealth::checked(Some(&refused), url, Some(200), home, None, 2);
+        assert_eq!((back.failures, back.broken), (0, false));
+        assert_eq!(back.final_url.as_deref(), Some("https://rusty.com/"));
+        let moved = PocketLinkHealth::checked(Some(&second), "https://rusty.com/new", Some(500), None, None, 2);
+        assert_eq!(moved.failures, 1);
+        assert!(second.to_document().get_datetime("checked_at").is_ok());
+    }
+}
@@ -0,0 +1,371 @@
+//! Local full-text index of saved pocket items, page content included
+#![allow(missing_docs)]
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, ConstScoreQuery, Occur, Query, QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+use super::pocket_model::DtkPocketData;
+use super::pocket_utils::{get_pocket_index_dir, get_pocket_index_fetch_timeout};
+use crate::dtkutils::dtk_error::DtkError;
+use crate::dtkutils::dtk_readability::HtmlToMdMode;
+use crate::dtkutils::dtk_reqwest::get_html_to_md;
+
+/// Memory used by the index writer before flushing to disk
+const POCKET_INDEX_WRITER_BYTES: usize = 50_000_000;
+/// Page content kept per item, in chars
+const POCKET_INDEX_BODY_MAX_CHARS: usize = 200_000;
+/// Length of highlighted snippets, in chars
+const POCKET_INDEX_SNIPPET_CHARS: usize = 200;
+
+/// What gets indexed for one item
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PocketIndexItem {
+    pub user_id: String,
+    pub item_id: String,
+    pub url: String,
+    pub title: String,
+    pub excerpt: String,
+    pub tags: Vec<String>,
+    /// Page content as markdown, empty when it could not be fetched
+    pub body: String,
+}
+
+impl PocketIndexItem {
+    pub fn new(user_id: &str, item: &DtkPocketData, body: String) -> PocketIndexItem {
+        PocketIndexItem {
+            user_id: user_id.to_string(),
+            item_id: item.item_id.clone(),
+            url: item.url.clone(),
+            title: item.title.clone(),
+            excerpt: item.excerpt.clone().unwrap_or_default(),
+            tags: item.tags.clone(),
+            body: body.chars().take(POCKET_INDEX_BODY_MAX_CHARS).collect(),
+        }
+    }
+}
+
+/// Search result, best matches first
+#[derive(Clone, Debug, PartialEq)]
+pub struct PocketSearchHit {
+    pub item_id: String,
+    pub score: f32,
+    /// Html snippet with the matched terms in `<b>`, empty when nothing matched in the text
+    pub highlight: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PocketIndexFields {
+    /// `user_id:item_id`, identifies a document when updating it
+    key: Field,
+    user_id: Field,
+    item_id: Field,
+    url: Field,
+    title: Field,
+    excerpt: Field,
+    tags: Field,
+    body: Field,
+}
+
+impl PocketIndexFields {
+    fn schema() -> (Schema, PocketIndexFields) {
+        let mut builder = Schema::builder();
+        let fields = PocketIndexFields {
+            key: builder.add_text_field("key", STRING),
+            user_id: builder.add_text_field("user_id", STRING | STORED),
+            item_id: builder.add_text_field("item_id", STRING | STORED),
+            url: builder.add_text_field("url", STORED),
+            title: builder.add_text_field("title", TEXT | STORED),
+            excerpt: builder.add_text_field("excerpt", TEXT | STORED),
+            tags: builder.add_text_field("tags", TEXT | STORED),
+            body: builder.add_text_field("body", TEXT | STORED),
+        };
+        (builder.build(), fields)
+    }
+}
+
+fn index_key(user_id: &str, item_id: &str) -> String {
+    format!("{user_id}:{item_id}")
+}
+
+/// Tantivy index ranking with BM25, updated as items are synced
+pub struct PocketIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: PocketIndexFields,
+}
+
+impl std::fmt::Debug for PocketIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PocketIndex").field("index", &self.index).finish()
+    }
+}
+
+impl PocketIndex {
+    /// Open the index in `dir`, created when missing
+    pub fn open(dir: &Path) -> Result<PocketIndex, DtkError> {
+        std::fs::create_dir_all(dir)?;
+        let (schema, fields) = PocketIndexFields::schema();
+        let directory = MmapDirectory::open(dir).map_err(|err| DtkError::from(err.to_string().as_str()))?;
+        PocketIndex::with_index(Index::open_or_create(directory, schema)?, fields)
+    }
+
+    /// Index kept in memory, nothing is written to disk
+    pub fn in_ram() -> Result<PocketIndex, DtkError> {
+        let (schema, fields) = PocketIndexFields::schema();
+        PocketIndex::with_index(Index::create_in_ram(schema), fields)
+    }
+
+    /// Index under `RUSTY_POCKET_INDEX_DIR`, None when disabled or unusable,
+    /// searches then fall back to mongodb `$text`
+    pub fn from_env() -> Option<Arc<PocketIndex>> {
+        let dir = get_pocket_index_dir();
+        if dir.is_empty() {
+            return None;
+        }
+        match PocketIndex::open(Path::new(&dir)) {
+            Ok(index) => Some(Arc::new(index)),
+            Err(err) => {
+                log::error!("[POCKET] Could not open the search index in {} => {}", dir, err);
+                None
+            }
+        }
+    }
+
+    fn with_index(index: Index, fields: PocketIndexFields) -> Result<PocketIndex, DtkError> {
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        let writer = index.writer_with_num_threads(1, POCKET_INDEX_WRITER_BYTES)?;
+        Ok(PocketIndex {
+            index,
+            reader,
+            writer: Mutex::new(writer),
+            fields,
+        })
+    }
+
+    /// Add or replace items, then remove deleted ones, in one commit
+    pub fn update(&self, items: &[PocketIndexItem], deleted: &[(String, String)]) -> Result<(), DtkError> {
+        let fields = self.fields;
+        let mut writer = self.writer.lock().unwrap();
+        for item in items {
+            let key = index_key(&item.user_id, &item.item_id);
+            writer.delete_term(Term::from_field_text(fields.key, &key));
+            let mut doc = TantivyDocument::default();
+            doc.add_text(fields.key, &key);
+            doc.add_text(fields.user_id, &item.user_id);
+            doc.add_text(fields.item_id, &item.item_id);
+            doc.add_text(fields.url, &item.url);
+            doc.add_text(fields.title, &item.title);
+            doc.add_text(fields.excerpt, &item.excerpt);
+            for tag in &item.tags {
+                doc.add_text(fields.tags, tag);
+            }
+            doc.add_text(fields.body, &item.body);
+            writer.add_document(doc)?;
+        }
+        for (user_id, item_id) in deleted {
+            writer.delete_term(Term::from_field_text(fields.key, &index_key(user_id, item_id)));
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn user_query(&self, user_id: &str) -> Box<dyn Query> {
+        let term = Term::from_field_text(self.fields.user_id, user_id);
+        Box::new(ConstScoreQuery::new(
+            Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+            0.0,
+        ))
+    }
+
+    /// Number of indexed items of the user
+    pub fn user_count(&self, user_id: &str) -> u64 {
+        let searcher = self.reader.searcher();
+        searcher.search(&self.user_query(user_id), &Count).unwrap_or(0) as u64
+    }
+
+    /// Ids of the indexed items of the user
+    pub fn user_item_ids(&self, user_id: &str) -> Result<HashSet<String>, DtkError> {
+        let searcher = self.reader.searcher();
+        let count = self.user_count(user_id) as usize;
+        let top_docs = searcher.search(&self.user_query(user_id), &TopDocs::with_limit(count.max(1)))?;
+        let mut item_ids = HashSet::new();
+        for (_, address) in top_docs {
+            let doc = searcher.doc::<TantivyDocument>(address)?;
+            if let Some(item_id) = doc.get_first(self.fields.item_id).and_then(|value| value.as_str()) {
+                item_ids.insert(item_id.to_string());
+            }
+        }
+        Ok(item_ids)
+    }
+
+    /// Url and page content already indexed for an item
+    pub fn indexed_body(&self, user_id: &str, item_id: &str) -> Option<(String, String)> {
+        let searcher = self.reader.searcher();
+        let term = Term::from_field_text(self.fields.key, &index_key(user_id, item_id));
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        let (_, address) = searcher.search(&query, &TopDocs::with_limit(1)).ok()?.into_iter().next()?;
+        let doc = searcher.doc::<TantivyDocument>(address).ok()?;
+        let text = |field: Field| {
+            doc.get_first(field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Some((text(self.fields.url), text(self.fields.body)))
+    }
+
+    /// Search the user items, `"phrases"` and `-excluded` terms are supported,
+    /// invalid syntax is ignored rather than rejected
+    pub fn search(&self, user_id: &str, search: &str, limit: usize) -> Result<Vec<PocketSearchHit>, DtkError> {
+        let fields = self.fields;
+        let text_fields = vec![fields.title, fields.excerpt, fields.tags, fields.body];
+        let mut parser = QueryParser::for_index(&self.index, text_fields);
+        parser.set_field_boost(fields.title, 2.0);
+        parser.set_field_boost(fields.tags, 1.5);
+        let (text_query, _) = parser.parse_query_lenient(search);
+        let query = BooleanQuery::new(vec![(Occur::Must, self.user_query(user_id)), (Occur::Must, text_query)]);
+
+        let searcher = self.reader.searcher();
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit.max(1)))?;
+        let mut generators = Vec::new();
+        for field in [fields.body, fields.excerpt, fields.title] {
+            let mut generator = SnippetGenerator::create(&searcher, &query, field)?;
+            generator.set_max_num_chars(POCKET_INDEX_SNIPPET_CHARS);
+            generators.push((field, generator));
+        }
+        let mut hits = Vec::new();
+        for (score, address) in top_docs {
+            let doc = searcher.doc::<TantivyDocument>(address)?;
+            let text = |field: Field| doc.get_first(field).and_then(|value| value.as_str()).unwrap_or_default();
+            let highlight = generators
+                .iter()
+                .map(|(field, generator)| generator.snippet(text(*field)))
+                .find(|snippet| !snippet.highlighted().is_empty())
+                .map(|snippet| snippet.to_html())
+                .unwrap_or_default();
+            hits.push(PocketSearchHit {
+                item_id: text(fields.item_id).to_string(),
+                score,
+                highlight,
+            });
+        }
+        Ok(hits)
+    }
+}
+
+/// Index synced items with their page content, fetched only for new or moved urls
+pub async fn index_pocket_items(
+    index: Arc<PocketIndex>,
+    user_id: String,
+    items: Vec<DtkPocketData>,
+    deleted_ids: Vec<String>,
+) {
+    let timeout = Duration::from_secs(get_pocket_index_fetch_timeout());
+    let mut index_items = Vec::new();
+    for item in items {
+        let body = match index.indexed_body(&user_id, &item.item_id) {
+            Some((url, body)) if url == item.url && !body.is_empty() => body,
+            _ => match tokio::time::timeout(timeout, get_html_to_md(&item.url, HtmlToMdMode::Article)).await {
+                Ok(Ok(page)) => page.markdown,
+                Ok(Err(err)) => {
+                    log::warn!("[POCKET] Could not fetch {} for the search index => {}", item.url, err);
+                    String::new()
+                }
+                Err(_) => {
+                    log::warn!("[POCKET] Timed out fetching {} for the search index", item.url);
+                    String::new()
+                }
+            },
+        };
+        index_items.push(PocketIndexItem::new(&user_id, &item, body));
+    }
+    let deleted: Vec<(String, String)> = deleted_ids.into_iter().map(|id| (user_id.clone(), id)).collect();
+    let count = index_items.len();
+    let res = tokio::task::spawn_blocking(move || index.update(&index_items, &deleted)).await;
+    match res {
+        Ok(Ok(())) => log::info!("[POCKET] Indexed {} items for {}", count, user_id),
+        Ok(Err(err)) => log::error!("[POCKET] Search index update failed for {} => {}", user_id, err),
+        Err(err) => log::error!("[POCKET] Search index update panicked for {} => {}", user_id, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(user_id: &str, item_id: &str, title: &str, body: &str) -> PocketIndexItem {
+        PocketIndexItem {
+            user_id: user_id.to_string(),
+            item_id: item_id.to_string(),
+            url: format!("https://rusty.com/{item_id}"),
+            title: title.to_string(),
+            excerpt: String::new(),
+            tags: vec!["rust".to_string()],
+            body: body.to_string(),
+        }
+    }
+
+    fn ids(hits: &[PocketSearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.item_id.as_str()).collect()
+    }
+
+    #[test]
+    fn search_ranks_and_highlights() {
+        let index = PocketIndex::in_ram().unwrap();
+        index
+            .update(
+                &[
+                    item("1", "a", "Async runtimes", "tokio is an async runtime for rust"),
+                    item("1", "b", "Borrow checker", "the borrow checker and async code"),
+                    item("2", "c", "Async runtimes", "someone else async item"),
+                ],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(index.user_count("1"), 2);
+        assert_eq!(index.user_count("3"), 0);
+        assert_eq!(index.user_item_ids("1").unwrap(), HashSet::from(["a".to_string(), "b".to_string()]));
+
+        let hits = index.search("1", "async runtime", 10).unwrap();
+        assert_eq!(ids(&hits), vec!["a", "b"]);
+        assert!(hits[0].score > hits[1].score);
+        assert!(hits[0].highlight.contains("<b>async</b>"));
+
+        let hits = index.search("1", "\"async code\"", 10).unwrap();
+        assert_eq!(ids(&hits), vec!["b"]);
+        let hits = index.search("1", "async -tokio", 10).unwrap();
+        assert_eq!(ids(&hits), vec!["b"]);
+        // invalid syntax is not an error
+        assert!(index.search("1", "title:(async", 10).is_ok());
+    }
+
+    #[test]
+    fn update_replaces_and_deletes() {
+        let index = PocketIndex::in_ram().unwrap();
+        index.update(&[item("1", "a", "Old title", "old body")], &[]).unwrap();
+        index.update(&[item("1", "a", "New title", "new body")], &[]).unwrap();
+        assert!(index.search("1", "old", 10).unwrap().is_empty());
+        assert_eq!(ids(&index.search("1", "new", 10).unwrap()), vec!["a"]);
+        assert_eq!(
+            index.indexed_body("1", "a"),
+            Some(("https://rusty.com/a".to_string(), "new body".to_string()))
+        );
+
+        index.update(&[], &[("1".to_string(), "a".to_string())]).unwrap();
+        assert!(index.search("1", "new", 10).unwrap().is_empty());
+        assert_eq!(index.indexed_body("1", "a"), None);
+        assert_eq!(index.user_count("1"), 0);
+        assert!(index.user_item_ids("1").unwrap().is_empty());
+    }
+}
@@ -1,13 +1,22 @@
 #![allow(missing_docs)]
 
+use std::collections::{BTreeMap, HashMap};
 use std::ops::{Index, IndexMut};
+use std::str::FromStr;
 
-use chrono::NaiveDateTime;
-use serde::{Deserialize, Serialize};
+use base64::Engine;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::dtkutils::utils::null_if_empty;
 
-use super::pocket_utils::{get_pocket_src_type, get_valid_title, get_valid_url, inject_tags_from_url};
+use super::pocket_query::{word_count_expr, PocketFacetCount};
+use super::pocket_classifier::PocketClassifier;
+use super::pocket_health::PocketLinkHealth;
+use super::pocket_dedup::canonicalize_url;
+use super::pocket_utils::{get_valid_title, get_valid_url, pocket_tags_to_vec};
 
 #[derive(Serialize)]
 /// Pocket url response
@@ -41,6 +50,13 @@ pub enum PocketSrcType {
     TWITTER,
     YOUTUBE,
     GITHUB,
+    REDDIT,
+    HACKERNEWS,
+    MASTODON,
+    ARXIV,
+    STACKOVERFLOW,
+    MEDIUM,
+    VIMEO,
     ARTICLE,
 }
 
@@ -51,33 +67,90 @@ impl PocketSrcType {
             PocketSrcType::TWITTER => "twitter",
             PocketSrcType::YOUTUBE => "youtube",
             PocketSrcType::GITHUB => "github",
+            PocketSrcType::REDDIT => "reddit",
+            PocketSrcType::HACKERNEWS => "hackernews",
+            PocketSrcType::MASTODON => "mastodon",
+            PocketSrcType::ARXIV => "arxiv",
+            PocketSrcType::STACKOVERFLOW => "stackoverflow",
+            PocketSrcType::MEDIUM => "medium",
+            PocketSrcType::VIMEO => "vimeo",
             PocketSrcType::ARTICLE => "article",
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+/// Accept strings, numbers and null where Pocket usually sends strings
+fn lenient_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(value) => value,
+        serde_json::Value::Number(value) => value.to_string(),
+        serde_json::Value::Bool(value) => (value as u8).to_string(),
+        _ => String::new(),
+    })
+}
+
+/// Accept numbers and numeric strings, anything else is 0
+fn lenient_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64> + Default,
+{
+    let value = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(value) => value.as_u64(),
+        serde_json::Value::String(value) => value.trim().parse::<u64>().ok(),
+        _ => None,
+    };
+    Ok(value.and_then(|value| T::try_from(value).ok()).unwrap_or_default())
+}
+
+/// Item as sent by Pocket, missing or oddly typed fields fall back to defaults
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct PocketData {
+    #[serde(deserialize_with = "lenient_string")]
     pub item_id: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub resolved_id: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub given_url: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub given_title: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub favorite: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub status: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub time_added: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub time_updated: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub time_read: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub time_favorited: String,
-    pub sort_id: u16,
+    #[serde(deserialize_with = "lenient_number")]
+    pub sort_id: u32,
+    #[serde(deserialize_with = "lenient_string")]
     pub resolved_title: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub resolved_url: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub excerpt: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub is_article: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub is_index: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub has_video: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub has_image: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub word_count: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub lang: String,
+    #[serde(deserialize_with = "lenient_number")]
     pub listen_duration_estimate: u16,
 
     pub tags: Option<serde_json::Value>,
@@ -88,6 +161,116 @@ pub struct PocketData {
     pub videos: Option<serde_json::Value>,
 }
 
+/// Item that could not be parsed, kept with its raw JSON in the quarantine collection
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketQuarantineItem {
+    pub item_id: String,
+    pub error: String,
+    pub raw: serde_json::Value,
+}
+
+/// Items retrieved in one sync, Pocket sends deleted items as `{"item_id", "status": "2"}` only
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PocketSyncBatch {
+    pub items: HashMap<String, PocketData>,
+    pub deleted_ids: Vec<String>,
+    pub quarantined: Vec<PocketQuarantineItem>,
+}
+
+/// What a sync run changed locally
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketSyncSummary {
+    pub added: u32,
+    pub updated: u32,
+    pub archived: u32,
+    pub deleted: u32,
+    #[serde(default)]
+    pub quarantined: u32,
+    /// Items sharing the canonical url of a saved item with other items
+    #[serde(default)]
+    pub duplicates: u32,
+}
+
+/// Pocket item `status` of archived items
+pub const POCKET_STATUS_ARCHIVED: u8 = 1;
+/// Pocket item `status` of deleted items, never stored
+pub const POCKET_STATUS_DELETED: &str = "2";
+
+impl PocketSyncSummary {
+    /// Count an upserted item given its previous local status, if it was stored
+    pub fn count_upsert(&mut self, previous_status: Option<u8>, status: u8) {
+        match previous_status {
+            None => self.added += 1,
+            Some(previous) if previous != POCKET_STATUS_ARCHIVED && status == POCKET_STATUS_ARCHIVED => {
+                self.archived += 1
+            }
+            Some(_) => self.updated += 1,
+        }
+    }
+}
+
+/// Action sent to Pocket `v3/send`, named as in the Pocket API
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PocketAction {
+    Archive { item_id: String },
+    Readd { item_id: String },
+    Favorite { item_id: String },
+    Unfavorite { item_id: String },
+    Delete { item_id: String },
+    TagsAdd { item_id: String, tags: Vec<String> },
+    TagsRemove { item_id: String, tags: Vec<String> },
+    TagsReplace { item_id: String, tags: Vec<String> },
+    TagRename { old_tag: String, new_tag: String },
+}
+
+impl PocketAction {
+    /// Item the action applies to, None for actions on every item of a tag
+    pub fn item_id(&self) -> Option<&str> {
+        match self {
+            PocketAction::Archive { item_id }
+            | PocketAction::Readd { item_id }
+            | PocketAction::Favorite { item_id }
+            | PocketAction::Unfavorite { item_id }
+            | PocketAction::Delete { item_id }
+            | PocketAction::TagsAdd { item_id, .. }
+            | PocketAction::TagsRemove { item_id, .. }
+            | PocketAction::TagsReplace { item_id, .. } => Some(item_id),
+            PocketAction::TagRename { .. } => None,
+        }
+    }
+
+    /// Action as expected by Pocket, tags are sent comma separated
+    pub fn to_pocket_json(&self) -> serde_json::Value {
+        let mut action = serde_json::to_value(self).unwrap();
+        if let Some(tags) = action.get_mut("tags") {
+            let joined = tags
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|tag| tag.as_str())
+                .collect::<Vec<&str>>()
+                .join(",");
+            *tags = serde_json::Value::String(joined);
+        }
+        action
+    }
+}
+
+/// Body of `/pocket/actions`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketActionRequest {
+    pub actions: Vec<PocketAction>,
+}
+
+/// Outcome of one action as reported by Pocket
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketActionResult {
+    #[serde(flatten)]
+    pub action: PocketAction,
+    pub ok: bool,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PocketSendResponse {
     pub action_results: serde_json::Value,
@@ -110,6 +293,160 @@ pub struct DtkPocketResponse {
     pub instagram: Vec<DtkPocketData>,
     pub twitter: Vec<DtkPocketData>,
     pub unique_tags: Vec<String>,
+    /// Items matching the filters, across all pages
+    #[serde(default)]
+    pub total: u64,
+    /// Cursor of the next page, None on the last one
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+    /// Facet counts of the filtered items, by facet name
+    #[serde(default)]
+    pub facets: BTreeMap<String, Vec<PocketFacetCount>>,
+    /// Matched text of the items, by item id, when searching the local index
+    #[serde(default)]
+    pub highlights: BTreeMap<String, String>,
+}
+
+/// Sort orders of the pocket endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PocketSort {
+    Added,
+    Updated,
+    Favorited,
+    WordCount,
+    /// Text search relevance, only with a search
+    Score,
+}
+
+impl FromStr for PocketSort {
+    type Err = String;
+
+    fn from_str(sort: &str) -> Result<Self, Self::Err> {
+        match sort {
+            "added" => Ok(PocketSort::Added),
+            "updated" => Ok(PocketSort::Updated),
+            "favorited" => Ok(PocketSort::Favorited),
+            "word_count" => Ok(PocketSort::WordCount),
+            "score" => Ok(PocketSort::Score),
+            _ => Err(format!("Unknown sort: {sort}")),
+        }
+    }
+}
+
+impl PocketSort {
+    /// Aggregation expression computing the sort key of an item
+    pub fn sort_key(&self) -> Bson {
+        match self {
+            PocketSort::Added => Bson::from("$time_added"),
+            PocketSort::Updated => Bson::from("$time_updated"),
+            PocketSort::Favorited => {
+                Bson::from(doc! { "$ifNull": ["$time_favorited", to_bson_date(DateTime::<Utc>::default())] })
+            }
+            PocketSort::WordCount => word_count_expr(),
+            PocketSort::Score => Bson::from(doc! { "$meta": "textScore" }),
+        }
+    }
+
+    /// Sort key following a ranking, best first when sorted in descending order
+    pub fn ranked_key(&self, ranking: &[String]) -> Bson {
+        match self {
+            PocketSort::Score => Bson::from(doc! { "$subtract": [0, { "$indexOfArray": [ranking, "$item_id"] }] }),
+            _ => self.sort_key(),
+        }
+    }
+}
+
+/// Position after the last item of a page, sent to clients as an opaque string
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PocketCursor {
+    pub key: Bson,
+    pub item_id: String,
+}
+
+impl PocketCursor {
+    pub fn encode(&self) -> String {
+        let bytes = mongodb::bson::to_vec(self).unwrap();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(cursor: &str) -> Result<PocketCursor, String> {
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| "Invalid cursor".to_string())?;
+        mongodb::bson::from_slice(&bytes).map_err(|_| "Invalid cursor".to_string())
+    }
+
+    /// Items after the cursor, `sort_key` then `item_id` break ties
+    pub fn filter(&self, ascending: bool) -> Document {
+        let op = if ascending { "$gt" } else { "$lt" };
+        doc! { "$or": [
+            { "sort_key": { op: &self.key } },
+            { "sort_key": &self.key, "item_id": { op: &self.item_id } },
+        ] }
+    }
+}
+
+/// Pagination options sent along with the user payload
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PocketPageRequest {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    /// added (default), updated, favorited, word_count or score
+    pub sort: Option<String>,
+    /// desc (default) or asc
+    pub order: Option<String>,
+}
+
+/// Validated pagination options
+#[derive(Clone, Debug, PartialEq)]
+pub struct PocketPageQuery {
+    pub limit: i64,
+    pub cursor: Option<PocketCursor>,
+    pub sort: PocketSort,
+    pub ascending: bool,
+    /// Item ids best first when searching the local index, sorting by score follows it instead of `$text`
+    pub ranking: Option<Vec<String>>,
+}
+
+/// Items per page when no limit is given
+pub const POCKET_PAGE_DEFAULT_LIMIT: i64 = 50;
+/// Max items per page
+pub const POCKET_PAGE_MAX_LIMIT: i64 = 200;
+
+impl PocketPageRequest {
+    pub fn parse(&self, has_search: bool) -> Result<PocketPageQuery, String> {
+        let sort = self.sort.as_deref().unwrap_or("added").parse::<PocketSort>()?;
+        if sort == PocketSort::Score && !has_search {
+            return Err("Sorting by score needs a search".to_string());
+        }
+        let ascending = match self.order.as_deref().unwrap_or("desc") {
+            "asc" => true,
+            "desc" => false,
+            order => return Err(format!("Unknown order: {order}")),
+        };
+        let cursor = match self.cursor.as_deref() {
+            Some(cursor) => Some(PocketCursor::decode(cursor)?),
+            None => None,
+        };
+        Ok(PocketPageQuery {
+            limit: self.limit.unwrap_or(POCKET_PAGE_DEFAULT_LIMIT).clamp(1, POCKET_PAGE_MAX_LIMIT),
+            cursor,
+            sort,
+            ascending,
+            ranking: None,
+        })
+    }
+}
+
+/// One page of items with the total count and facet counts
+#[derive(Clone, Debug, Default)]
+pub struct PocketPage {
+    pub items: Vec<DtkPocketData>,
+    pub total: u64,
+    pub next_cursor: Option<String>,
+    pub facets: BTreeMap<String, Vec<PocketFacetCount>>,
+    pub instagram: Vec<DtkPocketData>,
+    pub twitter: Vec<DtkPocketData>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -199,13 +536,22 @@ pub struct DtkPocketData {
     pub src_type: String,
     pub item_id: String,
     pub url: String,
+    /// `url` as compared to find duplicates, see `canonicalize_url`
+    #[serde(default)]
+    pub canonical_url: String,
     pub title: String,
     pub favorite: u8,
     pub status: u8,
-    pub time_added: String,
-    pub time_updated: String,
-    pub time_read: String,
-    pub time_favorited: String,
+    #[serde(with = "pocket_date")]
+    pub time_added: DateTime<Utc>,
+    #[serde(with = "pocket_date")]
+    pub time_updated: DateTime<Utc>,
+    /// None until the item is read
+    #[serde(default, with = "pocket_date_opt")]
+    pub time_read: Option<DateTime<Utc>>,
+    /// None until the item is favorited
+    #[serde(default, with = "pocket_date_opt")]
+    pub time_favorited: Option<DateTime<Utc>>,
     pub excerpt: Option<String>,
     pub is_article: u8,
     pub is_index: u8,
@@ -215,11 +561,17 @@ pub struct DtkPocketData {
     pub lang: String,
     pub listen_duration_estimate: u16,
     pub tags: Vec<String>,
+    /// Tags ever added by the classifier, the ones missing from `tags` were removed by the user
+    #[serde(default)]
+    pub derived_tags: Vec<String>,
     pub domain_metadata: Option<serde_json::Value>,
     pub authors: Option<serde_json::Value>,
     pub image: Option<serde_json::Value>,
     pub images: Option<serde_json::Value>,
     pub videos: Option<serde_json::Value>,
+    /// Last link check, only written by the link checker so syncs keep it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_health: Option<PocketLinkHealth>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -240,6 +592,7 @@ impl Index<&'_ str> for DtkPocketData {
         match s {
             "item_id" => &self.item_id,
             "url" => &self.url,
+            "canonical_url" => &self.canonical_url,
             _ => panic!("unknown field: {}", s),
         }
     }
@@ -250,43 +603,170 @@ impl IndexMut<&'_ str> for DtkPocketData {
         match s {
             "item_id" => &mut self.item_id,
             "url" => &mut self.url,
+            "canonical_url" => &mut self.canonical_url,
             _ => panic!("unknown field: {}", s),
         }
     }
 }
 
+/// Pocket flags are "0", "1" or "2" strings, anything else is 0
+fn parse_pocket_flag(flag: &str) -> u8 {
+    flag.trim().parse::<u8>().unwrap_or(0)
+}
+
+/// Pocket times are unix timestamps as strings, "0" means never
+fn parse_pocket_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    match timestamp.trim().parse::<i64>() {
+        Ok(timestamp) if timestamp > 0 => Utc.timestamp_opt(timestamp, 0).single(),
+        _ => None,
+    }
+}
+
+/// Parse a date given as RFC 3339, `%Y-%m-%d %H:%M:%S` (the legacy stored format) or `%Y-%m-%d`, in UTC
+pub fn parse_pocket_date(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+/// BSON datetime for mongodb filters and documents
+pub fn to_bson_date(date: DateTime<Utc>) -> BsonDateTime {
+    BsonDateTime::from_millis(date.timestamp_millis())
+}
+
+/// Read a date stored either as a BSON datetime or as a legacy string
+fn bson_to_pocket_date(value: Bson) -> Option<DateTime<Utc>> {
+    match value {
+        Bson::DateTime(date) => Utc.timestamp_millis_opt(date.timestamp_millis()).single(),
+        Bson::String(date) => parse_pocket_date(&date),
+        _ => None,
+    }
+}
+
+/// Dates are RFC 3339 strings in JSON, `DtkPocketData::to_document` stores them as BSON datetimes
+pub(crate) mod pocket_date {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        Ok(bson_to_pocket_date(Bson::deserialize(deserializer)?).unwrap_or_default())
+    }
+}
+
+/// Same as `pocket_date`, the legacy epoch sentinel reads as None
+mod pocket_date_opt {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(date: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => pocket_date::serialize(date, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Ok(bson_to_pocket_date(Bson::deserialize(deserializer)?).filter(|date| date.timestamp() > 0))
+    }
+}
+
+/// Date fields of `DtkPocketData`
+pub const POCKET_DATE_FIELDS: [&str; 4] = ["time_added", "time_updated", "time_read", "time_favorited"];
+
+/// Optional `added_after` and `read_before` filters sent along with the user payload
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PocketDateFilterRequest {
+    pub added_after: Option<String>,
+    pub read_before: Option<String>,
+}
+
+impl PocketDateFilterRequest {
+    /// Add the date range to mongodb filters
+    pub fn apply(&self, filters: &mut Document) -> Result<(), String> {
+        let parse = |date: &str| parse_pocket_date(date).ok_or_else(|| format!("Invalid date: {date}"));
+        if let Some(added_after) = self.added_after.as_deref() {
+            filters.insert("time_added", doc! { "$gt": to_bson_date(parse(added_after)?) });
+        }
+        if let Some(read_before) = self.read_before.as_deref() {
+            filters.insert("time_read", doc! { "$lt": to_bson_date(parse(read_before)?) });
+        }
+        Ok(())
+    }
+}
+
 impl DtkPocketData {
-    pub fn from_other_type(pocket_item: PocketData, user_id: &str) -> DtkPocketData {
+    /// Document to store, with dates as BSON datetimes
+    pub fn to_document(&self) -> Document {
+        let mut document = mongodb::bson::to_document(self).unwrap();
+        for field in POCKET_DATE_FIELDS {
+            let date = document.get_str(field).ok().and_then(parse_pocket_date);
+            if let Some(date) = date {
+                document.insert(field, to_bson_date(date));
+            }
+        }
+        document
+    }
+
+    /// Url of the main image, if any
+    pub fn image_src(&self) -> Option<String> {
+        self.image
+            .as_ref()
+            .and_then(|image| image.get("src"))
+            .and_then(|src| src.as_str())
+            .filter(|src| !src.is_empty())
+            .map(String::from)
+    }
+
+    /// Leave out the derived tags the user removed from the stored item, they are not added back on sync
+    pub fn keep_removed_derived_tags(&mut self, previous_tags: &[String], previous_derived_tags: &[String]) {
+        let removed: Vec<&String> = previous_derived_tags
+            .iter()
+            .filter(|tag| !previous_tags.contains(tag))
+            .collect();
+        let derived_tags = &self.derived_tags;
+        self.tags.retain(|tag| !(removed.contains(&tag) && derived_tags.contains(tag)));
+        for tag in previous_derived_tags {
+            if !self.derived_tags.contains(tag) {
+                self.derived_tags.push(tag.clone());
+            }
+        }
+    }
+
+    pub fn from_other_type(pocket_item: PocketData, user_id: &str, classifier: &PocketClassifier) -> DtkPocketData {
+        let url = get_valid_url(pocket_item.clone());
+        let user_tags = pocket_tags_to_vec(pocket_item.tags.clone()).unwrap_or_default();
+        let tags = classifier.tags(&url, &user_tags);
+        let derived_tags = tags.iter().filter(|tag| !user_tags.contains(tag)).cloned().collect();
         DtkPocketData {
             user_id: user_id.to_string(),
-            src_type: get_pocket_src_type(pocket_item.clone()),
-            url: get_valid_url(pocket_item.clone()),
+            src_type: classifier.classify(&url).src_type,
+            tags,
+            derived_tags,
+            canonical_url: canonicalize_url(&url),
+            url,
             title: get_valid_title(pocket_item.clone()),
-            tags: inject_tags_from_url(pocket_item.clone()),
             item_id: pocket_item.item_id,
-            favorite: pocket_item.favorite.parse::<u8>().unwrap(),
-            status: pocket_item.status.parse::<u8>().unwrap(),
-            time_added: NaiveDateTime::from_timestamp_opt(pocket_item.time_added.parse::<i64>().unwrap(), 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            time_updated: NaiveDateTime::from_timestamp_opt(pocket_item.time_updated.parse::<i64>().unwrap(), 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            time_read: NaiveDateTime::from_timestamp_opt(pocket_item.time_read.parse::<i64>().unwrap(), 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            time_favorited: NaiveDateTime::from_timestamp_opt(pocket_item.time_favorited.parse::<i64>().unwrap(), 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
+            favorite: parse_pocket_flag(&pocket_item.favorite),
+            status: parse_pocket_flag(&pocket_item.status),
+            time_added: parse_pocket_timestamp(&pocket_item.time_added).unwrap_or_default(),
+            time_updated: parse_pocket_timestamp(&pocket_item.time_updated).unwrap_or_default(),
+            time_read: parse_pocket_timestamp(&pocket_item.time_read),
+            time_favorited: parse_pocket_timestamp(&pocket_item.time_favorited),
             excerpt: null_if_empty(pocket_item.excerpt.clone()),
-            is_article: pocket_item.is_article.parse::<u8>().unwrap(),
-            is_index: pocket_item.is_index.parse::<u8>().unwrap(),
-            has_video: pocket_item.has_video.parse::<u8>().unwrap(),
-            has_image: pocket_item.has_image.parse::<u8>().unwrap(),
+            is_article: parse_pocket_flag(&pocket_item.is_article),
+            is_index: parse_pocket_flag(&pocket_item.is_index),
+            has_video: parse_pocket_flag(&pocket_item.has_video),
+            has_image: parse_pocket_flag(&pocket_item.has_image),
             word_count: pocket_item.word_count,
             lang: pocket_item.lang,
             listen_duration_estimate: pocket_item.listen_duration_estimate,
@@ -295,6 +775,132 @@ impl DtkPocketData {
             image: pocket_item.image,
             images: pocket_item.images,
             videos: pocket_item.videos,
+            link_health: None,
+        }
+    }
+}
+
+/// Item `item_id` of the `baakey` test user saved at `url`, the other Pocket fields from `pocket_item`
+#[cfg(test)]
+pub(crate) fn test_pocket_item(item_id: &str, url: &str, pocket_item: PocketData) -> DtkPocketData {
+    let pocket_item = PocketData {
+        item_id: item_id.to_string(),
+        given_url: url.to_string(),
+        ..pocket_item
+    };
+    DtkPocketData::from_other_type(pocket_item, "baakey", &PocketClassifier::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dtk_item() -> DtkPocketData {
+        let pocket_item = PocketData {
+            time_added: "1678886400".to_string(),
+            time_updated: "1678886400".to_string(),
+            time_read: "0".to_string(),
+            time_favorited: "1678890000".to_string(),
+            ..PocketData::default()
+        };
+        test_pocket_item("42", "https://baakeydow.dtksi.com", pocket_item)
+    }
+
+    #[test]
+    fn pocket_dates_in_json_and_bson() {
+        let item = dtk_item();
+        let json = serde_json::to_value(&item).unwrap();
+        assert_eq!(json["time_added"], "2023-03-15T13:20:00Z");
+        assert_eq!(json["time_read"], serde_json::Value::Null);
+        assert_eq!(serde_json::from_value::<DtkPocketData>(json).unwrap(), item);
+
+        let document = item.to_document();
+        assert!(matches!(document.get("time_added"), Some(Bson::DateTime(_))));
+        assert!(matches!(document.get("time_favorited"), Some(Bson::DateTime(_))));
+        assert_eq!(document.get("time_read"), Some(&Bson::Null));
+        assert_eq!(mongodb::bson::from_document::<DtkPocketData>(document).unwrap(), item);
+    }
+
+    #[test]
+    fn legacy_string_dates() {
+        let mut document = mongodb::bson::to_document(&dtk_item()).unwrap();
+        document.insert("time_added", "2023-03-15 13:20:00");
+        document.insert("time_read", "1970-01-01 00:00:00");
+        let item = mongodb::bson::from_document::<DtkPocketData>(document).unwrap();
+        assert_eq!(item.time_added, Utc.with_ymd_and_hms(2023, 3, 15, 13, 20, 0).unwrap());
+        assert_eq!(item.time_read, None);
+    }
+
+    #[test]
+    fn removed_derived_tags_stay_removed() {
+        let pocket_item = PocketData {
+            tags: Some(serde_json::json!({ "rust": { "tag": "rust" } })),
+            ..PocketData::default()
+        };
+        let mut item = test_pocket_item("42", "https://github.com/rust-lang", pocket_item);
+        assert_eq!(item.tags, vec!["rust", "github", "tech"]);
+        assert_eq!(item.derived_tags, vec!["github", "tech"]);
+
+        let stored_tags = vec!["rust".to_string(), "tech".to_string()];
+        item.keep_removed_derived_tags(&stored_tags, &["github".to_string(), "tech".to_string()]);
+        assert_eq!(item.tags, vec!["rust", "tech"]);
+        assert_eq!(item.derived_tags, vec!["github", "tech"]);
+    }
+
+    #[test]
+    fn page_request_and_cursor() {
+        let page = PocketPageRequest::default().parse(false).unwrap();
+        assert_eq!(page.limit, POCKET_PAGE_DEFAULT_LIMIT);
+        assert_eq!(page.sort, PocketSort::Added);
+        assert!(!page.ascending);
+        let score = PocketPageRequest {
+            sort: Some("score".to_string()),
+            ..PocketPageRequest::default()
+        };
+        assert!(score.parse(false).is_err());
+        assert!(score.parse(true).is_ok());
+
+        let cursor = PocketCursor {
+            key: Bson::from(to_bson_date(Utc.with_ymd_and_hms(2023, 3, 15, 13, 20, 0).unwrap())),
+            item_id: "42".to_string(),
+        };
+        let page = PocketPageRequest {
+            limit: Some(1000),
+            cursor: Some(cursor.encode()),
+            sort: Some("word_count".to_string()),
+            order: Some("asc".to_string()),
+        }
+        .parse(false)
+        .unwrap();
+        assert_eq!(page.limit, POCKET_PAGE_MAX_LIMIT);
+        assert_eq!(page.cursor.as_ref(), Some(&cursor));
+        assert_eq!(
+            cursor.filter(page.ascending),
+            doc! { "$or": [
+                { "sort_key": { "$gt": &cursor.key } },
+                { "sort_key": &cursor.key, "item_id": { "$gt": "42" } },
+            ] }
+        );
+        assert!(PocketCursor::decode("not a cursor").is_err());
+    }
+
+    #[test]
+    fn date_range_filters() {
+        let mut filters = doc! { "user_id": "baakey" };
+        PocketDateFilterRequest {
+            added_after: Some("2023-03-01".to_string()),
+            read_before: Some("2023-03-15T13:20:00+01:00".to_string()),
         }
+        .apply(&mut filters)
+        .unwrap();
+        let added_after = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let read_before = Utc.with_ymd_and_hms(2023, 3, 15, 12, 20, 0).unwrap();
+        assert_eq!(filters.get_document("time_added").unwrap(), &doc! { "$gt": to_bson_date(added_after) });
+        assert_eq!(filters.get_document("time_read").unwrap(), &doc! { "$lt": to_bson_date(read_before) });
+        let invalid = PocketDateFilterRequest {
+            added_after: Some("yesterday".to_string()),
+            read_before: None,
+        };
+        assert!(invalid.apply(&mut filters).is_err());
     }
 }
@@ -0,0 +1,363 @@
+//! Public profiles and visibility of pocket items
+#![allow(missing_docs)]
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, Document};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+use super::pocket_model::{pocket_date, to_bson_date, DtkPocketData};
+use super::pocket_tags::{is_tag_within, normalize_tag, tag_matchers};
+use super::pocket_utils::{
+    get_pocket_db_name, get_pocket_profiles_collection_name, get_pocket_public_email, get_pocket_public_slug,
+};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_main_db, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Who can see an item: everybody, whoever has its link, or its owner only
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PocketVisibility {
+    Public,
+    Unlisted,
+    #[default]
+    Private,
+}
+
+/// Profile of a user opting in to share items at `/pocket/public/{slug}`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketProfile {
+    #[serde(default)]
+    pub user_id: String,
+    pub slug: String,
+    /// Nothing is shared until enabled
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Visibility of items without tag or item visibility, nothing is shared unless told otherwise
+    #[serde(default)]
+    pub default_visibility: PocketVisibility,
+    /// Applies to the tag descendants, the most restrictive tag of an item wins
+    #[serde(default = "default_tag_visibility")]
+    pub tag_visibility: BTreeMap<String, PocketVisibility>,
+    /// Overrides the tag visibility
+    #[serde(default)]
+    pub item_visibility: BTreeMap<String, PocketVisibility>,
+    /// Last change of the profile or of a visibility
+    #[serde(default, with = "pocket_date")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Items tagged `private` stay hidden unless told otherwise
+fn default_tag_visibility() -> BTreeMap<String, PocketVisibility> {
+    BTreeMap::from([("private".to_string(), PocketVisibility::Private)])
+}
+
+/// Body of `/pocket/profile/save`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PocketProfileRequest {
+    pub profile: PocketProfile,
+}
+
+/// Body of `/pocket/visibility`, a None visibility removes the item or tag visibility
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketVisibilityRequest {
+    pub item_ids: Vec<String>,
+    pub tags: Vec<String>,
+    pub visibility: Option<PocketVisibility>,
+}
+
+/// Lowercase letters, digits and inner dashes, 3 to 40 chars
+pub fn is_valid_slug(slug: &str) -> bool {
+    (3..=40).contains(&slug.len())
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+}
+
+impl PocketProfile {
+    /// Visibility of an item, see `PocketVisibility`
+    pub fn visibility(&self, item: &DtkPocketData) -> PocketVisibility {
+        if let Some(visibility) = self.item_visibility.get(&item.item_id) {
+            return *visibility;
+        }
+        item.tags
+            .iter()
+            .flat_map(|tag| {
+                self.tag_visibility
+                    .iter()
+                    .filter(move |(parent, _)| is_tag_within(tag, parent))
+                    .map(|(_, visibility)| *visibility)
+            })
+            .max()
+            .unwrap_or(self.default_visibility)
+    }
+
+    /// Set or remove the visibility of the request items and tags
+    pub fn set_visibility(&mut self, request: &PocketVisibilityRequest) -> Result<(), DtkError> {
+        let tags: Vec<String> = request.tags.iter().map(|tag| normalize_tag(tag)).collect();
+        let keys = request.item_ids.iter().chain(tags.iter());
+        if keys.clone().any(|key| key.is_empty() || key.starts_with('$')) {
+            return Err(DtkError::from("Invalid item id or tag"));
+        }
+        let maps = [(&mut self.item_visibility, &request.item_ids), (&mut self.tag_visibility, &tags)];
+        for (visibilities, keys) in maps {
+            for key in keys {
+                match request.visibility {
+                    Some(visibility) => visibilities.insert(key.clone(), visibility),
+                    None => visibilities.remove(key),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Mongodb filter of the items listed on the profile
+    pub fn public_filter(&self) -> Document {
+        let items_with = |public: bool| -> Vec<String> {
+            self.item_visibility
+                .iter()
+                .filter(|(_, visibility)| (**visibility == PocketVisibility::Public) == public)
+                .map(|(item_id, _)| item_id.clone())
+                .collect()
+        };
+        let tags_with = |public: bool| -> Vec<String> {
+            self.tag_visibility
+                .iter()
+                .filter(|(_, visibility)| (**visibility == PocketVisibility::Public) == public)
+                .map(|(tag, _)| tag.clone())
+                .collect()
+        };
+        let mut by_tags = vec![
+            doc! { "item_id": { "$nin": items_with(false) } },
+            doc! { "tags": { "$nin": tag_matchers(&tags_with(false)) } },
+        ];
+        if self.default_visibility != PocketVisibility::Public {
+            by_tags.push(doc! { "tags": { "$in": tag_matchers(&tags_with(true)) } });
+        }
+        doc! {
+            "user_id": &self.user_id,
+            "$or": [
+                { "item_id": { "$in": items_with(true) } },
+                { "$and": by_tags },
+            ],
+        }
+    }
+}
+
+async fn profiles_collection() -> Collection<PocketProfile> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    client
+        .database(&get_pocket_db_name())
+        .collection::<PocketProfile>(&get_pocket_profiles_collection_name())
+}
+
+/// Profile of the user, None until saved
+pub async fn get_pocket_profile(user_id: &str) -> Result<Option<PocketProfile>, DtkError> {
+    Ok(profiles_collection().await.find_one(doc! { "user_id": user_id }, None).await?)
+}
+
+/// Enabled profile at `slug`
+pub async fn get_public_profile(slug: &str) -> Result<Option<PocketProfile>, DtkError> {
+    let filter = doc! { "slug": slug.to_lowercase(), "enabled": true };
+    Ok(profiles_collection().await.find_one(filter, None).await?)
+}
+
+/// Create or update the user profile, item visibilities are kept and set with `set_pocket_visibility`
+pub async fn save_pocket_profile(user_id: &str, profile: &PocketProfile) -> Result<PocketProfile, DtkError> {
+    let slug = profile.slug.trim().to_lowercase();
+    if !is_valid_slug(&slug) {
+        return Err(DtkError::from("Invalid slug"));
+    }
+    let coll = profiles_collection().await;
+    let model = IndexModel::builder()
+        .keys(doc! { "slug": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    coll.create_index(model, None).await?;
+    let taken = coll
+        .find_one(doc! { "slug": &slug, "user_id": { "$ne": user_id } }, None)
+        .await?;
+    if taken.is_some() {
+        return Err(DtkError::from("Slug already taken"));
+    }
+    let tag_visibility: Document = profile
+        .tag_visibility
+        .iter()
+        .map(|(tag, visibility)| (normalize_tag(tag), mongodb::bson::to_bson(visibility).unwrap()))
+        .filter(|(tag, _)| !tag.is_empty())
+        .collect();
+    let update = doc! { "$set": {
+        "slug": &slug,
+        "enabled": profile.enabled,
+        "display_name": &profile.display_name,
+        "default_visibility": mongodb::bson::to_bson(&profile.default_visibility).unwrap(),
+        "tag_visibility": tag_visibility,
+        "updated_at": to_bson_date(Utc::now()),
+    }, "$setOnInsert": { "item_visibility": {} } };
+    let options = UpdateOptions::builder().upsert(true).build();
+    coll.update_one(doc! { "user_id": user_id }, update, options).await?;
+    get_pocket_profile(user_id)
+        .await?
+        .ok_or_else(|| DtkError::from("Profile not saved"))
+}
+
+/// Set or remove the visibility of items and tags.
+/// The maps are written whole, tags like `node.js` would be nested documents with a dotted `$set`,
+/// so nothing is saved if the profile changed since it was read.
+pub async fn set_pocket_visibility(user_id: &str, request: &PocketVisibilityRequest) -> Result<(), DtkError> {
+    let mut profile = get_pocket_profile(user_id)
+        .await?
+        .ok_or_else(|| DtkError::from("Save a profile first"))?;
+    profile.set_visibility(request)?;
+    let to_document = |visibilities: &BTreeMap<String, PocketVisibility>| -> Document {
+        visibilities
+            .iter()
+            .map(|(key, visibility)| (key.clone(), mongodb::bson::to_bson(visibility).unwrap()))
+            .collect()
+    };
+    let update = doc! { "$set": {
+        "tag_visibility": to_document(&profile.tag_visibility),
+        "item_visibility": to_document(&profile.item_visibility),
+        "updated_at": to_bson_date(Utc::now()),
+    } };
+    // the edit applies to the maps as read, not to a concurrent change of them
+    let filter = doc! { "user_id": user_id, "updated_at": to_bson_date(profile.updated_at) };
+    let res = profiles_collection().await.update_one(filter, update, None).await?;
+    if res.matched_count == 0 {
+        return Err(DtkError::from("Profile changed meanwhile, retry"));
+    }
+    Ok(())
+}
+
+/// Profile serving the items previously listed at `/pocket/public`, run once as a migration.
+/// Shares everything but the `private` tag, as that page did.
+pub async fn migrate_public_pocket_profile() -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let user = client
+        .database(&get_mongodb_main_db())
+        .collection::<Document>("users")
+        .find_one(doc! { "email": get_pocket_public_email() }, None)
+        .await?;
+    let user_id = match user.as_ref().and_then(|user| user.get_object_id("_id").ok()) {
+        Some(user_id) => user_id.to_string(),
+        None => return Ok(0),
+    };
+    let coll = profiles_collection().await;
+    let slug = get_pocket_public_slug();
+    if coll.find_one(doc! { "slug": &slug }, None).await?.is_some() {
+        log::warn!("[POCKET] profile slug {} already taken, public profile not created", slug);
+        return Ok(0);
+    }
+    let update = doc! { "$setOnInsert": {
+        "slug": &slug,
+        "enabled": true,
+        "display_name": mongodb::bson::Bson::Null,
+        "default_visibility": mongodb::bson::to_bson(&PocketVisibility::Public).unwrap(),
+        "tag_visibility": { "private": mongodb::bson::to_bson(&PocketVisibility::Private).unwrap() },
+        "item_visibility": {},
+        "updated_at": to_bson_date(Utc::now()),
+    } };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let res = coll.update_one(doc! { "user_id": &user_id }, update, options).await?;
+    Ok(res.upserted_id.is_some() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtkpocket::pocket_model::{test_pocket_item, PocketData};
+
+    fn profile() -> PocketProfile {
+        serde_json::from_value(serde_json::json!({
+            "user_id": "baakey",
+            "slug": "baakey",
+            "enabled": true,
+            "default_visibility": "public",
+        }))
+        .unwrap()
+    }
+
+    fn item(item_id: &str, tags: &[&str]) -> DtkPocketData {
+        let mut item = test_pocket_item(item_id, "https://rusty.com", PocketData::default());
+        item.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        item
+    }
+
+    #[test]
+    fn slugs() {
+        assert!(is_valid_slug("baakey-dev"));
+        assert!(!is_valid_slug("ba"));
+        assert!(!is_valid_slug("-baakey"));
+        assert!(!is_valid_slug("Baakey"));
+        assert!(!is_valid_slug("baakey/dev"));
+    }
+
+    #[test]
+    fn item_visibility() {
+        let mut profile = profile();
+        assert_eq!(profile.visibility(&item("1", &["rust"])), PocketVisibility::Public);
+        assert_eq!(profile.visibility(&item("1", &["private/notes"])), PocketVisibility::Private);
+        profile.tag_visibility.insert("drafts".to_string(), PocketVisibility::Unlisted);
+        assert_eq!(profile.visibility(&item("1", &["drafts", "rust"])), PocketVisibility::Unlisted);
+        assert_eq!(profile.visibility(&item("1", &["drafts", "private"])), PocketVisibility::Private);
+        profile.item_visibility.insert("1".to_string(), PocketVisibility::Public);
+        assert_eq!(profile.visibility(&item("1", &["private"])), PocketVisibility::Public);
+        profile.default_visibility = PocketVisibility::Private;
+        assert_eq!(profile.visibility(&item("2", &[])), PocketVisibility::Private);
+    }
+
+    #[test]
+    fn private_by_default() {
+        let profile: PocketProfile = serde_json::from_value(serde_json::json!({ "slug": "baakey" })).unwrap();
+        assert_eq!(profile.visibility(&item("1", &["rust"])), PocketVisibility::Private);
+    }
+
+    #[test]
+    fn set_dotted_tag_visibility() {
+        let mut profile = profile();
+        let mut request = PocketVisibilityRequest {
+            item_ids: vec!["42".to_string()],
+            tags: vec!["Node.js".to_string()],
+            visibility: Some(PocketVisibility::Private),
+        };
+        profile.set_visibility(&request).unwrap();
+        assert_eq!(profile.tag_visibility.get("node.js"), Some(&PocketVisibility::Private));
+        assert_eq!(profile.visibility(&item("1", &["node.js"])), PocketVisibility::Private);
+        assert_eq!(profile.visibility(&item("42", &["rust"])), PocketVisibility::Private);
+        request.visibility = None;
+        profile.set_visibility(&request).unwrap();
+        assert!(profile.item_visibility.is_empty());
+        assert_eq!(profile.tag_visibility.keys().collect::<Vec<_>>(), vec!["private"]);
+        request.tags = vec!["$where".to_string()];
+        assert!(profile.set_visibility(&request).is_err());
+    }
+
+    #[test]
+    fn public_items_filter() {
+        let mut profile = profile();
+        profile.item_visibility.insert("1".to_string(), PocketVisibility::Public);
+        profile.item_visibility.insert("2".to_string(), PocketVisibility::Unlisted);
+        profile.tag_visibility.insert("rust".to_string(), PocketVisibility::Public);
+        let hidden_tags = tag_matchers(&["private".to_string()]);
+        assert_eq!(
+            profile.public_filter(),
+            doc! { "user_id": "baakey", "$or": [
+                { "item_id": { "$in": ["1"] } },
+                { "$and": [{ "item_id": { "$nin": ["2"] } }, { "tags": { "$nin": hidden_tags.clone() } }] },
+            ] }
+        );
+        profile.default_visibility = PocketVisibility::Private;
+        let filter = profile.public_filter();
+        let by_tags = filter.get_array("$or").unwrap()[1].as_document().unwrap().get_array("$and").unwrap();
+        assert_eq!(
+            by_tags[2],
+            doc! { "tags": { "$in": tag_matchers(&["rust".to_string()]) } }.into()
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Faceted search over saved pocket items
+#![allow(missing_docs)]
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use super::pocket_tags::{tag_matchers, tag_paths_expr};
+
+/// Read state of an item, Pocket `status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PocketReadState {
+    Unread,
+    Archived,
+}
+
+/// Item filters, every given filter must match
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketQuery {
+    /// Text search, `"quoted phrases"` and `-excluded` terms are supported
+    pub search: Option<String>,
+    /// Items having every one of these tags
+    pub tags_all: Vec<String>,
+    /// Items having at least one of these tags
+    pub tags_any: Vec<String>,
+    /// Items having none of these tags
+    pub tags_none: Vec<String>,
+    pub src_types: Vec<String>,
+    /// Domains, subdomains included, `www.` is ignored
+    pub domains: Vec<String>,
+    pub langs: Vec<String>,
+    pub state: Option<PocketReadState>,
+    pub favorite: Option<bool>,
+    pub has_video: Option<bool>,
+    pub has_image: Option<bool>,
+    pub word_count_min: Option<u32>,
+    pub word_count_max: Option<u32>,
+    /// Items whose link is broken, or not, see `PocketLinkHealth`
+    pub broken: Option<bool>,
+}
+
+/// Body of the pocket endpoints, `query` sits next to the user payload
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PocketQueryRequest {
+    pub query: Option<PocketQuery>,
+}
+
+/// Number of items for one facet value
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketFacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Word count facet buckets lower bounds, the last bucket is open
+const WORD_COUNT_BUCKETS: [i64; 5] = [0, 500, 1000, 2000, 5000];
+
+/// Make user input safe for `$text`: unbalanced quotes and backslashes are dropped,
+/// so are terms without any letter or digit, which `$text` would ignore or misread.
+pub fn escape_text_search(search: &str) -> String {
+    // `is_multiple_of` needs Rust 1.87, and newer clippy flags `% 2 == 0`
+    let balanced = search.matches('"').count() & 1 == 0;
+    let cleaned: String = search
+        .chars()
+        .filter(|c| *c != '\\' && (balanced || *c != '"'))
+        .collect();
+    cleaned
+        .split_whitespace()
+        .filter(|term| term.contains('"') || term.chars().any(char::is_alphanumeric))
+        .map(|term| match term.strip_prefix('-') {
+            Some(negated) => format!("-{}", negated.trim_start_matches('-')),
+            None => term.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Word count as a number, Pocket sends it as a string
+pub fn word_count_expr() -> Bson {
+    Bson::from(doc! {
+        "$convert": { "input": "$word_count", "to": "long", "onError": 0_i64, "onNull": 0_i64 }
+    })
+}
+
+/// Lowercase host of the item url, without `www.`
+pub fn domain_expr() -> Bson {
+    Bson::from(doc! {
+        "$let": {
+            "vars": {
+                "found": {
+                    "$regexFind": {
+                        "input": "$url",
+                        "regex": "^[a-z][a-z0-9+.-]*://(?:www\\.)?([^/:?#]+)",
+                        "options": "i",
+                    }
+                }
+            },
+            "in": { "$toLower": { "$arrayElemAt": ["$$found.captures", 0] } },
+        }
+    })
+}
+
+fn lowercase(values: &[String]) -> Vec<String> {
+    values.iter().map(|value| value.trim().to_lowercase()).collect()
+}
+
+impl PocketQuery {
+    /// Escaped text search, None when nothing searchable is left
+    pub fn text_search(&self) -> Option<String> {
+        let search = escape_text_search(self.search.as_deref().unwrap_or_default());
+        match search.is_empty() {
+            true => None,
+            false => Some(search),
+        }
+    }
+
+    /// Add the query to mongodb filters, Err on an inconsistent query
+    pub fn apply(&self, filters: &mut Document) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.word_count_min, self.word_count_max) {
+            if min > max {
+                return Err("word_count_min is greater than word_count_max".to_string());
+            }
+        }
+        if self.domains.iter().any(|domain| domain.trim().is_empty()) {
+            return Err("Empty domain".to_string());
+        }
+        if let Some(search) = self.text_search() {
+            filters.insert("$text", doc! { "$search": search });
+        }
+        let mut and = Vec::<Document>::new();
+        // a tag also matches its descendants, `lang` gives `lang/rust` items
+        for tag in self.tags_all.iter() {
+            and.push(doc! { "tags": { "$in": tag_matchers(std::slice::from_ref(tag)) } });
+        }
+        if !self.tags_any.is_empty() {
+            and.push(doc! { "tags": { "$in": tag_matchers(&self.tags_any) } });
+        }
+        if !self.tags_none.is_empty() {
+            and.push(doc! { "tags": { "$nin": tag_matchers(&self.tags_none) } });
+        }
+        if !self.src_types.is_empty() {
+            and.push(doc! { "src_type": { "$in": lowercase(&self.src_types) } });
+        }
+        if !self.domains.is_empty() {
+            let domains: Vec<Document> = lowercase(&self.domains)
+                .iter()
+                .map(|domain| {
+                    let domain = domain.trim_start_matches("www.");
+                    let pattern = format!(
+                        "^[a-z][a-z0-9+.-]*://([^/?#]*\\.)?{}([/:?#]|$)",
+                        regex::escape(domain)
+                    );
+                    doc! { "url": { "$regex": pattern, "$options": "i" } }
+                })
+                .collect();
+            and.push(doc! { "$or": domains });
+        }
+        if !self.langs.is_empty() {
+            and.push(doc! { "lang": { "$in": lowercase(&self.langs) } });
+        }
+        if let Some(state) = self.state {
+            let status = match state {
+                PocketReadState::Unread => 0,
+                PocketReadState::Archived => 1,
+            };
+            and.push(doc! { "status": status });
+        }
+        if let Some(favorite) = self.favorite {
+            and.push(doc! { "favorite": favorite as i32 });
+        }
+        if let Some(has_video) = self.has_video {
+            and.push(match has_video {
+                true => doc! { "has_video": { "$gt": 0 } },
+                false => doc! { "has_video": 0 },
+            });
+        }
+        if let Some(has_image) = self.has_image {
+            and.push(match has_image {
+                true => doc! { "has_image": { "$gt": 0 } },
+                false => doc! { "has_image": 0 },
+            });
+        }
+        let mut word_count = Vec::<Document>::new();
+        if let Some(min) = self.word_count_min {
+            word_count.push(doc! { "$gte": [word_count_expr(), min as i64] });
+        }
+        if let Some(max) = self.word_count_max {
+            word_count.push(doc! { "$lte": [word_count_expr(), max as i64] });
+        }
+        if !word_count.is_empty() {
+            and.push(doc! { "$expr": { "$and": word_count } });
+        }
+        if let Some(broken) = self.broken {
+            and.push(match broken {
+                true => doc! { "link_health.broken": true },
+                false => doc! { "link_health.broken": { "$ne": true } },
+            });
+        }
+        if !and.is_empty() {
+            filters.insert("$and", and);
+        }
+        Ok(())
+    }
+}
+
+fn count_by(expr: impl Into<Bson>) -> Vec<Document> {
+    vec![
+        doc! { "$group": { "_id": expr.into(), "count": { "$sum": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+    ]
+}
+
+/// `$facet` sub-pipelines counting the filtered items per facet value
+pub fn pocket_facets() -> Document {
+    // items count once for each of their tags and their parents
+    let mut tags = vec![
+        doc! { "$project": { "tags": tag_paths_expr() } },
+        doc! { "$unwind": "$tags" },
+    ];
+    tags.extend(count_by("$tags"));
+    doc! {
+        "tags": tags,
+        "src_type": count_by("$src_type"),
+        "domain": count_by(domain_expr()),
+        "lang": count_by("$lang"),
+        "state": count_by("$status"),
+        "favorite": count_by("$favorite"),
+        "has_video": count_by(doc! { "$gt": ["$has_video", 0] }),
+        "has_image": count_by(doc! { "$gt": ["$has_image", 0] }),
+        "word_count": [{
+            "$bucket": {
+                "groupBy": word_count_expr(),
+                "boundaries": WORD_COUNT_BUCKETS.to_vec(),
+                "default": "open",
+                "output": { "count": { "$sum": 1 } },
+            }
+        }],
+    }
+}
+
+/// Readable value of a facet bucket, None for empty values
+fn facet_label(facet: &str, value: &Bson) -> Option<String> {
+    let number = match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        _ => None,
+    };
+    match (facet, value, number) {
+        ("state", _, Some(0)) => Some("unread".to_string()),
+        ("state", _, Some(1)) => Some("archived".to_string()),
+        ("favorite", _, Some(number)) => Some((number > 0).to_string()),
+        ("word_count", Bson::String(_), _) => Some(format!("{}+", WORD_COUNT_BUCKETS[WORD_COUNT_BUCKETS.len() - 1])),
+        ("word_count", _, Some(lower)) => {
+            let upper = WORD_COUNT_BUCKETS.iter().find(|bound| **bound > lower)?;
+            Some(format!("{}-{}", lower, upper - 1))
+        }
+        (_, Bson::String(value), _) if !value.is_empty() => Some(value.clone()),
+        (_, Bson::Boolean(value), _) => Some(value.to_string()),
+        (_, _, Some(number)) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Read the `$facet` result of `pocket_facets`
+pub fn parse_pocket_facets(result: &Document) -> BTreeMap<String, Vec<PocketFacetCount>> {
+    pocket_facets()
+        .keys()
+        .map(|facet| {
+            let counts = result
+                .get_array(facet)
+                .map(|buckets| {
+                    buckets
+                        .iter()
+                        .filter_map(Bson::as_document)
+                        .filter_map(|bucket| {
+                            let count = match bucket.get("count") {
+                                Some(Bson::Int32(count)) => *count as u64,
+                                Some(Bson::Int64(count)) => *count as u64,
+                                _ => 0,
+                            };
+                            let value = facet_label(facet, bucket.get("_id")?)?;
+                            Some(PocketFacetCount { value, count })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            (facet.clone(), counts)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_search_input() {
+        assert_eq!(escape_text_search("c++ key:value"), "c++ key:value");
+        assert_eq!(escape_text_search("\"rust async\" -java"), "\"rust async\" -java");
+        assert_eq!(escape_text_search("\"unbalanced rust"), "unbalanced rust");
+        assert_eq!(escape_text_search("back\\slash -- ---go ?!"), "backslash -go");
+        assert_eq!(escape_text_search("  ...  "), "");
+    }
+
+    #[test]
+    fn query_to_filters() {
+        let query = PocketQuery {
+            search: Some("c++".to_string()),
+            tags_all: vec!["Rust".to_string()],
+            tags_none: vec!["private".to_string()],
+            domains: vec!["www.github.com".to_string()],
+            state: Some(PocketReadState::Unread),
+            has_video: Some(false),
+            word_count_min: Some(500),
+            broken: Some(true),
+            ..PocketQuery::default()
+        };
+        let mut filters = doc! { "user_id": "baakey" };
+        query.apply(&mut filters).unwrap();
+        assert_eq!(filters.get_document("$text").unwrap(), &doc! { "$search": "c++" });
+        let and = filters.get_array("$and").unwrap();
+        let matchers = |tag: &str| tag_matchers(&[tag.to_string()]);
+        assert_eq!(and[0], Bson::from(doc! { "tags": { "$in": matchers("rust") } }));
+        assert_eq!(and[1], Bson::from(doc! { "tags": { "$nin": matchers("private") } }));
+        assert_eq!(
+            and[2],
+            Bson::from(doc! { "$or": [{ "url": {
+                "$regex": "^[a-z][a-z0-9+.-]*://([^/?#]*\\.)?github\\.com([/:?#]|$)",
+                "$options": "i",
+            } }] })
+        );
+        assert_eq!(and[3], Bson::from(doc! { "status": 0 }));
+        assert_eq!(and[4], Bson::from(doc! { "has_video": 0 }));
+        assert_eq!(
+            and[5],
+            Bson::from(doc! { "$expr": { "$and": [{ "$gte": [word_count_expr(), 500_i64] }] } })
+        );
+        assert_eq!(and[6], Bson::from(doc! { "link_health.broken": true }));
+
+        let mut filters = doc! {};
+        PocketQuery::default().apply(&mut filters).unwrap();
+        assert!(filters.is_empty());
+        let inverted = PocketQuery {
+            word_count_min: Some(2000),
+            word_count_max: Some(500),
+            ..PocketQuery::default()
+        };
+        assert!(inverted.apply(&mut filters).is_err());
+    }
+
+    #[test]
+    fn read_facets() {
+        let result = doc! {
+            "tags": [{ "_id": "rust", "count": 3 }, { "_id": "", "count": 1 }],
+            "state": [{ "_id": 0, "count": 2 }, { "_id": 1, "count": 1 }],
+            "has_video": [{ "_id": false, "count": 3 }],
+            "word_count": [{ "_id": 500, "count": 2_i64 }, { "_id": "open", "count": 1 }],
+        };
+        let facets = parse_pocket_facets(&result);
+        let values = |facet: &str| -> Vec<(String, u64)> {
+            facets[facet].iter().map(|count| (count.value.clone(), count.count)).collect()
+        };
+        assert_eq!(values("tags"), vec![("rust".to_string(), 3)]);
+        assert_eq!(
+            values("state"),
+            vec![("unread".to_string(), 2), ("archived".to_string(), 1)]
+        );
+        assert_eq!(values("has_video"), vec![("false".to_string(), 3)]);
+        assert_eq!(
+            values("word_count"),
+            vec![("500-999".to_string(), 2), ("5000+".to_string(), 1)]
+        );
+        assert!(facets["domain"].is_empty());
+    }
+}
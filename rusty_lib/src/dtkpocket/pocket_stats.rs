@@ -0,0 +1,450 @@
+//! Reading statistics of the pocket items, cached for dashboards
+#![allow(missing_docs)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use serde::{Deserialize, Serialize};
+
+use super::pocket_model::{parse_pocket_date, to_bson_date};
+use super::pocket_query::{domain_expr, word_count_expr, PocketFacetCount};
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_db_name, get_pocket_stats_ttl};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Top domains and tags returned by default
+const POCKET_STATS_TOP: usize = 10;
+const POCKET_STATS_TOP_MAX: usize = 100;
+
+/// Body of `/pocket/stats`, dates as in `parse_pocket_date`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketStatsRequest {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Number of top domains and tags
+    pub top: Option<usize>,
+    /// Skip the cache
+    pub refresh: bool,
+}
+
+/// Checked `PocketStatsRequest`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PocketStatsQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub top: usize,
+}
+
+impl PocketStatsRequest {
+    pub fn parse(&self) -> Result<PocketStatsQuery, DtkError> {
+        let parse = |date: &Option<String>| match date.as_deref() {
+            Some(date) => parse_pocket_date(date)
+                .map(Some)
+                .ok_or_else(|| DtkError::from(format!("Invalid date: {date}").as_str())),
+            None => Ok(None),
+        };
+        let query = PocketStatsQuery {
+            from: parse(&self.from)?,
+            to: parse(&self.to)?,
+            top: self.top.unwrap_or(POCKET_STATS_TOP).clamp(1, POCKET_STATS_TOP_MAX),
+        };
+        if let (Some(from), Some(to)) = (query.from, query.to) {
+            if from > to {
+                return Err(DtkError::from("from is after to"));
+            }
+        }
+        Ok(query)
+    }
+}
+
+/// Items in an ISO week, `2024-W05`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketWeekCount {
+    pub week: String,
+    pub count: u64,
+}
+
+/// A top tag with its items per week
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketTagTrend {
+    pub tag: String,
+    pub count: u64,
+    pub weeks: Vec<PocketWeekCount>,
+}
+
+/// Current state of the items added in the range
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketBacklog {
+    pub unread: u64,
+    pub archived: u64,
+    pub favorite: u64,
+}
+
+/// Response of `/pocket/stats`, everything but `read_per_week` is about the items added in the range
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PocketStats {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub generated_at: String,
+    pub added_per_week: Vec<PocketWeekCount>,
+    pub read_per_week: Vec<PocketWeekCount>,
+    pub backlog: PocketBacklog,
+    /// Of the items with a word count
+    pub avg_word_count: Option<f64>,
+    /// In seconds, of the items with an estimate
+    pub avg_listen_duration: Option<f64>,
+    pub top_domains: Vec<PocketFacetCount>,
+    pub top_tags: Vec<PocketTagTrend>,
+    pub src_types: Vec<PocketFacetCount>,
+}
+
+fn date_range(query: &PocketStatsQuery) -> Document {
+    let mut range = Document::new();
+    if let Some(from) = query.from {
+        range.insert("$gte", to_bson_date(from));
+    }
+    if let Some(to) = query.to {
+        range.insert("$lte", to_bson_date(to));
+    }
+    range
+}
+
+fn iso_week(field: &str) -> Document {
+    doc! { "$dateToString": { "format": "%G-W%V", "date": field, "timezone": "UTC" } }
+}
+
+fn count_sum(condition: Document) -> Document {
+    doc! { "$sum": { "$cond": [condition, 1, 0] } }
+}
+
+/// Average of the positive values only, zero means unknown
+fn positive_avg(expr: impl Into<Bson>) -> Document {
+    let expr = expr.into();
+    doc! { "$avg": { "$cond": [{ "$gt": [expr.clone(), 0] }, expr, null] } }
+}
+
+/// Aggregation computing every stat in a single `$facet`
+pub fn pocket_stats_pipeline(user_id: &str, query: &PocketStatsQuery) -> Vec<Document> {
+    let range = date_range(query);
+    let mut read_range = range.clone();
+    // unread items have no read time, legacy ones the epoch
+    if query.from.is_none() {
+        read_range.insert("$gt", BsonDateTime::from_millis(0));
+    }
+    let mut user_match = doc! { "user_id": user_id };
+    if !range.is_empty() {
+        user_match.insert("$or", vec![doc! { "time_added": &range }, doc! { "time_read": &range }]);
+    }
+    let added = match range.is_empty() {
+        true => doc! { "$match": {} },
+        false => doc! { "$match": { "time_added": &range } },
+    };
+    let top = query.top as i64;
+    vec![
+        doc! { "$match": user_match },
+        doc! { "$facet": {
+            "added": [
+                added.clone(),
+                { "$group": { "_id": iso_week("$time_added"), "count": { "$sum": 1 } } },
+                { "$sort": { "_id": 1 } },
+            ],
+            "read": [
+                { "$match": { "status": 1, "time_read": read_range } },
+                { "$group": { "_id": iso_week("$time_read"), "count": { "$sum": 1 } } },
+                { "$sort": { "_id": 1 } },
+            ],
+            "backlog": [
+                added.clone(),
+                { "$group": {
+                    "_id": null,
+                    "unread": count_sum(doc! { "$eq": ["$status", 0] }),
+                    "archived": count_sum(doc! { "$eq": ["$status", 1] }),
+                    "favorite": count_sum(doc! { "$gt": ["$favorite", 0] }),
+                    "avg_word_count": positive_avg(word_count_expr()),
+                    "avg_listen_duration": positive_avg("$listen_duration_estimate"),
+                } },
+            ],
+            "domains": [
+                added.clone(),
+                { "$group": { "_id": domain_expr(), "count": { "$sum": 1 } } },
+                { "$match": { "_id": { "$nin": [null, ""] } } },
+                { "$sort": { "count": -1, "_id": 1 } },
+                { "$limit": top },
+            ],
+            "tags": [
+                added.clone(),
+                { "$unwind": "$tags" },
+                { "$group": { "_id": "$tags", "count": { "$sum": 1 } } },
+                { "$sort": { "count": -1, "_id": 1 } },
+                { "$limit": top },
+            ],
+            // weeks of the top tags only, ranked as in "tags"
+            "tag_weeks": [
+                added.clone(),
+                { "$unwind": "$tags" },
+                { "$group": { "_id": { "tag": "$tags", "week": iso_week("$time_added") }, "count": { "$sum": 1 } } },
+                { "$group": {
+                    "_id": "$_id.tag",
+                    "total": { "$sum": "$count" },
+                    "weeks": { "$push": { "week": "$_id.week", "count": "$count" } },
+                } },
+                { "$sort": { "total": -1, "_id": 1 } },
+                { "$limit": top },
+                { "$unwind": "$weeks" },
+                { "$project": { "_id": { "tag": "$_id", "week": "$weeks.week" }, "count": "$weeks.count" } },
+                { "$sort": { "_id.week": 1 } },
+            ],
+            "src_types": [
+                added,
+                { "$group": { "_id": "$src_type", "count": { "$sum": 1 } } },
+                { "$sort": { "count": -1, "_id": 1 } },
+            ],
+        } },
+    ]
+}
+
+fn to_count(count: Option<&Bson>) -> u64 {
+    match count {
+        Some(Bson::Int32(count)) => *count as u64,
+        Some(Bson::Int64(count)) => *count as u64,
+        _ => 0,
+    }
+}
+
+fn to_avg(avg: Option<&Bson>) -> Option<f64> {
+    match avg {
+        Some(Bson::Double(avg)) => Some((avg * 10.0).round() / 10.0),
+        Some(Bson::Int32(avg)) => Some(*avg as f64),
+        Some(Bson::Int64(avg)) => Some(*avg as f64),
+        _ => None,
+    }
+}
+
+fn buckets<'a>(result: &'a Document, facet: &str) -> impl Iterator<Item = &'a Document> {
+    result
+        .get_array(facet)
+        .map(|buckets| buckets.iter())
+        .unwrap_or_default()
+        .filter_map(Bson::as_document)
+}
+
+fn facet_counts(result: &Document, facet: &str) -> Vec<PocketFacetCount> {
+    buckets(result, facet)
+        .filter_map(|bucket| {
+            Some(PocketFacetCount {
+                value: bucket.get_str("_id").ok().filter(|value| !value.is_empty())?.to_string(),
+                count: to_count(bucket.get("count")),
+            })
+        })
+        .collect()
+}
+
+fn week_counts(result: &Document, facet: &str) -> Vec<PocketWeekCount> {
+    facet_counts(result, facet)
+        .into_iter()
+        .map(|count| PocketWeekCount {
+            week: count.value,
+            count: count.count,
+        })
+        .collect()
+}
+
+/// Read the result of `pocket_stats_pipeline`
+pub fn parse_pocket_stats(result: &Document, query: &PocketStatsQuery) -> PocketStats {
+    let to_rfc3339 = |date: Option<DateTime<Utc>>| date.map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true));
+    let backlog = buckets(result, "backlog").next().cloned().unwrap_or_default();
+    let tag_weeks: Vec<(String, PocketWeekCount)> = buckets(result, "tag_weeks")
+        .filter_map(|bucket| {
+            let id = bucket.get_document("_id").ok()?;
+            let week = PocketWeekCount {
+                week: id.get_str("week").ok()?.to_string(),
+                count: to_count(bucket.get("count")),
+            };
+            Some((id.get_str("tag").ok()?.to_string(), week))
+        })
+        .collect();
+    let top_tags = facet_counts(result, "tags")
+        .into_iter()
+        .map(|count| PocketTagTrend {
+            weeks: tag_weeks
+                .iter()
+                .filter(|(tag, _)| tag == &count.value)
+                .map(|(_, week)| week.clone())
+                .collect(),
+            tag: count.value,
+            count: count.count,
+        })
+        .collect();
+    PocketStats {
+        from: to_rfc3339(query.from),
+        to: to_rfc3339(query.to),
+        generated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        added_per_week: week_counts(result, "added"),
+        read_per_week: week_counts(result, "read"),
+        backlog: PocketBacklog {
+            unread: to_count(backlog.get("unread")),
+            archived: to_count(backlog.get("archived")),
+            favorite: to_count(backlog.get("favorite")),
+        },
+        avg_word_count: to_avg(backlog.get("avg_word_count")),
+        avg_listen_duration: to_avg(backlog.get("avg_listen_duration")),
+        top_domains: facet_counts(result, "domains"),
+        top_tags,
+        src_types: facet_counts(result, "src_types"),
+    }
+}
+
+/// Stats kept in memory for `RUSTY_POCKET_STATS_TTL` seconds, by user and query
+#[derive(Debug)]
+pub struct PocketStatsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, PocketStats)>>,
+}
+
+impl PocketStatsCache {
+    pub fn new(ttl: Duration) -> PocketStatsCache {
+        PocketStatsCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Arc<PocketStatsCache> {
+        Arc::new(PocketStatsCache::new(Duration::from_secs(get_pocket_stats_ttl())))
+    }
+
+    fn key(user_id: &str, query: &PocketStatsQuery) -> String {
+        let timestamp = |date: Option<DateTime<Utc>>| date.map(|date| date.timestamp()).unwrap_or_default();
+        format!("{}:{}:{}:{}", user_id, timestamp(query.from), timestamp(query.to), query.top)
+    }
+
+    pub fn get(&self, user_id: &str, query: &PocketStatsQuery) -> Option<PocketStats> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&PocketStatsCache::key(user_id, query))
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    /// Cache the stats, expired entries are dropped on the way
+    pub fn insert(&self, user_id: &str, query: &PocketStatsQuery, stats: PocketStats) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (cached_at, _)| cached_at.elapsed() < self.ttl);
+        entries.insert(PocketStatsCache::key(user_id, query), (Instant::now(), stats));
+    }
+
+    /// Forget the stats of a user, once its items changed
+    pub fn invalidate(&self, user_id: &str) {
+        let prefix = format!("{user_id}:");
+        self.entries.lock().unwrap().retain(|key, _| !key.starts_with(&prefix));
+    }
+}
+
+/// Stats of the user items, from the cache unless `refresh` is set
+pub async fn get_pocket_stats(
+    user_id: &str,
+    request: &PocketStatsRequest,
+    cache: &PocketStatsCache,
+) -> Result<PocketStats, DtkError> {
+    let query = request.parse()?;
+    if !request.refresh {
+        if let Some(stats) = cache.get(user_id, &query) {
+            return Ok(stats);
+        }
+    }
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let result = match coll.aggregate(pocket_stats_pipeline(user_id, &query), None).await?.next().await {
+        Some(result) => result?,
+        None => Document::new(),
+    };
+    let stats = parse_pocket_stats(&result, &query);
+    cache.insert(user_id, &query, stats.clone());
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(from: Option<&str>, to: Option<&str>) -> Result<PocketStatsQuery, DtkError> {
+        PocketStatsRequest {
+            from: from.map(String::from),
+            to: to.map(String::from),
+            ..PocketStatsRequest::default()
+        }
+        .parse()
+    }
+
+    #[test]
+    fn stats_request() {
+        let parsed = query(Some("2024-01-01"), Some("2024-02-01T00:00:00Z")).unwrap();
+        assert_eq!(parsed.top, POCKET_STATS_TOP);
+        assert!(query(Some("2024-02-01"), Some("2024-01-01")).is_err());
+        assert!(query(Some("yesterday"), None).is_err());
+        let pipeline = pocket_stats_pipeline("baakey", &parsed);
+        let range = date_range(&parsed);
+        assert_eq!(
+            pipeline[0],
+            doc! { "$match": { "user_id": "baakey", "$or": [{ "time_added": &range }, { "time_read": &range }] } }
+        );
+        let tag_weeks = pipeline[1].get_document("$facet").unwrap().get_array("tag_weeks").unwrap();
+        assert!(tag_weeks.contains(&doc! { "$limit": POCKET_STATS_TOP as i64 }.into()));
+        let unbounded = pocket_stats_pipeline("baakey", &query(None, None).unwrap());
+        assert_eq!(unbounded[0], doc! { "$match": { "user_id": "baakey" } });
+    }
+
+    #[test]
+    fn read_stats() {
+        let result = doc! {
+            "added": [{ "_id": "2024-W01", "count": 3 }, { "_id": "2024-W02", "count": 1_i64 }],
+            "read": [{ "_id": "2024-W02", "count": 2 }],
+            "backlog": [{ "_id": null, "unread": 2, "archived": 2, "favorite": 1, "avg_word_count": 1234.56 }],
+            "domains": [{ "_id": "rusty.com", "count": 4 }],
+            "tags": [{ "_id": "rust", "count": 2 }],
+            "tag_weeks": [
+                { "_id": { "tag": "rust", "week": "2024-W01" }, "count": 2 },
+                { "_id": { "tag": "go", "week": "2024-W01" }, "count": 1 },
+            ],
+            "src_types": [{ "_id": "article", "count": 4 }],
+        };
+        let stats = parse_pocket_stats(&result, &query(None, None).unwrap());
+        assert_eq!(stats.added_per_week[1].count, 1);
+        assert_eq!(stats.read_per_week[0].week, "2024-W02");
+        assert_eq!(
+            stats.backlog,
+            PocketBacklog {
+                unread: 2,
+                archived: 2,
+                favorite: 1
+            }
+        );
+        assert_eq!(stats.avg_word_count, Some(1234.6));
+        assert_eq!(stats.avg_listen_duration, None);
+        assert_eq!(stats.top_tags.len(), 1);
+        assert_eq!(stats.top_tags[0].weeks.len(), 1);
+        assert_eq!(stats.src_types[0].value, "article");
+        assert_eq!(parse_pocket_stats(&Document::new(), &query(None, None).unwrap()).backlog.unread, 0);
+    }
+
+    #[test]
+    fn stats_cache() {
+        let cache = PocketStatsCache::new(Duration::from_secs(60));
+        let query = query(None, None).unwrap();
+        cache.insert("baakey", &query, PocketStats::default());
+        assert!(cache.get("baakey", &query).is_some());
+        assert!(cache.get("other", &query).is_none());
+        cache.invalidate("baakey");
+        assert!(cache.get("baakey", &query).is_none());
+        let expired = PocketStatsCache::new(Duration::ZERO);
+        expired.insert("baakey", &query, PocketStats::default());
+        assert!(expired.get("baakey", &query).is_none());
+    }
+}
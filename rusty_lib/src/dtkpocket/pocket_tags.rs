@@ -0,0 +1,295 @@
+//! Tag listing and bulk tag edits, tags are hierarchical with `/` separated levels
+#![allow(missing_docs)]
+
+use std::sync::Arc;
+
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, Bson, Document, Regex};
+use serde::{Deserialize, Serialize};
+
+use super::pocket::run_pocket_actions;
+use super::pocket_index::PocketIndex;
+use super::pocket_model::{PocketAction, PocketActionResult};
+use super::pocket_utils::{get_pocket_collection_name, get_pocket_db_name};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+
+/// Separator of tag levels, `lang/rust` is below `lang`
+pub const TAG_SEPARATOR: char = '/';
+
+/// Lowercase tag without blank levels, ` Lang / Rust/` gives `lang/rust`
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split(TAG_SEPARATOR)
+        .map(|level| level.trim().to_lowercase())
+        .filter(|level| !level.is_empty())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Whether `tag` is `parent` or one of its descendants
+pub fn is_tag_within(tag: &str, parent: &str) -> bool {
+    tag == parent || (tag.starts_with(parent) && tag[parent.len()..].starts_with(TAG_SEPARATOR))
+}
+
+/// The tag and its parents, `lang/rust` gives `lang` and `lang/rust`
+pub fn tag_ancestors(tag: &str) -> Vec<String> {
+    let levels: Vec<&str> = tag.split(TAG_SEPARATOR).collect();
+    (1..=levels.len()).map(|depth| levels[..depth].join("/")).collect()
+}
+
+/// Values matching the tag and its descendants in a `$in` or `$nin`
+pub fn tag_matchers(tags: &[String]) -> Vec<Bson> {
+    let mut matchers = vec![];
+    for tag in tags.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()) {
+        matchers.push(Bson::RegularExpression(Regex {
+            pattern: format!("^{}/", regex::escape(&tag)),
+            options: String::new(),
+        }));
+        matchers.push(Bson::String(tag));
+    }
+    matchers
+}
+
+/// Aggregation expression of the item tags and all their parents, each once
+pub fn tag_paths_expr() -> Document {
+    let ancestors = doc! { "$let": {
+        "vars": { "levels": { "$split": ["$$this", "/"] } },
+        "in": { "$map": {
+            "input": { "$range": [1, { "$add": [{ "$size": "$$levels" }, 1] }] },
+            "as": "depth",
+            "in": { "$reduce": {
+                "input": { "$slice": ["$$levels", "$$depth"] },
+                "initialValue": "",
+                "in": { "$cond": [
+                    { "$eq": ["$$value", ""] },
+                    "$$this",
+                    { "$concat": ["$$value", "/", "$$this"] },
+                ] },
+            } },
+        } },
+    } };
+    doc! { "$setUnion": [{ "$reduce": {
+        "input": { "$ifNull": ["$tags", []] },
+        "initialValue": [],
+        "in": { "$concatArrays": ["$$value", ancestors] },
+    } }] }
+}
+
+/// A tag with its items count, `total` includes the items of its descendants
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketTagCount {
+    pub tag: String,
+    pub count: u64,
+    pub total: u64,
+}
+
+/// Body of `/pocket/tags/edit`, every edit applies to the tag descendants as well
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "edit", rename_all = "snake_case")]
+pub enum PocketTagEdit {
+    /// `lang` to `language` also gives `language/rust` from `lang/rust`
+    Rename { tag: String, new_tag: String },
+    /// Rename every one of `tags` to `into`
+    Merge { tags: Vec<String>, into: String },
+    Delete { tag: String },
+}
+
+impl PocketTagEdit {
+    /// Normalized (from, to) pairs, `to` is None for deletions
+    fn renames(&self) -> Result<Vec<(String, Option<String>)>, DtkError> {
+        let target = |tag: &str| match normalize_tag(tag) {
+            tag if tag.is_empty() => Err(DtkError::from("Empty tag")),
+            tag => Ok(tag),
+        };
+        Ok(match self {
+            PocketTagEdit::Rename { tag, new_tag } => vec![(target(tag)?, Some(target(new_tag)?))],
+            PocketTagEdit::Merge { tags, into } => {
+                let into = target(into)?;
+                let mut renames = vec![];
+                for tag in tags {
+                    renames.push((target(tag)?, Some(into.clone())));
+                }
+                renames
+            }
+            PocketTagEdit::Delete { tag } => vec![(target(tag)?, None)],
+        })
+    }
+
+    /// Tags once edited, None when unchanged
+    pub fn rewrite(&self, tags: &[String]) -> Result<Option<Vec<String>>, DtkError> {
+        let renames = self.renames()?;
+        let mut rewritten: Vec<String> = vec![];
+        for tag in tags {
+            let rename = renames.iter().find(|(from, _)| is_tag_within(tag, from));
+            let new_tag = match rename {
+                Some((from, Some(to))) => Some(format!("{}{}", to, &tag[from.len()..])),
+                Some((_, None)) => None,
+                None => Some(tag.clone()),
+            };
+            if let Some(new_tag) = new_tag.filter(|new_tag| !rewritten.contains(new_tag)) {
+                rewritten.push(new_tag);
+            }
+        }
+        Ok((rewritten != tags).then_some(rewritten))
+    }
+
+    /// Filter of the items touched by the edit
+    pub fn filter(&self, user_id: &str) -> Result<Document, DtkError> {
+        let tags: Vec<String> = self.renames()?.into_iter().map(|(from, _)| from).collect();
+        Ok(doc! { "user_id": user_id, "tags": { "$in": tag_matchers(&tags) } })
+    }
+}
+
+fn to_count(count: Option<&Bson>) -> u64 {
+    match count {
+        Some(Bson::Int32(count)) => *count as u64,
+        Some(Bson::Int64(count)) => *count as u64,
+        _ => 0,
+    }
+}
+
+/// Every tag of the user with its parents, sorted by tag
+pub async fn get_pocket_tags(user_id: &str) -> Result<Vec<PocketTagCount>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let pipeline = vec![
+        doc! { "$match": { "user_id": user_id } },
+        doc! { "$project": { "tags": 1, "tag_paths": tag_paths_expr() } },
+        doc! { "$facet": {
+            "count": [{ "$unwind": "$tags" }, { "$group": { "_id": "$tags", "count": { "$sum": 1 } } }],
+            "total": [{ "$unwind": "$tag_paths" }, { "$group": { "_id": "$tag_paths", "count": { "$sum": 1 } } }],
+        } },
+    ];
+    let result = match coll.aggregate(pipeline, None).await?.next().await {
+        Some(result) => result?,
+        None => return Ok(vec![]),
+    };
+    let counts = |facet: &str| -> Vec<(String, u64)> {
+        result
+            .get_array(facet)
+            .map(|counts| {
+                counts
+                    .iter()
+                    .filter_map(|count| count.as_document())
+                    .filter_map(|count| Some((count.get_str("_id").ok()?.to_string(), to_count(count.get("count")))))
+                    .filter(|(tag, _)| !tag.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let exact = counts("count");
+    let mut tags: Vec<PocketTagCount> = counts("total")
+        .into_iter()
+        .map(|(tag, total)| PocketTagCount {
+            count: exact.iter().find(|(exact_tag, _)| exact_tag == &tag).map(|(_, count)| *count).unwrap_or(0),
+            tag,
+            total,
+        })
+        .collect();
+    tags.sort_by(|a, b| a.tag.cmp(&b.tag));
+    Ok(tags)
+}
+
+/// Apply a tag edit to all the user items, sent to Pocket as `tags_replace` actions
+pub async fn edit_pocket_tags(
+    user_id: &str,
+    edit: &PocketTagEdit,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<Vec<PocketActionResult>, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let options = mongodb::options::FindOptions::builder()
+        .projection(doc! { "item_id": 1, "tags": 1 })
+        .build();
+    let mut items = coll.find(edit.filter(user_id)?, options).await?;
+    let mut actions = vec![];
+    while let Some(item) = items.next().await {
+        let item = item?;
+        let item_id = item.get_str("item_id").unwrap_or_default().to_string();
+        let tags: Vec<String> = item
+            .get_array("tags")
+            .map(|tags| tags.iter().filter_map(|tag| tag.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        // Pocket refuses an empty tags_replace
+        match edit.rewrite(&tags)? {
+            Some(new_tags) if new_tags.is_empty() => actions.push(PocketAction::TagsRemove { item_id, tags }),
+            Some(new_tags) => actions.push(PocketAction::TagsReplace { item_id, tags: new_tags }),
+            None => (),
+        }
+    }
+    if actions.is_empty() {
+        return Ok(vec![]);
+    }
+    run_pocket_actions(user_id, actions, index).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn hierarchical_tags() {
+        assert_eq!(normalize_tag(" Lang / Rust/"), "lang/rust");
+        assert_eq!(normalize_tag("//"), "");
+        assert!(is_tag_within("lang/rust", "lang"));
+        assert!(is_tag_within("lang", "lang"));
+        assert!(!is_tag_within("language", "lang"));
+        assert_eq!(tag_ancestors("lang/rust/async"), tags(&["lang", "lang/rust", "lang/rust/async"]));
+        assert_eq!(
+            tag_matchers(&tags(&["C++"])),
+            vec![
+                Bson::RegularExpression(Regex {
+                    pattern: "^c\\+\\+/".to_string(),
+                    options: String::new()
+                }),
+                Bson::String("c++".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrite_tags() {
+        let item_tags = tags(&["lang", "lang/rust", "language/go", "tech"]);
+        let rename = PocketTagEdit::Rename {
+            tag: "Lang".to_string(),
+            new_tag: "language".to_string(),
+        };
+        assert_eq!(
+            rename.rewrite(&item_tags).unwrap(),
+            Some(tags(&["language", "language/rust", "language/go", "tech"]))
+        );
+        let merge = PocketTagEdit::Merge {
+            tags: tags(&["tech", "lang"]),
+            into: "dev".to_string(),
+        };
+        assert_eq!(
+            merge.rewrite(&item_tags).unwrap(),
+            Some(tags(&["dev", "dev/rust", "language/go"]))
+        );
+        let delete = PocketTagEdit::Delete { tag: "lang".to_string() };
+        assert_eq!(delete.rewrite(&item_tags).unwrap(), Some(tags(&["language/go", "tech"])));
+        assert_eq!(delete.rewrite(&tags(&["language"])).unwrap(), None);
+        let empty = PocketTagEdit::Rename {
+            tag: "lang".to_string(),
+            new_tag: " / ".to_string(),
+        };
+        assert!(empty.rewrite(&item_tags).is_err());
+    }
+
+    #[test]
+    fn edit_from_json() {
+        let edit: PocketTagEdit = serde_json::from_str(r#"{ "edit": "merge", "tags": ["a"], "into": "b" }"#).unwrap();
+        assert_eq!(
+            edit.filter("baakey").unwrap(),
+            doc! { "user_id": "baakey", "tags": { "$in": tag_matchers(&tags(&["a"])) } }
+        );
+    }
+}
@@ -0,0 +1,686 @@
+//! Bookmark import and export: Netscape bookmarks, CSV, JSON lines and Pocket `ril_export.html`
+#![allow(missing_docs)]
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::stream::StreamExt;
+use markup5ever_rcdom::{Handle, RcDom};
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::pocket::run_pocket_actions;
+use super::pocket_auth::{push_pocket_data, PocketPushData};
+use super::pocket_dedup::canonicalize_url;
+use super::pocket_feed::escape_xml;
+use super::pocket_index::PocketIndex;
+use super::pocket_model::{parse_pocket_date, DtkPocketData, PocketAction, PocketData, PocketSyncBatch};
+use super::pocket_tags::{is_tag_within, normalize_tag, tag_matchers};
+use super::pocket_utils::{
+    get_pocket_collection_name, get_pocket_db_name, get_pocket_users_collection_name, update_pocket_data,
+};
+use crate::dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri};
+use crate::dtkutils::dtk_error::DtkError;
+use crate::dtkutils::dtk_readability::{attr, clean_text, tag_name, text_content};
+
+/// Adds sent to Pocket in a single request
+const POCKET_IMPORT_BATCH: usize = 100;
+
+/// Prefix of the item ids of items imported without Pocket
+pub const POCKET_IMPORT_ID_PREFIX: &str = "import-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PocketTransferFormat {
+    /// Netscape bookmarks, as exported and imported by browsers
+    Netscape,
+    /// `title,url,time_added,tags,status` as in the Pocket CSV export, tags `|` separated, then `favorite`
+    Csv,
+    /// One `PocketBookmark` per line
+    Jsonl,
+    /// Pocket `ril_export.html`
+    Pocket,
+}
+
+impl FromStr for PocketTransferFormat {
+    type Err = DtkError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_lowercase().as_str() {
+            "netscape" | "html" | "bookmarks" => Ok(PocketTransferFormat::Netscape),
+            "csv" => Ok(PocketTransferFormat::Csv),
+            "jsonl" | "json" | "ndjson" => Ok(PocketTransferFormat::Jsonl),
+            "pocket" | "ril_export" => Ok(PocketTransferFormat::Pocket),
+            _ => Err(DtkError::from("Unknown bookmarks format")),
+        }
+    }
+}
+
+impl PocketTransferFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PocketTransferFormat::Netscape | PocketTransferFormat::Pocket => "text/html; charset=utf-8",
+            PocketTransferFormat::Csv => "text/csv; charset=utf-8",
+            PocketTransferFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            PocketTransferFormat::Netscape => "bookmarks.html",
+            PocketTransferFormat::Csv => "pocket.csv",
+            PocketTransferFormat::Jsonl => "pocket.jsonl",
+            PocketTransferFormat::Pocket => "ril_export.html",
+        }
+    }
+
+    /// Guess the format of a file, both html formats are read the same way
+    pub fn detect(data: &str) -> PocketTransferFormat {
+        match data.trim_start_matches('\u{feff}').trim_start().chars().next() {
+            Some('<') => PocketTransferFormat::Netscape,
+            Some('{') | Some('[') => PocketTransferFormat::Jsonl,
+            _ => PocketTransferFormat::Csv,
+        }
+    }
+}
+
+/// A saved url as found in bookmark files, times are unix timestamps
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketBookmark {
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub time_added: Option<i64>,
+    /// 1 once archived
+    #[serde(default)]
+    pub status: u8,
+    #[serde(default)]
+    pub favorite: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excerpt: Option<String>,
+}
+
+impl From<&DtkPocketData> for PocketBookmark {
+    fn from(item: &DtkPocketData) -> Self {
+        PocketBookmark {
+            url: item.url.clone(),
+            title: item.title.clone(),
+            tags: item.tags.clone(),
+            time_added: Some(item.time_added.timestamp()).filter(|time| *time > 0),
+            status: item.status,
+            favorite: item.favorite,
+            excerpt: item.excerpt.clone(),
+        }
+    }
+}
+
+impl PocketBookmark {
+    /// Item as if it came from Pocket, with an id derived from the user and its canonical url,
+    /// item ids are unique across users
+    fn to_pocket_data(&self, user_id: &str) -> PocketData {
+        let hash = Sha256::digest(format!("{}\n{}", user_id, canonicalize_url(&self.url)).as_bytes());
+        let item_id = format!("{}{}", POCKET_IMPORT_ID_PREFIX, &format!("{hash:x}")[..16]);
+        let tags: serde_json::Map<String, serde_json::Value> = self
+            .tags
+            .iter()
+            .map(|tag| (tag.clone(), serde_json::json!({ "item_id": &item_id, "tag": tag })))
+            .collect();
+        let time_added = self.time_added.unwrap_or_else(|| chrono::Utc::now().timestamp()).to_string();
+        PocketData {
+            item_id,
+            given_url: self.url.clone(),
+            given_title: self.title.clone(),
+            favorite: self.favorite.to_string(),
+            status: self.status.to_string(),
+            time_updated: time_added.clone(),
+            time_added,
+            time_read: "0".to_string(),
+            time_favorited: "0".to_string(),
+            excerpt: self.excerpt.clone().unwrap_or_default(),
+            tags: Some(serde_json::Value::Object(tags)),
+            ..PocketData::default()
+        }
+    }
+}
+
+fn unix_time(time: &str) -> Option<i64> {
+    let time = time.trim();
+    time.parse::<i64>()
+        .ok()
+        .or_else(|| parse_pocket_date(time).map(|date| date.timestamp()))
+        .filter(|time| *time > 0)
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    let separator = if tags.contains('|') { '|' } else { ',' };
+    tags.split(separator)
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+fn csv_flag(value: &str) -> u8 {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "archive" | "archived" | "read" => 1,
+        _ => 0,
+    }
+}
+
+fn html_bookmarks(node: &Handle, folders: &mut Vec<String>, status: &mut u8, bookmarks: &mut Vec<PocketBookmark>) {
+    match tag_name(node).as_deref() {
+        // Pocket export sections, `Unread` then `Read Archive`
+        Some("h1") => {
+            let title = clean_text(&text_content(node)).unwrap_or_default().to_lowercase();
+            *status = title.contains("archive") as u8;
+            return;
+        }
+        Some("a") => {
+            if let Some(url) = attr(node, "href") {
+                let mut tags = attr(node, "tags").map(|tags| split_tags(&tags)).unwrap_or_default();
+                if !folders.is_empty() {
+                    tags.push(folders.join("/"));
+                }
+                let time_added = attr(node, "time_added").or_else(|| attr(node, "add_date"));
+                bookmarks.push(PocketBookmark {
+                    url,
+                    title: clean_text(&text_content(node)).unwrap_or_default(),
+                    tags,
+                    time_added: time_added.as_deref().and_then(unix_time),
+                    status: *status,
+                    ..PocketBookmark::default()
+                });
+            }
+            return;
+        }
+        _ => (),
+    }
+    // a Netscape folder is a `dt` holding its `h3` name and a `dl` of bookmarks
+    let folder = node
+        .children
+        .borrow()
+        .iter()
+        .filter(|child| tag_name(child).as_deref() == Some("h3"))
+        .find(|h3| attr(h3, "personal_toolbar_folder").is_none() && attr(h3, "unfiled_bookmarks_folder").is_none())
+        .and_then(|h3| clean_text(&text_content(h3)));
+    let children: Vec<Handle> = node.children.borrow().iter().cloned().collect();
+    match folder.filter(|_| tag_name(node).as_deref() == Some("dt")) {
+        Some(folder) => {
+            folders.push(folder);
+            children.iter().for_each(|child| html_bookmarks(child, folders, status, bookmarks));
+            folders.pop();
+        }
+        None => children.iter().for_each(|child| html_bookmarks(child, folders, status, bookmarks)),
+    }
+}
+
+fn csv_bookmarks(data: &str) -> Result<Vec<PocketBookmark>, DtkError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data.as_bytes());
+    let headers: Vec<String> = reader.headers()?.iter().map(|header| header.trim().to_lowercase()).collect();
+    let column = |names: &[&str]| headers.iter().position(|header| names.contains(&header.as_str()));
+    let url_column = column(&["url", "href", "link"]).ok_or_else(|| DtkError::from("No url column"))?;
+    let title_column = column(&["title", "name"]);
+    let time_column = column(&["time_added", "add_date", "added", "created"]);
+    let tags_column = column(&["tags", "tag", "labels"]);
+    let status_column = column(&["status", "archived"]);
+    let favorite_column = column(&["favorite", "starred"]);
+    let mut bookmarks = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let field = |column: Option<usize>| column.and_then(|column| record.get(column)).unwrap_or_default();
+        bookmarks.push(PocketBookmark {
+            url: field(Some(url_column)).trim().to_string(),
+            title: field(title_column).trim().to_string(),
+            tags: split_tags(field(tags_column)),
+            time_added: unix_time(field(time_column)),
+            status: csv_flag(field(status_column)),
+            favorite: csv_flag(field(favorite_column)),
+            excerpt: None,
+        });
+    }
+    Ok(bookmarks)
+}
+
+fn jsonl_bookmarks(data: &str) -> Result<Vec<PocketBookmark>, DtkError> {
+    let data = data.trim_start_matches('\u{feff}').trim();
+    if data.starts_with('[') {
+        return serde_json::from_str(data).map_err(|err| DtkError::from(err.to_string().as_str()));
+    }
+    let mut bookmarks = vec![];
+    for (number, line) in data.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let bookmark = serde_json::from_str(line)
+            .map_err(|err| DtkError::from(format!("Line {}: {}", number + 1, err).as_str()))?;
+        bookmarks.push(bookmark);
+    }
+    Ok(bookmarks)
+}
+
+/// Read the bookmarks of a file
+pub fn parse_bookmarks(data: &str, format: PocketTransferFormat) -> Result<Vec<PocketBookmark>, DtkError> {
+    match format {
+        PocketTransferFormat::Netscape | PocketTransferFormat::Pocket => {
+            use html5ever::tendril::TendrilSink;
+            let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(data);
+            let mut bookmarks = vec![];
+            html_bookmarks(&dom.document, &mut vec![], &mut 0, &mut bookmarks);
+            Ok(bookmarks)
+        }
+        PocketTransferFormat::Csv => csv_bookmarks(data),
+        PocketTransferFormat::Jsonl => jsonl_bookmarks(data),
+    }
+}
+
+fn netscape_bookmarks(bookmarks: &[PocketBookmark]) -> String {
+    let mut html = String::from(concat!(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n",
+        "<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n",
+        "<TITLE>Bookmarks</TITLE>\n<H1>Bookmarks</H1>\n<DL><p>\n",
+    ));
+    for bookmark in bookmarks {
+        let time = bookmark.time_added.unwrap_or_default();
+        html.push_str(&format!(
+            "    <DT><A HREF=\"{}\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\" TAGS=\"{}\">{}</A>\n",
+            escape_xml(&bookmark.url),
+            time,
+            time,
+            escape_xml(&bookmark.tags.join(",")),
+            escape_xml(&bookmark.title)
+        ));
+        if let Some(excerpt) = bookmark.excerpt.as_deref() {
+            html.push_str(&format!("    <DD>{}\n", escape_xml(excerpt)));
+        }
+    }
+    html.push_str("</DL><p>\n");
+    html
+}
+
+fn pocket_bookmarks(bookmarks: &[PocketBookmark]) -> String {
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n<html>\n<head>\n",
+        "<meta http-equiv=\"Content-Type\" content=\"text/html; charset=UTF-8\" />\n",
+        "<title>Pocket Export</title>\n</head>\n<body>\n",
+    ));
+    for (section, status) in [("Unread", 0), ("Read Archive", 1)] {
+        html.push_str(&format!("<h1>{section}</h1>\n<ul>\n"));
+        for bookmark in bookmarks.iter().filter(|bookmark| bookmark.status == status) {
+            html.push_str(&format!(
+                "<li><a href=\"{}\" time_added=\"{}\" tags=\"{}\">{}</a></li>\n",
+                escape_xml(&bookmark.url),
+                bookmark.time_added.unwrap_or_default(),
+                escape_xml(&bookmark.tags.join(",")),
+                escape_xml(&bookmark.title)
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn csv_export(bookmarks: &[PocketBookmark]) -> Result<String, DtkError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["title", "url", "time_added", "tags", "status", "favorite"])?;
+    for bookmark in bookmarks {
+        writer.write_record([
+            bookmark.title.as_str(),
+            bookmark.url.as_str(),
+            &bookmark.time_added.unwrap_or_default().to_string(),
+            &bookmark.tags.join("|"),
+            if bookmark.status == 1 { "archive" } else { "unread" },
+            &bookmark.favorite.to_string(),
+        ])?;
+    }
+    let data = writer.into_inner().map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    String::from_utf8(data).map_err(|err| DtkError::from(err.to_string().as_str()))
+}
+
+/// Write the bookmarks in the given format
+pub fn render_bookmarks(bookmarks: &[PocketBookmark], format: PocketTransferFormat) -> Result<String, DtkError> {
+    match format {
+        PocketTransferFormat::Netscape => Ok(netscape_bookmarks(bookmarks)),
+        PocketTransferFormat::Pocket => Ok(pocket_bookmarks(bookmarks)),
+        PocketTransferFormat::Csv => csv_export(bookmarks),
+        PocketTransferFormat::Jsonl => Ok(bookmarks
+            .iter()
+            .map(|bookmark| serde_json::to_string(bookmark).unwrap() + "\n")
+            .collect()),
+    }
+}
+
+/// Body of `/pocket/export`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketExportRequest {
+    pub format: Option<String>,
+    /// Only export items within one of these tags
+    pub tags: Vec<String>,
+}
+
+/// Options of an import, sent as the `options` part of `/pocket/import`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PocketImportOptions {
+    /// Guessed from the file when None
+    pub format: Option<String>,
+    /// Tag renames applied to the tag descendants as well, an empty tag drops it
+    pub tag_map: BTreeMap<String, String>,
+    /// Tags added to every imported item
+    pub tags: Vec<String>,
+    /// Save the items here only instead of adding them to Pocket
+    pub local_only: bool,
+}
+
+impl PocketImportOptions {
+    /// Normalized tags of an imported item once mapped
+    pub fn map_tags(&self, tags: &[String]) -> Vec<String> {
+        let tag_map: Vec<(String, String)> = self
+            .tag_map
+            .iter()
+            .map(|(from, to)| (normalize_tag(from), normalize_tag(to)))
+            .filter(|(from, _)| !from.is_empty())
+            .collect();
+        let mut mapped: Vec<String> = vec![];
+        for tag in tags.iter().chain(self.tags.iter()).map(|tag| normalize_tag(tag)) {
+            let new_tag = match tag_map.iter().find(|(from, _)| is_tag_within(&tag, from)) {
+                Some((_, to)) if to.is_empty() => continue,
+                Some((from, to)) => format!("{}{}", to, &tag[from.len()..]),
+                None => tag,
+            };
+            if !new_tag.is_empty() && !mapped.contains(&new_tag) {
+                mapped.push(new_tag);
+            }
+        }
+        mapped
+    }
+}
+
+/// Outcome of an import
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PocketImportSummary {
+    pub parsed: u32,
+    pub imported: u32,
+    /// Already saved or found earlier in the file
+    pub duplicates: u32,
+    /// Not an http url
+    pub invalid: u32,
+    /// Refused by Pocket
+    pub failed: u32,
+}
+
+/// Bookmarks to import with their mapped tags, `existing` holds the canonical urls already saved
+pub fn prepare_import(
+    bookmarks: Vec<PocketBookmark>,
+    options: &PocketImportOptions,
+    existing: &HashSet<String>,
+) -> (Vec<PocketBookmark>, PocketImportSummary) {
+    let mut summary = PocketImportSummary {
+        parsed: bookmarks.len() as u32,
+        ..PocketImportSummary::default()
+    };
+    let mut seen = existing.clone();
+    let mut prepared = vec![];
+    for mut bookmark in bookmarks {
+        let is_http = url::Url::parse(bookmark.url.trim())
+            .map(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some())
+            .unwrap_or(false);
+        if !is_http {
+            summary.invalid += 1;
+            continue;
+        }
+        bookmark.url = bookmark.url.trim().to_string();
+        if !seen.insert(canonicalize_url(&bookmark.url)) {
+            summary.duplicates += 1;
+            continue;
+        }
+        bookmark.tags = options.map_tags(&bookmark.tags);
+        prepared.push(bookmark);
+    }
+    (prepared, summary)
+}
+
+/// Export the user items, newest first
+pub async fn export_pocket_bookmarks(
+    user_id: &str,
+    format: PocketTransferFormat,
+    tags: &[String],
+) -> Result<String, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<DtkPocketData>(&get_pocket_collection_name());
+    let mut filter = doc! { "user_id": user_id };
+    if !tags.is_empty() {
+        filter.insert("tags", doc! { "$in": tag_matchers(tags) });
+    }
+    let options = FindOptions::builder().sort(doc! { "time_added": -1 }).build();
+    let mut items = coll.find(filter, options).await?;
+    let mut bookmarks = vec![];
+    while let Some(item) = items.next().await {
+        bookmarks.push(PocketBookmark::from(&item?));
+    }
+    render_bookmarks(&bookmarks, format)
+}
+
+/// Import a bookmarks file, skipping the urls already saved by the user.
+/// Items are added to Pocket and come back with the next sync, unless `local_only` is set.
+pub async fn import_pocket_bookmarks(
+    user_id: &str,
+    data: &str,
+    options: &PocketImportOptions,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<PocketImportSummary, DtkError> {
+    let format = match options.format.as_deref() {
+        Some(format) => format.parse()?,
+        None => PocketTransferFormat::detect(data),
+    };
+    let bookmarks = parse_bookmarks(data, format)?;
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let db = client.database(&get_pocket_db_name());
+    let coll = db.collection::<Document>(&get_pocket_collection_name());
+    let canonical_urls: Vec<String> = bookmarks.iter().map(|bookmark| canonicalize_url(&bookmark.url)).collect();
+    let filter = doc! { "user_id": user_id, "canonical_url": { "$in": canonical_urls } };
+    let existing: HashSet<String> = coll
+        .distinct("canonical_url", filter, None)
+        .await?
+        .into_iter()
+        .filter_map(|url| url.as_str().map(String::from))
+        .collect();
+    let (bookmarks, mut summary) = prepare_import(bookmarks, options, &existing);
+    if bookmarks.is_empty() {
+        return Ok(summary);
+    }
+
+    if options.local_only {
+        let items: HashMap<String, PocketData> = bookmarks
+            .iter()
+            .map(|bookmark| bookmark.to_pocket_data(user_id))
+            .map(|item| (item.item_id.clone(), item))
+            .collect();
+        let batch = PocketSyncBatch {
+            items,
+            ..PocketSyncBatch::default()
+        };
+        let saved = update_pocket_data(
+            &client,
+            &get_pocket_db_name(),
+            &get_pocket_collection_name(),
+            user_id,
+            batch,
+            index,
+        )
+        .await?;
+        summary.imported = saved.added + saved.updated;
+        return Ok(summary);
+    }
+
+    let user = db
+        .collection::<Document>(&get_pocket_users_collection_name())
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or_else(|| DtkError::from("Pocket user not found"))?;
+    let access_token = user.get_str("pocket_token").unwrap_or_default().to_string();
+    let mut follow_ups = vec![];
+    for chunk in bookmarks.chunks(POCKET_IMPORT_BATCH) {
+        let push_data = chunk
+            .iter()
+            .map(|bookmark| PocketPushData {
+                url: bookmark.url.clone(),
+                title: bookmark.title.clone(),
+                tags: bookmark.tags.join(","),
+                time: bookmark.time_added,
+            })
+            .collect();
+        let response = push_pocket_data(&access_token, push_data).await.map_err(|err| {
+            DtkError::from(format!("Could not add the bookmarks to Pocket: {}", err).as_str())
+        })?;
+        let results = response.action_results.as_array().cloned().unwrap_or_default();
+        for (index, bookmark) in chunk.iter().enumerate() {
+            // added items come back as the Pocket item, refused ones as false
+            let item_id = results
+                .get(index)
+                .and_then(|result| result.get("item_id"))
+                .and_then(|item_id| item_id.as_str().map(String::from));
+            let item_id = match item_id {
+                Some(item_id) => item_id,
+                None => {
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+            summary.imported += 1;
+            if bookmark.status == 1 {
+                follow_ups.push(PocketAction::Archive { item_id: item_id.clone() });
+            }
+            if bookmark.favorite == 1 {
+                follow_ups.push(PocketAction::Favorite { item_id });
+            }
+        }
+    }
+    if !follow_ups.is_empty() {
+        run_pocket_actions(user_id, follow_ups, index).await?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn bookmark(url: &str, tag_list: &[&str], status: u8) -> PocketBookmark {
+        PocketBookmark {
+            url: url.to_string(),
+            title: "Rusty & co".to_string(),
+            tags: tags(tag_list),
+            time_added: Some(1_678_886_400),
+            status,
+            ..PocketBookmark::default()
+        }
+    }
+
+    #[test]
+    fn netscape_bookmarks_with_folders() {
+        let html = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+    <DT><H3 PERSONAL_TOOLBAR_FOLDER="true">Bookmarks bar</H3>
+    <DL><p>
+        <DT><A HREF="https://rusty.com/a" ADD_DATE="1678886400" TAGS="rust,Web">A</A>
+        <DT><H3>Lang</H3>
+        <DL><p>
+            <DT><A HREF="https://rusty.com/b">B</A>
+        </DL><p>
+    </DL><p>
+</DL><p>"#;
+        let bookmarks = parse_bookmarks(html, PocketTransferFormat::detect(html)).unwrap();
+        assert_eq!(bookmarks.len(), 2);
+        assert_eq!(bookmarks[0].tags, tags(&["rust", "Web"]));
+        assert_eq!(bookmarks[0].time_added, Some(1_678_886_400));
+        assert_eq!(bookmarks[1].tags, tags(&["Lang"]));
+        assert_eq!(bookmarks[1].title, "B");
+    }
+
+    #[test]
+    fn round_trips() {
+        let bookmarks = vec![bookmark("https://rusty.com/a", &["rust"], 0), bookmark("https://rusty.com/b", &[], 1)];
+        for format in [
+            PocketTransferFormat::Netscape,
+            PocketTransferFormat::Pocket,
+            PocketTransferFormat::Csv,
+            PocketTransferFormat::Jsonl,
+        ] {
+            let data = render_bookmarks(&bookmarks, format).unwrap();
+            let mut parsed = parse_bookmarks(&data, PocketTransferFormat::detect(&data)).unwrap();
+            if format == PocketTransferFormat::Netscape {
+                // browsers have no read status
+                parsed[1].status = 1;
+            }
+            assert_eq!(parsed, bookmarks, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn csv_columns() {
+        let data = "URL,Title,Tags,Status\nhttps://rusty.com,Rusty,\"a,b\",archived\n";
+        let bookmarks = parse_bookmarks(data, PocketTransferFormat::Csv).unwrap();
+        assert_eq!(bookmarks[0].tags, tags(&["a", "b"]));
+        assert_eq!(bookmarks[0].status, 1);
+        let favorite = PocketBookmark {
+            favorite: 1,
+            ..bookmark("https://rusty.com/a", &["rust"], 0)
+        };
+        let data = render_bookmarks(std::slice::from_ref(&favorite), PocketTransferFormat::Csv).unwrap();
+        assert_eq!(parse_bookmarks(&data, PocketTransferFormat::Csv).unwrap(), vec![favorite]);
+        assert!(parse_bookmarks("title\nRusty\n", PocketTransferFormat::Csv).is_err());
+        assert!(parse_bookmarks("{\"url\": 1}", PocketTransferFormat::Jsonl).is_err());
+    }
+
+    #[test]
+    fn tag_map_and_dedup() {
+        let options = PocketImportOptions {
+            tag_map: BTreeMap::from([
+                ("Bookmarks bar".to_string(), String::new()),
+                ("lang".to_string(), "dev".to_string()),
+            ]),
+            tags: tags(&["imported"]),
+            ..PocketImportOptions::default()
+        };
+        assert_eq!(
+            options.map_tags(&tags(&["Lang/Rust", "bookmarks bar/news", "web"])),
+            tags(&["dev/rust", "web", "imported"])
+        );
+        let existing = HashSet::from([canonicalize_url("https://rusty.com/a")]);
+        let bookmarks = vec![
+            bookmark("https://www.rusty.com/a/?utm_source=x", &[], 0),
+            bookmark("https://rusty.com/b", &["lang"], 0),
+            bookmark("https://rusty.com/b/", &[], 0),
+            bookmark("javascript:alert(1)", &[], 0),
+        ];
+        let (prepared, summary) = prepare_import(bookmarks, &options, &existing);
+        assert_eq!(prepared.len(), 1);
+        assert_eq!(prepared[0].tags, tags(&["dev", "imported"]));
+        assert_eq!(
+            summary,
+            PocketImportSummary {
+                parsed: 4,
+                imported: 0,
+                duplicates: 2,
+                invalid: 1,
+                failed: 0,
+            }
+        );
+        let item = prepared[0].to_pocket_data("baakey");
+        assert!(item.item_id.starts_with(POCKET_IMPORT_ID_PREFIX));
+        assert_ne!(item.item_id, prepared[0].to_pocket_data("rusty").item_id);
+        assert_eq!(item.time_added, "1678886400");
+    }
+}
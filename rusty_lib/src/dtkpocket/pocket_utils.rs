@@ -3,19 +3,30 @@
 use crate::{
     dtkmongo::dtk_connect::{self, get_dtkmongo_client, get_mongodb_uri},
     dtkpocket::pocket_model::PocketData,
-    dtkutils::{dtk_github::retreive_github_data, utils::remove_duplicate_hashmap},
+    dtkutils::{dtk_error::DtkError, dtk_github::retreive_github_data},
 };
 use mongodb::{
     bson::{doc, Document},
     options::IndexOptions,
     Client, IndexModel,
 };
+use futures::stream::StreamExt;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use super::{
+    pocket::run_pocket_actions,
+    pocket_archive::{queue_pocket_archives, remove_pocket_archives},
     pocket_auth::{push_pocket_data, PocketPushData},
-    pocket_model::{DtkPocketData, PocketSrcType},
+    pocket_autotag::PocketAutoTagger,
+    pocket_classifier::PocketClassifier,
+    pocket_collection::remove_from_pocket_collections,
+    pocket_index::{index_pocket_items, PocketIndex},
+    pocket_model::{
+        DtkPocketData, PocketAction, PocketQuarantineItem, PocketSyncBatch, PocketSyncSummary,
+        POCKET_STATUS_DELETED,
+    },
 };
 
 /// import github stars to pocket
@@ -46,42 +57,76 @@ pub async fn import_github_stars() {
             let url = repo.html_url.unwrap_or_else(|| repo.url.unwrap());
             let title = repo.name.unwrap_or_else(|| repo.full_name.unwrap());
             let tags = repo.language.unwrap_or_else(|| "".to_string());
-            PocketPushData { url, title, tags }
+            PocketPushData {
+                url,
+                title,
+                tags,
+                time: None,
+            }
         })
         .collect();
-    if push_data.len() > 0 {
+    if !push_data.is_empty() {
         log::info!("Pushing {} data to pocket", push_data.len());
-        let _res = push_pocket_data(&root_user_token, push_data).await;
+        if let Err(err) = push_pocket_data(&root_user_token, push_data).await {
+            log::error!("Could not push data to pocket => {}", err);
+        }
     }
 }
 
-/// Get pocket Since
-pub async fn get_pocket_since() -> Option<i64> {
-    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
-    let db_name = get_pocket_db_name();
-    let coll_name = get_pocket_collection_name();
-    if pocket_collection_exist(&client, &db_name, &coll_name).await {
-        Some(chrono::Utc::now().timestamp() - 12 * 60 * 60)
-    } else {
-        None
+/// Get the user sync cursor, None until a first sync succeeded
+pub fn get_pocket_since(pocket_user: &Document) -> Option<i64> {
+    match pocket_user.get("sync_since") {
+        Some(mongodb::bson::Bson::Int64(since)) => Some(*since),
+        Some(mongodb::bson::Bson::Int32(since)) => Some(*since as i64),
+        _ => None,
     }
 }
 
-/// Get pocket source type
-pub fn get_pocket_src_type(pocket_item: PocketData) -> String {
-    let url = get_valid_url(pocket_item);
-    match url.as_str() {
-        x if x.contains("instagram.com") => PocketSrcType::INSTAGRAM.to_string().into(),
-        x if x.contains("twitter.com") => PocketSrcType::TWITTER.to_string().into(),
-        x if x.contains("youtube.com") || x.contains("youtu.be") => PocketSrcType::YOUTUBE.to_string().into(),
-        x if x.contains("github.com") || x.contains("github1s.com") => PocketSrcType::GITHUB.to_string().into(),
-        _ => PocketSrcType::ARTICLE.to_string().into(),
+/// Parse the `since` returned by Pocket, sent either as a number or a string
+pub fn parse_pocket_since(since: &Value) -> Option<i64> {
+    match since {
+        Value::Number(since) => since.as_i64(),
+        Value::String(since) => since.parse::<i64>().ok(),
+        _ => None,
+    }
+}
+
+/// Parse the retrieved items, Pocket sends an empty array instead of an empty object
+pub fn parse_pocket_list(list: Value) -> Result<PocketSyncBatch, DtkError> {
+    let list = match list {
+        Value::Array(items) if items.is_empty() => return Ok(PocketSyncBatch::default()),
+        list => serde_json::from_value::<HashMap<String, Value>>(list)
+            .map_err(|err| DtkError::from(err.to_string().as_str()))?,
+    };
+    let mut batch = PocketSyncBatch::default();
+    for (item_id, item) in list {
+        if item["status"] == POCKET_STATUS_DELETED {
+            batch.deleted_ids.push(item_id);
+            continue;
+        }
+        // one odd item must not fail the whole sync
+        match serde_json::from_value::<PocketData>(item.clone()) {
+            Ok(pocket_item) if !pocket_item.item_id.is_empty() => {
+                batch.items.insert(item_id, pocket_item);
+            }
+            Ok(_) => batch.quarantined.push(PocketQuarantineItem {
+                item_id,
+                error: "missing item_id".to_string(),
+                raw: item,
+            }),
+            Err(err) => batch.quarantined.push(PocketQuarantineItem {
+                item_id,
+                error: err.to_string(),
+                raw: item,
+            }),
+        }
     }
+    Ok(batch)
 }
 
 /// Check if pocket collection exist
 pub async fn pocket_collection_exist(client: &mongodb::Client, db_name: &str, coll_name: &str) -> bool {
-    let all_collection = dtk_connect::get_collection_names(&client, &db_name).await;
+    let all_collection = dtk_connect::get_collection_names(client, db_name).await;
     all_collection.contains(&coll_name.to_string())
 }
 
@@ -100,6 +145,205 @@ pub fn get_pocket_collection_name() -> String {
     std::env::var("RUSTY_POCKET_COLL").unwrap_or_else(|_| "pocket_data".into())
 }
 
+/// Get pocket users collection name
+pub fn get_pocket_users_collection_name() -> String {
+    "pocket_users".to_string()
+}
+
+/// Get the collection keeping pocket items that could not be parsed
+pub fn get_pocket_quarantine_collection_name() -> String {
+    std::env::var("RUSTY_POCKET_QUARANTINE_COLL").unwrap_or_else(|_| "pocket_quarantine".into())
+}
+
+/// Get the collection of public profiles
+pub fn get_pocket_profiles_collection_name() -> String {
+    std::env::var("RUSTY_POCKET_PROFILES_COLL").unwrap_or_else(|_| "pocket_profiles".into())
+}
+
+/// Get the collection of curated item collections
+pub fn get_pocket_collections_collection_name() -> String {
+    std::env::var("RUSTY_POCKET_COLLECTIONS_COLL").unwrap_or_else(|_| "pocket_collections".into())
+}
+
+/// Get the profile served at `/pocket/public` without slug
+pub fn get_pocket_public_slug() -> String {
+    std::env::var("RUSTY_POCKET_PUBLIC_SLUG").unwrap_or_else(|_| "baakey".into())
+}
+
+/// Get the email of the user whose items `/pocket/public` listed before profiles existed
+pub fn get_pocket_public_email() -> String {
+    std::env::var("RUSTY_POCKET_PUBLIC_EMAIL").unwrap_or_else(|_| "baakey@rusty.com".into())
+}
+
+/// Get how long in seconds public pocket responses may be cached
+pub fn get_pocket_public_max_age() -> u64 {
+    std::env::var("RUSTY_POCKET_PUBLIC_MAX_AGE")
+        .ok()
+        .and_then(|max_age| max_age.parse().ok())
+        .unwrap_or(300)
+}
+
+/// Get the url this api is reached at, used for links in feeds
+pub fn get_pocket_public_url() -> String {
+    std::env::var("RUSTY_POCKET_PUBLIC_URL").unwrap_or_else(|_| "http://localhost:1342".into())
+}
+
+/// Get the number of items in a feed
+pub fn get_pocket_feed_size() -> i64 {
+    std::env::var("RUSTY_POCKET_FEED_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(50)
+}
+
+/// Get how long in seconds pocket stats are cached
+pub fn get_pocket_stats_ttl() -> u64 {
+    std::env::var("RUSTY_POCKET_STATS_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(300)
+}
+
+/// Get how long in seconds a checked link stays fresh
+pub fn get_pocket_link_check_interval() -> u64 {
+    std::env::var("RUSTY_POCKET_LINK_CHECK_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(7 * 24 * 3600)
+}
+
+/// Get the max number of links checked per run
+pub fn get_pocket_link_check_batch() -> i64 {
+    std::env::var("RUSTY_POCKET_LINK_CHECK_BATCH")
+        .ok()
+        .and_then(|batch| batch.parse().ok())
+        .unwrap_or(100)
+}
+
+/// Get the number of hosts checked at the same time
+pub fn get_pocket_link_check_concurrency() -> usize {
+    std::env::var("RUSTY_POCKET_LINK_CHECK_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse().ok())
+        .unwrap_or(8)
+}
+
+/// Get the delay in seconds between two checks on the same host
+pub fn get_pocket_link_check_domain_delay() -> u64 {
+    std::env::var("RUSTY_POCKET_LINK_CHECK_DOMAIN_DELAY")
+        .ok()
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(2)
+}
+
+/// Get the failed checks in a row after which a link is broken
+pub fn get_pocket_link_max_failures() -> u32 {
+    std::env::var("RUSTY_POCKET_LINK_MAX_FAILURES")
+        .ok()
+        .and_then(|failures| failures.parse().ok())
+        .unwrap_or(3)
+}
+
+/// Tag broken items `dead-link`, in Pocket too
+pub fn is_pocket_tag_dead_links() -> bool {
+    std::env::var("RUSTY_POCKET_TAG_DEAD_LINKS")
+        .unwrap_or_else(|_| "false".into())
+        .parse()
+        .unwrap_or(false)
+}
+
+/// Get the max size in bytes of an uploaded bookmarks file
+pub fn get_pocket_import_max_size() -> usize {
+    std::env::var("RUSTY_POCKET_IMPORT_MAX_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(10 * 1024 * 1024)
+}
+
+/// Get the directory of the local search index, empty to disable it
+pub fn get_pocket_index_dir() -> String {
+    std::env::var("RUSTY_POCKET_INDEX_DIR").unwrap_or_else(|_| "runtime/pocket_index".into())
+}
+
+/// Get the json file of extra source type rules, see `PocketClassifierConfig`
+pub fn get_pocket_classifier_file() -> String {
+    std::env::var("RUSTY_POCKET_CLASSIFIER_FILE").unwrap_or_else(|_| "runtime/pocket_classifier.json".into())
+}
+
+/// Get the max number of local search index hits used to filter items
+pub fn get_pocket_index_max_hits() -> usize {
+    std::env::var("RUSTY_POCKET_INDEX_MAX_HITS")
+        .ok()
+        .and_then(|hits| hits.parse().ok())
+        .unwrap_or(1000)
+}
+
+/// Get the timeout in seconds when fetching a page for the local search index
+pub fn get_pocket_index_fetch_timeout() -> u64 {
+    std::env::var("RUSTY_POCKET_INDEX_FETCH_TIMEOUT")
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .unwrap_or(20)
+}
+
+/// Get the collection keeping the archive state of pocket items
+pub fn get_pocket_archive_collection_name() -> String {
+    std::env::var("RUSTY_POCKET_ARCHIVE_COLL").unwrap_or_else(|_| "pocket_archive".into())
+}
+
+/// Get the directory of the archived snapshots
+pub fn get_pocket_archive_dir() -> String {
+    std::env::var("RUSTY_POCKET_ARCHIVE_DIR").unwrap_or_else(|_| "runtime/pocket_archive".into())
+}
+
+/// Keep the raw html next to the markdown snapshot
+pub fn is_pocket_archive_html() -> bool {
+    std::env::var("RUSTY_POCKET_ARCHIVE_HTML")
+        .unwrap_or_else(|_| "false".into())
+        .parse()
+        .unwrap_or(false)
+}
+
+/// Keep the main image next to the markdown snapshot
+pub fn is_pocket_archive_images() -> bool {
+    std::env::var("RUSTY_POCKET_ARCHIVE_IMAGES")
+        .unwrap_or_else(|_| "false".into())
+        .parse()
+        .unwrap_or(false)
+}
+
+/// Get the max number of items archived per run
+pub fn get_pocket_archive_batch() -> i64 {
+    std::env::var("RUSTY_POCKET_ARCHIVE_BATCH")
+        .ok()
+        .and_then(|batch| batch.parse().ok())
+        .unwrap_or(50)
+}
+
+/// Get the delay in seconds between two requests to the same domain
+pub fn get_pocket_archive_domain_delay() -> u64 {
+    std::env::var("RUSTY_POCKET_ARCHIVE_DOMAIN_DELAY")
+        .ok()
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(5)
+}
+
+/// Get the wait in seconds before the first retry, doubled after each failure
+pub fn get_pocket_archive_retry_secs() -> u64 {
+    std::env::var("RUSTY_POCKET_ARCHIVE_RETRY_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(300)
+}
+
+/// Get the number of attempts before an archive is given up
+pub fn get_pocket_archive_max_attempts() -> u32 {
+    std::env::var("RUSTY_POCKET_ARCHIVE_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|attempts| attempts.parse().ok())
+        .unwrap_or(5)
+}
+
 /// Get valid pocket url
 pub fn get_valid_url(pocket_item: PocketData) -> String {
     if pocket_item.given_url.is_empty() {
@@ -118,74 +362,63 @@ pub fn get_valid_title(pocket_item: PocketData) -> String {
     }
 }
 
-/// Creates an index on the "item_id" field to force the values to be unique and "url" field to be text
+/// Unique index of the items, Pocket item ids are global so several users can save the same one
+fn pocket_item_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { "user_id": 1, "item_id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build()
+}
+
+/// Filter of one item of a user, matching `pocket_item_index`
+pub fn pocket_item_filter(user_id: &str, item_id: &str) -> Document {
+    doc! { "user_id": user_id, "item_id": item_id }
+}
+
+/// Creates a unique index on the "user_id" and "item_id" fields, "url" field to be text
+/// and on "canonical_url" to find duplicates
 pub async fn create_pocket_coll_indexes(client: &Client, coll_name: &str) {
     println!("# => Creating pocket indexes for {}", coll_name);
-    let model_item = IndexModel::builder()
-        .keys(doc! { "item_id": 1 })
-        .options(IndexOptions::builder().unique(true).build())
-        .build();
     let model_text = IndexModel::builder()
         .keys(doc! { "url": "text", "title": "text", "excerpt": "text" })
         .options(IndexOptions::builder().unique(false).build())
         .build();
+    let model_canonical_url = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "canonical_url": 1 })
+        .build();
     client
         .database(get_pocket_db_name().as_str())
         .collection::<PocketData>(coll_name)
-        .create_indexes(vec![model_item, model_text], None)
+        .create_indexes(vec![pocket_item_index(), model_text, model_canonical_url], None)
         .await
         .expect("creating indexes should succeed");
 }
 
-/// Get default article tag from url
-pub fn get_default_article_tag(url: &str) -> String {
-    match url {
-        x if x.contains("instagram.com") => "instagram".to_string(),
-        x if x.contains("twitter.com") => "twitter".to_string(),
-        x if x.contains("youtube.com") || x.contains("youtu.be") => "youtube".to_string(),
-        x if x.contains("github.com") || x.contains("github1s.com") => "github".to_string(),
-        _ => "article".to_string(),
-    }
-}
-
-/// Inject tag from pocket url
-pub fn inject_tags_from_url(pocket_item: PocketData) -> Vec<String> {
-    let url = if pocket_item.given_url.is_empty() {
-        pocket_item.resolved_url
-    } else {
-        pocket_item.given_url
+/// Replace the unique `item_id` index, it refused items already saved by another user.
+/// Run once as a migration, returns 1 when the old index was dropped.
+pub async fn migrate_pocket_item_index() -> Result<u64, DtkError> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_collection_name());
+    let names = match coll.list_index_names().await {
+        Ok(names) => names,
+        // no collection yet, it gets the new index when created
+        Err(_) => return Ok(0),
     };
-    let mut tags = pocket_tags_to_vec(pocket_item.tags.clone()).unwrap_or_default();
-    let platform_tags = vec![
-        ("youtube.com", "youtube"),
-        ("youtu.be", "youtube"),
-        ("github.com", "github"),
-        ("github1s.com", "github"),
-        ("twitter.com", "twitter"),
-        ("instagram.com", "instagram"),
-    ];
-    for (platform, tag) in platform_tags {
-        if url.contains(platform) && !tags.contains(&tag.to_string()) {
-            tags.push(tag.to_string());
-        }
-    }
-    if tags.contains(&"github".to_string()) {
-        tags.push("tech".to_string());
-    }
-    if tags.is_empty() {
-        tags.push("article".to_string());
+    coll.create_index(pocket_item_index(), None).await?;
+    if !names.iter().any(|name| name == "item_id_1") {
+        return Ok(0);
     }
-    tags
+    coll.drop_index("item_id_1", None).await?;
+    Ok(1)
 }
 
 /// Format pocket tags to vec
 pub fn pocket_tags_to_vec(option: Option<Value>) -> Option<Vec<String>> {
-    if !option.is_some() {
-        return None;
-    }
     let mut result = Vec::new();
-    for (_key, value) in option.unwrap().as_object().unwrap() {
-        let tag = value["tag"].as_str().unwrap();
+    for (key, value) in option?.as_object()? {
+        let tag = value["tag"].as_str().unwrap_or(key);
         result.push(tag.to_lowercase().to_owned());
     }
     Some(result)
@@ -194,41 +427,242 @@ pub fn pocket_tags_to_vec(option: Option<Value>) -> Option<Vec<String>> {
 /// Format PocketData into DtkPocketData
 pub fn format_pocket_data(pocket_data: HashMap<String, PocketData>, user_id: &str) -> HashMap<String, DtkPocketData> {
     let mut dtk_pocket_data: HashMap<String, DtkPocketData> = HashMap::new();
+    let classifier = PocketClassifier::from_env();
     for (item_id, pocket_item) in pocket_data {
-        let dtk_pocket_item = DtkPocketData::from_other_type(pocket_item, user_id);
+        let dtk_pocket_item = DtkPocketData::from_other_type(pocket_item, user_id, &classifier);
         dtk_pocket_data.insert(item_id, dtk_pocket_item);
     }
-    remove_duplicate_hashmap(&mut dtk_pocket_data, "url");
+    // duplicates are kept, they are grouped by canonical url for the user to merge
     dtk_pocket_data
 }
 
-/// Save pocket data to mongodb
+/// Mirror a sync batch in mongodb: upsert changed items, remove deleted ones.
+/// The search index, when given, is updated in the background once the batch is saved.
 pub async fn update_pocket_data(
     client: &Client,
     db_name: &str,
     coll_name: &str,
     user_id: &str,
-    pocket_data: HashMap<String, PocketData>,
-) {
+    pocket_data: PocketSyncBatch,
+    index: Option<Arc<PocketIndex>>,
+) -> Result<PocketSyncSummary, DtkError> {
     if !pocket_collection_exist(client, db_name, coll_name).await {
         create_pocket_coll_indexes(client, coll_name).await;
     }
     let coll = client.database(db_name).collection::<DtkPocketData>(coll_name);
+    let mut summary = PocketSyncSummary::default();
 
-    let dtk_pocket_data = format_pocket_data(pocket_data, user_id);
+    let dtk_pocket_data = format_pocket_data(pocket_data.items, user_id);
+    let mut saved_items = Vec::new();
+    let tagger = PocketAutoTagger::for_user(client, user_id).await.unwrap_or_else(|err| {
+        log::error!("# => Invalid tag rules for {} => {}", user_id, err);
+        PocketAutoTagger::default()
+    });
+    let mut tag_actions = Vec::new();
 
-    for (item_id, pocket_item) in dtk_pocket_data {
+    for (item_id, mut pocket_item) in dtk_pocket_data {
+        let added = tagger.apply(&mut pocket_item);
+        if tagger.push_to_pocket && !added.is_empty() {
+            tag_actions.push(PocketAction::TagsAdd {
+                item_id: item_id.clone(),
+                tags: added,
+            });
+        }
         log::info!(
             "# => Saving pocket data for item_id: {} => [{}] => ({})",
             item_id,
             pocket_item.title,
             pocket_item.url
         );
-        let filter = doc! { "item_id": item_id, "url": &pocket_item.url };
-        let update = doc! { "$set": mongodb::bson::to_document(&pocket_item).unwrap() };
+        let filter = pocket_item_filter(user_id, &item_id);
+        let options = mongodb::options::FindOneOptions::builder()
+            .projection(doc! { "status": 1, "tags": 1, "derived_tags": 1 })
+            .build();
+        let previous = coll
+            .clone_with_type::<Document>()
+            .find_one(filter.clone(), options)
+            .await?;
+        if let Some(previous) = &previous {
+            let string_array = |key: &str| -> Vec<String> {
+                previous
+                    .get_array(key)
+                    .map(|tags| tags.iter().filter_map(|tag| tag.as_str().map(String::from)).collect())
+                    .unwrap_or_default()
+            };
+            pocket_item.keep_removed_derived_tags(&string_array("tags"), &string_array("derived_tags"));
+        }
+        let update = doc! { "$set": pocket_item.to_document() };
         let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
-        coll.update_one(filter, update, Some(options))
-            .await
-            .expect("update should succeed");
+        coll.update_one(filter, update, options).await?;
+        let previous_status = previous.and_then(|doc| doc.get_i32("status").ok()).map(|status| status as u8);
+        summary.count_upsert(previous_status, pocket_item.status);
+        saved_items.push(pocket_item);
+    }
+
+    let quarantine_coll = client
+        .database(db_name)
+        .collection::<Document>(&get_pocket_quarantine_collection_name());
+    // items quarantined by an earlier sync are released once they parse, or once deleted
+    let released_ids: Vec<&String> = saved_items
+        .iter()
+        .map(|item| &item.item_id)
+        .chain(pocket_data.deleted_ids.iter())
+        .collect();
+    if !released_ids.is_empty() {
+        let filter = doc! { "user_id": user_id, "item_id": { "$in": released_ids } };
+        quarantine_coll.delete_many(filter, None).await?;
+    }
+    if !pocket_data.quarantined.is_empty() {
+        for item in pocket_data.quarantined {
+            log::warn!("# => Quarantined pocket item {} => {}", item.item_id, item.error);
+            let filter = doc! { "user_id": user_id, "item_id": &item.item_id };
+            let update = doc! { "$set": {
+                "error": &item.error,
+                "raw": mongodb::bson::to_bson(&item.raw).unwrap(),
+                "date": chrono::Utc::now().to_rfc3339(),
+            } };
+            let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
+            quarantine_coll.update_one(filter, update, options).await?;
+            summary.quarantined += 1;
+        }
+    }
+
+    if !pocket_data.deleted_ids.is_empty() {
+        log::info!("# => Removing deleted pocket items: {:?}", pocket_data.deleted_ids);
+        let filter = doc! { "user_id": user_id, "item_id": { "$in": &pocket_data.deleted_ids } };
+        summary.deleted = coll.delete_many(filter, None).await?.deleted_count as u32;
+    }
+
+    let canonical_urls: Vec<&String> = saved_items.iter().map(|item| &item.canonical_url).collect();
+    let pipeline = vec![
+        doc! { "$match": { "user_id": user_id, "canonical_url": { "$in": canonical_urls } } },
+        doc! { "$group": { "_id": "$canonical_url", "count": { "$sum": 1 } } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+    ];
+    let mut duplicates = coll.aggregate(pipeline, None).await?;
+    while let Some(duplicate) = duplicates.next().await {
+        summary.duplicates += duplicate?.get_i32("count").unwrap_or_default() as u32;
+    }
+
+    if !tag_actions.is_empty() {
+        // the saved items are indexed below, rule tags included
+        if let Err(err) = run_pocket_actions(user_id, tag_actions, None).await {
+            log::error!("# => Could not push rule tags for {} => {}", user_id, err);
+        }
+    }
+    if let Err(err) = queue_pocket_archives(client, user_id, &saved_items).await {
+        log::error!("# => Could not queue pocket archives for {} => {}", user_id, err);
+    }
+    if let Err(err) = remove_pocket_archives(client, user_id, &pocket_data.deleted_ids).await {
+        log::error!("# => Could not remove pocket archives for {} => {}", user_id, err);
+    }
+    if let Err(err) = remove_from_pocket_collections(client, user_id, &pocket_data.deleted_ids).await {
+        log::error!("# => Could not remove items from pocket collections for {} => {}", user_id, err);
+    }
+    if let Some(index) = index {
+        tokio::spawn(index_pocket_items(
+            index,
+            user_id.to_string(),
+            saved_items,
+            pocket_data.deleted_ids,
+        ));
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_item_for_two_users() {
+        let index_keys: Vec<String> = pocket_item_index().keys.keys().cloned().collect();
+        let filters = [pocket_item_filter("baakey", "42"), pocket_item_filter("rusty", "42")];
+        for filter in filters.iter() {
+            assert_eq!(filter.keys().cloned().collect::<Vec<String>>(), index_keys);
+        }
+        // both upserts hit their own document, the unique index tells them apart
+        assert_ne!(filters[0], filters[1]);
+        assert_eq!(pocket_item_index().options.and_then(|options| options.unique), Some(true));
+    }
+
+    #[test]
+    fn sync_cursor_from_user_and_response() {
+        assert_eq!(get_pocket_since(&doc! { "user_id": "1" }), None);
+        assert_eq!(get_pocket_since(&doc! { "sync_since": 1_700_000_000_i64 }), Some(1_700_000_000));
+        assert_eq!(get_pocket_since(&doc! { "sync_since": 42 }), Some(42));
+        assert_eq!(parse_pocket_since(&serde_json::json!(1_700_000_000)), Some(1_700_000_000));
+        assert_eq!(parse_pocket_since(&serde_json::json!("1700000000")), Some(1_700_000_000));
+        assert_eq!(parse_pocket_since(&Value::Null), None);
+    }
+
+    #[test]
+    fn empty_pocket_list() {
+        assert_eq!(parse_pocket_list(serde_json::json!([])).unwrap(), PocketSyncBatch::default());
+        assert_eq!(parse_pocket_list(serde_json::json!({})).unwrap(), PocketSyncBatch::default());
+        assert!(parse_pocket_list(serde_json::json!("nope")).is_err());
+    }
+
+    #[test]
+    fn deleted_pocket_items() {
+        let batch = parse_pocket_list(serde_json::json!({
+            "229279689": { "item_id": "229279689", "status": "2" }
+        }))
+        .unwrap();
+        assert!(batch.items.is_empty());
+        assert_eq!(batch.deleted_ids, vec!["229279689".to_string()]);
+    }
+
+    #[test]
+    fn lenient_pocket_items() {
+        let batch = parse_pocket_list(serde_json::json!({
+            "1": {
+                "item_id": "1",
+                "given_url": "https://github.com/baakeydow/rusty-playground",
+                "favorite": "1",
+                "status": "0",
+                "time_added": "1678886400",
+                "sort_id": 70000,
+                "word_count": "",
+                "listen_duration_estimate": "12",
+                "tags": { "Rust": { "item_id": "1", "tag": "Rust" } }
+            },
+            "2": { "resolved_title": "no id" },
+            "3": "not an item"
+        }))
+        .unwrap();
+        let pocket_item = batch.items["1"].clone();
+        assert_eq!(pocket_item.sort_id, 70000);
+        assert_eq!(pocket_item.listen_duration_estimate, 12);
+        assert_eq!(pocket_item.resolved_title, "");
+        let dtk_item = DtkPocketData::from_other_type(pocket_item, "baakey", &PocketClassifier::default());
+        assert_eq!(dtk_item.favorite, 1);
+        assert_eq!(dtk_item.is_article, 0);
+        assert_eq!(dtk_item.time_added.to_rfc3339(), "2023-03-15T13:20:00+00:00");
+        assert_eq!(dtk_item.time_read, None);
+        assert_eq!(dtk_item.tags, vec!["rust".to_string(), "github".to_string(), "tech".to_string()]);
+        let mut quarantined: Vec<String> = batch.quarantined.iter().map(|item| item.item_id.clone()).collect();
+        quarantined.sort();
+        assert_eq!(quarantined, vec!["2".to_string(), "3".to_string()]);
+    }
+
+    #[test]
+    fn sync_summary_counts() {
+        let mut summary = PocketSyncSummary::default();
+        summary.count_upsert(None, 0);
+        summary.count_upsert(Some(0), 1);
+        summary.count_upsert(Some(1), 1);
+        summary.count_upsert(Some(1), 0);
+        assert_eq!(
+            summary,
+            PocketSyncSummary {
+                added: 1,
+                updated: 2,
+                archived: 1,
+                deleted: 0,
+                quarantined: 0,
+                duplicates: 0,
+            }
+        );
     }
 }
@@ -23,7 +23,7 @@ where
     T: ?Sized + Deref,
 {
     fn eq(&self, other: &Self) -> bool {
-        self.addr() == other.addr()
+        std::ptr::addr_eq(self.addr(), other.addr())
     }
 }
 
@@ -25,6 +25,30 @@ impl std::convert::From<std::io::Error> for DtkError {
     }
 }
 
+impl std::convert::From<mongodb::error::Error> for DtkError {
+    fn from(error: mongodb::error::Error) -> Self {
+        DtkError(error.to_string())
+    }
+}
+
+impl std::convert::From<reqwest::Error> for DtkError {
+    fn from(error: reqwest::Error) -> Self {
+        DtkError(error.to_string())
+    }
+}
+
+impl std::convert::From<tantivy::TantivyError> for DtkError {
+    fn from(error: tantivy::TantivyError) -> Self {
+        DtkError(error.to_string())
+    }
+}
+
+impl std::convert::From<csv::Error> for DtkError {
+    fn from(error: csv::Error) -> Self {
+        DtkError(error.to_string())
+    }
+}
+
 impl ResponseError for DtkError {
     fn error_response(&self) -> HttpResponse {
         HttpResponse::Forbidden().json("Invalid token")
@@ -13,6 +13,16 @@ use mongodb::bson::Document;
 use serde::Deserialize;
 use serde::Serialize;
 
+/// Get mongodb db name for github data
+pub fn get_github_db_name() -> String {
+    std::env::var("RUSTY_GITHUB_DB").unwrap_or_else(|_| "rusty-github".into())
+}
+
+/// Get mongodb collection name for the starred repositories
+pub fn get_github_collection_name() -> String {
+    std::env::var("RUSTY_GITHUB_COLL").unwrap_or_else(|_| "baakeydow".into())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// Dummy struct for deserializing json
 pub struct GithubData {}
@@ -107,8 +117,8 @@ pub async fn get_starred(owner: &str, mut page: i32, recurse: bool) -> Result<Ve
 
 /// Save all starred repositories for baakeydow
 pub async fn save_all_starred() {
-    let github_db_name = "rusty-github".to_string();
-    let github_coll_name = "baakeydow".to_string();
+    let github_db_name = get_github_db_name();
+    let github_coll_name = get_github_collection_name();
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let github_coll = client
         .database(&github_db_name)
@@ -136,8 +146,8 @@ pub async fn save_all_starred() {
 
 /// Get all starred repositories for baakeydow
 pub async fn retreive_github_data() -> Vec<StarredRepo> {
-    let github_db_name = "rusty-github".to_string();
-    let github_coll_name = "baakeydow".to_string();
+    let github_db_name = get_github_db_name();
+    let github_coll_name = get_github_collection_name();
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let github_coll = client
         .database(&github_db_name)
@@ -152,4 +162,35 @@ pub async fn retreive_github_data() -> Vec<StarredRepo> {
                 .collect::<Vec<StarredRepo>>()
                 .await;
     github_data
-}
\ No newline at end of file
+}
+
+/// Search saved starred repositories by name, description, topic or language
+pub async fn search_starred(query: Option<&str>, limit: i64) -> Vec<StarredRepo> {
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let github_coll = client
+        .database(&get_github_db_name())
+        .collection::<Document>(&get_github_collection_name());
+    let filter = query.filter(|query| !query.is_empty()).map(|query| {
+        let pattern = mongodb::bson::Regex {
+            pattern: regex::escape(query),
+            options: "i".to_string(),
+        };
+        mongodb::bson::doc! {
+            "$or": [
+                { "full_name": &pattern },
+                { "description": &pattern },
+                { "topics": &pattern },
+                { "language": &pattern },
+            ]
+        }
+    });
+    let options = mongodb::options::FindOptions::builder()
+        .sort(mongodb::bson::doc! { "stargazers_count": -1 })
+        .limit(limit)
+        .build();
+    let cursor = github_coll.find(filter, options).await.unwrap();
+    cursor
+        .map(|res| mongodb::bson::from_bson(mongodb::bson::Bson::Document(res.unwrap())).unwrap())
+        .collect::<Vec<StarredRepo>>()
+        .await
+}
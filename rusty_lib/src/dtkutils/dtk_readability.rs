@@ -0,0 +1,422 @@
+//! Readability style extraction of the main content of html pages
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use encoding_rs::{Encoding, UTF_8};
+use html5ever::serialize::{serialize, SerializeOpts};
+use html5ever::tendril::{StrTendril, TendrilSink};
+use markup5ever_rcdom::{Handle, NodeData, RcDom, SerializableHandle};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Words read per minute, used for the reading time
+const WORDS_PER_MINUTE: usize = 200;
+/// Bytes looked at for a `<meta charset>`
+const CHARSET_SNIFF_BYTES: usize = 4096;
+/// Paragraphs shorter than this do not count toward their parents score
+const MIN_PARAGRAPH_CHARS: usize = 25;
+
+/// Tags never part of the content
+const JUNK_TAGS: [&str; 14] = [
+    "script", "style", "noscript", "nav", "footer", "aside", "form", "iframe", "svg", "button", "select", "input",
+    "textarea", "template",
+];
+
+/// How much of the page ends up in the markdown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlToMdMode {
+    /// Whole page, minus styles, scripts and images
+    #[default]
+    Page,
+    /// Main content only, navigation, footers and banners are left out
+    Article,
+}
+
+/// Markdown of a page with what could be found about it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DtkMarkdownPage {
+    /// Title of the page
+    pub title: Option<String>,
+    /// Author(s) of the article
+    pub byline: Option<String>,
+    /// Publication date of the article
+    pub published: Option<DateTime<Utc>>,
+    /// Words in the markdown
+    pub word_count: usize,
+    /// Reading time in minutes, rounded up
+    pub reading_minutes: usize,
+    /// Markdown content
+    pub markdown: String,
+}
+
+/// Decode a page with the `Content-Type` charset, else the `<meta charset>`, else utf-8.
+/// Invalid bytes are replaced rather than failing.
+pub fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    let charset_re = Regex::new(r#"(?i)charset\s*=\s*["']?([a-z0-9_:.-]+)"#).unwrap();
+    let from_header = content_type
+        .and_then(|content_type| charset_re.captures(content_type))
+        .and_then(|captures| Encoding::for_label(captures[1].as_bytes()));
+    let from_meta = || {
+        let head = String::from_utf8_lossy(&bytes[..bytes.len().min(CHARSET_SNIFF_BYTES)]).to_string();
+        let meta_re = Regex::new(r#"(?i)<meta[^>]*charset\s*=\s*["']?([a-z0-9_:.-]+)"#).unwrap();
+        meta_re
+            .captures(&head)
+            .and_then(|captures| Encoding::for_label(captures[1].as_bytes()))
+    };
+    let encoding = from_header.or_else(from_meta).unwrap_or(UTF_8);
+    // a byte order mark wins over the declared encoding
+    let (html, _, _) = encoding.decode(bytes);
+    html.into_owned()
+}
+
+pub(crate) fn tag_name(node: &Handle) -> Option<String> {
+    match &node.data {
+        NodeData::Element { name, .. } => Some(name.local.to_lowercase()),
+        _ => None,
+    }
+}
+
+pub(crate) fn attr(node: &Handle, attr_name: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|attr| attr.name.local.as_ref().eq_ignore_ascii_case(attr_name))
+            .map(|attr| attr.value.to_string()),
+        _ => None,
+    }
+}
+
+pub(crate) fn text_content(node: &Handle) -> String {
+    let mut text = String::new();
+    let mut stack = vec![node.clone()];
+    while let Some(node) = stack.pop() {
+        if let NodeData::Text { contents } = &node.data {
+            text.push_str(&contents.borrow());
+        }
+        stack.extend(node.children.borrow().iter().rev().cloned());
+    }
+    text
+}
+
+pub(crate) fn clean_text(text: &str) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match text.is_empty() {
+        true => None,
+        false => Some(text),
+    }
+}
+
+fn descendants(node: &Handle) -> Vec<Handle> {
+    let mut nodes = Vec::new();
+    let mut stack = vec![node.clone()];
+    while let Some(node) = stack.pop() {
+        stack.extend(node.children.borrow().iter().rev().cloned());
+        nodes.push(node);
+    }
+    nodes
+}
+
+fn find_tag(root: &Handle, tag: &str) -> Option<Handle> {
+    descendants(root)
+        .into_iter()
+        .find(|node| tag_name(node).as_deref() == Some(tag))
+}
+
+fn meta_content(root: &Handle, keys: &[&str]) -> Option<String> {
+    let metas: Vec<Handle> = descendants(root)
+        .into_iter()
+        .filter(|node| tag_name(node).as_deref() == Some("meta"))
+        .collect();
+    keys.iter().find_map(|key| {
+        metas.iter().find_map(|meta| {
+            let name = attr(meta, "property").or_else(|| attr(meta, "name"))?;
+            match name.eq_ignore_ascii_case(key) {
+                true => attr(meta, "content").and_then(|content| clean_text(&content)),
+                false => None,
+            }
+        })
+    })
+}
+
+fn parse_published(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let day = date.get(..10)?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+fn class_and_id(node: &Handle) -> String {
+    format!(
+        "{} {}",
+        attr(node, "class").unwrap_or_default(),
+        attr(node, "id").unwrap_or_default()
+    )
+}
+
+struct Readability {
+    unlikely: Regex,
+    positive: Regex,
+    negative: Regex,
+}
+
+impl Readability {
+    fn new() -> Readability {
+        Readability {
+            unlikely: Regex::new(concat!(
+                r"(?i)banner|breadcrumb|combx|comment|community|consent|cookie|disqus|footer|gdpr|header|legends|",
+                r"menu|modal|newsletter|popup|related|remark|replies|rss|share|shoutbox|sidebar|skyscraper|social|",
+                r"sponsor|subscribe|ad-break|agegate|pagination|pager",
+            ))
+            .unwrap(),
+            positive: Regex::new(r"(?i)article|body|content|entry|hentry|main|page|post|text|blog|story").unwrap(),
+            negative: Regex::new(concat!(
+                r"(?i)hidden|banner|combx|comment|com-|contact|foot|footer|footnote|masthead|media|meta|outbrain|",
+                r"promo|related|scroll|share|shoutbox|sidebar|skyscraper|sponsor|shopping|tags|tool|widget",
+            ))
+            .unwrap(),
+        }
+    }
+
+    fn class_weight(&self, node: &Handle) -> f64 {
+        let names = class_and_id(node);
+        let mut weight = 0.0;
+        if self.negative.is_match(&names) {
+            weight -= 25.0;
+        }
+        if self.positive.is_match(&names) {
+            weight += 25.0;
+        }
+        weight
+    }
+
+    /// Drop junk tags and unlikely candidates, the content is never inside them
+    fn strip_junk(&self, node: &Handle) {
+        node.children.borrow_mut().retain(|child| {
+            let Some(tag) = tag_name(child) else {
+                return !matches!(child.data, NodeData::Comment { .. });
+            };
+            if JUNK_TAGS.contains(&tag.as_str()) {
+                return false;
+            }
+            let names = class_and_id(child);
+            let keep = matches!(tag.as_str(), "body" | "article" | "main")
+                || !self.unlikely.is_match(&names)
+                || self.positive.is_match(&names);
+            let hidden = attr(child, "hidden").is_some() || attr(child, "aria-hidden").as_deref() == Some("true");
+            keep && !hidden
+        });
+        for child in node.children.borrow().iter() {
+            self.strip_junk(child);
+        }
+    }
+
+    fn link_density(node: &Handle) -> f64 {
+        let text_len = text_content(node).trim().len();
+        if text_len == 0 {
+            return 0.0;
+        }
+        let link_len: usize = descendants(node)
+            .iter()
+            .filter(|node| tag_name(node).as_deref() == Some("a"))
+            .map(|link| text_content(link).trim().len())
+            .sum();
+        link_len as f64 / text_len as f64
+    }
+
+    fn tag_score(tag: &str) -> f64 {
+        match tag {
+            "article" | "main" => 10.0,
+            "div" | "section" => 5.0,
+            "pre" | "td" | "blockquote" => 3.0,
+            "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Node holding the main content, scored from the paragraphs it contains
+    fn best_candidate(&self, body: &Handle) -> Option<Handle> {
+        let mut parents: HashMap<*const markup5ever_rcdom::Node, Handle> = HashMap::new();
+        let mut stack = vec![body.clone()];
+        while let Some(node) = stack.pop() {
+            for child in node.children.borrow().iter() {
+                parents.insert(Rc::as_ptr(child), node.clone());
+                stack.push(child.clone());
+            }
+        }
+        let mut scores: HashMap<*const markup5ever_rcdom::Node, (Handle, f64)> = HashMap::new();
+        for paragraph in descendants(body) {
+            if !matches!(tag_name(&paragraph).as_deref(), Some("p" | "pre" | "td" | "blockquote")) {
+                continue;
+            }
+            let text = text_content(&paragraph);
+            let text = text.trim();
+            if text.len() < MIN_PARAGRAPH_CHARS {
+                continue;
+            }
+            let score = 1.0 + text.matches(',').count() as f64 + (text.len() as f64 / 100.0).min(3.0);
+            let mut ancestor = parents.get(&Rc::as_ptr(&paragraph)).cloned();
+            for share in [1.0, 0.5] {
+                let Some(node) = ancestor else { break };
+                let entry = scores.entry(Rc::as_ptr(&node)).or_insert_with(|| {
+                    let tag = tag_name(&node).unwrap_or_default();
+                    (node.clone(), Readability::tag_score(&tag) + self.class_weight(&node))
+                });
+                entry.1 += score * share;
+                ancestor = parents.get(&Rc::as_ptr(&node)).cloned();
+            }
+        }
+        scores
+            .into_values()
+            .map(|(node, score)| {
+                let score = score * (1.0 - Readability::link_density(&node));
+                (node, score)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(node, _)| node)
+    }
+}
+
+/// Make links and image sources absolute
+fn resolve_links(node: &Handle, base_url: &Url) {
+    for node in descendants(node) {
+        if let NodeData::Element { attrs, .. } = &node.data {
+            for attr in attrs.borrow_mut().iter_mut() {
+                if !matches!(&*attr.name.local, "href" | "src") {
+                    continue;
+                }
+                if let Ok(url) = base_url.join(&attr.value) {
+                    attr.value = StrTendril::from(url.as_str());
+                }
+            }
+        }
+    }
+}
+
+fn inner_html(node: &Handle) -> String {
+    let mut bytes = Vec::new();
+    let handle: SerializableHandle = node.clone().into();
+    serialize(&mut bytes, &handle, SerializeOpts::default()).ok();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Convert a page to markdown, `base_url` resolves relative links
+pub fn convert_html_to_md(html: &str, base_url: Option<&Url>, mode: HtmlToMdMode) -> DtkMarkdownPage {
+    let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(html);
+    let root = dom.document;
+
+    let title = meta_content(&root, &["og:title", "twitter:title"])
+        .or_else(|| find_tag(&root, "title").and_then(|title| clean_text(&text_content(&title))))
+        .or_else(|| find_tag(&root, "h1").and_then(|h1| clean_text(&text_content(&h1))));
+    let byline = meta_content(&root, &["author", "article:author", "byl", "dc.creator"]).or_else(|| {
+        descendants(&root)
+            .into_iter()
+            .find(|node| {
+                attr(node, "rel").as_deref() == Some("author")
+                    || attr(node, "itemprop").as_deref() == Some("author")
+                    || attr(node, "class").map(|class| class.contains("byline")).unwrap_or(false)
+            })
+            .and_then(|node| clean_text(&text_content(&node)))
+    });
+    let published = meta_content(
+        &root,
+        &["article:published_time", "datePublished", "date", "pubdate", "dc.date"],
+    )
+    .or_else(|| find_tag(&root, "time").and_then(|time| attr(&time, "datetime")))
+    .and_then(|date| parse_published(&date));
+
+    if let Some(base_url) = base_url {
+        resolve_links(&root, base_url);
+    }
+    let content_html = match mode {
+        HtmlToMdMode::Page => inner_html(&root),
+        HtmlToMdMode::Article => {
+            let readability = Readability::new();
+            let body = find_tag(&root, "body").unwrap_or_else(|| root.clone());
+            readability.strip_junk(&body);
+            let content = readability.best_candidate(&body).unwrap_or(body);
+            inner_html(&content)
+        }
+    };
+    let markdown = super::dtk_reqwest::parse_html_to_md(&content_html);
+    let word_count = markdown.split_whitespace().count();
+    DtkMarkdownPage {
+        title,
+        byline,
+        published,
+        word_count,
+        reading_minutes: word_count.div_ceil(WORDS_PER_MINUTE),
+        markdown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="Rusty readability">
+        <meta name="author" content="Baakey">
+        <meta property="article:published_time" content="2023-03-15T10:00:00+01:00">
+    </head><body>
+        <nav><a href="/">Home</a> <a href="/blog">Blog</a></nav>
+        <div class="cookie-banner">We use cookies, accept them all, please, thank you.</div>
+        <div class="sidebar"><p>Subscribe to the newsletter, it is great, really, we promise, daily.</p></div>
+        <article class="post">
+            <h1>Rusty readability</h1>
+            <p>Rust is a language empowering everyone to build reliable and efficient software,
+                see <a href="/docs/book">the book</a>.</p>
+            <p>It has a borrow checker, a great compiler, cargo, crates.io, and a friendly community of people.</p>
+        </article>
+        <footer>Copyright, all rights reserved, terms, privacy, contact us.</footer>
+    </body></html>"#;
+
+    #[test]
+    fn decode_charsets() {
+        // "café" in latin-1
+        let latin1 = b"<html><head><meta charset=\"iso-8859-1\"></head><body>caf\xe9</body></html>";
+        assert!(decode_html(latin1, None).contains("café"));
+        assert!(decode_html(b"caf\xe9", Some("text/html; charset=ISO-8859-1")).contains("café"));
+        // undeclared and invalid utf-8 does not panic
+        assert!(decode_html(b"caf\xe9", None).contains("caf\u{fffd}"));
+        assert_eq!(decode_html("café".as_bytes(), Some("text/html")), "café");
+    }
+
+    #[test]
+    fn extract_main_content_and_metadata() {
+        let base_url = Url::parse("https://rusty.com/blog/post").unwrap();
+        let page = convert_html_to_md(ARTICLE, Some(&base_url), HtmlToMdMode::Article);
+        assert_eq!(page.title.as_deref(), Some("Rusty readability"));
+        assert_eq!(page.byline.as_deref(), Some("Baakey"));
+        assert_eq!(page.published, parse_published("2023-03-15T09:00:00Z"));
+        assert!(page.markdown.contains("borrow checker"));
+        assert!(page.markdown.contains("https://rusty.com/docs/book"));
+        assert!(!page.markdown.contains("cookies"));
+        assert!(!page.markdown.contains("newsletter"));
+        assert!(!page.markdown.contains("Copyright"));
+        assert_eq!(page.reading_minutes, 1);
+
+        let page = convert_html_to_md(ARTICLE, None, HtmlToMdMode::Page);
+        assert!(page.markdown.contains("cookies"));
+        assert!(page.markdown.contains("(/docs/book)"));
+    }
+
+    #[test]
+    fn published_dates() {
+        assert_eq!(
+            parse_published("2023-03-15"),
+            DateTime::parse_from_rfc3339("2023-03-15T00:00:00Z").ok().map(|date| date.with_timezone(&Utc))
+        );
+        assert_eq!(parse_published("yesterday"), None);
+    }
+}
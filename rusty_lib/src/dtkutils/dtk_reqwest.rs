@@ -9,8 +9,10 @@ use std::fs::File;
 use std::io::Read;
 
 use crate::dtkchat::chat_model::{DtkChat, DtkChatUser, DtkChatMessage};
+use crate::dtknotify::notify_channel::resolve_public_addr;
 
 use super::dtk_error::DtkError;
+use super::dtk_readability::{convert_html_to_md, decode_html, DtkMarkdownPage, HtmlToMdMode};
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -121,10 +123,17 @@ pub fn get_data_from_body(req_body: String) -> DtkRequestBody {
                     .unwrap()
                     .to_vec()
                     .iter()
-                    .map(|x| DtkChatMessage {
-                        sender_id: x["sender_id"].as_str().unwrap().to_string(),
-                        date: x["date"].as_str().unwrap().to_string(),
-                        message: x["message"].as_str().unwrap().to_string(),
+                    .map(|x| {
+                        let mut message = DtkChatMessage::new(
+                            x["sender_id"].as_str().unwrap().to_string(),
+                            x["date"].as_str().unwrap().to_string(),
+                            x["message"].as_str().unwrap().to_string(),
+                        );
+                        if let Some(id) = x["id"].as_str().filter(|id| !id.is_empty()) {
+                            message.id = id.to_string();
+                        }
+                        message.parent_id = x["parent_id"].as_str().map(|id| id.to_string());
+                        message
                     })
                     .collect::<Vec<DtkChatMessage>>(),
             }
@@ -191,27 +200,74 @@ pub async fn send_post_request(url: &str, body: serde_json::Value) -> Result<req
         .await
 }
 
+/// Redirects followed by `send_public_request`
+const PUBLIC_REQUEST_MAX_REDIRECTS: usize = 10;
+/// User agent of the pages fetched for users
+pub const FETCH_USER_AGENT: &str = "Mozilla/5.0 (compatible; rusty)";
+
+/// Request an url given by a user, only reaching public hosts: each hop is resolved, checked and pinned,
+/// and redirects are followed one by one so that they are checked too. The response may be an error status.
+pub async fn send_public_request(
+    method: reqwest::Method,
+    url: &str,
+    user_agent: &str,
+) -> Result<reqwest::Response, DtkError> {
+    let mut url = url::Url::parse(url.trim()).map_err(|err| DtkError::from(err.to_string().as_str()))?;
+    let mut method = method;
+    for _ in 0..=PUBLIC_REQUEST_MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(DtkError::from("Only http and https urls are fetched"));
+        }
+        let addr = resolve_public_addr(&url).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(url.host_str().unwrap_or_default(), addr)
+            .user_agent(user_agent)
+            .build()?;
+        let response = client.request(method.clone(), url.clone()).send().await?;
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok());
+        let next_url = match (response.status().is_redirection(), location) {
+            (true, Some(location)) => url.join(location).map_err(|err| DtkError::from(err.to_string().as_str()))?,
+            _ => return Ok(response),
+        };
+        if response.status() == reqwest::StatusCode::SEE_OTHER {
+            method = reqwest::Method::GET;
+        }
+        url = next_url;
+    }
+    Err(DtkError::from("Too many redirects"))
+}
+
+/// Get html from url, decoded with the page charset, and the url it was served from.
+/// Only public hosts are fetched, see `send_public_request`.
+pub async fn fetch_html(url: &str) -> Result<(String, url::Url), DtkError> {
+    let response = send_public_request(reqwest::Method::GET, url, FETCH_USER_AGENT).await?.error_for_status()?;
+    let final_url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let bytes = response.bytes().await?;
+    Ok((decode_html(&bytes, content_type.as_deref()), final_url))
+}
+
 /// Get html from url
-pub async fn get_html(url: &str) -> Result<String, reqwest::Error> {
-    let response = reqwest::get(url).await?;
-    // let mut html = String::new();
-    let crazy = &response.bytes().await.unwrap();
-    let answer = std::str::from_utf8(crazy).unwrap();
-    // response.unwrap().read_to_string(&mut html)?;
-    Ok(answer.to_string())
+pub async fn get_html(url: &str) -> Result<String, DtkError> {
+    fetch_html(url).await.map(|(html, _)| html)
 }
 
-/// Get html to markdown
-pub async fn get_html_to_md(url: &str) -> String {
+/// Convert html to markdown, without styles, scripts and images
+pub fn parse_html_to_md(html: &str) -> String {
     // Define a custom tag handler that removes style tags
     struct RemoveUnwantedTagHandler {}
     impl TagHandler for RemoveUnwantedTagHandler {
         fn handle(&mut self, tag: &Handle, printer: &mut StructuredPrinter) {
-            match tag.data {
-                NodeData::Element { .. } => {
-                    printer.append_str("");
-                }
-                _ => return,
+            if let NodeData::Element { .. } = tag.data {
+                printer.append_str("");
             }
         }
 
@@ -220,7 +276,7 @@ pub async fn get_html_to_md(url: &str) -> String {
         }
 
         fn skip_descendants(&self) -> bool {
-            return true;
+            true
         }
     }
 
@@ -228,16 +284,20 @@ pub async fn get_html_to_md(url: &str) -> String {
     struct RemoveTagHandlerFactory {}
     impl TagHandlerFactory for RemoveTagHandlerFactory {
         fn instantiate(&self) -> Box<dyn TagHandler> {
-            return Box::new(RemoveUnwantedTagHandler {});
+            Box::new(RemoveUnwantedTagHandler {})
         }
     }
-    let html = get_html(url).await.unwrap();
     let mut tag_factory: HashMap<String, Box<dyn TagHandlerFactory>> = HashMap::new();
     tag_factory.insert(String::from("style"), Box::new(RemoveTagHandlerFactory {}));
     tag_factory.insert(String::from("script"), Box::new(RemoveTagHandlerFactory {}));
     tag_factory.insert(String::from("img"), Box::new(RemoveTagHandlerFactory {}));
-    let markdown = html2md::parse_html_custom(&html, &tag_factory);
-    markdown
+    html2md::parse_html_custom(html, &tag_factory)
+}
+
+/// Get html to markdown, the whole page or only its main content, with the page metadata
+pub async fn get_html_to_md(url: &str, mode: HtmlToMdMode) -> Result<DtkMarkdownPage, DtkError> {
+    let (html, final_url) = fetch_html(url).await?;
+    Ok(convert_html_to_md(&html, Some(&final_url), mode))
 }
 
 #[derive(Debug)]
@@ -254,15 +314,41 @@ pub struct DtkResonse {
 /// parse Response into DtkResponse
 pub async fn get_dtk_response(r: reqwest::Response) -> DtkResonse {
     DtkResonse {
-        addr: r.remote_addr().clone().unwrap(),
+        addr: r.remote_addr().unwrap(),
         headers: r.headers().clone(),
         res: r.json::<serde_json::Value>().await,
     }
 }
 
+/// test html to md without fetching
+#[test]
+fn html_to_md_strips_scripts() {
+    let md = parse_html_to_md("<h1>Rusty</h1><script>alert(1)</script><p>saved <b>article</b></p>");
+    assert!(md.contains("Rusty"));
+    assert!(md.contains("**article**"));
+    assert!(!md.contains("alert"));
+}
+
+/// test that user given urls can't reach the server or its network
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn public_requests_only() {
+    for url in [
+        "http://127.0.0.1:27017/",
+        "http://localhost:1342/hey",
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.12/",
+        "http://[::1]/",
+        "file:///etc/passwd",
+    ] {
+        assert!(send_public_request(reqwest::Method::GET, url, FETCH_USER_AGENT).await.is_err(), "{url}");
+    }
+}
+
 /// test html to md
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn html_to_md() {
-    let md = get_html_to_md("https://baakeydow.dtksi.com/md/rust/baakeydow").await;
-    assert!(md.len() > 0);
+    let md = get_html_to_md("https://baakeydow.dtksi.com/md/rust/baakeydow", HtmlToMdMode::Page)
+        .await
+        .unwrap();
+    assert!(!md.markdown.is_empty());
 }
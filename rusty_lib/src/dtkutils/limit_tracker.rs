@@ -27,10 +27,31 @@ where
         }
     }
 
-    /// set_value method
+    /// new method for a tracker already at some value, nothing is sent
+    pub fn with_value(messenger: &'a T, max: usize, value: usize) -> LimitTracker<'a, T> {
+        LimitTracker { messenger, value, max }
+    }
+
+    /// 0 under 75%, then 1, 2 and 3 for the 75%, 90% and 100% thresholds
+    fn level(&self) -> u8 {
+        let percentage_of_max = self.value as f64 / self.max as f64;
+        match percentage_of_max {
+            p if p >= 1.0 => 3,
+            p if p >= 0.9 => 2,
+            p if p >= 0.75 => 1,
+            _ => 0,
+        }
+    }
+
+    /// set_value method, a message is sent only when a new threshold is crossed
     pub fn set_value(&mut self, value: usize) {
+        let previous_level = self.level();
         self.value = value;
 
+        if self.level() <= previous_level {
+            return;
+        }
+
         let percentage_of_max = self.value as f64 / self.max as f64;
 
         if percentage_of_max >= 1.0 {
@@ -77,4 +98,20 @@ mod tests {
 
         assert_eq!(mock_messenger.sent_messages.borrow().len(), 1);
     }
+
+    #[test]
+    fn it_sends_each_warning_once() {
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::with_value(&mock_messenger, 100, 80);
+
+        limit_tracker.set_value(85);
+        limit_tracker.set_value(92);
+        limit_tracker.set_value(95);
+        limit_tracker.set_value(100);
+
+        let sent_messages = mock_messenger.sent_messages.borrow();
+        assert_eq!(sent_messages.len(), 2);
+        assert!(sent_messages[0].starts_with("Urgent warning"));
+        assert!(sent_messages[1].starts_with("Error"));
+    }
 }
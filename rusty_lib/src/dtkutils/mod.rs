@@ -1,6 +1,7 @@
 //! RUSTY_UTILS
 pub mod utils;
 pub mod dtk_reqwest;
+pub mod dtk_readability;
 pub mod dtk_github;
 pub mod dtk_error;
 pub mod by_address;
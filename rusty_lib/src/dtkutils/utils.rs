@@ -103,7 +103,7 @@ fn get_element_from_vec_by_type(vec: Vec<String>) -> (Vec<String>, Vec<i128>, Ve
     let mut str_args: Vec<String> = Vec::new();
     let mut int_args: Vec<i128> = Vec::new();
     let mut float_args: Vec<f64> = Vec::new();
-    if vec.len() == 0 {
+    if vec.is_empty() {
         return (str_args, int_args, float_args);
     }
     for val in vec.into_iter() {
@@ -163,10 +163,10 @@ mod tests {
     #[test]
     fn test_is_valid_mongo_search() {
         let mut search = "go ? baakey@github.com";
-        assert_eq!(is_valid_mongo_search(search), true);
+        assert!(is_valid_mongo_search(search));
         search = "let's push,_ this - !";
-        assert_eq!(is_valid_mongo_search(search), true);
+        assert!(is_valid_mongo_search(search));
         search = "let:s push this";
-        assert_eq!(is_valid_mongo_search(search), false);
+        assert!(!is_valid_mongo_search(search));
     }
 }
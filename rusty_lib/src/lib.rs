@@ -7,6 +7,7 @@ pub mod dtkexamples;
 pub mod dtkchat;
 pub mod dtkmongo;
 pub mod dtkpocket;
+pub mod dtknotify;
 
 #[cfg(test)]
 mod rusty_lib_main_tests {
@@ -69,7 +70,7 @@ mod rusty_lib_main_tests {
         if res.is_err() {
             panic!("{:#?}", res.err().unwrap());
         }
-        assert_eq!(true, res.is_ok());
+        assert!(res.is_ok());
     }
 
     #[test]
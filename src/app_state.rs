@@ -1,11 +1,12 @@
 use crate::core_args::{CoreArgs, LogLevel};
-use clap::Parser;
+use rusty_lib::dtknotify::notify::Notifier;
+use rusty_lib::dtkpocket::pocket_index::PocketIndex;
+use rusty_lib::dtkpocket::pocket_stats::PocketStatsCache;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-pub fn build_app_state<'a>() -> AppState<'a> {
-    let args = CoreArgs::parse();
+pub fn build_app_state(args: &CoreArgs) -> AppState {
     let env_file = if args.dev { ".env.dev" } else { ".env" };
     dotenv::from_filename(env_file).ok();
     println!("{:?}", args);
@@ -14,9 +15,12 @@ pub fn build_app_state<'a>() -> AppState<'a> {
         log_level: args.log_level,
         app_name: String::from("RUSTY CORE API"),
         cron_time: Duration::from_secs(args.cron_time),
-        scheduler_time: std::env::var("RUSTY_SCHEDULER").unwrap_or_else(|_| args.sch_time),
+        scheduler_time: std::env::var("RUSTY_SCHEDULER").unwrap_or_else(|_| args.sch_time.clone()),
         max_endpoint_count: args.max_endpoint_count,
-        arc_map: Arc::new(Mutex::new(HashMap::<&str, CtxRequesterDataPerEndpoint>::new())),
+        arc_map: Arc::new(Mutex::new(HashMap::<String, CtxRequesterDataPerEndpoint>::new())),
+        notifier: Arc::new(Notifier::from_env()),
+        pocket_index: PocketIndex::from_env(),
+        pocket_stats: PocketStatsCache::from_env(),
     }
 }
 
@@ -27,34 +31,37 @@ pub struct CtxRequesterDataPerEndpoint {
 
 impl CtxRequesterDataPerEndpoint {
     pub fn add_endpoint_count(&mut self) {
-        self.endpoint_count_for_ip = self.endpoint_count_for_ip + 1;
+        self.endpoint_count_for_ip += 1;
     }
 }
 
 #[derive(Debug)]
-pub struct AppState<'a> {
+pub struct AppState {
     pub dev_mode: bool,
     pub log_level: LogLevel,
     pub app_name: String,
     pub cron_time: Duration,
     pub scheduler_time: String,
     pub max_endpoint_count: u64,
-    pub arc_map: Arc<Mutex<HashMap<&'a str, CtxRequesterDataPerEndpoint>>>,
+    /// Request counts keyed by `{route pattern}-{ip}`
+    pub arc_map: Arc<Mutex<HashMap<String, CtxRequesterDataPerEndpoint>>>,
+    pub notifier: Arc<Notifier>,
+    /// Local search index of pocket items, None when disabled
+    pub pocket_index: Option<Arc<PocketIndex>>,
+    /// Cached `/pocket/stats` responses
+    pub pocket_stats: Arc<PocketStatsCache>,
 }
 
-impl<'a> AppState<'a> {
-    pub fn update_endpoint_count(&mut self, key: &'a str) {
+impl AppState {
+    pub fn update_endpoint_count(&mut self, key: &str) {
         let mut map = self.arc_map.lock().unwrap();
-        let data_for_endpoint = CtxRequesterDataPerEndpoint {
-            endpoint_count_for_ip: 0,
-        };
-        map.entry(&key).or_insert(data_for_endpoint);
-        let data_for_endpoint = CtxRequesterDataPerEndpoint {
-            endpoint_count_for_ip: map.get(&key).unwrap().endpoint_count_for_ip + 1,
-        };
-        map.insert(&key, data_for_endpoint);
+        map.entry(key.to_string())
+            .or_insert(CtxRequesterDataPerEndpoint {
+                endpoint_count_for_ip: 0,
+            })
+            .add_endpoint_count();
     }
-    pub fn get_endpoint_count(&self, key: &'a str) -> u32 {
-        self.arc_map.lock().unwrap().get(key).unwrap().endpoint_count_for_ip
+    pub fn get_endpoint_count(&self, key: &str) -> u32 {
+        self.arc_map.lock().unwrap().get(key).map_or(0, |data| data.endpoint_count_for_ip)
     }
 }
@@ -1,7 +1,8 @@
-use clap::{arg_enum, Parser};
+use clap::{arg_enum, Parser, Subcommand};
+use std::path::PathBuf;
 
 arg_enum! {
-    #[derive(PartialEq, Debug)]
+    #[derive(Clone, Copy, PartialEq, Debug)]
     pub enum LogLevel {
         ERROR,
         WARN,
@@ -34,4 +35,38 @@ pub struct CoreArgs {
     /// Log level
     #[clap(short, long, default_value = "INFO")]
     pub log_level: LogLevel,
+
+    /// Run a command instead of the server
+    #[clap(subcommand)]
+    pub command: Option<CoreCommand>,
+}
+
+/// RUSTY CORE commands
+#[derive(Subcommand, Debug)]
+pub enum CoreCommand {
+    /// Import a bookmarks file: Netscape bookmarks, Pocket ril_export.html, CSV or JSON lines
+    ImportPocket {
+        /// Owner of the imported items
+        #[clap(long)]
+        user_id: String,
+
+        /// Bookmarks file
+        file: PathBuf,
+
+        /// netscape, pocket, csv or jsonl, guessed from the file when missing
+        #[clap(long)]
+        format: Option<String>,
+
+        /// Tag rename as `from=to`, an empty `to` drops the tag
+        #[clap(long = "tag-map")]
+        tag_map: Vec<String>,
+
+        /// Tag added to every imported item
+        #[clap(long = "tag")]
+        tags: Vec<String>,
+
+        /// Save the items here only instead of adding them to Pocket
+        #[clap(long)]
+        local_only: bool,
+    },
 }
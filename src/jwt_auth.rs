@@ -1,5 +1,6 @@
 extern crate jsonwebtoken as jwt;
 use actix_web::guard::GuardContext;
+use actix_web::http::header::HeaderMap;
 use rusty_lib::dtkutils::dtk_error::DtkError;
 use rusty_lib::dtkutils::dtk_reqwest::{get_token_info, TokenInfo};
 use serde::{Deserialize, Serialize};
@@ -18,22 +19,27 @@ pub struct JwtAuth {
 
 impl JwtAuth {
     pub fn new(ctx: &GuardContext) -> Result<Self, DtkError> {
-        let user_id = match ctx.head().headers().get("user_id") {
+        Self::from_headers(ctx.head().headers())
+    }
+
+    /// Verify the `user_id` and `Authorization` headers, the claims id is the verified user
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, DtkError> {
+        let user_id = match headers.get("user_id").and_then(|user_id| user_id.to_str().ok()) {
             Some(user_id) => user_id,
             None => return Err(DtkError::from("No user_id found in request header")),
         };
-        let auth_header = match ctx.head().headers().get("Authorization") {
+        let auth_header = match headers.get("Authorization") {
             Some(auth_header) => auth_header,
             None => return Err(DtkError::from("No Authorization header found")),
         };
-        let auth_value = auth_header.to_str().map_err(DtkError::from).unwrap();
+        let auth_value = auth_header.to_str().map_err(|err| DtkError::from(err.to_string().as_str()))?;
         let token = auth_value.replace("Bearer ", "");
-        let auth_data = get_token_info(token.clone(), user_id.clone().to_str().unwrap().to_string()).unwrap();
+        let auth_data = get_token_info(token.clone(), user_id.to_string())?;
         let jwt = JwtAuth {
             claims: auth_data,
             token,
         };
-        if jwt.claims.id != user_id.to_str().unwrap() {
+        if jwt.claims.id != user_id {
             return Err(DtkError::from("JWT not valid"));
         }
         log::info!("auth_data: {:?}", &jwt);
@@ -5,11 +5,26 @@ use actix_web::guard::fn_guard;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpResponse, HttpServer};
 use core_rusty_api::jwt_auth::JwtAuth;
+use clap::Parser;
+use core_rusty_api::core_args::CoreArgs;
 use core_rusty_api::routes::chat::chat_route;
+use core_rusty_api::toolz::commands::run_core_command;
 use core_rusty_api::toolz::dtksi_cron::run_main_cron;
 use core_rusty_api::toolz::scheduler::start_scheduler;
+use core_rusty_api::ws_chat::notify_channel::ChatWsChannel;
 use core_rusty_api::ws_chat::server;
-use core_rusty_api::{app_state::build_app_state, routes::chat, routes::common, toolz::utils::setup_core_env};
+use rusty_lib::dtkchat::chat::migrate_chat_message_ids;
+use rusty_lib::dtkchat::chat_bot::BotRegistry;
+use rusty_lib::dtkmongo::dtk_migrate::run_migration;
+use rusty_lib::dtkpocket::pocket::{backfill_pocket_index, migrate_pocket_dates};
+use rusty_lib::dtkpocket::pocket_classifier::{reclassify_pocket_data, PocketClassifier};
+use rusty_lib::dtkpocket::pocket_collection::create_pocket_collection_indexes;
+use rusty_lib::dtkpocket::pocket_dedup::backfill_canonical_urls;
+use rusty_lib::dtkpocket::pocket_profile::migrate_public_pocket_profile;
+use rusty_lib::dtkpocket::pocket_utils::migrate_pocket_item_index;
+use core_rusty_api::{
+    app_state::build_app_state, routes::chat, routes::common, routes::notify, toolz::utils::setup_core_env,
+};
 use futures_util::future::FutureExt;
 use log::debug;
 use std::sync::atomic::AtomicUsize;
@@ -17,17 +32,84 @@ use std::sync::{Arc, Mutex};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_data = web::Data::new(Mutex::new(build_app_state()));
+    let args = CoreArgs::parse();
+    let app_data = web::Data::new(Mutex::new(build_app_state(&args)));
     setup_core_env(&app_data);
-    run_main_cron(app_data.clone()).await;
-    start_scheduler(app_data.clone()).await;
-    println!("[RUSTY_CORE_API](init) => {:#?}", &app_data);
+    if let Some(command) = args.command {
+        let pocket_index = app_data.lock().unwrap().pocket_index.clone();
+        return run_core_command(command, pocket_index).await;
+    }
     // set up applications state
     // keep a count of the number of visitors
     let chat_state = Arc::new(AtomicUsize::new(0));
 
     // start chat server actor
     let server = server::ChatServer::new(chat_state.clone()).start();
+    // notifications reach online users through their chat sessions
+    app_data
+        .lock()
+        .unwrap()
+        .notifier
+        .add_channel(Arc::new(ChatWsChannel { addr: server.clone() }));
+
+    // slash-command bots available in chat sessions
+    let bots = Arc::new(BotRegistry::with_default_bots());
+
+    // chat messages used to be stored without id
+    actix_web::rt::spawn(async {
+        match migrate_chat_message_ids().await {
+            Ok(migrated) => log::info!("[CHAT] migrated message ids of {} chats", migrated),
+            Err(err) => log::error!("[CHAT] message id migration failed => {}", err),
+        }
+    });
+    actix_web::rt::spawn(async {
+        if let Err(err) = create_pocket_collection_indexes().await {
+            log::error!("[POCKET] collection indexes not created => {}", err);
+        }
+    });
+    // pocket dates used to be stored as strings
+    let pocket_index = app_data.lock().unwrap().pocket_index.clone();
+    actix_web::rt::spawn(async move {
+        match migrate_pocket_dates().await {
+            Ok(migrated) => log::info!("[POCKET] migrated dates of {} items", migrated),
+            Err(err) => log::error!("[POCKET] date migration failed => {}", err),
+        }
+        // item ids used to be unique across users
+        match run_migration("pocket_item_index_v1", migrate_pocket_item_index).await {
+            Ok(Some(dropped)) => log::info!("[POCKET] dropped {} global item id index", dropped),
+            Ok(None) => log::debug!("[POCKET] item index migration already applied"),
+            Err(err) => log::error!("[POCKET] item index migration failed => {}", err),
+        }
+        // items saved before derived tags were recorded, run once so tag edits are kept
+        let classifier = PocketClassifier::from_env();
+        match run_migration("pocket_reclassify_v1", || reclassify_pocket_data(&classifier)).await {
+            Ok(Some(changed)) => log::info!("[POCKET] reclassified {} items", changed),
+            Ok(None) => log::debug!("[POCKET] reclassification already applied"),
+            Err(err) => log::error!("[POCKET] reclassification failed => {}", err),
+        }
+        match run_migration("pocket_canonical_urls_v1", backfill_canonical_urls).await {
+            Ok(Some(changed)) => log::info!("[POCKET] canonical urls of {} items", changed),
+            Ok(None) => log::debug!("[POCKET] canonical url backfill already applied"),
+            Err(err) => log::error!("[POCKET] canonical url backfill failed => {}", err),
+        }
+        // `/pocket/public` listed a hardcoded user before profiles existed
+        match run_migration("pocket_public_profile_v1", migrate_public_pocket_profile).await {
+            Ok(Some(created)) => log::info!("[POCKET] created {} public profile", created),
+            Ok(None) => log::debug!("[POCKET] public profile migration already applied"),
+            Err(err) => log::error!("[POCKET] public profile migration failed => {}", err),
+        }
+        // items saved before the search index existed, or while it was unavailable
+        if let Some(pocket_index) = pocket_index {
+            match backfill_pocket_index(pocket_index).await {
+                Ok(indexed) => log::info!("[POCKET] indexed {} missing items", indexed),
+                Err(err) => log::error!("[POCKET] search index backfill failed => {}", err),
+            }
+        }
+    });
+
+    run_main_cron(app_data.clone()).await;
+    start_scheduler(app_data.clone()).await;
+    println!("[RUSTY_CORE_API](init) => {:#?}", &app_data);
 
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -42,6 +124,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::from(chat_state.clone()))
             .app_data(web::Data::new(server.clone()))
+            .app_data(web::Data::from(bots.clone()))
             .app_data(web::PayloadConfig::default().limit(1024 * 1024 * 1024))
             .app_data(app_data.clone())
             .wrap(Logger::default())
@@ -60,7 +143,16 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/chat")
                     .guard(fn_guard(|ctx| JwtAuth::new(ctx).is_ok()))
                     .route("/get", web::post().to(chat::get_chat))
-                    .route("/post", web::post().to(chat::post_chat_message)),
+                    .route("/post", web::post().to(chat::post_chat_message))
+                    .route("/export", web::post().to(chat::export_chat)),
+            )
+            .service(
+                web::scope("/notify")
+                    .guard(fn_guard(|ctx| JwtAuth::new(ctx).is_ok()))
+                    .route("/inbox", web::post().to(notify::get_inbox))
+                    .route("/inbox/read", web::post().to(notify::read_inbox))
+                    .route("/settings", web::post().to(notify::get_settings))
+                    .route("/settings/save", web::post().to(notify::save_settings)),
             )
             .service(
                 web::scope("/pocket")
@@ -68,13 +160,46 @@ async fn main() -> std::io::Result<()> {
                     .route("/connect", web::post().to(common::connect_token))
                     .route("/user", web::post().to(common::get_current_user))
                     .route("/delete", web::post().to(common::delete_current_user))
+                    .route("/resync", web::post().to(common::resync_pocket))
+                    .route("/actions", web::post().to(common::send_pocket_actions))
+                    .route("/archive", web::post().to(common::get_pocket_archive))
+                    .route("/stats", web::post().to(common::get_pocket_stats))
+                    .route("/tags", web::post().to(common::get_pocket_tags))
+                    .route("/tags/edit", web::post().to(common::edit_pocket_tags))
+                    .route("/tag_rules", web::post().to(common::get_pocket_tag_rules))
+                    .route("/tag_rules/save", web::post().to(common::save_pocket_tag_rules))
+                    .route("/tag_rules/dry_run", web::post().to(common::dry_run_pocket_tag_rules))
+                    .route("/tag_rules/backfill", web::post().to(common::backfill_pocket_tag_rules))
+                    .route("/duplicates", web::post().to(common::get_pocket_duplicates))
+                    .route("/duplicates/merge", web::post().to(common::merge_pocket_duplicates))
+                    .route("/export", web::post().to(common::export_pocket))
+                    .route("/import", web::post().to(common::import_pocket))
+                    .route("/collections", web::post().to(common::get_pocket_collections))
+                    .route("/collections/get", web::post().to(common::get_pocket_collection))
+                    .route("/collections/save", web::post().to(common::save_pocket_collection))
+                    .route("/collections/items", web::post().to(common::edit_pocket_collection))
+                    .route("/collections/delete", web::post().to(common::delete_pocket_collection))
+                    .route("/profile", web::post().to(common::get_pocket_profile))
+                    .route("/profile/save", web::post().to(common::save_pocket_profile))
+                    .route("/visibility", web::post().to(common::set_pocket_visibility))
                     .route("/url", web::post().to(common::get_pocket_url))
                     .route("/private", web::post().to(common::get_private_pocket)),
             )
             .route("/chat/ws", web::get().to(chat_route))
+            .route("/chat/sse", web::get().to(chat::chat_sse_route))
             .route("/pocket/public", web::post().to(common::get_public_pocket))
+            .route("/pocket/public/{slug}", web::post().to(common::get_public_pocket))
+            .route("/pocket/public/{slug}", web::get().to(common::get_public_pocket))
+            .route("/pocket/public/{slug}/feed/{format}", web::get().to(common::get_public_pocket_feed))
+            .route("/pocket/public/{slug}/feed/{format}/{tag:.+}", web::get().to(common::get_public_pocket_feed))
+            .route("/pocket/public/{slug}/collections", web::get().to(common::get_public_pocket_collections))
+            .route(
+                "/pocket/public/{slug}/collections/{collection}",
+                web::get().to(common::get_public_pocket_collection),
+            )
+            .route("/pocket/public/{slug}/{item_id}", web::get().to(common::get_public_pocket_item))
             .route("/hey", web::get().to(common::hey))
-            .default_service(web::route().to(|| HttpResponse::Unauthorized()))
+            .default_service(web::route().to(HttpResponse::Unauthorized))
     })
     .bind(("0.0.0.0", 1342))?
     .run()
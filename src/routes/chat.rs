@@ -1,27 +1,36 @@
 use std::env;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use std::time::Instant;
 
 use crate::jwt_auth::JwtAuth;
 use crate::ws_chat;
+use crate::ws_chat::sse_session::SseChatSession;
 use actix::*;
 use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
 use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
+use futures_channel::mpsc;
+use futures_util::{stream, StreamExt};
 use rusty_lib::dtkutils::dtk_reqwest::get_token_info;
 use rusty_lib::{
     dtkchat::{
-        chat::{create_dtk_chat_message, get_all_chat_users, get_all_dtk_chat_for_user},
-        chat_model::{ChatForUsers, DtkChat, DtkChatUser},
+        chat::{
+            create_dtk_chat_message, get_all_chat_users, get_all_dtk_chat_for_user, get_dtk_chat_stream_for_user,
+        },
+        chat_bot::BotRegistry,
+        chat_export::{parse_export_timezone, ChatExportFormat, ChatExporter},
+        chat_model::{ChatExportRequest, ChatForUsers, DtkChat, DtkChatMessage, DtkChatUser},
+        chat_utils::{format_sse_event, get_event_id},
     },
-    dtkutils::dtk_reqwest::get_data_from_body,
+    dtknotify::{notify::Notifier, notify_model::DtkNotification},
 };
 use serde::Deserialize;
 
 use crate::{
     app_state::AppState,
-    toolz::utils::{get_ip_addr, inc_request_count},
+    toolz::utils::{get_ip_addr, get_user_data_from_body, inc_user_request_count},
 };
 
 #[derive(Debug, Deserialize)]
@@ -29,6 +38,8 @@ pub struct AuthenticatedRequest {
     user_id: String,
     channel_id: String,
     token: String,
+    /// EventSource polyfills can't set headers, so resume can also come from the query
+    last_event_id: Option<String>,
 }
 
 pub async fn chat_ws_index() -> impl Responder {
@@ -42,6 +53,7 @@ pub async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<ws_chat::server::ChatServer>>,
+    bots: web::Data<BotRegistry>,
     info: web::Query<AuthenticatedRequest>,
 ) -> Result<HttpResponse, Error> {
     let token = info.token.clone();
@@ -64,15 +76,78 @@ pub async fn chat_route(
         token: Some(token),
         user_id: Some(user_id.clone()),
         channel_id: Some(channel_id.clone()),
+        bots: bots.into_inner(),
     };
     ws::start(actor, &req, stream)
 }
 
-pub async fn get_chat((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>)) -> impl Responder {
+/// Server-Sent Events fallback for `/chat/ws`, send messages with `/chat/post`
+pub async fn chat_sse_route(
+    req: HttpRequest,
+    srv: web::Data<Addr<ws_chat::server::ChatServer>>,
+    info: web::Query<AuthenticatedRequest>,
+) -> HttpResponse {
+    let user_id = info.user_id.clone();
+    let channel_id = info.channel_id.clone();
+    let auth_data = match get_token_info(info.token.clone(), user_id.clone()) {
+        Ok(auth_data) if auth_data.id == user_id => auth_data,
+        _ => return HttpResponse::Unauthorized().finish(),
+    };
+    // only members join the room
+    let chat = match get_dtk_chat_stream_for_user(&user_id, Some(channel_id.clone()))
+        .await
+        .next()
+        .await
+    {
+        Some(chat) => chat,
+        None => return HttpResponse::Forbidden().finish(),
+    };
+    let (tx, rx) = mpsc::unbounded::<web::Bytes>();
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .or_else(|| info.last_event_id.clone());
+    if let Some(last_event_id) = last_event_id {
+        let mut chat = chat;
+        chat.messages = chat.messages_after(&last_event_id);
+        if !chat.messages.is_empty() {
+            let payload = serde_json::to_string(&ChatForUsers {
+                chat: vec![chat],
+                users: get_all_chat_users().await,
+                id: "single".to_string(),
+                count: 0,
+            })
+            .unwrap();
+            let event_id = get_event_id(&payload);
+            tx.unbounded_send(web::Bytes::from(format_sse_event(event_id.as_deref(), &payload))).unwrap();
+        }
+    }
+
+    log::info!("SSE session for {} in {}", auth_data.email, channel_id);
+    SseChatSession {
+        id: 0,
+        room: channel_id,
+        user_id,
+        addr: srv.get_ref().clone(),
+        tx,
+    }
+    .start();
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(rx.map(Ok::<_, Error>))
+}
+
+pub async fn get_chat((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
     println!("{:#?}", req_body);
-    let count = inc_request_count(&req, data);
+    let count = inc_user_request_count(&req, data);
     let ip = get_ip_addr(&req);
-    let payload = get_data_from_body(req_body);
+    let payload = get_user_data_from_body(&req, req_body);
     let chat = get_all_dtk_chat_for_user(DtkChatUser {
         id: payload.id,
         name: payload.name,
@@ -89,26 +164,49 @@ pub async fn get_chat((req, req_body, data): (HttpRequest, String, web::Data<Mut
 }
 
 pub async fn post_chat_message(
-    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, data, srv): (
+        HttpRequest,
+        String,
+        web::Data<Mutex<AppState>>,
+        web::Data<Addr<ws_chat::server::ChatServer>>,
+    ),
 ) -> impl Responder {
-    let count = inc_request_count(&req, data);
+    let notifier = data.lock().unwrap().notifier.clone();
+    let count = inc_user_request_count(&req, data);
     let ip = get_ip_addr(&req);
-    let payload = get_data_from_body(req_body);
+    let payload = get_user_data_from_body(&req, req_body);
     let channel_id = payload.chat_payload.channel_id;
-    create_dtk_chat_message(DtkChat {
+    let new_messages = create_dtk_chat_message(DtkChat {
         channel_id: channel_id.clone(),
         last_update: chrono::Utc::now().to_string(),
         users: payload.chat_payload.users,
         messages: payload.chat_payload.messages,
     })
     .await;
+    notify_mentions(notifier, &channel_id, new_messages).await;
     let chat = get_all_dtk_chat_for_user(DtkChatUser {
-        id: payload.id,
+        id: payload.id.clone(),
         name: payload.name,
         email: payload.email,
     })
     .await;
     let users = get_all_chat_users().await;
+    // send to the websocket and SSE sessions in the room, the sender already has the response
+    srv.do_send(ws_chat::server::RoomMessage {
+        skip_user_id: payload.id,
+        msg: serde_json::to_string(&ChatForUsers {
+            chat: chat
+                .iter()
+                .filter(|dtk_chat| dtk_chat.channel_id == channel_id)
+                .cloned()
+                .collect(),
+            users: users.clone(),
+            id: "multiple".to_string(),
+            count: 0,
+        })
+        .unwrap(),
+        room: channel_id,
+    });
     HttpResponse::Ok().json(ChatForUsers {
         count,
         id: ip,
@@ -116,3 +214,52 @@ pub async fn post_chat_message(
         users,
     })
 }
+
+/// Notify mentioned users, through the websocket when online and their other enabled channels
+async fn notify_mentions(notifier: Arc<Notifier>, channel_id: &str, messages: Vec<DtkChatMessage>) {
+    for msg in messages {
+        for user_id in msg.mentions.iter() {
+            let notification = DtkNotification::new(user_id, "mention", "New mention", &msg.message).with_data(
+                serde_json::json!({
+                    "channel_id": channel_id,
+                    "message_id": msg.id,
+                    "parent_id": msg.parent_id,
+                    "sender_id": msg.sender_id,
+                }),
+            );
+            notifier.notify(notification).await;
+        }
+    }
+}
+
+/// Download one channel, or every channel of the user, as JSON lines, Markdown or HTML
+pub async fn export_chat((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
+    inc_user_request_count(&req, data);
+    let export_request = match serde_json::from_str::<ChatExportRequest>(&req_body) {
+        Ok(export_request) => export_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let format = match export_request.format.as_deref().unwrap_or("json").parse::<ChatExportFormat>() {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let timezone = match parse_export_timezone(export_request.timezone.as_deref()) {
+        Ok(timezone) => timezone,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let payload = get_user_data_from_body(&req, req_body);
+    let exporter = ChatExporter::new(format, timezone, get_all_chat_users().await);
+    let chats = get_dtk_chat_stream_for_user(&payload.id, export_request.channel_id).await;
+    let (header, footer) = (exporter.header(), exporter.footer());
+    let body = stream::once(async move { header })
+        .chain(chats.map(move |chat| exporter.render_chat(&chat)))
+        .chain(stream::once(async move { footer }))
+        .map(|chunk| Ok::<_, Error>(web::Bytes::from(chunk)));
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("chat-export.{}", format.extension()))],
+        })
+        .streaming(body)
+}
@@ -1,31 +1,52 @@
 use crate::{
     app_state::AppState,
-    toolz::utils::{get_ip_addr, inc_request_count},
+    toolz::utils::{get_ip_addr, get_user_data_from_body, get_user_id, inc_request_count},
 };
+use actix_multipart::Multipart;
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
 use rusty_lib::{
     dtkmongo::dtk_connect::{get_dtkmongo_client, get_mongodb_uri},
     dtkpocket::{
-        pocket::{self, save_pocket},
-        pocket_model::{DtkPocketData, DtkPocketResponse, PockerUrlResponse, QualifiedPocketData},
-        pocket_utils::{get_pocket_collection_name, get_pocket_db_name},
-    },
-    dtkutils::{
-        dtk_reqwest::{get_data_from_body, RequestBodyParser},
-        utils::{is_rusty_dev, is_valid_mongo_search},
+        pocket::{self, sync_user_pocket},
+        pocket_archive::{
+            self, PocketArchiveFormat, PocketArchiveRequest, POCKET_ARCHIVE_FAILED, POCKET_ARCHIVE_PENDING,
+        },
+        pocket_autotag::{self, PocketTagRulesRequest},
+        pocket_collection::{
+            self, PocketCollectionEditRequest, PocketCollectionRequest, PocketCollectionSlugRequest,
+        },
+        pocket_dedup::{self, PocketMergeRequest},
+        pocket_feed::{self, PocketFeedFormat},
+        pocket_index::{PocketIndex, PocketSearchHit},
+        pocket_model::{
+            DtkPocketData, DtkPocketResponse, PockerUrlResponse, PocketActionRequest, PocketDateFilterRequest,
+            PocketPage, PocketPageQuery, PocketPageRequest, QualifiedPocketData,
+        },
+        pocket_profile::{self, PocketProfile, PocketProfileRequest, PocketVisibility, PocketVisibilityRequest},
+        pocket_query::{PocketQuery, PocketQueryRequest},
+        pocket_stats::{self, PocketStatsRequest},
+        pocket_tags::{self, PocketTagEdit},
+        pocket_transfer::{self, PocketExportRequest, PocketImportOptions, PocketTransferFormat},
+        pocket_utils::{
+            get_pocket_collection_name, get_pocket_db_name, get_pocket_import_max_size, get_pocket_index_max_hits,
+            get_pocket_public_max_age, get_pocket_public_slug, get_pocket_users_collection_name,
+        },
     },
+    dtkutils::dtk_reqwest::{get_data_from_body, DtkRequestBody, RequestBodyParser},
 };
 use mongodb::bson::{doc, Document};
-use std::{collections::HashSet, sync::Mutex};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
-pub async fn hey(req: HttpRequest, data: web::Data<Mutex<AppState<'_>>>) -> impl Responder {
+pub async fn hey(req: HttpRequest, data: web::Data<Mutex<AppState>>) -> impl Responder {
     let count = inc_request_count(&req, data);
     let ip = get_ip_addr(&req);
     HttpResponse::Ok().body(format!("Hey {ip} welcome, endpoint requested: {count}"))
 }
 
 #[get("/")]
-pub async fn hello(req: HttpRequest, data: web::Data<Mutex<AppState<'_>>>) -> impl Responder {
+pub async fn hello(req: HttpRequest, data: web::Data<Mutex<AppState>>) -> impl Responder {
     let count = inc_request_count(&req, data);
     let ip = get_ip_addr(&req);
     HttpResponse::Ok().body(format!(
@@ -34,7 +55,7 @@ pub async fn hello(req: HttpRequest, data: web::Data<Mutex<AppState<'_>>>) -> im
 }
 
 #[post("/echo")]
-pub async fn echo((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>)) -> impl Responder {
+pub async fn echo((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
     let count = inc_request_count(&req, data);
     let ip = get_ip_addr(&req);
     log::info!("Hey {ip}, endpoint requested: {count}");
@@ -42,9 +63,9 @@ pub async fn echo((req, req_body, data): (HttpRequest, String, web::Data<Mutex<A
 }
 
 pub async fn get_current_user(
-    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
-    let dtk_user_body = get_data_from_body(req_body);
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let coll = client
         .database(&get_pocket_db_name())
@@ -63,9 +84,9 @@ pub async fn get_current_user(
 }
 
 pub async fn delete_current_user(
-    (_req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
-    let dtk_user_body = get_data_from_body(req_body);
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let user_coll = client
         .database(&get_pocket_db_name())
@@ -87,9 +108,9 @@ pub async fn delete_current_user(
 }
 
 pub async fn get_pocket_url(
-    (_req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
-    let dtk_user_body = get_data_from_body(req_body);
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
     let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
     let user_coll = client
         .database(&get_pocket_db_name())
@@ -107,203 +128,736 @@ pub async fn get_pocket_url(
 }
 
 pub async fn connect_token(
-    (_req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
     let payload = serde_json::from_str::<RequestBodyParser>(&req_body).unwrap();
     let pocket_body_res = rusty_lib::dtkpocket::pocket_auth::get_access_token(&payload.code.clone().unwrap()).await;
     println!("{:#?}", pocket_body_res);
-    if !pocket_body_res.is_ok() {
-        return HttpResponse::BadRequest().body("Invalid code");
+    let pocket_body = match pocket_body_res {
+        Ok(pocket_body) => pocket_body,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid code"),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>("pocket_users");
+    let filter = doc! {
+        "user_email": dtk_user_body.email.clone(),
+    };
+    let user_doc = coll.find_one(filter, None).await.unwrap();
+    if user_doc.is_none() {
+        let access_token = pocket_body.access_token.clone();
+        let user_name = pocket_body.username.clone();
+        let user_doc = doc! {
+            "user_id": dtk_user_body.id.clone(),
+            "user_email": dtk_user_body.email,
+            "user_name": dtk_user_body.name,
+            "user_lvl": dtk_user_body.lvl,
+            "user_token": dtk_user_body.token,
+            "pocket_code": payload.code.unwrap(),
+            "pocket_token": access_token.clone(),
+            "pocket_user_name": user_name,
+        };
+        coll.insert_one(user_doc, None).await.unwrap();
+        let pocket_index = data.lock().unwrap().pocket_index.clone();
+        if let Err(err) = sync_user_pocket(&dtk_user_body.id, true, pocket_index).await {
+            log::error!("[POCKET] first sync failed for {} => {}", dtk_user_body.id, err);
+        }
     } else {
-        let dtk_user_body = get_data_from_body(req_body);
-        let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
-        let coll = client
-            .database(&get_pocket_db_name())
-            .collection::<Document>("pocket_users");
-        let filter = doc! {
-            "user_email": dtk_user_body.email.clone(),
+        return HttpResponse::BadRequest().body("Pocket already connected");
+    }
+    HttpResponse::Ok().json(pocket_body)
+}
+
+/// Archive, favorite, tag or delete items in Pocket and in our copy
+pub async fn send_pocket_actions(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let action_request = match serde_json::from_str::<PocketActionRequest>(&req_body) {
+        Ok(action_request) => action_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    match pocket::run_pocket_actions(&dtk_user_body.id, action_request.actions, pocket_index).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(err) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+/// Tags of the user items with their counts
+pub async fn get_pocket_tags(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_tags::get_pocket_tags(&dtk_user_body.id).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Reading statistics of the user items, cached for `RUSTY_POCKET_STATS_TTL` seconds
+pub async fn get_pocket_stats(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let stats_request = match serde_json::from_str::<PocketStatsRequest>(&req_body) {
+        Ok(stats_request) => stats_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let pocket_stats = data.lock().unwrap().pocket_stats.clone();
+    match pocket_stats::get_pocket_stats(&dtk_user_body.id, &stats_request, &pocket_stats).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Rename, merge or delete a tag on every item of the user
+pub async fn edit_pocket_tags(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let tag_edit = match serde_json::from_str::<PocketTagEdit>(&req_body) {
+        Ok(tag_edit) => tag_edit,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    match pocket_tags::edit_pocket_tags(&dtk_user_body.id, &tag_edit, pocket_index).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(err) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+/// Auto-tag rules of the user
+pub async fn get_pocket_tag_rules(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    match pocket_autotag::get_pocket_tag_rules(&client, &dtk_user_body.id).await {
+        Ok(tag_rules) => HttpResponse::Ok().json(tag_rules),
+        Err(err) => HttpResponse::NotFound().body(err.to_string()),
+    }
+}
+
+/// Replace the auto-tag rules of the user, they apply to the next synced items
+pub async fn save_pocket_tag_rules(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let tag_rules = match serde_json::from_str::<PocketTagRulesRequest>(&req_body) {
+        Ok(PocketTagRulesRequest {
+            tag_rules: Some(tag_rules),
+        }) => tag_rules,
+        Ok(_) => return HttpResponse::BadRequest().body("Missing tag_rules"),
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_autotag::set_pocket_tag_rules(&dtk_user_body.id, &tag_rules).await {
+        Ok(()) => HttpResponse::Ok().json(tag_rules),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Items the given rules, or the stored ones, would tag, nothing is changed
+pub async fn dry_run_pocket_tag_rules(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let rules_request = match serde_json::from_str::<PocketTagRulesRequest>(&req_body) {
+        Ok(rules_request) => rules_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_autotag::preview_tag_rules(&dtk_user_body.id, rules_request.tag_rules.as_ref()).await {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Apply the stored auto-tag rules to the items already saved
+pub async fn backfill_pocket_tag_rules(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    match pocket_autotag::backfill_tag_rules(&dtk_user_body.id, pocket_index).await {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(err) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+/// Items saved more than once, grouped by canonical url or title
+pub async fn get_pocket_duplicates(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_dedup::get_pocket_duplicates(&dtk_user_body.id).await {
+        Ok(groups) => HttpResponse::Ok().json(groups),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Keep one item of a duplicate group, the others give it their tags and are deleted
+pub async fn merge_pocket_duplicates(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let merge_request = match serde_json::from_str::<PocketMergeRequest>(&req_body) {
+        Ok(merge_request) => merge_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    match pocket_dedup::merge_pocket_duplicates(&dtk_user_body.id, &merge_request, pocket_index).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Download the user items as a bookmarks file, Netscape bookmarks by default
+pub async fn export_pocket(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let export_request = serde_json::from_str::<PocketExportRequest>(&req_body).unwrap_or_default();
+    let format = match export_request.format.as_deref().map(str::parse::<PocketTransferFormat>) {
+        Some(Ok(format)) => format,
+        Some(Err(err)) => return HttpResponse::BadRequest().body(err.to_string()),
+        None => PocketTransferFormat::Netscape,
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_transfer::export_pocket_bookmarks(&dtk_user_body.id, format, &export_request.tags).await {
+        Ok(export) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ))
+            .body(export),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Import a bookmarks file sent as the `file` part of a multipart form, with an optional json `options` part
+pub async fn import_pocket(
+    (req, mut payload, data): (HttpRequest, Multipart, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let user_id = get_user_id(&req);
+    let max_size = get_pocket_import_max_size();
+    let mut file: Vec<u8> = vec![];
+    let mut options = PocketImportOptions::default();
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
         };
-        let user_doc = coll.find_one(filter, None).await.unwrap();
-        if user_doc.is_none() {
-            let access_token = pocket_body_res.as_ref().unwrap().access_token.clone();
-            let user_name = pocket_body_res.as_ref().unwrap().username.clone();
-            let user_doc = doc! {
-                "user_id": dtk_user_body.id.clone(),
-                "user_email": dtk_user_body.email,
-                "user_name": dtk_user_body.name,
-                "user_lvl": dtk_user_body.lvl,
-                "user_token": dtk_user_body.token,
-                "pocket_code": payload.code.unwrap(),
-                "pocket_token": access_token.clone(),
-                "pocket_user_name": user_name,
-            };
-            coll.insert_one(user_doc, None).await.unwrap();
-            save_pocket(dtk_user_body.id.to_string(), access_token, None).await;
-        } else {
-            return HttpResponse::BadRequest().body("Pocket already connected");
+        let name = field.name().unwrap_or_default().to_string();
+        let mut content: Vec<u8> = vec![];
+        while let Some(chunk) = field.next().await {
+            match chunk {
+                Ok(chunk) if content.len() + chunk.len() <= max_size => content.extend_from_slice(&chunk),
+                Ok(_) => return HttpResponse::PayloadTooLarge().body(format!("Files are limited to {max_size} bytes")),
+                Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+            }
         }
+        match name.as_str() {
+            "file" => file = content,
+            "options" => match serde_json::from_slice::<PocketImportOptions>(&content) {
+                Ok(parsed) => options = parsed,
+                Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+            },
+            _ => (),
+        }
+    }
+    if file.is_empty() {
+        return HttpResponse::BadRequest().body("No file to import");
+    }
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    let file = String::from_utf8_lossy(&file);
+    let res = pocket_transfer::import_pocket_bookmarks(&user_id, &file, &options, pocket_index).await;
+    data.lock().unwrap().pocket_stats.invalidate(&user_id);
+    match res {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
     }
-    HttpResponse::Ok().json(pocket_body_res.unwrap())
+}
+
+/// Serve the archived copy of an item, markdown by default
+pub async fn get_pocket_archive(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let archive_request = match serde_json::from_str::<PocketArchiveRequest>(&req_body) {
+        Ok(archive_request) => archive_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let format = match archive_request.format.as_deref().unwrap_or("markdown").parse::<PocketArchiveFormat>() {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let archive = match pocket_archive::get_pocket_archive(&dtk_user_body.id, &archive_request.item_id).await {
+        Ok(Some(archive)) => archive,
+        Ok(None) => return HttpResponse::NotFound().body("Item not archived"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    match archive.status.as_str() {
+        POCKET_ARCHIVE_PENDING => {
+            return HttpResponse::Accepted().body(format!("Archive pending, {} attempts", archive.attempts));
+        }
+        POCKET_ARCHIVE_FAILED => {
+            let error = archive.error.unwrap_or_default();
+            return HttpResponse::NotFound().body(format!("Archive failed => {error}"));
+        }
+        _ => (),
+    }
+    match archive.read(format).await {
+        Ok((content, content_type)) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("X-Content-Hash", archive.content_hash.unwrap_or_default()))
+            .body(content),
+        Err(err) => HttpResponse::NotFound().body(err.to_string()),
+    }
+}
+
+/// Forget the user sync cursor and import everything again in the background
+pub async fn resync_pocket(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
+    let user_coll = client
+        .database(&get_pocket_db_name())
+        .collection::<Document>(&get_pocket_users_collection_name());
+    let user = user_coll
+        .find_one(doc! { "user_id": dtk_user_body.id.clone() }, None)
+        .await
+        .unwrap();
+    if user.is_none() {
+        return HttpResponse::NotFound().body("Pocket not connected");
+    }
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    let pocket_stats = data.lock().unwrap().pocket_stats.clone();
+    actix_web::rt::spawn(async move {
+        let res = sync_user_pocket(&dtk_user_body.id, true, pocket_index).await;
+        pocket_stats.invalidate(&dtk_user_body.id);
+        match res {
+            Ok(summary) => log::info!("[POCKET] full resync for {} => {:?}", dtk_user_body.id, summary),
+            Err(err) => log::error!("[POCKET] full resync failed for {} => {}", dtk_user_body.id, err),
+        }
+    });
+    HttpResponse::Accepted().finish()
 }
 
 fn get_pocket_filters(
     id: Option<String>,
-    src_type: Option<String>,
-    filter_tags: Vec<String>,
-    filter_search: Option<String>,
+    date_filters: &PocketDateFilterRequest,
+    query: &PocketQuery,
 ) -> mongodb::bson::Document {
     let mut filters = doc! {};
+    // dates and query are validated by the handlers
+    date_filters.apply(&mut filters).ok();
+    query.apply(&mut filters).ok();
+
+    if let Some(id) = id {
+        filters.insert("user_id", id);
+    }
+
+    filters
+}
+
+/// Read and validate `query`, the legacy `filter_search` and `filter_tags` fill it in
+fn get_pocket_query(req_body: &str, payload: &DtkRequestBody) -> Result<PocketQuery, String> {
+    let query_request = serde_json::from_str::<PocketQueryRequest>(req_body).map_err(|err| err.to_string())?;
+    let mut query = query_request.query.unwrap_or_default();
+    if query.search.is_none() && !payload.filter_search.is_empty() {
+        query.search = Some(payload.filter_search.clone());
+    }
+    query.tags_any.extend(payload.filter_tags.iter().cloned());
+    query.apply(&mut doc! {})?;
+    Ok(query)
+}
+
+/// Read and validate `added_after` / `read_before`
+fn get_pocket_date_filters(req_body: &str) -> Result<PocketDateFilterRequest, String> {
+    let date_filters = serde_json::from_str::<PocketDateFilterRequest>(req_body).map_err(|err| err.to_string())?;
+    date_filters.apply(&mut doc! {})?;
+    Ok(date_filters)
+}
 
-    if filter_search.is_some()
-        && filter_search.as_ref().unwrap().len() > 0
-        && is_valid_mongo_search(filter_search.as_ref().unwrap())
-    {
-        filters.insert("$text", doc! { "$search": filter_search.unwrap() });
+/// Read and validate `limit`, `cursor`, `sort` and `order`
+fn get_pocket_page_query(req_body: &str, query: &PocketQuery) -> Result<PocketPageQuery, String> {
+    let page_request = serde_json::from_str::<PocketPageRequest>(req_body).map_err(|err| err.to_string())?;
+    page_request.parse(query.text_search().is_some())
+}
+
+/// Search the local index when it has all the user items, None to fall back to mongodb `$text`
+async fn search_pocket_index(
+    index: Option<Arc<PocketIndex>>,
+    user_id: &str,
+    query: &PocketQuery,
+) -> Option<Vec<PocketSearchHit>> {
+    let (index, search) = (index?, query.text_search()?);
+    // items not indexed yet would be missing from the results, until the backfill catches up
+    match pocket::count_pocket_items(user_id).await {
+        Ok(count) if index.user_count(user_id) >= count => (),
+        Ok(count) => {
+            log::debug!("[POCKET] Search index has {} of {} items for {}", index.user_count(user_id), count, user_id);
+            return None;
+        }
+        Err(err) => {
+            log::error!("[POCKET] Could not count items of {} => {}", user_id, err);
+            return None;
+        }
     }
-    if id.is_some() {
-        filters.insert("user_id", id.as_ref().unwrap());
+    match index.search(user_id, &search, get_pocket_index_max_hits()) {
+        Ok(hits) => Some(hits),
+        Err(err) => {
+            log::error!("[POCKET] Search index failed for {} => {}", user_id, err);
+            None
+        }
     }
-    if src_type.is_some() {
-        filters.insert("src_type", src_type.unwrap());
+}
+
+fn get_pocket_response(
+    page: PocketPage,
+    unique_tags: Vec<String>,
+    mut highlights: BTreeMap<String, String>,
+) -> DtkPocketResponse {
+    highlights.retain(|item_id, _| page.items.iter().any(|item| &item.item_id == item_id));
+    DtkPocketResponse {
+        qualified: page.items.into_iter().map(QualifiedPocketData::from).collect(),
+        unique_tags,
+        instagram: page.instagram,
+        twitter: page.twitter,
+        total: page.total,
+        next_cursor: page.next_cursor,
+        facets: page.facets,
+        highlights,
     }
-    if filter_tags.len() > 0 {
-        filters.insert("tags", doc! {"$in": filter_tags});
+}
+
+/// Too many requests from this ip for this endpoint, see `max_endpoint_count`
+fn rate_limited(req: &HttpRequest, data: web::Data<Mutex<AppState>>) -> Option<HttpResponse> {
+    let max = data.lock().unwrap().max_endpoint_count;
+    let count = inc_request_count(req, data);
+    match count as u64 > max {
+        true => Some(HttpResponse::TooManyRequests().body("Too many requests")),
+        false => None,
     }
+}
 
-    filters
+/// Enabled profile at the `slug` path segment, RUSTY_POCKET_PUBLIC_SLUG without one
+async fn get_public_profile(req: &HttpRequest) -> Result<PocketProfile, HttpResponse> {
+    let slug = match req.match_info().get("slug") {
+        Some(slug) => slug.to_string(),
+        None => get_pocket_public_slug(),
+    };
+    match pocket_profile::get_public_profile(&slug).await {
+        Ok(Some(profile)) => Ok(profile),
+        Ok(None) => Err(HttpResponse::NotFound().body("Profile not found")),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
 }
 
+fn public_cache_control() -> (&'static str, String) {
+    ("Cache-Control", format!("public, max-age={}", get_pocket_public_max_age()))
+}
+
+/// Items listed on a public profile, same query as `/pocket/private`
 pub async fn get_public_pocket(
-    (_req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
-    let payload = get_data_from_body(req_body);
-    let client = get_dtkmongo_client(get_mongodb_uri().as_str()).await;
-    let db_name = if is_rusty_dev() {
-        "baakey_dev_rusty"
-    } else {
-        "baakey_prod_rusty"
+    if let Some(limited) = rate_limited(&req, data) {
+        return limited;
+    }
+    let req_body = match req_body.trim().is_empty() {
+        true => "{}".to_string(),
+        false => req_body,
+    };
+    let date_filters = match get_pocket_date_filters(&req_body) {
+        Ok(date_filters) => date_filters,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let payload = get_data_from_body(req_body.clone());
+    let query = match get_pocket_query(&req_body, &payload) {
+        Ok(query) => query,
+        Err(err) => return HttpResponse::BadRequest().body(err),
     };
-    let user_coll = client.database(db_name).collection::<Document>("users");
+    let page_query = match get_pocket_page_query(&req_body, &query) {
+        Ok(page_query) => page_query,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let profile = match get_public_profile(&req).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let mut filters = get_pocket_filters(Some(profile.user_id.clone()), &date_filters, &query);
+    let mut and = filters.get_array("$and").cloned().unwrap_or_default();
+    and.push(profile.public_filter().into());
+    filters.insert("$and", and);
+    let tags_filter = doc! { "user_id": &profile.user_id, "$and": [profile.public_filter()] };
 
-    let root_user = user_coll
-        .find_one(
-            mongodb::bson::doc! {
-                "email": "baakey@rusty.com",
-            },
-            None,
-        )
+    let page = pocket::get_pocket_page(filters, &page_query, false).await;
+    match (page, pocket::get_pocket_tags(tags_filter).await) {
+        (Ok(page), Ok(unique_tags)) => HttpResponse::Ok()
+            .insert_header(public_cache_control())
+            .json(get_pocket_response(page, unique_tags, BTreeMap::new())),
+        (Err(err), _) | (_, Err(err)) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// One public or unlisted item of a public profile
+pub async fn get_public_pocket_item(
+    (req, _req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    if let Some(limited) = rate_limited(&req, data) {
+        return limited;
+    }
+    let profile = match get_public_profile(&req).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let item_id = req.match_info().get("item_id").unwrap_or_default();
+    let filters = doc! { "user_id": &profile.user_id, "item_id": item_id };
+    let item = pocket::get_pocket_data(filters, false)
         .await
-        .unwrap();
+        .into_iter()
+        .find(|item| profile.visibility(item) != PocketVisibility::Private);
+    match item {
+        Some(item) => HttpResponse::Ok()
+            .insert_header(public_cache_control())
+            .json(QualifiedPocketData::from(item)),
+        None => HttpResponse::NotFound().body("Item not found"),
+    }
+}
 
-    let root_user_id = root_user
-        .unwrap()
-        .get("_id")
-        .unwrap()
-        .as_object_id()
-        .unwrap()
-        .to_string();
+/// Public collections of a public profile, without their items
+pub async fn get_public_pocket_collections(
+    (req, _req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    if let Some(limited) = rate_limited(&req, data) {
+        return limited;
+    }
+    let profile = match get_public_profile(&req).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    match pocket_collection::get_pocket_collections(&profile.user_id, Some(PocketVisibility::Public)).await {
+        Ok(collections) => HttpResponse::Ok().insert_header(public_cache_control()).json(collections),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
 
-    log::info!("[Payload from body] => {:#?}", payload);
+/// Public or unlisted collection of a public profile, private items are left out
+pub async fn get_public_pocket_collection(
+    (req, _req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    if let Some(limited) = rate_limited(&req, data) {
+        return limited;
+    }
+    let profile = match get_public_profile(&req).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let slug = req.match_info().get("collection").unwrap_or_default();
+    match pocket_collection::get_pocket_collection(&profile.user_id, slug).await {
+        Ok(Some(collection)) if collection.visibility != PocketVisibility::Private => HttpResponse::Ok()
+            .insert_header(public_cache_control())
+            .json(pocket_collection::view_pocket_collection(&collection, Some(&profile)).await),
+        Ok(_) => HttpResponse::NotFound().body("Collection not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// RSS, Atom or JSON feed of a public profile, of a tag and its descendants when the path has one
+pub async fn get_public_pocket_feed(
+    (req, _req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    if let Some(limited) = rate_limited(&req, data) {
+        return limited;
+    }
+    let format = match req.match_info().get("format").unwrap_or_default().parse::<PocketFeedFormat>() {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let profile = match get_public_profile(&req).await {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let feed = match pocket_feed::get_pocket_feed(&profile, req.match_info().get("tag")).await {
+        Ok(feed) => feed,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let etag = ("ETag", feed.etag(format));
+    let last_modified = ("Last-Modified", pocket_feed::http_date(feed.last_modified()));
+    if feed.is_not_modified(format, header("If-None-Match"), header("If-Modified-Since")) {
+        return HttpResponse::NotModified()
+            .insert_header(etag)
+            .insert_header(last_modified)
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(etag)
+        .insert_header(last_modified)
+        .insert_header(public_cache_control())
+        .body(feed.render(format))
+}
 
-    let all = pocket::get_pocket_data(
-        get_pocket_filters(Some(root_user_id), None, [].to_vec(), Some(payload.filter_search)),
-        false,
-    )
-    .await;
+/// Public profile of the user
+pub async fn get_pocket_profile(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_profile::get_pocket_profile(&dtk_user_body.id).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => HttpResponse::NotFound().body("No profile"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
 
-    let dtk_pocket_data: Vec<QualifiedPocketData> = all
-        .clone()
-        .into_iter()
-        .map(|elem| QualifiedPocketData::from(elem))
-        .collect();
-
-    HttpResponse::Ok().json(DtkPocketResponse {
-        qualified: dtk_pocket_data
-            .into_iter()
-            .filter(|item| !item.rusty_pocket_item.tags.contains(&"private".to_string()))
-            .collect(),
-        unique_tags: [].to_vec(),
-        instagram: [].to_vec(),
-        twitter: [].to_vec(),
-    })
+/// Create or update the public profile of the user
+pub async fn save_pocket_profile(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let profile_request = match serde_json::from_str::<PocketProfileRequest>(&req_body) {
+        Ok(profile_request) => profile_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_profile::save_pocket_profile(&dtk_user_body.id, &profile_request.profile).await {
+        Ok(profile) => HttpResponse::Ok().json(profile),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
 }
 
-pub async fn get_private_pocket(
-    (_req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState<'_>>>),
+/// Make items or tags public, unlisted or private
+pub async fn set_pocket_visibility(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
 ) -> impl Responder {
-    let payload = get_data_from_body(req_body);
+    let visibility_request = match serde_json::from_str::<PocketVisibilityRequest>(&req_body) {
+        Ok(visibility_request) => visibility_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_profile::set_pocket_visibility(&dtk_user_body.id, &visibility_request).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
 
-    log::info!("[Payload from body] => {:#?}", payload);
+/// Collections of the user, without their items
+pub async fn get_pocket_collections(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_collection::get_pocket_collections(&dtk_user_body.id, None).await {
+        Ok(collections) => HttpResponse::Ok().json(collections),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
 
-    let id = payload.id;
-    let filter_tags = payload.filter_tags.clone();
-    let filter_search = payload.filter_search.clone();
+/// One collection of the user with its items in order
+pub async fn get_pocket_collection(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let collection_request = match serde_json::from_str::<PocketCollectionSlugRequest>(&req_body) {
+        Ok(collection_request) => collection_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_collection::get_pocket_collection(&dtk_user_body.id, &collection_request.slug).await {
+        Ok(Some(collection)) => {
+            HttpResponse::Ok().json(pocket_collection::view_pocket_collection(&collection, None).await)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Collection not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
 
-    let without_filters =
-        pocket::get_pocket_data(get_pocket_filters(Some(id.clone()), None, [].to_vec(), None), false).await;
+/// Create or update a collection of the user
+pub async fn save_pocket_collection(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let collection_request = match serde_json::from_str::<PocketCollectionRequest>(&req_body) {
+        Ok(collection_request) => collection_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_collection::save_pocket_collection(&dtk_user_body.id, &collection_request.collection).await {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
 
-    let all = pocket::get_pocket_data(
-        get_pocket_filters(Some(id.clone()), None, filter_tags.clone(), Some(filter_search.clone())),
-        false,
-    )
-    .await;
+/// Add, remove, move, annotate or reorder the items of a collection
+pub async fn edit_pocket_collection(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let edit_request = match serde_json::from_str::<PocketCollectionEditRequest>(&req_body) {
+        Ok(edit_request) => edit_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_collection::edit_pocket_collection(&dtk_user_body.id, &edit_request.slug, &edit_request.edits).await {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
 
-    let instagram_tags = if filter_tags.clone().contains(&"instagram".to_string()) {
-        filter_tags.clone()
-    } else {
-        filter_tags
-            .clone()
-            .into_iter()
-            .chain(["instagram".to_string()].to_vec().into_iter())
-            .collect()
-    };
-    let instagram = pocket::get_pocket_data(
-        get_pocket_filters(
-            Some(id.clone()),
-            Some("instagram".to_string()),
-            instagram_tags,
-            Some(filter_search.clone()),
-        ),
-        false,
-    )
-    .await;
-    let twitter_tags = if filter_tags.clone().contains(&"twitter".to_string()) {
-        filter_tags.clone()
-    } else {
-        filter_tags
-            .clone()
-            .into_iter()
-            .chain(["twitter".to_string()].to_vec().into_iter())
-            .collect()
-    };
-    let twitter = pocket::get_pocket_data(
-        get_pocket_filters(
-            Some(id.clone()),
-            Some("twitter".to_string()),
-            twitter_tags,
-            Some(filter_search),
-        ),
-        true,
-    )
-    .await;
-
-    let mut unique_tags: HashSet<String> = HashSet::new();
-    for item in without_filters.iter().cloned() {
-        unique_tags.extend(item.tags);
-    }
-    let mut sorted_tags = unique_tags.into_iter().collect::<Vec<String>>();
-    sorted_tags.sort_by(|a, b| a.cmp(b));
-
-    let qualified_pocket_data: Vec<QualifiedPocketData> = all
-        .clone()
-        .into_iter()
-        .map(|elem| QualifiedPocketData::from(elem))
-        .collect();
-
-    HttpResponse::Ok().json(DtkPocketResponse {
-        qualified: qualified_pocket_data,
-        unique_tags: sorted_tags,
-        instagram,
-        twitter,
-    })
+/// Delete a collection of the user, its items are kept
+pub async fn delete_pocket_collection(
+    (req, req_body, _data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let collection_request = match serde_json::from_str::<PocketCollectionSlugRequest>(&req_body) {
+        Ok(collection_request) => collection_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let dtk_user_body = get_user_data_from_body(&req, req_body);
+    match pocket_collection::delete_pocket_collection(&dtk_user_body.id, &collection_request.slug).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Collection not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+pub async fn get_private_pocket(
+    (req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>),
+) -> impl Responder {
+    let date_filters = match get_pocket_date_filters(&req_body) {
+        Ok(date_filters) => date_filters,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let payload = get_user_data_from_body(&req, req_body.clone());
+    let mut query = match get_pocket_query(&req_body, &payload) {
+        Ok(query) => query,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let mut page_query = match get_pocket_page_query(&req_body, &query) {
+        Ok(page_query) => page_query,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    log::info!("[Payload from body] => {:#?}", payload);
+
+    let pocket_index = data.lock().unwrap().pocket_index.clone();
+    let hits = search_pocket_index(pocket_index, &payload.id, &query).await;
+    // the local index replaces `$text`, items are then ranked by its scores
+    if hits.is_some() {
+        query.search = None;
+    }
+    let tags_filter = doc! { "user_id": &payload.id };
+    let mut filters = get_pocket_filters(Some(payload.id), &date_filters, &query);
+    let mut highlights = BTreeMap::new();
+    if let Some(hits) = hits {
+        let ranking: Vec<String> = hits.iter().map(|hit| hit.item_id.clone()).collect();
+        filters.insert("item_id", doc! { "$in": &ranking });
+        page_query.ranking = Some(ranking);
+        highlights = hits.into_iter().map(|hit| (hit.item_id, hit.highlight)).collect();
+    }
+
+    let page = pocket::get_pocket_page(filters, &page_query, true).await;
+    match (page, pocket::get_pocket_tags(tags_filter).await) {
+        (Ok(page), Ok(unique_tags)) => HttpResponse::Ok().json(get_pocket_response(page, unique_tags, highlights)),
+        (Err(err), _) | (_, Err(err)) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
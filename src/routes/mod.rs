@@ -1,2 +1,3 @@
 pub mod common;
-pub mod chat;
\ No newline at end of file
+pub mod chat;
+pub mod notify;
\ No newline at end of file
@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusty_lib::dtknotify::{
+    notify::{get_notifications, get_notify_settings, mark_notifications_read, save_notify_settings},
+    notify_channel::check_webhook_url,
+    notify_model::{NotifyInboxRequest, NotifySettingsRequest},
+    notify_smtp::is_valid_email,
+};
+
+use crate::{
+    app_state::AppState,
+    toolz::utils::{get_user_data_from_body, inc_user_request_count},
+};
+
+pub async fn get_inbox((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
+    inc_user_request_count(&req, data);
+    let inbox_request = match serde_json::from_str::<NotifyInboxRequest>(&req_body) {
+        Ok(inbox_request) => inbox_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let payload = get_user_data_from_body(&req, req_body);
+    let notifications = get_notifications(&payload.id, inbox_request.unread_only.unwrap_or(false)).await;
+    HttpResponse::Ok().json(notifications)
+}
+
+pub async fn read_inbox((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
+    inc_user_request_count(&req, data);
+    let inbox_request = match serde_json::from_str::<NotifyInboxRequest>(&req_body) {
+        Ok(inbox_request) => inbox_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let payload = get_user_data_from_body(&req, req_body);
+    let modified = mark_notifications_read(&payload.id, inbox_request.notification_ids).await;
+    HttpResponse::Ok().json(serde_json::json!({ "read": modified }))
+}
+
+pub async fn get_settings((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
+    inc_user_request_count(&req, data);
+    let payload = get_user_data_from_body(&req, req_body);
+    HttpResponse::Ok().json(get_notify_settings(&payload.id).await)
+}
+
+/// Update the delivery settings, omitted fields are left unchanged
+pub async fn save_settings((req, req_body, data): (HttpRequest, String, web::Data<Mutex<AppState>>)) -> impl Responder {
+    inc_user_request_count(&req, data);
+    let settings_request = match serde_json::from_str::<NotifySettingsRequest>(&req_body) {
+        Ok(settings_request) => settings_request,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let payload = get_user_data_from_body(&req, req_body);
+    let mut settings = get_notify_settings(&payload.id).await;
+    if let Some(channels) = settings_request.channels {
+        settings.channels = channels;
+    }
+    if let Some(email) = settings_request.email {
+        if !is_valid_email(&email) {
+            return HttpResponse::BadRequest().body("Invalid email");
+        }
+        settings.email = Some(email);
+    }
+    if let Some(webhook_url) = settings_request.webhook_url {
+        if let Err(err) = check_webhook_url(&webhook_url) {
+            return HttpResponse::BadRequest().body(err.to_string());
+        }
+        settings.webhook_url = Some(webhook_url);
+    }
+    save_notify_settings(&settings).await;
+    HttpResponse::Ok().json(settings)
+}
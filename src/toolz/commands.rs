@@ -0,0 +1,40 @@
+use crate::core_args::CoreCommand;
+use rusty_lib::dtkpocket::pocket_index::PocketIndex;
+use rusty_lib::dtkpocket::pocket_transfer::{import_pocket_bookmarks, PocketImportOptions};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Run a command given on the command line, the server is not started
+pub async fn run_core_command(command: CoreCommand, pocket_index: Option<Arc<PocketIndex>>) -> std::io::Result<()> {
+    match command {
+        CoreCommand::ImportPocket {
+            user_id,
+            file,
+            format,
+            tag_map,
+            tags,
+            local_only,
+        } => {
+            let data = std::fs::read(&file)?;
+            let mut options = PocketImportOptions {
+                format,
+                tag_map: BTreeMap::new(),
+                tags,
+                local_only,
+            };
+            for mapping in tag_map {
+                let (from, to) = mapping
+                    .split_once('=')
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid tag map: {mapping}")))?;
+                options.tag_map.insert(from.to_string(), to.to_string());
+            }
+            let data = String::from_utf8_lossy(&data);
+            let summary = import_pocket_bookmarks(&user_id, &data, &options, pocket_index)
+                .await
+                .map_err(|err| Error::other(err.to_string()))?;
+            println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+            Ok(())
+        }
+    }
+}
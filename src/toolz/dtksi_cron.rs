@@ -7,7 +7,7 @@ use std::sync::Mutex;
 use super::utils::display_cron_debug;
 
 // main application tick tracker with AppState
-pub async fn run_main_cron(shared_data: web::Data<Mutex<AppState<'static>>>) {
+pub async fn run_main_cron(shared_data: web::Data<Mutex<AppState>>) {
     spawn(async move {
         let mut interval = interval(shared_data.lock().unwrap().cron_time);
         loop {
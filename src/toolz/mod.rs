@@ -1,3 +1,4 @@
 pub mod utils;
 pub mod scheduler;
 pub mod dtksi_cron;
+pub mod commands;
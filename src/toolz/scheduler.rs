@@ -3,11 +3,17 @@ use actix::prelude::*;
 use actix_web::web;
 use chrono::Local;
 use cron::Schedule;
+use rusty_lib::dtknotify::notify::Notifier;
+use rusty_lib::dtknotify::notify_model::DtkNotification;
+use rusty_lib::dtknotify::notify_utils::get_notify_admin_id;
 use rusty_lib::dtkpocket::pocket::save_all_pocket;
+use rusty_lib::dtkpocket::pocket_archive::run_pocket_archiver;
+use rusty_lib::dtkpocket::pocket_health::run_pocket_link_checker;
 use rusty_lib::dtkpocket::pocket_utils::import_github_stars;
 use rusty_lib::dtkutils::dtk_github::save_all_starred;
 use rusty_lib::dtkutils::utils::is_rusty_dev;
-use std::sync::Mutex;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 use std::{str::FromStr, time::Duration};
 
 /// process main task with AppState in ref_data
@@ -22,22 +28,25 @@ fn process_main_task(sch: &Scheduler) {
         sch.ref_data.lock().unwrap().arc_map.lock().unwrap().keys().len()
     );
     let mut_r_data = sch.ref_data.lock().unwrap();
-    if mut_r_data.dev_mode == false {
+    if !mut_r_data.dev_mode {
         log::debug!("[RUSTY_CRON]: (BEFORE DELETE) => {:#?}", mut_r_data);
         mut_r_data.arc_map.lock().unwrap().clear();
         log::debug!("[RUSTY_CRON]: ArcMap Cleared !");
     }
 
     if !is_rusty_dev() {
-        actix_web::rt::spawn(async move {
-            save_all_pocket().await;
-        });
-        actix_web::rt::spawn(async move {
-            save_all_starred().await;
-        });
-        actix_web::rt::spawn(async move {
-            import_github_stars().await;
-        });
+        let notifier = mut_r_data.notifier.clone();
+        let pocket_index = mut_r_data.pocket_index.clone();
+        let pocket_stats = mut_r_data.pocket_stats.clone();
+        spawn_reported(
+            notifier.clone(),
+            "save_all_pocket",
+            save_all_pocket(pocket_index.clone(), pocket_stats),
+        );
+        spawn_reported(notifier.clone(), "archive_pocket", run_pocket_archiver());
+        spawn_reported(notifier.clone(), "check_pocket_links", run_pocket_link_checker(pocket_index));
+        spawn_reported(notifier.clone(), "save_all_starred", save_all_starred());
+        spawn_reported(notifier, "import_github_stars", import_github_stars());
     } else {
         log::info!("save_pocket is disabled in dev mode");
     }
@@ -47,20 +56,39 @@ fn process_main_task(sch: &Scheduler) {
     log::debug!("^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^");
 }
 
+/// spawn a scheduled job, a panic is reported to RUSTY_NOTIFY_ADMIN_ID
+fn spawn_reported<F>(notifier: Arc<Notifier>, job: &'static str, task: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    let handle = actix_web::rt::spawn(task);
+    actix_web::rt::spawn(async move {
+        if let Err(err) = handle.await {
+            log::error!("[SCHEDULER] {} failed => {}", job, err);
+            if let Some(admin_id) = get_notify_admin_id() {
+                let message = format!("{} failed: {}", job, err);
+                notifier
+                    .notify(DtkNotification::new(&admin_id, "scheduler", "Scheduled job failed", &message))
+                    .await;
+            }
+        }
+    });
+}
+
 // Define msg
 #[derive(Message)]
 #[rtype(result = "Result<bool, std::io::Error>")]
 pub struct Ping {
-    pub ref_data: web::Data<Mutex<AppState<'static>>>,
+    pub ref_data: web::Data<Mutex<AppState>>,
 }
 
 // Define actor
 pub struct Scheduler {
-    pub ref_data: web::Data<Mutex<AppState<'static>>>,
+    pub ref_data: web::Data<Mutex<AppState>>,
 }
 
 // send AppState to scheduler context
-pub async fn start_scheduler(shared_data: web::Data<Mutex<AppState<'static>>>) {
+pub async fn start_scheduler(shared_data: web::Data<Mutex<AppState>>) {
     let addr = Scheduler {
         ref_data: shared_data.clone(),
     }
@@ -75,7 +103,7 @@ pub async fn start_scheduler(shared_data: web::Data<Mutex<AppState<'static>>>) {
 // Task Event logic
 impl Scheduler {
     fn schedule_task(&self, ctx: &mut Context<Self>) {
-        process_main_task(&self);
+        process_main_task(self);
         ctx.run_later(
             duration_until_next(&self.ref_data.lock().unwrap().scheduler_time[..]),
             move |this, ctx| this.schedule_task(ctx),
@@ -101,7 +129,7 @@ impl Actor for Scheduler {
     }
 }
 
-impl<'a> Handler<Ping> for Scheduler {
+impl Handler<Ping> for Scheduler {
     type Result = Result<bool, std::io::Error>;
 
     // Save AppState
@@ -1,12 +1,16 @@
 use crate::app_state::AppState;
+use crate::jwt_auth::JwtAuth;
 use actix_web::web;
 use actix_web::HttpRequest;
 use chrono::Local;
+use rusty_lib::dtknotify::notify::NotifyMessenger;
+use rusty_lib::dtkutils::dtk_reqwest::{get_data_from_body, DtkRequestBody};
+use rusty_lib::dtkutils::limit_tracker::LimitTracker;
 use rusty_lib::dtkutils::utils::format_datetime;
 use rusty_lib::dtkutils::utils::log_env_vars;
 use std::sync::Mutex;
 
-pub fn display_cron_debug(shared_data: &web::Data<Mutex<AppState<'static>>>) {
+pub fn display_cron_debug(shared_data: &web::Data<Mutex<AppState>>) {
     log::debug!("[RUSTY_CRON]: => =========================>");
     log::debug!("[RUSTY_CRON]: => {}", format_datetime(Local::now()));
     if let Ok(mut_r_data) = &mut shared_data.lock() {
@@ -21,29 +25,64 @@ pub fn display_cron_debug(shared_data: &web::Data<Mutex<AppState<'static>>>) {
     log::debug!("-------------------------------------------");
 }
 
-pub fn setup_core_env(shared_data: &web::Data<Mutex<AppState<'static>>>) {
+pub fn setup_core_env(shared_data: &web::Data<Mutex<AppState>>) {
     let log_level = shared_data.lock().unwrap().log_level.to_string();
     let dev_mode = shared_data.lock().unwrap().dev_mode;
     std::env::set_var("RUST_LOG", "actix_web=info");
     std::env::set_var("RUSTY_DEV_MODE", dev_mode.to_string());
     env_logger::init_from_env(
         env_logger::Env::new()
-            .filter_or("RUSTY_LOG_LEVEL", format!("{log_level}").to_lowercase())
+            .filter_or("RUSTY_LOG_LEVEL", log_level.to_string().to_lowercase())
             .write_style_or("RUSTY_LOG_STYLE", "always"),
     );
     log_env_vars();
 }
 
-pub fn inc_request_count(req: &HttpRequest, data: web::Data<Mutex<AppState<'_>>>) -> u32 {
+pub fn inc_request_count(req: &HttpRequest, data: web::Data<Mutex<AppState>>) -> u32 {
     let mut state = data.lock().unwrap();
     let ip = get_ip_addr(req);
-    let request_id = req.path();
-    let key = Box::leak(format!("{request_id}-{ip}").into_boxed_str());
-    state.update_endpoint_count(key);
-    let count = state.get_endpoint_count(key);
+    // the route pattern, not the path, so that path segments can't make new keys
+    let request_id = req.match_pattern().unwrap_or_default();
+    let key = format!("{request_id}-{ip}");
+    state.update_endpoint_count(&key);
+    state.get_endpoint_count(&key)
+}
+
+/// Count the request of a JwtAuth guarded route, the verified user gets a notification
+/// for each quota threshold crossed
+pub fn inc_user_request_count(req: &HttpRequest, data: web::Data<Mutex<AppState>>) -> u32 {
+    let count = inc_request_count(req, data.clone());
+    if let Ok(jwt) = JwtAuth::from_headers(req.headers()) {
+        let state = data.lock().unwrap();
+        let messenger = NotifyMessenger {
+            notifier: state.notifier.clone(),
+            user_id: jwt.claims.id,
+            kind: "quota".to_string(),
+            title: "Quota warning".to_string(),
+        };
+        let max = state.max_endpoint_count as usize;
+        LimitTracker::with_value(&messenger, max, count as usize - 1).set_value(count as usize);
+    }
     count
 }
 
+/// User of a JwtAuth guarded route, from the `user_id` header the guard checked against the token
+pub fn get_user_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get("user_id")
+        .and_then(|user_id| user_id.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Body of a JwtAuth guarded route, its user id is the verified one and not the one sent in the body
+pub fn get_user_data_from_body(req: &HttpRequest, req_body: String) -> DtkRequestBody {
+    DtkRequestBody {
+        id: get_user_id(req),
+        ..get_data_from_body(req_body)
+    }
+}
+
 pub fn get_ip_addr(req: &HttpRequest) -> String {
     let ip = match req.connection_info().realip_remote_addr() {
         Some(val) => val.to_string(),
@@ -1,2 +1,4 @@
+pub mod notify_channel;
 pub mod server;
-pub mod session;
\ No newline at end of file
+pub mod session;
+pub mod sse_session;
\ No newline at end of file
@@ -0,0 +1,42 @@
+//! Deliver notifications to every open websocket or SSE session of the user
+
+use actix::Addr;
+use futures_util::future::{BoxFuture, FutureExt};
+use rusty_lib::dtknotify::notify_channel::NotificationChannel;
+use rusty_lib::dtknotify::notify_model::{DtkNotification, DtkNotifySettings};
+use rusty_lib::dtkutils::dtk_error::DtkError;
+
+use super::server;
+
+pub struct ChatWsChannel {
+    pub addr: Addr<server::ChatServer>,
+}
+
+impl NotificationChannel for ChatWsChannel {
+    fn name(&self) -> &'static str {
+        "ws"
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        notification: &'a DtkNotification,
+        _settings: &'a DtkNotifySettings,
+    ) -> BoxFuture<'a, Result<(), DtkError>> {
+        async move {
+            let offline = self
+                .addr
+                .send(server::NotifyUsers {
+                    user_ids: vec![notification.user_id.clone()],
+                    msg: serde_json::to_string(notification).unwrap(),
+                })
+                .await
+                .map_err(|err| DtkError::from(err.to_string().as_str()))?;
+            // offline users will find it in their inbox
+            if !offline.is_empty() {
+                log::debug!("[NOTIFY] {} is offline", notification.user_id);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -18,13 +18,15 @@ use rand::{self, rngs::ThreadRng, Rng};
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
-/// Message for chat server communications
+// Message for chat server communications
 
 /// New chat session is created
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    /// Authenticated user behind the session
+    pub user_id: Option<String>,
 }
 
 /// Session is disconnected
@@ -46,6 +48,29 @@ pub struct ClientMessage {
     pub room: String,
 }
 
+/// Send message to the sessions of a room, except the ones of a user
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomMessage {
+    /// User whose sessions are skipped
+    pub skip_user_id: String,
+    /// Peer message
+    pub msg: String,
+    /// Room name
+    pub room: String,
+}
+
+/// Send message to every session of the given users,
+/// returns the users that have no session open
+pub struct NotifyUsers {
+    pub user_ids: Vec<String>,
+    pub msg: String,
+}
+
+impl actix::Message for NotifyUsers {
+    type Result = Vec<String>;
+}
+
 /// List of available rooms
 pub struct ListRooms;
 
@@ -71,6 +96,7 @@ pub struct Join {
 pub struct ChatServer {
     sessions: HashMap<usize, Recipient<Message>>,
     rooms: HashMap<String, HashSet<usize>>,
+    users: HashMap<String, HashSet<usize>>,
     rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
 }
@@ -84,6 +110,7 @@ impl ChatServer {
         ChatServer {
             sessions: HashMap::new(),
             rooms,
+            users: HashMap::new(),
             rng: rand::thread_rng(),
             visitor_count,
         }
@@ -124,11 +151,14 @@ impl Handler<Connect> for ChatServer {
         // register session with random id
         let id = self.rng.gen::<usize>();
         self.sessions.insert(id, msg.addr);
+        if let Some(user_id) = msg.user_id {
+            self.users.entry(user_id).or_default().insert(id);
+        }
 
         // auto join session to main room
         self.rooms
             .entry("main".to_owned())
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(id);
 
         let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
@@ -157,6 +187,10 @@ impl Handler<Disconnect> for ChatServer {
                 }
             }
         }
+        self.users.retain(|_, sessions| {
+            sessions.remove(&msg.id);
+            !sessions.is_empty()
+        });
     }
 }
 
@@ -169,6 +203,44 @@ impl Handler<ClientMessage> for ChatServer {
     }
 }
 
+/// Handler for `RoomMessage` message.
+impl Handler<RoomMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomMessage, _: &mut Context<Self>) {
+        let skipped = self.users.get(&msg.skip_user_id).cloned().unwrap_or_default();
+        if let Some(sessions) = self.rooms.get(&msg.room) {
+            for id in sessions.difference(&skipped) {
+                if let Some(addr) = self.sessions.get(id) {
+                    addr.do_send(Message(msg.msg.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Handler for `NotifyUsers` message.
+impl Handler<NotifyUsers> for ChatServer {
+    type Result = MessageResult<NotifyUsers>;
+
+    fn handle(&mut self, msg: NotifyUsers, _: &mut Context<Self>) -> Self::Result {
+        let mut offline = Vec::new();
+        for user_id in msg.user_ids {
+            match self.users.get(&user_id) {
+                Some(sessions) => {
+                    for id in sessions {
+                        if let Some(addr) = self.sessions.get(id) {
+                            addr.do_send(Message(msg.msg.clone()));
+                        }
+                    }
+                }
+                None => offline.push(user_id),
+            }
+        }
+        MessageResult(offline)
+    }
+}
+
 /// Handler for `ListRooms` message.
 impl Handler<ListRooms> for ChatServer {
     type Result = MessageResult<ListRooms>;
@@ -200,7 +272,7 @@ impl Handler<Join> for ChatServer {
             }
         }
 
-        self.rooms.entry(name.clone()).or_insert_with(HashSet::new).insert(id);
+        self.rooms.entry(name.clone()).or_default().insert(id);
 
     }
 }
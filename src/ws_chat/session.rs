@@ -1,13 +1,14 @@
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
 use rusty_lib::{
     dtkchat::{
-        chat::{get_all_chat_users, get_all_dtk_chat_for_user},
-        chat_model::{ChatForUsers, DtkChatUser},
+        chat_bot::{BotContext, BotRegistry, BotReply, ChatBotMessage},
+        chat_model::DtkChatUser,
     },
-    dtkutils::dtk_reqwest::get_token_info,
+    dtkutils::{dtk_error::DtkError, dtk_reqwest::get_token_info},
 };
 
 use super::server;
@@ -44,6 +45,9 @@ pub struct WsChatSession {
 
     /// Channel ID
     pub channel_id: Option<String>,
+
+    /// Bots answering the slash commands
+    pub bots: Arc<BotRegistry>,
 }
 
 impl WsChatSession {
@@ -70,6 +74,88 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Run the bot registered for the command, with the verified user context when it requires one
+    fn run_bot(&self, line: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let (bot, args) = match self.bots.find(line) {
+            Some(found) => found,
+            None => {
+                ctx.text(format!("!!! unknown command: {line:?}"));
+                return;
+            }
+        };
+        let requires_auth = bot.requires_auth();
+        let command = bot.command();
+        let (token, user_id) = match (self.token.clone(), self.user_id.clone()) {
+            (Some(token), Some(user_id)) if requires_auth => (Some(token), user_id),
+            (_, user_id) if !requires_auth => (None, user_id.unwrap_or_default()),
+            _ => {
+                ctx.text("!!! authentication is required");
+                return;
+            }
+        };
+        let name = self.name.clone().unwrap_or_default();
+        let room = self.room.clone();
+        let future = async move {
+            let user = match token {
+                Some(token) => match get_token_info(token, user_id.clone()) {
+                    Ok(auth_data) if auth_data.id == user_id => DtkChatUser {
+                        id: user_id,
+                        name: auth_data.name,
+                        email: auth_data.email,
+                    },
+                    _ => return Err(DtkError::from("authentication failed")),
+                },
+                None => DtkChatUser {
+                    id: user_id,
+                    name,
+                    email: String::new(),
+                },
+            };
+            let bot_ctx = BotContext {
+                user,
+                channel_id: room,
+                args,
+            };
+            bot.run(&bot_ctx).await.map(|reply| (bot_ctx.user.id, reply))
+        };
+        let future = future.into_actor(self).map(move |res, act, ctx| match res {
+            Ok((user_id, reply)) => act.apply_bot_reply(command, &user_id, reply, ctx),
+            Err(err) => {
+                log::debug!("[BOT] {} failed for {} => {}", command, act.id, err);
+                ctx.text(format!("!!! {command}: {err}"));
+            }
+        });
+        // session commands must apply before the next message, e.g. a `/join` followed by a message
+        if requires_auth {
+            future.spawn(ctx);
+        } else {
+            future.wait(ctx);
+        }
+    }
+
+    /// Send the bot reply where it goes, or update the session
+    fn apply_bot_reply(&mut self, command: &str, user_id: &str, reply: BotReply, ctx: &mut ws::WebsocketContext<Self>) {
+        let wrap = |text: &str| serde_json::to_string(&ChatBotMessage::new(command, user_id, text)).unwrap();
+        match reply {
+            BotReply::Private(text) => ctx.text(wrap(&text)),
+            BotReply::Payload(text) => ctx.text(text),
+            // id 0 is never a session id, so the sender gets it too
+            BotReply::Room(text) => self.addr.do_send(server::ClientMessage {
+                id: 0,
+                msg: wrap(&text),
+                room: self.room.clone(),
+            }),
+            BotReply::Join(room) => {
+                self.room = room;
+                self.addr.do_send(server::Join {
+                    id: self.id,
+                    name: self.room.clone(),
+                });
+            }
+            BotReply::Rename(name) => self.name = Some(name),
+        }
+    }
 }
 
 impl Actor for WsChatSession {
@@ -88,7 +174,10 @@ impl Actor for WsChatSession {
         // across all routes within application
         let addr = ctx.address();
         self.addr
-            .send(server::Connect { addr: addr.recipient() })
+            .send(server::Connect {
+                addr: addr.recipient(),
+                user_id: self.user_id.clone(),
+            })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
@@ -141,97 +230,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 let m = text.trim();
                 // we check for /sss type of messages
                 if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-                            }
-                        }
-                        "/get" => {
-                            let token = self.token.as_ref().unwrap().clone();
-                            let user_id = self.user_id.as_ref().unwrap().clone();
-                            let recipient = ctx.address();
-                            let future = async move {
-                                let auth_data = get_token_info(token, user_id.clone()).unwrap();
-                                let chat = get_all_dtk_chat_for_user(DtkChatUser {
-                                    id: user_id.clone(),
-                                    name: auth_data.name,
-                                    email: auth_data.email,
-                                })
-                                .await;
-                                let users = get_all_chat_users().await;
-                                // send back to the user
-                                recipient.do_send(server::Message(
-                                    serde_json::to_string(&ChatForUsers {
-                                        chat: chat.clone(),
-                                        users: users.clone(),
-                                        id: "single".to_string(),
-                                        count: 0,
-                                    })
-                                    .unwrap(),
-                                ));
-                            };
-                            future.into_actor(self).spawn(ctx);
-                        }
-                        "/post" => {
-                            let token = self.token.as_ref().unwrap().clone();
-                            let user_id = self.user_id.as_ref().unwrap().clone();
-                            let room = self.room.clone();
-                            let id = self.id;
-                            let addr = self.addr.clone();
-                            let channel_id = self.channel_id.as_ref().unwrap().clone();
-                            let recipient = ctx.address();
-                            let future = async move {
-                                let auth_data = get_token_info(token, user_id.clone()).unwrap();
-                                let chat = get_all_dtk_chat_for_user(DtkChatUser {
-                                    id: user_id.clone(),
-                                    name: auth_data.name,
-                                    email: auth_data.email,
-                                })
-                                .await;
-                                let users = get_all_chat_users().await;
-                                // send back to the user
-                                recipient.do_send(server::Message(
-                                    serde_json::to_string(&ChatForUsers {
-                                        chat: chat.clone(),
-                                        users: users.clone(),
-                                        id: "single".to_string(),
-                                        count: 0,
-                                    })
-                                    .unwrap(),
-                                ));
-                                // send to all users in the room
-                                addr.do_send(server::ClientMessage {
-                                    id,
-                                    msg: serde_json::to_string(&ChatForUsers {
-                                        chat: chat
-                                            .into_iter()
-                                            .filter(|dtk_chat| dtk_chat.channel_id == channel_id)
-                                            .collect(),
-                                        users,
-                                        id: "multiple".to_string(),
-                                        count: 0,
-                                    })
-                                    .unwrap(),
-                                    room,
-                                });
-                            };
-                            future.into_actor(self).spawn(ctx);
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
-                    }
+                    self.run_bot(m, ctx);
                 } else {
                     let msg = if let Some(ref name) = self.name {
                         format!("{name}: {m}")
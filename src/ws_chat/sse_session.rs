@@ -0,0 +1,96 @@
+//! `SseChatSession` relays `ChatServer` room messages to a Server-Sent Events stream,
+//! for clients sitting behind proxies that block websocket upgrades.
+
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use futures_channel::mpsc::UnboundedSender;
+use rusty_lib::dtkchat::chat_utils::{format_sse_event, get_event_id};
+
+use super::server;
+
+/// How often keep-alive comments are sent, also used to notice closed streams
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub struct SseChatSession {
+    /// unique session id
+    pub id: usize,
+
+    /// joined room
+    pub room: String,
+
+    /// User ID
+    pub user_id: String,
+
+    /// Chat server
+    pub addr: Addr<server::ChatServer>,
+
+    /// Sender side of the SSE response body
+    pub tx: UnboundedSender<Bytes>,
+}
+
+impl SseChatSession {
+    /// helper method that sends a keep-alive comment every 15 seconds (KEEP_ALIVE_INTERVAL).
+    ///
+    /// the session stops as soon as the client closed the stream
+    fn keep_alive(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+            if act.tx.unbounded_send(Bytes::from_static(b": keep-alive\n\n")).is_err() {
+                println!("SSE client disconnected!");
+                ctx.stop();
+            }
+        });
+    }
+}
+
+impl Actor for SseChatSession {
+    type Context = Context<Self>;
+
+    /// Register with ChatServer, then join the channel room
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.keep_alive(ctx);
+
+        let addr = ctx.address();
+        self.addr
+            .send(server::Connect {
+                addr: addr.recipient(),
+                user_id: Some(self.user_id.clone()),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(res) => {
+                        act.id = res;
+                        act.addr.do_send(server::Join {
+                            id: act.id,
+                            name: act.room.clone(),
+                        });
+                    }
+                    // something is wrong with chat server
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        // notify chat server
+        self.addr.do_send(server::Disconnect { id: self.id });
+        Running::Stop
+    }
+}
+
+/// Forward messages from chat server to the event stream
+impl Handler<server::Message> for SseChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
+        let event_id = get_event_id(&msg.0);
+        if self.tx.unbounded_send(Bytes::from(format_sse_event(event_id.as_deref(), &msg.0))).is_err() {
+            ctx.stop();
+        }
+    }
+}